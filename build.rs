@@ -0,0 +1,30 @@
+//! Bakes in version details that `/version` reports
+
+use std::{fs, process::Command};
+
+fn main() {
+    // Builds from a source tarball have no git repo, so this just gets left unset
+    let commit = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok());
+    if let Some(commit) = commit {
+        println!("cargo:rustc-env=RAMBOT_GIT_COMMIT={}", commit.trim());
+    }
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+
+    // Only the lockfile knows which exact whisper-rs version got resolved
+    let whisper_rs = fs::read_to_string("Cargo.lock").ok().and_then(|lock| {
+        let mut lines = lock.lines();
+        lines.find(|line| *line == r#"name = "whisper-rs""#)?;
+        let version = lines.next()?.strip_prefix("version = ")?;
+        Some(version.trim_matches('"').to_owned())
+    });
+    if let Some(version) = whisper_rs {
+        println!("cargo:rustc-env=RAMBOT_WHISPER_RS_VERSION={version}");
+    }
+    println!("cargo:rerun-if-changed=Cargo.lock");
+}
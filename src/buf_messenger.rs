@@ -4,38 +4,164 @@
 //! work to the telegram API, or we can slap this little bad boi on top of things to get buffering
 //! (coalescing multiple edits together) and avoiding resending identical edits without having to
 //! worry about it (well we worry about it here, but nowhere else)
+//!
+//! Some chats don't let us edit our own messages at all. Once we see that we stop trying for the
+//! chat and just send the final text as a new message when it gets flushed instead
+
+use std::{
+    collections::{hash_map::RandomState, HashMap, HashSet},
+    hash::{BuildHasher, Hasher},
+    sync::{Arc, Mutex, OnceLock},
+    time::Duration,
+};
+
+#[cfg(feature = "metrics")]
+use crate::metrics;
+use crate::{
+    telegram::{self, EditMessage},
+    HandlerError, HandlerResult,
+};
+
+use teloxide::{types, ApiError, RequestError};
+use tokio::{
+    sync::{
+        mpsc,
+        oneshot::{self, error::TryRecvError},
+    },
+    time,
+};
+
+const DEFAULT_EDIT_DEBOUNCE: Duration = Duration::from_millis(200);
+/// Sends get retried a few times on errors that tend to clear up on their own
+const SEND_ATTEMPTS: u32 = 4;
+const SEND_BACKOFF_BASE: Duration = Duration::from_millis(500);
+
+#[derive(Clone, Copy, Debug)]
+pub struct Config {
+    /// How long an edit waits around to get coalesced with any fresher edits
+    pub edit_debounce: Duration,
+}
 
-use std::time::Duration;
+impl Config {
+    pub fn from_env() -> Self {
+        let edit_debounce = match std::env::var("RAMBOT_EDIT_DEBOUNCE_MS") {
+            Ok(ms) => match ms.parse() {
+                Ok(ms) => Duration::from_millis(ms),
+                Err(e) => {
+                    log::warn!("Ignoring invalid RAMBOT_EDIT_DEBOUNCE_MS {ms:?}: {e}");
+                    DEFAULT_EDIT_DEBOUNCE
+                }
+            },
+            Err(_) => DEFAULT_EDIT_DEBOUNCE,
+        };
 
-use crate::{telegram, HandlerError, HandlerResult};
+        Self { edit_debounce }
+    }
+}
 
-use teloxide::types;
-use tokio::{sync::mpsc, time};
+/// Chats where editing messages is known to fail
+type UneditableChats = Arc<Mutex<HashSet<types::ChatId>>>;
+/// The id of whichever message currently holds the text. `None` until the first send goes through
+type SentId = Arc<Mutex<Option<types::MessageId>>>;
 
 #[derive(Clone)]
 pub struct SendMsgHandle {
     req_tx: mpsc::UnboundedSender<SendReq>,
+    config: Config,
+    /// Starts off the logs for any messages sent through this handle
+    log_prefix: Arc<str>,
 }
 
 impl SendMsgHandle {
+    pub fn config(&self) -> Config {
+        self.config
+    }
+
+    /// A handle whose messages' logs all start with `prefix` to tie them back to a job
+    pub fn with_log_prefix(&self, prefix: &str) -> Self {
+        Self {
+            log_prefix: format!("{prefix} ").into(),
+            ..self.clone()
+        }
+    }
+
     pub fn dispatch_send_msg<S: Into<String>>(
         &self,
         chat_id: types::ChatId,
         reply_to: types::MessageId,
+        topic: Option<i32>,
+        text: S,
+        markup: Option<types::InlineKeyboardMarkup>,
+        parse_mode: Option<types::ParseMode>,
+    ) -> HandlerResult<UpdateMsgHandle> {
+        let content = Content {
+            text: text.into(),
+            markup,
+            parse_mode,
+        };
+        self.dispatch(chat_id, reply_to, topic, None, content)
+    }
+
+    /// Takes over one of our existing messages instead of sending a new one. `reply_to` is only
+    /// used if the edits have to fall back to sending
+    #[allow(clippy::too_many_arguments)]
+    pub fn dispatch_adopt_msg<S: Into<String>>(
+        &self,
+        chat_id: types::ChatId,
+        reply_to: types::MessageId,
+        topic: Option<i32>,
+        msg_id: types::MessageId,
         text: S,
+        markup: Option<types::InlineKeyboardMarkup>,
+        parse_mode: Option<types::ParseMode>,
+    ) -> HandlerResult<UpdateMsgHandle> {
+        // We don't know what the message says right now, so start blank to force the first edit
+        let blank = Content {
+            text: String::new(),
+            markup,
+            parse_mode,
+        };
+        let mut handle = self.dispatch(chat_id, reply_to, topic, Some(msg_id), blank)?;
+        handle.dispatch_edit_text(text)?;
+        Ok(handle)
+    }
+
+    fn dispatch(
+        &self,
+        chat_id: types::ChatId,
+        reply_to: types::MessageId,
+        topic: Option<i32>,
+        existing: Option<types::MessageId>,
+        content: Content,
     ) -> HandlerResult<UpdateMsgHandle> {
+        let Content {
+            markup, parse_mode, ..
+        } = content.clone();
         let (req_tx, req_rx) = mpsc::unbounded_channel();
         let (resp_tx, resp_rx) = mpsc::unbounded_channel();
+        let sent_id = SentId::default();
         self.req_tx
             .send(SendReq {
                 chat_id,
                 reply_to,
-                text: text.into(),
+                topic,
+                existing,
+                content,
                 req_rx,
                 resp_tx,
+                config: self.config,
+                log_prefix: Arc::clone(&self.log_prefix),
+                sent_id: Arc::clone(&sent_id),
             })
             .map_err(|_| HandlerError::SendMsgWorkerDied)?;
-        Ok(UpdateMsgHandle { req_tx, resp_rx })
+        Ok(UpdateMsgHandle {
+            req_tx,
+            resp_rx,
+            markup,
+            parse_mode,
+            log_prefix: Arc::clone(&self.log_prefix),
+            sent_id,
+        })
     }
 }
 
@@ -44,15 +170,35 @@ impl SendMsgHandle {
 pub struct UpdateMsgHandle {
     req_tx: mpsc::UnboundedSender<UpdateReq>,
     resp_rx: mpsc::UnboundedReceiver<MsgResp>,
+    // Has to get passed along with every edit to keep it around
+    markup: Option<types::InlineKeyboardMarkup>,
+    parse_mode: Option<types::ParseMode>,
+    log_prefix: Arc<str>,
+    sent_id: SentId,
 }
 
 impl UpdateMsgHandle {
+    /// Drops the inline keyboard starting with the next edit
+    pub fn remove_markup(&mut self) {
+        self.markup = None;
+    }
+
+    /// The message that's actually in the chat. Only settled after a `.flush()` since falling back
+    /// to sending can swap it out for a new one
+    pub fn msg_id(&self) -> Option<types::MessageId> {
+        *self.sent_id.lock().unwrap()
+    }
+
     pub fn dispatch_edit_text<S: Into<String>>(&mut self, text: S) -> HandlerResult<()> {
-        let text = text.into();
+        let content = Content {
+            text: text.into(),
+            markup: self.markup.clone(),
+            parse_mode: self.parse_mode,
+        };
         self.req_tx
-            .send(UpdateReq::Edit(text))
+            .send(UpdateReq::Edit(content))
             .map_err(|_| HandlerError::UpdateMsgWorkerDied)?;
-        while let Ok(resp) = self.resp_rx.try_recv() {
+        if let Ok(resp) = self.resp_rx.try_recv() {
             match resp {
                 MsgResp::Flush(_) => unreachable!("Should never be seen outside a `.flush()` call"),
                 MsgResp::Error(e) => return Err(e),
@@ -78,7 +224,7 @@ impl UpdateMsgHandle {
                 // cause of) the worker dying. Return that when available
                 None => break Err(delayed_error.unwrap_or(HandlerError::UpdateMsgWorkerDied)),
                 Some(MsgResp::Error(e)) => {
-                    log::info!("Captured delayed error: {e}");
+                    log::info!("{}Captured delayed error: {e}", self.log_prefix);
                     delayed_error = Some(e)
                 }
                 Some(MsgResp::Flush(None)) => {
@@ -96,18 +242,42 @@ impl UpdateMsgHandle {
         self.flush().await?;
         Ok(())
     }
+
+    /// Deletes the message instead of leaving it around. Any edits that haven't gone out yet get
+    /// dropped
+    pub async fn delete(mut self) -> HandlerResult<()> {
+        self.req_tx
+            .send(UpdateReq::Delete)
+            .map_err(|_| HandlerError::UpdateMsgWorkerDied)?;
+        self.flush().await
+    }
 }
 
 struct SendReq {
     chat_id: types::ChatId,
     reply_to: types::MessageId,
-    text: String,
+    /// The forum topic that the message goes in, if any
+    topic: Option<i32>,
+    /// Set when adopting a message instead of sending one
+    existing: Option<types::MessageId>,
+    content: Content,
     req_rx: mpsc::UnboundedReceiver<UpdateReq>,
     resp_tx: mpsc::UnboundedSender<MsgResp>,
+    config: Config,
+    log_prefix: Arc<str>,
+    sent_id: SentId,
+}
+
+#[derive(Clone, PartialEq)]
+struct Content {
+    text: String,
+    markup: Option<types::InlineKeyboardMarkup>,
+    parse_mode: Option<types::ParseMode>,
 }
 
 enum UpdateReq {
-    Edit(String),
+    Edit(Content),
+    Delete,
     Flush,
 }
 
@@ -116,87 +286,679 @@ enum MsgResp {
     Error(HandlerError),
 }
 
-async fn run_send_worker(mut rx: mpsc::UnboundedReceiver<SendReq>, bot: telegram::Bot) {
+async fn run_send_worker<B: telegram::Api>(mut rx: mpsc::UnboundedReceiver<SendReq>, bot: B) {
+    let uneditable = UneditableChats::default();
+    // Each send runs on its own so that one chat waiting out retries doesn't hold up the rest.
+    // Sends within a chat still go out in order by waiting on whichever send came before them
+    let mut last_sends: HashMap<types::ChatId, oneshot::Receiver<()>> = HashMap::new();
     while let Some(req) = rx.recv().await {
-        let SendReq {
-            chat_id,
-            reply_to,
-            text,
-            req_rx,
-            resp_tx,
-        } = req;
-        let msg = match bot.send_message(chat_id, reply_to, text.clone()).await {
-            Ok(msg) => msg,
-            Err(e) => {
-                let _ = resp_tx.send(MsgResp::Error(e.into()));
-                continue;
+        last_sends.retain(|_, sent| matches!(sent.try_recv(), Err(TryRecvError::Empty)));
+        let (sent_tx, sent_rx) = oneshot::channel();
+        let prev_send = last_sends.insert(req.chat_id, sent_rx);
+        let uneditable = Arc::clone(&uneditable);
+        tokio::task::spawn(send_then_update(
+            req,
+            bot.clone(),
+            uneditable,
+            prev_send,
+            sent_tx,
+        ));
+    }
+}
+
+/// Sends the message once the chat's previous send is done, then sticks around handling its
+/// updates. `sent` gets dropped as soon as the send itself is over
+async fn send_then_update<B: telegram::Api>(
+    req: SendReq,
+    bot: B,
+    uneditable: UneditableChats,
+    prev_send: Option<oneshot::Receiver<()>>,
+    sent: oneshot::Sender<()>,
+) {
+    let SendReq {
+        chat_id,
+        reply_to,
+        topic,
+        existing,
+        content,
+        req_rx,
+        resp_tx,
+        config,
+        log_prefix,
+        sent_id,
+    } = req;
+    if let Some(prev_send) = prev_send {
+        // Only ever gets dropped, so there's nothing to get back
+        let _ = prev_send.await;
+    }
+    let msg = match existing {
+        Some(msg_id) => bot.existing_message(chat_id, msg_id),
+        None => {
+            match send_with_retries(&bot, chat_id, reply_to, topic, &content, &log_prefix).await {
+                Ok(msg) => msg,
+                Err(e) => {
+                    log::warn!("{log_prefix}Failed sending message: {e}");
+                    let _ = resp_tx.send(MsgResp::Error(e));
+                    return;
+                }
             }
+        }
+    };
+    *sent_id.lock().unwrap() = Some(msg.id());
+    drop(sent);
+
+    let worker = UpdateWorker {
+        rx: req_rx,
+        tx: resp_tx,
+        bot,
+        chat_id,
+        reply_to,
+        topic,
+        msg,
+        current: content,
+        unsent: None,
+        config,
+        log_prefix,
+        uneditable,
+        sent_id,
+    };
+    worker.run().await;
+}
+
+/// Sends a one-off plain text message right away with the same retries and logging as buffered
+/// sends
+///
+/// Command replies go through here instead of the worker. They get sent once and edited at most
+/// once, so there's nothing to coalesce, and their callers want the message back right away
+/// (`/vroom` even times the send itself) instead of queueing behind transcriptions
+pub async fn send_now<B: telegram::Api>(
+    bot: &B,
+    chat_id: types::ChatId,
+    reply_to: types::MessageId,
+    topic: Option<i32>,
+    text: String,
+) -> HandlerResult<B::Message> {
+    let content = Content {
+        text,
+        markup: None,
+        parse_mode: None,
+    };
+    let log_prefix = format!("[chat {chat_id}] ");
+    send_with_retries(bot, chat_id, reply_to, topic, &content, &log_prefix)
+        .await
+        .inspect_err(|e| log::warn!("{log_prefix}Failed sending message: {e}"))
+}
+
+async fn send_with_retries<B: telegram::Api>(
+    bot: &B,
+    chat_id: types::ChatId,
+    reply_to: types::MessageId,
+    topic: Option<i32>,
+    content: &Content,
+    log_prefix: &str,
+) -> HandlerResult<B::Message> {
+    let mut attempt = 1;
+    loop {
+        let Content {
+            text,
+            markup,
+            parse_mode,
+        } = content.clone();
+        let err = match bot
+            .send_message_with_markup(chat_id, reply_to, topic, text, markup, parse_mode)
+            .await
+        {
+            Ok(msg) => return Ok(msg),
+            Err(e) => e,
         };
+        match retry_delay(&err, attempt) {
+            Some(delay) if attempt < SEND_ATTEMPTS => {
+                log::info!(
+                    "{log_prefix}Send attempt {attempt}/{SEND_ATTEMPTS} failed. Retrying in \
+                    {delay:?}: {err}"
+                );
+                time::sleep(delay).await;
+                attempt += 1;
+            }
+            _ => return Err(err),
+        }
+    }
+}
 
-        // Detach a worker for handling message updates
-        let _ = tokio::task::spawn(run_update_worker(req_rx, resp_tx, msg, text));
+/// How long to wait before retrying. `None` when the error isn't going to go away by retrying
+fn retry_delay(e: &HandlerError, attempt: u32) -> Option<Duration> {
+    match e {
+        HandlerError::Request(RequestError::RetryAfter(after)) => Some(*after),
+        // Telegram's gateway errors tend to come back as HTML instead of JSON
+        HandlerError::Request(
+            RequestError::Network(_) | RequestError::Io(_) | RequestError::InvalidJson { .. },
+        ) => Some(backoff(attempt)),
+        _ => None,
     }
 }
 
-async fn run_update_worker(
-    mut rx: mpsc::UnboundedReceiver<UpdateReq>,
+/// Doubles with each attempt with up to 50% extra jitter so that a pile of sends that failed
+/// together don't all retry together too
+fn backoff(attempt: u32) -> Duration {
+    let delay = SEND_BACKOFF_BASE * 2_u32.pow(attempt.saturating_sub(1));
+    // Randomly seeded hashers are plenty random for jitter
+    let jitter = (RandomState::new().build_hasher().finish() % 1_000) as f64 / 2_000.0;
+    delay + delay.mul_f64(jitter)
+}
+
+/// Whether the error means that editing will never work in this chat
+///
+/// Losing the rights to post is a different problem. Sending the text instead wouldn't get
+/// through either, so those go through the usual error handling
+fn is_uneditable(e: &HandlerError) -> bool {
+    match e {
+        HandlerError::Request(RequestError::Api(api_err)) => match api_err {
+            ApiError::MessageCantBeEdited => true,
+            // teloxide doesn't have a variant for this one
+            ApiError::Unknown(reason) => reason.contains("not enough rights to edit"),
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+struct UpdateWorker<B: telegram::Api> {
+    rx: mpsc::UnboundedReceiver<UpdateReq>,
     tx: mpsc::UnboundedSender<MsgResp>,
-    msg: telegram::Message,
-    mut current_text: String,
-) {
-    while let Some(req) = rx.recv().await {
-        match req {
-            UpdateReq::Flush => _ = tx.send(MsgResp::Flush(None)),
-            UpdateReq::Edit(mut text) => {
-                let slight_delay = time::Instant::now() + Duration::from_millis(200);
-                let mut flush_after = false;
-
-                // Instead of editing immediately we wait for a bit of time to coalesce any more
-                // edits together (breaking early if we get a flush)
-                loop {
-                    // NOTE: Receiving on a channel is cancellation safe, so no work gets lost
-                    tokio::select! {
-                        _ = time::sleep_until(slight_delay) => break,
-                        maybe_req = rx.recv() => {
-                            let Some(req) = maybe_req else {
-                                // Msg handler hung up
-                                break;
-                            };
-                            match req {
-                                UpdateReq::Edit(fresher_text) => {
-                                    log::trace!("Coalescing edits together");
-                                    text = fresher_text;
-                                },
-                                UpdateReq::Flush => {
-                                    flush_after = true;
+    bot: B,
+    chat_id: types::ChatId,
+    reply_to: types::MessageId,
+    topic: Option<i32>,
+    msg: B::Message,
+    current: Content,
+    /// The latest content that couldn't be edited in. Gets sent as a new message on flush
+    unsent: Option<Content>,
+    config: Config,
+    log_prefix: Arc<str>,
+    uneditable: UneditableChats,
+    sent_id: SentId,
+}
+
+impl<B: telegram::Api> UpdateWorker<B> {
+    async fn run(mut self) {
+        let log_prefix = Arc::clone(&self.log_prefix);
+        while let Some(req) = self.rx.recv().await {
+            match req {
+                UpdateReq::Flush => self.flush().await,
+                UpdateReq::Delete => self.delete().await,
+                UpdateReq::Edit(mut content) => {
+                    let slight_delay = time::Instant::now() + self.config.edit_debounce;
+                    let mut flush_after = false;
+                    let mut delete_instead = false;
+
+                    // Instead of editing immediately we wait for a bit of time to coalesce any
+                    // more edits together (breaking early if we get a flush)
+                    loop {
+                        // NOTE: Receiving on a channel is cancellation safe, so no work gets lost
+                        tokio::select! {
+                            _ = time::sleep_until(slight_delay) => break,
+                            maybe_req = self.rx.recv() => {
+                                let Some(req) = maybe_req else {
+                                    // Msg handler hung up
                                     break;
+                                };
+                                match req {
+                                    UpdateReq::Edit(fresher_content) => {
+                                        log::trace!("{log_prefix}Coalescing edits together");
+                                        content = fresher_content;
+                                    },
+                                    UpdateReq::Flush => {
+                                        flush_after = true;
+                                        break;
+                                    }
+                                    // No point in editing a message that's about to be gone
+                                    UpdateReq::Delete => {
+                                        delete_instead = true;
+                                        break;
+                                    }
                                 }
                             }
-                        }
-                    };
-                }
+                        };
+                    }
+
+                    if delete_instead {
+                        self.delete().await;
+                    } else if content == self.current {
+                        log::trace!("{log_prefix}Skipping duplicate message text");
+                    } else {
+                        self.current = content.clone();
+                        self.edit(content).await;
+                    }
 
-                if text == current_text {
-                    log::trace!("Skipping duplicate message text");
-                } else {
-                    current_text = text.clone();
-                    if let Err(e) = msg.edit_text(text).await {
-                        let _ = tx.send(MsgResp::Error(e.into()));
+                    if flush_after {
+                        self.flush().await;
                     }
                 }
+            }
+        }
+    }
+
+    async fn edit(&mut self, content: Content) {
+        let log_prefix = &self.log_prefix;
+        if self.uneditable.lock().unwrap().contains(&self.chat_id) {
+            log::trace!(
+                "{log_prefix}Holding onto edit for uneditable chat {}",
+                self.chat_id
+            );
+            self.unsent = Some(content);
+            return;
+        }
 
-                if flush_after {
-                    let _ = tx.send(MsgResp::Flush(None));
+        let Content {
+            text,
+            markup,
+            parse_mode,
+        } = content.clone();
+        log::trace!("{log_prefix}Editing message {}", self.msg.id());
+        match self
+            .msg
+            .edit_text_with_markup(text, markup, parse_mode)
+            .await
+        {
+            Ok(()) => {
+                #[cfg(feature = "metrics")]
+                metrics::MESSAGE_EDITS.inc();
+            }
+            Err(e) if is_uneditable(&e) => {
+                #[cfg(feature = "metrics")]
+                metrics::MESSAGE_EDIT_FAILURES.inc();
+                log::warn!(
+                    "{log_prefix}Can't edit messages in chat {}. Sending final text as a new \
+                    message instead: {e}",
+                    self.chat_id
+                );
+                self.uneditable.lock().unwrap().insert(self.chat_id);
+                self.unsent = Some(content);
+            }
+            Err(e) => {
+                #[cfg(feature = "metrics")]
+                metrics::MESSAGE_EDIT_FAILURES.inc();
+                log::debug!("{log_prefix}Failed editing message {}: {e}", self.msg.id());
+                let _ = self.tx.send(MsgResp::Error(e));
+            }
+        }
+    }
+
+    async fn delete(&mut self) {
+        self.unsent = None;
+        log::trace!("{}Deleting message {}", self.log_prefix, self.msg.id());
+        if let Err(e) = self.msg.delete().await {
+            log::debug!(
+                "{}Failed deleting message {}: {e}",
+                self.log_prefix,
+                self.msg.id()
+            );
+            let _ = self.tx.send(MsgResp::Error(e));
+        }
+    }
+
+    async fn flush(&mut self) {
+        let mut error = None;
+        if let Some(content) = self.unsent.take() {
+            let sent = send_with_retries(
+                &self.bot,
+                self.chat_id,
+                self.reply_to,
+                self.topic,
+                &content,
+                &self.log_prefix,
+            )
+            .await;
+            match sent {
+                Ok(msg) => {
+                    *self.sent_id.lock().unwrap() = Some(msg.id());
+                    self.msg = msg;
+                }
+                Err(e) => {
+                    log::warn!("{}Failed sending final text: {e}", self.log_prefix);
+                    error = Some(e);
                 }
             }
         }
+        let _ = self.tx.send(MsgResp::Flush(error));
     }
 }
 
-// TODO: use a once_<something> to get this to only ever run once
-pub fn init(bot: telegram::Bot) -> SendMsgHandle {
+static SEND_MSG_HANDLE: OnceLock<SendMsgHandle> = OnceLock::new();
+
+/// Starts the send worker on the first call. Later calls hand back the same worker's handle
+/// since two workers would race each other's edits
+pub fn init<B: telegram::Api>(bot: B, config: Config) -> SendMsgHandle {
+    let mut started = false;
+    let handle = SEND_MSG_HANDLE.get_or_init(|| {
+        started = true;
+        spawn(bot, config)
+    });
+    if !started {
+        log::warn!("Send worker was already started. Reusing it");
+    }
+    handle.clone()
+}
+
+/// A worker of its own that doesn't go through the shared one from [`init()`]. Tests each want
+/// their own bot
+pub fn spawn<B: telegram::Api>(bot: B, config: Config) -> SendMsgHandle {
+    log::debug!("Starting send worker with {config:?}");
     let (req_tx, req_rx) = mpsc::unbounded_channel();
-    let _ = tokio::task::spawn(run_send_worker(req_rx, bot));
+    tokio::task::spawn(run_send_worker(req_rx, bot));
+
+    SendMsgHandle {
+        req_tx,
+        config,
+        log_prefix: "".into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{
+        future::{self, Future},
+        io,
+        sync::atomic::{AtomicI32, AtomicU32, Ordering},
+    };
+
+    const CHAT: types::ChatId = types::ChatId(-100);
+    const OTHER_CHAT: types::ChatId = types::ChatId(-200);
+    const REPLY_TO: types::MessageId = types::MessageId(1);
+    // Long enough that only a flush can cut it short
+    const CONFIG: Config = Config {
+        edit_debounce: Duration::from_secs(60),
+    };
+
+    #[derive(Clone, Debug, PartialEq)]
+    enum Call {
+        Send(i32, String),
+        Edit(i32, String),
+        Delete(i32),
+    }
+
+    /// Records every send and edit instead of talking to telegram
+    #[derive(Clone, Default)]
+    struct MockBot {
+        calls: Arc<Mutex<Vec<Call>>>,
+        last_id: Arc<AtomicI32>,
+        block_edits: bool,
+        /// How many of the upcoming sends fail like the network dropped out
+        flaky_sends: Arc<AtomicU32>,
+        /// Sends to this chat keep getting told to back off for a minute
+        rate_limited: Option<types::ChatId>,
+    }
+
+    impl MockBot {
+        fn calls(&self) -> Vec<Call> {
+            self.calls.lock().unwrap().clone()
+        }
+    }
+
+    struct MockMessage {
+        bot: MockBot,
+        id: types::MessageId,
+    }
+
+    impl telegram::Api for MockBot {
+        type Message = MockMessage;
+
+        fn send_message_with_markup(
+            &self,
+            chat_id: types::ChatId,
+            _: types::MessageId,
+            _: Option<i32>,
+            text: String,
+            _: Option<types::InlineKeyboardMarkup>,
+            _: Option<types::ParseMode>,
+        ) -> impl Future<Output = HandlerResult<Self::Message>> + Send {
+            if self.rate_limited == Some(chat_id) {
+                let err = RequestError::RetryAfter(Duration::from_secs(60));
+                return future::ready(Err(err.into()));
+            }
+            let is_flaky = self
+                .flaky_sends
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1))
+                .is_ok();
+            if is_flaky {
+                let err = RequestError::Io(io::Error::from(io::ErrorKind::ConnectionReset));
+                return future::ready(Err(err.into()));
+            }
+            let id = self.last_id.fetch_add(1, Ordering::Relaxed) + 1;
+            self.calls.lock().unwrap().push(Call::Send(id, text));
+            future::ready(Ok(MockMessage {
+                bot: self.clone(),
+                id: types::MessageId(id),
+            }))
+        }
+
+        fn existing_message(&self, _: types::ChatId, msg_id: types::MessageId) -> Self::Message {
+            MockMessage {
+                bot: self.clone(),
+                id: msg_id,
+            }
+        }
+    }
+
+    impl EditMessage for MockMessage {
+        fn id(&self) -> types::MessageId {
+            self.id
+        }
+
+        fn edit_text_with_markup(
+            &self,
+            text: String,
+            _: Option<types::InlineKeyboardMarkup>,
+            _: Option<types::ParseMode>,
+        ) -> impl Future<Output = HandlerResult> + Send {
+            self.bot
+                .calls
+                .lock()
+                .unwrap()
+                .push(Call::Edit(self.id.0, text));
+            future::ready(if self.bot.block_edits {
+                Err(RequestError::Api(ApiError::MessageCantBeEdited).into())
+            } else {
+                Ok(())
+            })
+        }
+
+        fn delete(&self) -> impl Future<Output = HandlerResult> + Send {
+            self.bot.calls.lock().unwrap().push(Call::Delete(self.id.0));
+            future::ready(Ok(()))
+        }
+    }
+
+    async fn send_and_edit(handle: &SendMsgHandle, edits: &[&str]) -> HandlerResult {
+        let mut msg = handle.dispatch_send_msg(CHAT, REPLY_TO, None, "Queued", None, None)?;
+        for edit in edits {
+            msg.dispatch_edit_text(*edit)?;
+        }
+        msg.close().await
+    }
+
+    // The only test that goes through `init()` since its worker sticks around for good
+    #[tokio::test]
+    async fn init_only_ever_starts_one_worker() {
+        let (first_bot, second_bot) = (MockBot::default(), MockBot::default());
+        let first = init(first_bot.clone(), CONFIG);
+        let second = init(second_bot.clone(), CONFIG);
+        assert!(first.req_tx.same_channel(&second.req_tx));
+
+        send_and_edit(&second, &[]).await.unwrap();
+        assert_eq!(first_bot.calls(), [Call::Send(1, "Queued".into())]);
+        assert!(second_bot.calls().is_empty());
+    }
+
+    #[tokio::test]
+    async fn edits_get_coalesced() {
+        let bot = MockBot::default();
+        let handle = spawn(bot.clone(), CONFIG);
+
+        send_and_edit(&handle, &["one", "two", "two", "three"])
+            .await
+            .unwrap();
+
+        assert_eq!(
+            bot.calls(),
+            [
+                Call::Send(1, "Queued".into()),
+                Call::Edit(1, "three".into())
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn uneditable_chat_falls_back_to_sending() {
+        let bot = MockBot {
+            block_edits: true,
+            ..Default::default()
+        };
+        let handle = spawn(bot.clone(), CONFIG);
+
+        send_and_edit(&handle, &["first"]).await.unwrap();
+        // Edits in the same chat don't get attempted again
+        send_and_edit(&handle, &["second"]).await.unwrap();
+
+        assert_eq!(
+            bot.calls(),
+            [
+                Call::Send(1, "Queued".into()),
+                Call::Edit(1, "first".into()),
+                Call::Send(2, "first".into()),
+                Call::Send(3, "Queued".into()),
+                Call::Send(4, "second".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn only_edit_errors_make_a_chat_uneditable() {
+        let api_err = |err| HandlerError::Request(RequestError::Api(err));
+        let unknown = |reason: &str| api_err(ApiError::Unknown(reason.into()));
+        assert!(is_uneditable(&api_err(ApiError::MessageCantBeEdited)));
+        assert!(is_uneditable(&unknown(
+            "Bad Request: not enough rights to edit messages in the chat"
+        )));
+
+        assert!(!is_uneditable(&api_err(
+            ApiError::NotEnoughRightsToPostMessages
+        )));
+        assert!(!is_uneditable(&unknown(
+            "Bad Request: not enough rights to send text messages to the chat"
+        )));
+        assert!(!is_uneditable(&api_err(ApiError::MessageToEditNotFound)));
+    }
+
+    #[tokio::test]
+    async fn adopted_msgs_get_edited_instead_of_sent() {
+        let bot = MockBot::default();
+        let handle = spawn(bot.clone(), CONFIG);
+
+        let mut msg = handle
+            .dispatch_adopt_msg(
+                CHAT,
+                REPLY_TO,
+                None,
+                types::MessageId(7),
+                "Redo",
+                None,
+                None,
+            )
+            .unwrap();
+        msg.flush().await.unwrap();
+
+        assert_eq!(msg.msg_id(), Some(types::MessageId(7)));
+        assert_eq!(bot.calls(), [Call::Edit(7, "Redo".into())]);
+    }
 
-    SendMsgHandle { req_tx }
+    #[tokio::test]
+    async fn msg_id_follows_the_fallback_send() {
+        let bot = MockBot {
+            block_edits: true,
+            ..Default::default()
+        };
+        let handle = spawn(bot.clone(), CONFIG);
+
+        let mut msg = handle
+            .dispatch_send_msg(CHAT, REPLY_TO, None, "Queued", None, None)
+            .unwrap();
+        msg.flush().await.unwrap();
+        assert_eq!(msg.msg_id(), Some(types::MessageId(1)));
+        msg.dispatch_edit_text("done").unwrap();
+        msg.flush().await.unwrap();
+        assert_eq!(msg.msg_id(), Some(types::MessageId(2)));
+    }
+
+    #[tokio::test]
+    async fn deleting_drops_pending_edits() {
+        let bot = MockBot::default();
+        let handle = spawn(bot.clone(), CONFIG);
+
+        let mut msg = handle
+            .dispatch_send_msg(CHAT, REPLY_TO, None, "Queued", None, None)
+            .unwrap();
+        msg.dispatch_edit_text("never seen").unwrap();
+        msg.delete().await.unwrap();
+
+        assert_eq!(
+            bot.calls(),
+            [Call::Send(1, "Queued".into()), Call::Delete(1)]
+        );
+    }
+
+    #[tokio::test]
+    async fn flaky_send_gets_retried() {
+        let bot = MockBot {
+            flaky_sends: Arc::new(AtomicU32::new(1)),
+            ..Default::default()
+        };
+        let handle = spawn(bot.clone(), CONFIG);
+
+        send_and_edit(&handle, &["done"]).await.unwrap();
+
+        assert_eq!(
+            bot.calls(),
+            [Call::Send(1, "Queued".into()), Call::Edit(1, "done".into())]
+        );
+    }
+
+    #[tokio::test]
+    async fn sends_dont_wait_on_other_chats_retrying() {
+        let bot = MockBot {
+            rate_limited: Some(OTHER_CHAT),
+            ..Default::default()
+        };
+        let handle = spawn(bot.clone(), CONFIG);
+
+        let _stuck = handle
+            .dispatch_send_msg(OTHER_CHAT, REPLY_TO, None, "Stuck", None, None)
+            .unwrap();
+        time::timeout(Duration::from_secs(5), send_and_edit(&handle, &["done"]))
+            .await
+            .expect("Send waited on the other chat")
+            .unwrap();
+
+        assert_eq!(
+            bot.calls(),
+            [Call::Send(1, "Queued".into()), Call::Edit(1, "done".into())]
+        );
+    }
+
+    #[tokio::test]
+    async fn one_off_sends_get_retried_too() {
+        let bot = MockBot {
+            flaky_sends: Arc::new(AtomicU32::new(1)),
+            ..Default::default()
+        };
+
+        let msg = send_now(&bot, CHAT, REPLY_TO, None, "Pong".into())
+            .await
+            .unwrap();
+
+        assert_eq!(msg.id(), types::MessageId(1));
+        assert_eq!(bot.calls(), [Call::Send(1, "Pong".into())]);
+    }
 }
@@ -0,0 +1,125 @@
+//! Lets users cancel transcriptions that are still in progress
+//!
+//! Each running transcription registers itself here and gets a small numeric id that's small
+//! enough to stuff into a button's callback data
+
+use std::{
+    collections::HashMap,
+    fmt,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use teloxide::types;
+use tokio::sync::oneshot;
+
+const CALLBACK_PREFIX: &str = "cancel:";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct JobId(u64);
+
+impl JobId {
+    pub fn callback_data(self) -> String {
+        format!("{CALLBACK_PREFIX}{self}")
+    }
+
+    pub fn from_callback_data(data: &str) -> Option<Self> {
+        data.strip_prefix(CALLBACK_PREFIX)?.parse().ok()
+    }
+
+    /// Starts off every log line about the job so that they can be followed through the pipeline
+    pub fn log_prefix(self) -> String {
+        format!("[job {self}]")
+    }
+
+    pub fn button(self) -> types::InlineKeyboardMarkup {
+        let cancel = types::InlineKeyboardButton::callback("Cancel ✋", self.callback_data());
+        types::InlineKeyboardMarkup::new([[cancel]])
+    }
+}
+
+impl fmt::Display for JobId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl FromStr for JobId {
+    type Err = std::num::ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse().map(Self)
+    }
+}
+
+/// A running job along with who asked for it
+struct Pending {
+    requester: types::UserId,
+    tx: oneshot::Sender<()>,
+}
+
+#[derive(Clone, Default)]
+pub struct Registry {
+    next_id: Arc<AtomicU64>,
+    pending: Arc<Mutex<HashMap<JobId, Pending>>>,
+}
+
+impl Registry {
+    /// `requester` is the user who gets to cancel the job (along with the owner and chat admins)
+    pub fn register(&self, requester: types::UserId) -> CancelHandle {
+        let id = JobId(self.next_id.fetch_add(1, Ordering::Relaxed));
+        let (tx, rx) = oneshot::channel();
+        self.pending
+            .lock()
+            .unwrap()
+            .insert(id, Pending { requester, tx });
+
+        CancelHandle {
+            id,
+            rx,
+            registry: self.clone(),
+        }
+    }
+
+    /// Who asked for the job or `None` when it's not running anymore
+    pub fn requester(&self, id: JobId) -> Option<types::UserId> {
+        let pending = self.pending.lock().unwrap();
+        pending.get(&id).map(|pending| pending.requester)
+    }
+
+    /// Returns whether there was a running job to cancel
+    pub fn cancel(&self, id: JobId) -> bool {
+        match self.pending.lock().unwrap().remove(&id) {
+            Some(pending) => pending.tx.send(()).is_ok(),
+            None => false,
+        }
+    }
+}
+
+/// Unregisters the job when dropped
+pub struct CancelHandle {
+    id: JobId,
+    rx: oneshot::Receiver<()>,
+    registry: Registry,
+}
+
+impl CancelHandle {
+    pub fn id(&self) -> JobId {
+        self.id
+    }
+
+    /// Resolves once someone cancels the job
+    pub async fn cancelled(&mut self) {
+        // The sender only goes away when we're dropped or actually cancelled
+        let _ = (&mut self.rx).await;
+    }
+}
+
+impl Drop for CancelHandle {
+    fn drop(&mut self) {
+        self.registry.pending.lock().unwrap().remove(&self.id);
+    }
+}
@@ -1,22 +1,214 @@
+use std::{convert::Infallible, error::Error as StdError, fmt, str::FromStr};
+
 use crate::db;
 
-use teloxide::utils::command::BotCommands;
+use teloxide::{types, utils::command::BotCommands};
 
 #[derive(BotCommands, Clone, Debug)]
 #[command(rename_rule = "lowercase")]
 pub enum Command {
+    #[command(description = "Learn how to get started")]
+    Start,
     #[command(description = "Vroom vroom mother trucker ;V")]
     Vroom,
+    #[command(description = "Report API latency and how busy the transcribers are")]
+    Ping,
+    #[command(description = "Report the bot's version along with what it transcribes with")]
+    Version,
     #[command(description = "Manually transcribe the voice message")]
     Transcribe,
-    #[command(description = "Attach a sidecar for longer voice messages")]
-    AttachSidecar(String),
-    #[command(description = "Detach the sidecar for/from this chat")]
+    #[command(description = "Quickly transcribe just the first minute of the voice message")]
+    Quick,
+    #[command(description = "Transcribe a voice message by its telegram file_id (owner only)")]
+    TranscribeId(FileId),
+    #[command(description = "Summarize a transcribed voice message (reply to the voice message)")]
+    Summary,
+    #[command(description = "Retry a failed transcription (reply to the error message)")]
+    Retry,
+    #[command(description = "Flag a bad transcription with a note (reply to the transcript)")]
+    Feedback(String),
+    #[command(
+        description = "Attach a sidecar for longer voice messages by title, @username, or chat id. End with --long-only to skip the preview here (owner only)"
+    )]
+    AttachSidecar(SidecarTarget),
+    #[command(
+        description = "Make this chat the sidecar for another chat by title, @username, or chat id. End with --long-only to skip the preview there (owner only)"
+    )]
+    BecomeSidecar(SidecarTarget),
+    #[command(description = "Detach the sidecar for/from this chat (owner only)")]
     DetachSidecar,
     #[command(description = "Get your current transcription trigger")]
     GetTrigger,
     #[command(description = "Set your user's transcription trigger")]
     SetTrigger(db::TranscribeTrigger),
-    #[command(description = "Add a user for the bot to recognize")]
+    #[command(description = "Translate voice messages you request to English (true/false)")]
+    SetTranslate(bool),
+    #[command(
+        description = "Pick the model used for your requests (default/tiny/base/small/medium)"
+    )]
+    SetModel(db::ModelSize),
+    #[command(description = "Never transcribe your voice messages in this chat unless you ask")]
+    OptOut,
+    #[command(description = "Undo /optout for this chat")]
+    OptIn,
+    #[command(
+        description = "Set the minimum transcription trigger for this chat (owner only, else admins)"
+    )]
+    SetChatTrigger(db::TranscribeTrigger),
+    #[command(
+        description = "Pick which transcription messages get sent here (preview/long/both, owner only, else admins)"
+    )]
+    SetLayout(db::Layout),
+    #[command(
+        description = "Delete the preview once the sidecar has the full transcript (true/false, owner only, else admins)"
+    )]
+    SetDeletePreview(bool),
+    #[command(
+        description = "Post the long message as one collapsed quote that expands on tap (true/false, owner only, else admins)"
+    )]
+    SetCollapsed(bool),
+    #[command(
+        description = "Skip auto-transcribing voice messages shorter than this many seconds (0 for off, owner only, else admins)"
+    )]
+    SetMinDuration(u32),
+    #[command(
+        description = "Allow voice messages up to this many seconds here (0 for the bot's default, owner only)"
+    )]
+    SetMaxDuration(u32),
+    #[command(
+        description = "Keep this chat's transcripts for /export (true/false, owner only, else admins)"
+    )]
+    SetKeepTranscripts(bool),
+    #[command(description = "Export this chat's kept transcripts as a text file")]
+    Export,
+    #[command(
+        description = "Attach a .jsonl of each transcription here (true/false, owner only, else admins)"
+    )]
+    SetJsonl(bool),
+    #[command(
+        description = "Attach subtitles to transcriptions here (off/srt/vtt, owner only, else admins)"
+    )]
+    SetSubtitles(db::Subtitles),
+    #[command(
+        description = "Wrap transcription lines at this many columns (0 for off, owner only, else admins)"
+    )]
+    SetWrap(u16),
+    #[command(
+        description = "Names and jargon to help transcriptions here spell them right (empty to clear, owner only, else admins)"
+    )]
+    SetPrompt(String),
+    #[command(
+        description = "Lay out transcription lines with {ts} and {text}, like [{ts}] {text} (empty for the default, owner only, else admins)"
+    )]
+    SetTemplate(String),
+    #[command(description = "Add a user for the bot to recognize (owner only)")]
     AddUser(String),
+    #[command(description = "Show how much of your audio has been transcribed")]
+    Stats,
+    #[command(description = "Show your id and current settings")]
+    WhoAmI,
+    #[command(description = "Show transcription stats across all users (owner only)")]
+    AllStats,
+    #[command(
+        description = "Transcribe a sample to check that everything's installed right (owner only)"
+    )]
+    SelfTest,
+    #[command(description = "Show the bot's effective configuration (owner only)")]
+    Config,
+}
+
+/// Telegram's file ids are base64url-ish and well under this long
+const MAX_FILE_ID_LEN: usize = 256;
+
+/// A raw telegram `file_id` that's at least shaped like one
+#[derive(Clone, Debug)]
+pub struct FileId(pub String);
+
+impl FromStr for FileId {
+    type Err = ParseFileIdError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let is_valid = !s.is_empty()
+            && s.len() <= MAX_FILE_ID_LEN
+            && s.chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if is_valid {
+            Ok(Self(s.to_owned()))
+        } else {
+            Err(ParseFileIdError(s.to_owned()))
+        }
+    }
+}
+
+pub struct ParseFileIdError(String);
+
+impl fmt::Debug for ParseFileIdError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "That doesn't look like a telegram file_id: {:?}. They're made of letters, digits, - \
+            and _",
+            self.0
+        )
+    }
+}
+
+impl fmt::Display for ParseFileIdError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl StdError for ParseFileIdError {}
+
+/// Tacked onto the end of a sidecar's selector to keep the main chat free of previews
+const LONG_ONLY_FLAG: &str = "--long-only";
+
+/// The other end of a sidecar attachment along with how it gets attached
+#[derive(Clone, Debug)]
+pub struct SidecarTarget {
+    pub chat: ChatSelector,
+    pub preview_in_main: bool,
+}
+
+impl FromStr for SidecarTarget {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (chat, preview_in_main) = match s.strip_suffix(LONG_ONLY_FLAG) {
+            Some(chat) => (chat, false),
+            None => (s, true),
+        };
+        Ok(Self {
+            chat: chat.parse()?,
+            preview_in_main,
+        })
+    }
+}
+
+/// The different ways of picking out a chat the bot knows about
+#[derive(Clone, Debug)]
+pub enum ChatSelector {
+    Id(types::ChatId),
+    /// Without the leading `@`
+    Username(String),
+    Title(String),
+}
+
+impl FromStr for ChatSelector {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let selector = if let Ok(id) = s.parse() {
+            Self::Id(types::ChatId(id))
+        } else if let Some(username) = s.strip_prefix('@') {
+            Self::Username(username.to_owned())
+        } else {
+            Self::Title(s.to_owned())
+        };
+        Ok(selector)
+    }
 }
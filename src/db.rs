@@ -1,16 +1,22 @@
 //! The global config for the bot
 //!
 //! Considering only one bot is running at any given time the config is simply a global
-//! `RwLock<_>` which dumps its internal representation on mutations
+//! `RwLock<_>` which dumps its internal representation on mutations. Mutations journal the
+//! entries they touch so that a failed one can be rolled back without copying the whole db
 
 use std::{
-    collections::BTreeMap, error::Error as StdError, fmt, io, path::PathBuf,
-    result::Result as StdResult, str::FromStr, sync::Arc,
+    collections::{btree_map, BTreeMap, BTreeSet},
+    error::Error as StdError,
+    fmt, io,
+    path::{Path, PathBuf},
+    result::Result as StdResult,
+    str::FromStr,
+    sync::Arc,
 };
 
 use crate::{
     error::{DbError, DbResult, UserError},
-    HandlerResult,
+    utils, HandlerResult,
 };
 
 use serde::{Deserialize, Serialize};
@@ -27,11 +33,26 @@ impl Db {
     pub async fn load() -> DbResult<Self> {
         let path = Self::db_path()?;
         let inner: Inner = match fs::read_to_string(&path).await {
-            Ok(contents) => ron::from_str(&contents).map_err(DbError::FailedDeserialize),
+            Ok(contents) => {
+                let inner = ron::from_str(&contents).map_err(DbError::FailedDeserialize)?;
+                // Otherwise a read-only db only gets noticed once someone tries changing a setting
+                fs::OpenOptions::new()
+                    .append(true)
+                    .open(&path)
+                    .await
+                    .map_err(|e| DbError::ReadOnly(path.clone(), e))?;
+                Ok(inner)
+            }
             Err(e) => {
                 if e.kind() == io::ErrorKind::NotFound {
                     log::warn!("No existing db found. Loading default configuration");
-                    Ok(Inner::default())
+                    // Saving the fresh db right away doubles as checking that it can be saved
+                    let inner = Inner::default();
+                    Self::write(&path, &inner).await.map_err(|e| match e {
+                        DbError::FailedWrite(e) => DbError::ReadOnly(path.clone(), e),
+                        e => e,
+                    })?;
+                    Ok(inner)
                 } else {
                     Err(DbError::FailedRead(e))
                 }
@@ -41,32 +62,45 @@ impl Db {
         Ok(Self { inner, path })
     }
 
+    /// A fresh db that saves into `dir` instead of the data dir
+    #[cfg(test)]
+    pub fn in_dir(dir: &Path) -> Self {
+        Self {
+            inner: Default::default(),
+            path: dir.join("db.ron"),
+        }
+    }
+
+    async fn write(path: &Path, inner: &Inner) -> DbResult {
+        let contents = ron::ser::to_string_pretty(inner, ron::ser::PrettyConfig::new())
+            .map_err(DbError::FailedSerialize)?;
+        fs::create_dir_all(path.parent().unwrap())
+            .await
+            .map_err(DbError::FailedWrite)?;
+        fs::write(path, &contents)
+            .await
+            .map_err(DbError::FailedWrite)?;
+        log::debug!("Dumped new database info");
+        Ok(())
+    }
+
     // TODO: `.write()` really shouldn't be called outside of this. Restrict the API more?
     async fn dump_after<F>(&self, f: F) -> HandlerResult
     where
-        F: FnOnce(&mut Inner) -> HandlerResult,
+        F: FnOnce(&mut Txn<'_>) -> HandlerResult,
     {
         let mut write_handle = self.inner.write().await;
-        let prev = write_handle.clone();
-        let delayed_res = f(&mut *write_handle);
+        let mut txn = Txn::new(&mut write_handle);
+        let delayed_res = f(&mut txn);
         // Roll the db back if there was an error
         if let Err(e) = &delayed_res {
-            *write_handle = prev.clone();
+            txn.rollback();
             log::warn!("Aborted db transaction due to error: {e}");
         } else {
-            if prev == *write_handle {
+            if !txn.is_dirty() {
                 log::trace!("Skipping dumping identical db state");
             } else {
-                let contents =
-                    ron::ser::to_string_pretty(&*write_handle, ron::ser::PrettyConfig::new())
-                        .map_err(DbError::FailedSerialize)?;
-                fs::create_dir_all(&self.path.parent().unwrap())
-                    .await
-                    .map_err(DbError::FailedWrite)?;
-                fs::write(&self.path, &contents)
-                    .await
-                    .map_err(DbError::FailedWrite)?;
-                log::debug!("Dumped new database info");
+                Self::write(&self.path, &write_handle).await?;
             }
         }
 
@@ -74,23 +108,21 @@ impl Db {
     }
 
     fn db_path() -> DbResult<PathBuf> {
-        match dirs::data_dir() {
-            Some(dir) => Ok(dir.join("rambot").join("db.ron")),
+        match utils::data_dir() {
+            Some(dir) => Ok(dir.join("db.ron")),
             None => Err(DbError::NoDataDir),
         }
     }
 
     pub async fn update_metadata(&self, msg: &types::Message) -> HandlerResult {
-        self.dump_after(|inner| {
+        self.dump_after(|txn| {
             let kind = ChatKind::from(&msg.chat.kind);
-            inner
-                .chats
-                .entry(msg.chat.id)
+            txn.chat_entry(msg.chat.id)
                 .and_modify(|entry| entry.kind = kind.clone())
                 .or_insert_with(|| Chat::new(kind));
 
             if let Some(from) = msg.from() {
-                inner.users.entry(from.id).or_default();
+                txn.user_entry(from.id).or_default();
             }
 
             Ok(())
@@ -106,15 +138,25 @@ impl Db {
             .iter()
             .filter_map(|(id, chat)| match &chat.kind {
                 ChatKind::Private => None,
-                ChatKind::Public(public) => public
-                    .title
-                    .as_deref()
-                    .map_or(false, |t| t == title)
-                    .then_some(*id),
+                ChatKind::Public(public) => (public.title.as_deref() == Some(title)).then_some(*id),
             })
             .collect()
     }
 
+    /// Usernames are case-insensitive and unique, so there's at most one match
+    pub async fn get_chat_id_by_username(&self, username: &str) -> Option<types::ChatId> {
+        let inner = self.inner.read().await;
+
+        inner.chats.iter().find_map(|(id, chat)| match &chat.kind {
+            ChatKind::Private => None,
+            ChatKind::Public(public) => public
+                .username
+                .as_deref()
+                .is_some_and(|name| name.eq_ignore_ascii_case(username))
+                .then_some(*id),
+        })
+    }
+
     pub async fn get_sidecar_attach(
         &self,
         chat_id: types::ChatId,
@@ -129,27 +171,38 @@ impl Db {
         &self,
         chat_id: types::ChatId,
         sidecar_id: types::ChatId,
+        preview_in_main: bool,
     ) -> HandlerResult {
-        self.dump_after(|inner| {
+        self.dump_after(|txn| {
+            // Both ends of the attachment would end up on the same chat and clobber each other
+            if chat_id == sidecar_id {
+                return Err(UserError::SelfSidecar.into());
+            }
             if let Some(Chat {
                 sidecar_attach: Some(attach),
                 ..
-            }) = inner.chats.get(&chat_id)
+            }) = txn.chat(chat_id)
             {
                 return Err(UserError::ChatAlreadyHasAttach(attach.self_kind).into());
             };
             if let Some(Chat {
                 sidecar_attach: Some(attach),
                 ..
-            }) = inner.chats.get(&sidecar_id)
+            }) = txn.chat(sidecar_id)
             {
                 return Err(UserError::SidecarAlreadyHasAttach(attach.self_kind).into());
             };
 
-            let chat = inner.chats.get_mut(&chat_id).unwrap();
-            chat.sidecar_attach = Some(SidecarAttach::has_sidecar(sidecar_id));
-            let sidecar = inner.chats.get_mut(&sidecar_id).unwrap();
-            sidecar.sidecar_attach = Some(SidecarAttach::is_sidecar(chat_id));
+            // Neither chat has an attachment at this point, so the two always end up linked to each
+            // other which is what keeps `detach_sidecar()` from ever seeing a half-attached pair
+            let chat = txn
+                .chat_mut(chat_id)
+                .ok_or(UserError::MissingChat(chat_id))?;
+            chat.sidecar_attach = Some(SidecarAttach::has_sidecar(sidecar_id, preview_in_main));
+            let sidecar = txn
+                .chat_mut(sidecar_id)
+                .ok_or(UserError::MissingChat(sidecar_id))?;
+            sidecar.sidecar_attach = Some(SidecarAttach::is_sidecar(chat_id, preview_in_main));
 
             Ok(())
         })
@@ -157,10 +210,9 @@ impl Db {
     }
 
     pub async fn detach_sidecar(&self, chat_id: types::ChatId) -> HandlerResult {
-        self.dump_after(|inner| {
-            let chat = inner
-                .chats
-                .get_mut(&chat_id)
+        self.dump_after(|txn| {
+            let chat = txn
+                .chat_mut(chat_id)
                 .ok_or_else(|| UserError::MissingChat(chat_id))?;
             let sidecar_attach = chat
                 .sidecar_attach
@@ -168,10 +220,7 @@ impl Db {
                 .ok_or(UserError::MissingSidecarAttach)?;
 
             // Sidecar should always have a valid attachment
-            let sidecar = inner
-                .chats
-                .get_mut(&sidecar_attach.to)
-                .ok_or(DbError::Corrupt)?;
+            let sidecar = txn.chat_mut(sidecar_attach.to).ok_or(DbError::Corrupt)?;
             // Sidecar itself wasn't attached to anything
             sidecar.sidecar_attach.take().ok_or(DbError::Corrupt)?;
 
@@ -195,7 +244,7 @@ impl Db {
         user_id: types::UserId,
         trigger: TranscribeTrigger,
     ) -> HandlerResult {
-        self.dump_after(|inner| match inner.users.get_mut(&user_id) {
+        self.dump_after(|txn| match txn.user_mut(user_id) {
             Some(user) => {
                 user.trigger = trigger;
                 Ok(())
@@ -205,6 +254,276 @@ impl Db {
         .await
     }
 
+    pub async fn get_chat_default_trigger(
+        &self,
+        chat_id: types::ChatId,
+    ) -> HandlerResult<Option<TranscribeTrigger>> {
+        match self.inner.read().await.chats.get(&chat_id) {
+            Some(chat) => Ok(chat.default_trigger),
+            None => Err(UserError::MissingChat(chat_id).into()),
+        }
+    }
+
+    pub async fn set_chat_default_trigger(
+        &self,
+        chat_id: types::ChatId,
+        trigger: TranscribeTrigger,
+    ) -> HandlerResult {
+        self.dump_after(|txn| match txn.chat_mut(chat_id) {
+            Some(chat) => {
+                chat.default_trigger = Some(trigger);
+                Ok(())
+            }
+            None => Err(UserError::MissingChat(chat_id).into()),
+        })
+        .await
+    }
+
+    pub async fn keeps_transcripts(&self, chat_id: types::ChatId) -> HandlerResult<bool> {
+        match self.inner.read().await.chats.get(&chat_id) {
+            Some(chat) => Ok(chat.keep_transcripts),
+            None => Err(UserError::MissingChat(chat_id).into()),
+        }
+    }
+
+    pub async fn set_keep_transcripts(&self, chat_id: types::ChatId, keep: bool) -> HandlerResult {
+        self.dump_after(|txn| match txn.chat_mut(chat_id) {
+            Some(chat) => {
+                chat.keep_transcripts = keep;
+                Ok(())
+            }
+            None => Err(UserError::MissingChat(chat_id).into()),
+        })
+        .await
+    }
+
+    /// The chat's layout override, or else the default for the kind of chat
+    ///
+    /// DMs default to just the long message while group chats default to just a preview to keep the
+    /// noise down. Having a sidecar gives the long message somewhere out of the way to go though, so
+    /// those get both
+    pub async fn get_chat_layout(&self, chat_id: types::ChatId) -> HandlerResult<Layout> {
+        let inner = self.inner.read().await;
+        let chat = inner
+            .chats
+            .get(&chat_id)
+            .ok_or(UserError::MissingChat(chat_id))?;
+        let has_sidecar = chat
+            .sidecar_attach
+            .as_ref()
+            .is_some_and(|attach| attach.self_kind == SidecarKind::HasSidecar);
+        let layout = chat.layout.unwrap_or(match chat.kind {
+            _ if has_sidecar => Layout::Both,
+            ChatKind::Private => Layout::Long,
+            ChatKind::Public(_) => Layout::Preview,
+        });
+        Ok(layout)
+    }
+
+    pub async fn set_chat_layout(&self, chat_id: types::ChatId, layout: Layout) -> HandlerResult {
+        self.dump_after(|txn| match txn.chat_mut(chat_id) {
+            Some(chat) => {
+                chat.layout = Some(layout);
+                Ok(())
+            }
+            None => Err(UserError::MissingChat(chat_id).into()),
+        })
+        .await
+    }
+
+    pub async fn attaches_jsonl(&self, chat_id: types::ChatId) -> HandlerResult<bool> {
+        match self.inner.read().await.chats.get(&chat_id) {
+            Some(chat) => Ok(chat.attach_jsonl),
+            None => Err(UserError::MissingChat(chat_id).into()),
+        }
+    }
+
+    pub async fn set_attach_jsonl(&self, chat_id: types::ChatId, attach: bool) -> HandlerResult {
+        self.dump_after(|txn| match txn.chat_mut(chat_id) {
+            Some(chat) => {
+                chat.attach_jsonl = attach;
+                Ok(())
+            }
+            None => Err(UserError::MissingChat(chat_id).into()),
+        })
+        .await
+    }
+
+    pub async fn deletes_preview(&self, chat_id: types::ChatId) -> HandlerResult<bool> {
+        match self.inner.read().await.chats.get(&chat_id) {
+            Some(chat) => Ok(chat.delete_preview),
+            None => Err(UserError::MissingChat(chat_id).into()),
+        }
+    }
+
+    pub async fn set_delete_preview(&self, chat_id: types::ChatId, delete: bool) -> HandlerResult {
+        self.dump_after(|txn| match txn.chat_mut(chat_id) {
+            Some(chat) => {
+                chat.delete_preview = delete;
+                Ok(())
+            }
+            None => Err(UserError::MissingChat(chat_id).into()),
+        })
+        .await
+    }
+
+    pub async fn collapses_long(&self, chat_id: types::ChatId) -> HandlerResult<bool> {
+        match self.inner.read().await.chats.get(&chat_id) {
+            Some(chat) => Ok(chat.collapse_long),
+            None => Err(UserError::MissingChat(chat_id).into()),
+        }
+    }
+
+    pub async fn set_collapse_long(&self, chat_id: types::ChatId, collapse: bool) -> HandlerResult {
+        self.dump_after(|txn| match txn.chat_mut(chat_id) {
+            Some(chat) => {
+                chat.collapse_long = collapse;
+                Ok(())
+            }
+            None => Err(UserError::MissingChat(chat_id).into()),
+        })
+        .await
+    }
+
+    pub async fn get_subtitles(&self, chat_id: types::ChatId) -> HandlerResult<Subtitles> {
+        match self.inner.read().await.chats.get(&chat_id) {
+            Some(chat) => Ok(chat.subtitles),
+            None => Err(UserError::MissingChat(chat_id).into()),
+        }
+    }
+
+    pub async fn set_subtitles(
+        &self,
+        chat_id: types::ChatId,
+        subtitles: Subtitles,
+    ) -> HandlerResult {
+        self.dump_after(|txn| match txn.chat_mut(chat_id) {
+            Some(chat) => {
+                chat.subtitles = subtitles;
+                Ok(())
+            }
+            None => Err(UserError::MissingChat(chat_id).into()),
+        })
+        .await
+    }
+
+    pub async fn get_wrap_width(&self, chat_id: types::ChatId) -> HandlerResult<Option<usize>> {
+        match self.inner.read().await.chats.get(&chat_id) {
+            Some(chat) => Ok(chat.wrap_width.map(usize::from)),
+            None => Err(UserError::MissingChat(chat_id).into()),
+        }
+    }
+
+    pub async fn set_wrap_width(
+        &self,
+        chat_id: types::ChatId,
+        wrap_width: Option<u16>,
+    ) -> HandlerResult {
+        self.dump_after(|txn| match txn.chat_mut(chat_id) {
+            Some(chat) => {
+                chat.wrap_width = wrap_width;
+                Ok(())
+            }
+            None => Err(UserError::MissingChat(chat_id).into()),
+        })
+        .await
+    }
+
+    pub async fn get_min_duration(&self, chat_id: types::ChatId) -> HandlerResult<Option<u32>> {
+        match self.inner.read().await.chats.get(&chat_id) {
+            Some(chat) => Ok(chat.min_duration_secs),
+            None => Err(UserError::MissingChat(chat_id).into()),
+        }
+    }
+
+    pub async fn set_min_duration(
+        &self,
+        chat_id: types::ChatId,
+        min_duration_secs: Option<u32>,
+    ) -> HandlerResult {
+        self.dump_after(|txn| match txn.chat_mut(chat_id) {
+            Some(chat) => {
+                chat.min_duration_secs = min_duration_secs;
+                Ok(())
+            }
+            None => Err(UserError::MissingChat(chat_id).into()),
+        })
+        .await
+    }
+
+    pub async fn get_max_duration(&self, chat_id: types::ChatId) -> HandlerResult<Option<u32>> {
+        match self.inner.read().await.chats.get(&chat_id) {
+            Some(chat) => Ok(chat.max_duration_secs),
+            None => Err(UserError::MissingChat(chat_id).into()),
+        }
+    }
+
+    pub async fn set_max_duration(
+        &self,
+        chat_id: types::ChatId,
+        max_duration_secs: Option<u32>,
+    ) -> HandlerResult {
+        self.dump_after(|txn| match txn.chat_mut(chat_id) {
+            Some(chat) => {
+                chat.max_duration_secs = max_duration_secs;
+                Ok(())
+            }
+            None => Err(UserError::MissingChat(chat_id).into()),
+        })
+        .await
+    }
+
+    pub async fn get_prompt(&self, chat_id: types::ChatId) -> HandlerResult<Option<String>> {
+        match self.inner.read().await.chats.get(&chat_id) {
+            Some(chat) => Ok(chat.prompt.clone()),
+            None => Err(UserError::MissingChat(chat_id).into()),
+        }
+    }
+
+    /// Templates get validated when they're set, so anything stored is good to go
+    pub async fn get_line_template(
+        &self,
+        chat_id: types::ChatId,
+    ) -> HandlerResult<Option<utils::LineTemplate>> {
+        match self.inner.read().await.chats.get(&chat_id) {
+            Some(chat) => Ok(chat
+                .line_template
+                .as_deref()
+                .and_then(|template| template.parse().ok())),
+            None => Err(UserError::MissingChat(chat_id).into()),
+        }
+    }
+
+    pub async fn set_line_template(
+        &self,
+        chat_id: types::ChatId,
+        template: Option<String>,
+    ) -> HandlerResult {
+        self.dump_after(|txn| match txn.chat_mut(chat_id) {
+            Some(chat) => {
+                chat.line_template = template;
+                Ok(())
+            }
+            None => Err(UserError::MissingChat(chat_id).into()),
+        })
+        .await
+    }
+
+    pub async fn set_prompt(
+        &self,
+        chat_id: types::ChatId,
+        prompt: Option<String>,
+    ) -> HandlerResult {
+        self.dump_after(|txn| match txn.chat_mut(chat_id) {
+            Some(chat) => {
+                chat.prompt = prompt;
+                Ok(())
+            }
+            None => Err(UserError::MissingChat(chat_id).into()),
+        })
+        .await
+    }
+
     pub async fn is_trusted_user(&self, user_id: types::UserId) -> HandlerResult<bool> {
         match self.inner.read().await.users.get(&user_id) {
             Some(user) => Ok(user.trusted_user.is_some()),
@@ -212,9 +531,103 @@ impl Db {
         }
     }
 
+    async fn get_translate(&self, user_id: types::UserId) -> HandlerResult<bool> {
+        match self.inner.read().await.users.get(&user_id) {
+            Some(user) => Ok(user.translate),
+            None => Err(UserError::MissingUser(user_id).into()),
+        }
+    }
+
+    async fn set_translate(&self, user_id: types::UserId, translate: bool) -> HandlerResult {
+        self.dump_after(|txn| match txn.user_mut(user_id) {
+            Some(user) => {
+                user.translate = translate;
+                Ok(())
+            }
+            None => Err(UserError::MissingUser(user_id).into()),
+        })
+        .await
+    }
+
+    async fn get_model(&self, user_id: types::UserId) -> HandlerResult<ModelSize> {
+        match self.inner.read().await.users.get(&user_id) {
+            Some(user) => Ok(user.model),
+            None => Err(UserError::MissingUser(user_id).into()),
+        }
+    }
+
+    async fn set_model(&self, user_id: types::UserId, model: ModelSize) -> HandlerResult {
+        self.dump_after(|txn| match txn.user_mut(user_id) {
+            Some(user) => {
+                user.model = model;
+                Ok(())
+            }
+            None => Err(UserError::MissingUser(user_id).into()),
+        })
+        .await
+    }
+
+    async fn is_opted_out(
+        &self,
+        user_id: types::UserId,
+        chat_id: types::ChatId,
+    ) -> HandlerResult<bool> {
+        match self.inner.read().await.users.get(&user_id) {
+            Some(user) => Ok(user.opted_out_chats.contains(&chat_id)),
+            None => Err(UserError::MissingUser(user_id).into()),
+        }
+    }
+
+    async fn set_opted_out(
+        &self,
+        user_id: types::UserId,
+        chat_id: types::ChatId,
+        opted_out: bool,
+    ) -> HandlerResult {
+        self.dump_after(|txn| match txn.user_mut(user_id) {
+            Some(user) => {
+                if opted_out {
+                    user.opted_out_chats.insert(chat_id);
+                } else {
+                    user.opted_out_chats.remove(&chat_id);
+                }
+                Ok(())
+            }
+            None => Err(UserError::MissingUser(user_id).into()),
+        })
+        .await
+    }
+
+    async fn get_stats(&self, user_id: types::UserId) -> HandlerResult<Stats> {
+        match self.inner.read().await.users.get(&user_id) {
+            Some(user) => Ok(user.stats),
+            None => Err(UserError::MissingUser(user_id).into()),
+        }
+    }
+
+    async fn record_transcription(&self, user_id: types::UserId, secs: u32) -> HandlerResult {
+        self.dump_after(|txn| match txn.user_mut(user_id) {
+            Some(user) => {
+                user.stats.transcribe_count += 1;
+                user.stats.total_secs += u64::from(secs);
+                Ok(())
+            }
+            None => Err(UserError::MissingUser(user_id).into()),
+        })
+        .await
+    }
+
+    pub async fn total_stats(&self) -> Stats {
+        let mut total = Stats::default();
+        for user in self.inner.read().await.users.values() {
+            total += user.stats;
+        }
+        total
+    }
+
     pub async fn add_trusted_user(&self, user_id: types::UserId, name: String) -> HandlerResult {
-        self.dump_after(|inner| {
-            let user = inner.users.entry(user_id).or_default();
+        self.dump_after(|txn| {
+            let user = txn.user_entry(user_id).or_default();
             user.trusted_user = Some(name);
             Ok(())
         })
@@ -224,7 +637,7 @@ impl Db {
     // NOTE: we MUST NEVER remove a user from the database to keep the invariant that a user
     // returned from here is valid forever
     pub async fn user(&self, user_id: types::UserId) -> Option<DbUser> {
-        if self.inner.read().await.users.get(&user_id).is_some() {
+        if self.inner.read().await.users.contains_key(&user_id) {
             Some(DbUser {
                 db: self.to_owned(),
                 user_id,
@@ -235,12 +648,103 @@ impl Db {
     }
 }
 
-#[derive(Clone, Default, Deserialize, PartialEq, Serialize)]
+#[derive(Default, Deserialize, Serialize)]
 struct Inner {
     chats: BTreeMap<types::ChatId, Chat>,
     users: BTreeMap<types::UserId, User>,
 }
 
+/// A mutation in progress that can be rolled back
+///
+/// Everything that hands out mutable access saves off the entry's original value first, so a
+/// rollback only has to restore the handful of entries that were actually touched
+struct Txn<'db> {
+    inner: &'db mut Inner,
+    chats: Journal<types::ChatId, Chat>,
+    users: Journal<types::UserId, User>,
+}
+
+impl<'db> Txn<'db> {
+    fn new(inner: &'db mut Inner) -> Self {
+        Self {
+            inner,
+            chats: Journal::default(),
+            users: Journal::default(),
+        }
+    }
+
+    fn chat(&self, chat_id: types::ChatId) -> Option<&Chat> {
+        self.inner.chats.get(&chat_id)
+    }
+
+    fn chat_mut(&mut self, chat_id: types::ChatId) -> Option<&mut Chat> {
+        self.chats.save(&self.inner.chats, chat_id);
+        self.inner.chats.get_mut(&chat_id)
+    }
+
+    fn chat_entry(&mut self, chat_id: types::ChatId) -> btree_map::Entry<'_, types::ChatId, Chat> {
+        self.chats.save(&self.inner.chats, chat_id);
+        self.inner.chats.entry(chat_id)
+    }
+
+    fn user_mut(&mut self, user_id: types::UserId) -> Option<&mut User> {
+        self.users.save(&self.inner.users, user_id);
+        self.inner.users.get_mut(&user_id)
+    }
+
+    fn user_entry(&mut self, user_id: types::UserId) -> btree_map::Entry<'_, types::UserId, User> {
+        self.users.save(&self.inner.users, user_id);
+        self.inner.users.entry(user_id)
+    }
+
+    /// Whether anything actually changed, which isn't a given just because it was touched
+    fn is_dirty(&self) -> bool {
+        self.chats.is_dirty(&self.inner.chats) || self.users.is_dirty(&self.inner.users)
+    }
+
+    fn rollback(self) {
+        self.chats.rollback(&mut self.inner.chats);
+        self.users.rollback(&mut self.inner.users);
+    }
+}
+
+/// The original values of the entries touched in a map with `None` for ones that didn't exist
+struct Journal<K, V> {
+    prev: BTreeMap<K, Option<V>>,
+}
+
+impl<K, V> Default for Journal<K, V> {
+    fn default() -> Self {
+        Self {
+            prev: BTreeMap::new(),
+        }
+    }
+}
+
+impl<K: Copy + Ord, V: Clone + PartialEq> Journal<K, V> {
+    /// Only the first save for an entry sticks since that's the one from before the transaction
+    fn save(&mut self, map: &BTreeMap<K, V>, key: K) {
+        if let btree_map::Entry::Vacant(entry) = self.prev.entry(key) {
+            entry.insert(map.get(&key).cloned());
+        }
+    }
+
+    fn is_dirty(&self, map: &BTreeMap<K, V>) -> bool {
+        self.prev
+            .iter()
+            .any(|(key, prev)| prev.as_ref() != map.get(key))
+    }
+
+    fn rollback(self, map: &mut BTreeMap<K, V>) {
+        for (key, prev) in self.prev {
+            match prev {
+                Some(value) => map.insert(key, value),
+                None => map.remove(&key),
+            };
+        }
+    }
+}
+
 pub struct DbUser {
     db: Db,
     user_id: types::UserId,
@@ -262,6 +766,57 @@ impl DbUser {
     pub async fn set_transcribe_trigger(&self, trigger: TranscribeTrigger) -> HandlerResult {
         self.db.set_transcribe_trigger(self.user_id, trigger).await
     }
+
+    /// The trigger that actually applies to the user's voice messages in a chat
+    ///
+    /// A chat's default can only make transcription more eager, so whichever of the user's
+    /// trigger and the chat's default is higher wins. Opting out of the chat trumps both and
+    /// leaves only the user themselves able to summon the bot on their voice messages
+    pub async fn get_effective_trigger(
+        &self,
+        chat_id: types::ChatId,
+    ) -> HandlerResult<TranscribeTrigger> {
+        let user_trigger = self.get_transcribe_trigger().await;
+        let chat_default = self.db.get_chat_default_trigger(chat_id).await?;
+        if self.is_opted_out(chat_id).await {
+            return Ok(user_trigger.min(TranscribeTrigger::SummonBySelf));
+        }
+        Ok(chat_default.map_or(user_trigger, |chat| chat.max(user_trigger)))
+    }
+
+    pub async fn is_opted_out(&self, chat_id: types::ChatId) -> bool {
+        self.db.is_opted_out(self.user_id, chat_id).await.unwrap()
+    }
+
+    pub async fn set_opted_out(&self, chat_id: types::ChatId, opted_out: bool) -> HandlerResult {
+        self.db
+            .set_opted_out(self.user_id, chat_id, opted_out)
+            .await
+    }
+
+    pub async fn get_translate(&self) -> bool {
+        self.db.get_translate(self.user_id).await.unwrap()
+    }
+
+    pub async fn set_translate(&self, translate: bool) -> HandlerResult {
+        self.db.set_translate(self.user_id, translate).await
+    }
+
+    pub async fn get_model(&self) -> ModelSize {
+        self.db.get_model(self.user_id).await.unwrap()
+    }
+
+    pub async fn set_model(&self, model: ModelSize) -> HandlerResult {
+        self.db.set_model(self.user_id, model).await
+    }
+
+    pub async fn get_stats(&self) -> Stats {
+        self.db.get_stats(self.user_id).await.unwrap()
+    }
+
+    pub async fn record_transcription(&self, secs: u32) -> HandlerResult {
+        self.db.record_transcription(self.user_id, secs).await
+    }
 }
 
 impl PartialEq for DbUser {
@@ -274,9 +829,32 @@ impl PartialEq for DbUser {
 struct User {
     trusted_user: Option<String>,
     trigger: TranscribeTrigger,
+    #[serde(default)]
+    stats: Stats,
+    #[serde(default)]
+    translate: bool,
+    #[serde(default)]
+    model: ModelSize,
+    /// Chats where the user's voice messages are off-limits no matter the chat's default
+    #[serde(default)]
+    opted_out_chats: BTreeSet<types::ChatId>,
 }
 
 #[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct Stats {
+    pub transcribe_count: u64,
+    pub total_secs: u64,
+}
+
+impl std::ops::AddAssign for Stats {
+    fn add_assign(&mut self, other: Self) {
+        self.transcribe_count += other.transcribe_count;
+        self.total_secs += other.total_secs;
+    }
+}
+
+/// Variants are ordered from least to most eager to transcribe
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq, PartialOrd, Ord, Serialize)]
 pub enum TranscribeTrigger {
     #[default]
     Never,
@@ -286,6 +864,35 @@ pub enum TranscribeTrigger {
 }
 
 impl TranscribeTrigger {
+    pub const ALL: [Self; 4] = [
+        Self::Never,
+        Self::SummonBySelf,
+        Self::SummonByAny,
+        Self::Always,
+    ];
+
+    /// The values that `/settrigger` accepts, ready to show to users
+    pub fn accepted_values() -> String {
+        let values: Vec<_> = Self::ALL.iter().map(|trigger| trigger.as_str()).collect();
+        values.join(", ")
+    }
+
+    /// Whether someone can summon the bot on a voice message with this trigger. Summoning on your
+    /// own voice messages needs less than summoning on someone else's
+    pub fn allows_summon(self, by_author: bool) -> bool {
+        let needed = if by_author {
+            Self::SummonBySelf
+        } else {
+            Self::SummonByAny
+        };
+        self >= needed
+    }
+
+    /// Whether voice messages get transcribed without anyone asking
+    pub fn is_automatic(self) -> bool {
+        self >= Self::Always
+    }
+
     pub fn as_str(self) -> &'static str {
         match self {
             Self::Never => "never",
@@ -315,15 +922,17 @@ impl FromStr for TranscribeTrigger {
     type Err = ParseTriggerError;
 
     fn from_str(s: &str) -> StdResult<Self, Self::Err> {
-        let trigger = match s {
+        // People type these however they like, e.g. `/settrigger Always`
+        let normalized = s.trim().to_lowercase();
+        let trigger = match normalized.as_str() {
             "never" => Self::Never,
             "self" => Self::SummonBySelf,
             "anyone" => Self::SummonByAny,
             "always" => Self::Always,
-            unknown => return Err(ParseTriggerError(unknown.to_owned())),
+            _ => return Err(ParseTriggerError(s.to_owned())),
         };
         // Sanity check that the values all match
-        assert_eq!(s, trigger.as_str());
+        assert_eq!(normalized, trigger.as_str());
 
         Ok(trigger)
     }
@@ -341,8 +950,9 @@ impl fmt::Debug for ParseTriggerError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
             f,
-            "Unknown trigger: {}. Accepted values: never, self, anyone, or always",
-            self.0
+            "Unknown trigger: {}. Accepted values: {}",
+            self.0,
+            TranscribeTrigger::accepted_values()
         )
     }
 }
@@ -355,10 +965,255 @@ impl fmt::Display for ParseTriggerError {
 
 impl StdError for ParseTriggerError {}
 
+/// Which whisper model to transcribe with. Smaller models are faster, but less accurate
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq, Hash, Serialize)]
+pub enum ModelSize {
+    /// Whatever model was installed as the plain `model.bin`
+    #[default]
+    Default,
+    Tiny,
+    Base,
+    Small,
+    Medium,
+}
+
+impl ModelSize {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Default => "default",
+            Self::Tiny => "tiny",
+            Self::Base => "base",
+            Self::Small => "small",
+            Self::Medium => "medium",
+        }
+    }
+
+    /// The model's file name within the bot's data dir
+    pub fn file_name(self) -> String {
+        match self {
+            Self::Default => "model.bin".to_owned(),
+            sized => format!("model-{sized}.bin"),
+        }
+    }
+}
+
+impl FromStr for ModelSize {
+    type Err = ParseModelSizeError;
+
+    fn from_str(s: &str) -> StdResult<Self, Self::Err> {
+        let model = match s {
+            "default" => Self::Default,
+            "tiny" => Self::Tiny,
+            "base" => Self::Base,
+            "small" => Self::Small,
+            "medium" => Self::Medium,
+            unknown => return Err(ParseModelSizeError(unknown.to_owned())),
+        };
+        // Sanity check that the values all match
+        assert_eq!(s, model.as_str());
+
+        Ok(model)
+    }
+}
+
+impl fmt::Display for ModelSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+pub struct ParseModelSizeError(String);
+
+impl fmt::Debug for ParseModelSizeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Unknown model: {}. Accepted values: default, tiny, base, small, or medium",
+            self.0
+        )
+    }
+}
+
+impl fmt::Display for ParseModelSizeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl StdError for ParseModelSizeError {}
+
+/// Which of the transcription messages get sent for a voice message
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub enum Layout {
+    /// Just the start of the transcription
+    Preview,
+    /// The full transcription split over as many messages as it takes
+    Long,
+    /// A preview with the long message following it (in the sidecar if there is one)
+    Both,
+}
+
+impl Layout {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Preview => "preview",
+            Self::Long => "long",
+            Self::Both => "both",
+        }
+    }
+
+    pub fn has_preview(self) -> bool {
+        matches!(self, Self::Preview | Self::Both)
+    }
+
+    pub fn has_long(self) -> bool {
+        matches!(self, Self::Long | Self::Both)
+    }
+}
+
+impl FromStr for Layout {
+    type Err = ParseLayoutError;
+
+    fn from_str(s: &str) -> StdResult<Self, Self::Err> {
+        let layout = match s {
+            "preview" => Self::Preview,
+            "long" => Self::Long,
+            "both" => Self::Both,
+            unknown => return Err(ParseLayoutError(unknown.to_owned())),
+        };
+        // Sanity check that the values all match
+        assert_eq!(s, layout.as_str());
+
+        Ok(layout)
+    }
+}
+
+impl fmt::Display for Layout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+pub struct ParseLayoutError(String);
+
+impl fmt::Debug for ParseLayoutError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Unknown layout: {}. Accepted values: preview, long, or both",
+            self.0
+        )
+    }
+}
+
+impl fmt::Display for ParseLayoutError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl StdError for ParseLayoutError {}
+
+/// Which subtitle file (if any) gets attached to transcriptions
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq, Serialize)]
+pub enum Subtitles {
+    #[default]
+    Off,
+    Srt,
+    Vtt,
+}
+
+impl Subtitles {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Off => "off",
+            Self::Srt => "srt",
+            Self::Vtt => "vtt",
+        }
+    }
+}
+
+impl FromStr for Subtitles {
+    type Err = ParseSubtitlesError;
+
+    fn from_str(s: &str) -> StdResult<Self, Self::Err> {
+        let subtitles = match s {
+            "off" => Self::Off,
+            "srt" => Self::Srt,
+            "vtt" => Self::Vtt,
+            unknown => return Err(ParseSubtitlesError(unknown.to_owned())),
+        };
+        // Sanity check that the values all match
+        assert_eq!(s, subtitles.as_str());
+
+        Ok(subtitles)
+    }
+}
+
+impl fmt::Display for Subtitles {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+pub struct ParseSubtitlesError(String);
+
+impl fmt::Debug for ParseSubtitlesError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Unknown subtitle format: {}. Accepted values: off, srt, or vtt",
+            self.0
+        )
+    }
+}
+
+impl fmt::Display for ParseSubtitlesError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl StdError for ParseSubtitlesError {}
+
 #[derive(Clone, Deserialize, PartialEq, Serialize)]
 struct Chat {
     kind: ChatKind,
     sidecar_attach: Option<SidecarAttach>,
+    #[serde(default)]
+    default_trigger: Option<TranscribeTrigger>,
+    /// Opts the chat into storing its transcripts for `/export`
+    #[serde(default)]
+    keep_transcripts: bool,
+    /// Overrides the default layout for the kind of chat
+    #[serde(default)]
+    layout: Option<Layout>,
+    /// Opts the chat into getting a `.jsonl` document alongside each transcription
+    #[serde(default)]
+    attach_jsonl: bool,
+    #[serde(default)]
+    subtitles: Subtitles,
+    /// Soft-wraps transcription lines for narrow screens when set
+    #[serde(default)]
+    wrap_width: Option<u16>,
+    /// Voice messages shorter than this don't get transcribed automatically
+    #[serde(default)]
+    min_duration_secs: Option<u32>,
+    /// Overrides the bot's max duration for chats that need longer transcripts
+    #[serde(default)]
+    max_duration_secs: Option<u32>,
+    /// Deletes the preview once the sidecar has the full transcript
+    #[serde(default)]
+    delete_preview: bool,
+    /// Posts the long message as one collapsed quote instead of parts while it fits
+    #[serde(default)]
+    collapse_long: bool,
+    /// Names and jargon that transcriptions here should know how to spell
+    #[serde(default)]
+    prompt: Option<String>,
+    /// Lays out transcription lines. Kept as the source so that it's readable in the db
+    #[serde(default)]
+    line_template: Option<String>,
 }
 
 impl Chat {
@@ -366,6 +1221,18 @@ impl Chat {
         Self {
             kind,
             sidecar_attach: None,
+            default_trigger: None,
+            keep_transcripts: false,
+            layout: None,
+            attach_jsonl: false,
+            subtitles: Subtitles::Off,
+            wrap_width: None,
+            min_duration_secs: None,
+            max_duration_secs: None,
+            delete_preview: false,
+            collapse_long: false,
+            prompt: None,
+            line_template: None,
         }
     }
 }
@@ -388,12 +1255,21 @@ impl From<&types::ChatKind> for ChatKind {
 #[derive(Clone, Deserialize, PartialEq, Serialize)]
 struct ChatPublic {
     title: Option<String>,
+    /// Only supergroups and channels can have one
+    #[serde(default)]
+    username: Option<String>,
 }
 
 impl From<&types::ChatPublic> for ChatPublic {
     fn from(public: &types::ChatPublic) -> Self {
+        let username = match &public.kind {
+            types::PublicChatKind::Channel(channel) => channel.username.clone(),
+            types::PublicChatKind::Supergroup(supergroup) => supergroup.username.clone(),
+            types::PublicChatKind::Group(_) => None,
+        };
         Self {
             title: public.title.clone(),
+            username,
         }
     }
 }
@@ -402,22 +1278,32 @@ impl From<&types::ChatPublic> for ChatPublic {
 pub struct SidecarAttach {
     pub to: types::ChatId,
     pub self_kind: SidecarKind,
+    /// Whether the main chat still gets a preview or if everything goes to the sidecar
+    #[serde(default = "SidecarAttach::default_preview_in_main")]
+    pub preview_in_main: bool,
 }
 
 impl SidecarAttach {
-    fn is_sidecar(to: types::ChatId) -> Self {
+    pub fn is_sidecar(to: types::ChatId, preview_in_main: bool) -> Self {
         Self {
             to,
             self_kind: SidecarKind::IsSidecar,
+            preview_in_main,
         }
     }
 
-    fn has_sidecar(to: types::ChatId) -> Self {
+    pub fn has_sidecar(to: types::ChatId, preview_in_main: bool) -> Self {
         Self {
             to,
             self_kind: SidecarKind::HasSidecar,
+            preview_in_main,
         }
     }
+
+    // Attachments from before long-only sidecars were a thing always left a preview behind
+    fn default_preview_in_main() -> bool {
+        true
+    }
 }
 
 #[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
@@ -425,3 +1311,286 @@ pub enum SidecarKind {
     IsSidecar,
     HasSidecar,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::HandlerError;
+
+    #[test]
+    fn trigger_str_round_trip() {
+        for trigger in TranscribeTrigger::ALL {
+            assert_eq!(
+                trigger.as_str().parse::<TranscribeTrigger>().unwrap(),
+                trigger
+            );
+        }
+    }
+
+    #[test]
+    fn triggers_parse_regardless_of_case_and_padding() {
+        for input in ["Always", "ALWAYS", " always ", "aLwAyS\n"] {
+            assert_eq!(
+                input.parse::<TranscribeTrigger>().unwrap(),
+                TranscribeTrigger::Always
+            );
+        }
+        assert_eq!(
+            " Self".parse::<TranscribeTrigger>().unwrap(),
+            TranscribeTrigger::SummonBySelf
+        );
+        for unknown in ["", "sometimes", "al ways"] {
+            assert!(unknown.parse::<TranscribeTrigger>().is_err());
+        }
+    }
+
+    #[test]
+    fn triggers_order_from_least_to_most_eager() {
+        use TranscribeTrigger::*;
+        assert!(Never < SummonBySelf);
+        assert!(SummonBySelf < SummonByAny);
+        assert!(SummonByAny < Always);
+        let mut sorted = TranscribeTrigger::ALL;
+        sorted.sort();
+        assert_eq!(sorted, TranscribeTrigger::ALL);
+        // A chat's default can only ever raise a user's trigger
+        assert_eq!(SummonByAny.max(SummonBySelf), SummonByAny);
+    }
+
+    #[test]
+    fn summoning_escalates_with_the_trigger() {
+        let by_author: Vec<_> = TranscribeTrigger::ALL
+            .iter()
+            .map(|trigger| trigger.allows_summon(true))
+            .collect();
+        assert_eq!(by_author, [false, true, true, true]);
+        let by_others: Vec<_> = TranscribeTrigger::ALL
+            .iter()
+            .map(|trigger| trigger.allows_summon(false))
+            .collect();
+        assert_eq!(by_others, [false, false, true, true]);
+        assert!(TranscribeTrigger::ALL[..3]
+            .iter()
+            .all(|t| !t.is_automatic()));
+        assert!(TranscribeTrigger::Always.is_automatic());
+    }
+
+    fn large_db() -> Inner {
+        let mut inner = Inner::default();
+        for id in 0..50_000 {
+            inner
+                .chats
+                .insert(types::ChatId(id), Chat::new(ChatKind::Private));
+            inner
+                .users
+                .insert(types::UserId(id as u64), User::default());
+        }
+        inner
+    }
+
+    #[test]
+    fn writes_only_journal_what_they_touch() {
+        let mut inner = large_db();
+        let chat_id = types::ChatId(42);
+
+        let mut txn = Txn::new(&mut inner);
+        txn.chat_mut(chat_id).unwrap().keep_transcripts = true;
+        txn.user_mut(types::UserId(42)).unwrap().translate = true;
+        txn.user_entry(types::UserId(u64::MAX)).or_default();
+        assert_eq!(txn.chats.prev.len(), 1);
+        assert_eq!(txn.users.prev.len(), 2);
+        assert!(txn.is_dirty());
+    }
+
+    #[test]
+    fn rollback_restores_touched_entries() {
+        let mut inner = large_db();
+        let chat_id = types::ChatId(42);
+        let new_user = types::UserId(u64::MAX);
+
+        let mut txn = Txn::new(&mut inner);
+        txn.chat_mut(chat_id).unwrap().min_duration_secs = Some(5);
+        txn.chat_mut(chat_id).unwrap().min_duration_secs = Some(10);
+        txn.user_entry(new_user).or_default();
+        txn.rollback();
+
+        assert!(inner.chats[&chat_id].min_duration_secs.is_none());
+        assert!(!inner.users.contains_key(&new_user));
+        assert_eq!(inner.users.len(), 50_000);
+    }
+
+    #[test]
+    fn chats_from_before_prompts_still_load() {
+        let chat: Chat = ron::from_str("(kind: Private, sidecar_attach: None)").unwrap();
+        assert!(chat.prompt.is_none());
+    }
+
+    #[test]
+    fn users_from_before_translating_still_load() {
+        let user: User =
+            ron::from_str(r#"(trusted_user: Some("Author"), trigger: Always)"#).unwrap();
+        assert!(!user.translate);
+        assert_eq!(user.trigger, TranscribeTrigger::Always);
+
+        // And turning it on sticks around through a save
+        let user = User {
+            translate: true,
+            ..user
+        };
+        let saved = ron::to_string(&user).unwrap();
+        let loaded: User = ron::from_str(&saved).unwrap();
+        assert!(loaded.translate);
+    }
+
+    #[tokio::test]
+    async fn opting_out_beats_a_chat_wide_always() {
+        let (chat_id, other_chat) = (types::ChatId(1), types::ChatId(2));
+        let user_id = types::UserId(1);
+        let inner = Inner {
+            chats: [chat_id, other_chat]
+                .into_iter()
+                .map(|id| {
+                    let mut chat = Chat::new(ChatKind::Private);
+                    chat.default_trigger = Some(TranscribeTrigger::Always);
+                    (id, chat)
+                })
+                .collect(),
+            users: [(
+                user_id,
+                User {
+                    trigger: TranscribeTrigger::SummonByAny,
+                    ..Default::default()
+                },
+            )]
+            .into(),
+        };
+        let db = temp_db("opt-out", inner);
+        let user = db.user(user_id).await.unwrap();
+        assert_eq!(
+            user.get_effective_trigger(chat_id).await.unwrap(),
+            TranscribeTrigger::Always
+        );
+
+        user.set_opted_out(chat_id, true).await.unwrap();
+        let trigger = user.get_effective_trigger(chat_id).await.unwrap();
+        assert!(!trigger.is_automatic());
+        assert!(trigger.allows_summon(true));
+        assert!(!trigger.allows_summon(false));
+        // Even the user's own trigger can't bring automatic transcription back
+        user.set_transcribe_trigger(TranscribeTrigger::Always)
+            .await
+            .unwrap();
+        assert_eq!(
+            user.get_effective_trigger(chat_id).await.unwrap(),
+            TranscribeTrigger::SummonBySelf
+        );
+        // Only the chat that was opted out of is affected
+        assert_eq!(
+            user.get_effective_trigger(other_chat).await.unwrap(),
+            TranscribeTrigger::Always
+        );
+
+        user.set_opted_out(chat_id, false).await.unwrap();
+        assert_eq!(
+            user.get_effective_trigger(chat_id).await.unwrap(),
+            TranscribeTrigger::Always
+        );
+    }
+
+    fn temp_db(name: &str, inner: Inner) -> Db {
+        let dir = std::env::temp_dir().join(format!("rambot-{name}-{}", std::process::id()));
+        Db {
+            inner: Arc::new(RwLock::new(inner)),
+            path: dir.join("db.ron"),
+        }
+    }
+
+    #[tokio::test]
+    async fn sidecars_attach_the_same_from_either_end() {
+        let (main_chat, sidecar) = (types::ChatId(1), types::ChatId(2));
+        let fresh_db = |name: &str| {
+            let inner = Inner {
+                chats: [main_chat, sidecar]
+                    .into_iter()
+                    .map(|id| (id, Chat::new(ChatKind::Private)))
+                    .collect(),
+                ..Default::default()
+            };
+            temp_db(name, inner)
+        };
+
+        // `/attachsidecar` gets run in the main chat
+        let attached = fresh_db("attach-sidecar");
+        let (ran_in, selected) = (main_chat, sidecar);
+        attached
+            .attach_sidecar(ran_in, selected, false)
+            .await
+            .unwrap();
+        // `/becomesidecar` gets run in the sidecar
+        let became = fresh_db("become-sidecar");
+        let (ran_in, selected) = (sidecar, main_chat);
+        became
+            .attach_sidecar(selected, ran_in, false)
+            .await
+            .unwrap();
+
+        for db in [&attached, &became] {
+            assert_eq!(
+                db.get_sidecar_attach(main_chat).await.unwrap(),
+                Some(SidecarAttach::has_sidecar(sidecar, false))
+            );
+            assert_eq!(
+                db.get_sidecar_attach(sidecar).await.unwrap(),
+                Some(SidecarAttach::is_sidecar(main_chat, false))
+            );
+            // Neither end can get attached again until it's detached
+            for (chat_id, sidecar_id) in [(main_chat, sidecar), (sidecar, main_chat)] {
+                assert!(db.attach_sidecar(chat_id, sidecar_id, true).await.is_err());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn sidecars_cant_loop_back_around() {
+        let (a, b) = (types::ChatId(1), types::ChatId(2));
+        let inner = Inner {
+            chats: [a, b]
+                .into_iter()
+                .map(|id| (id, Chat::new(ChatKind::Private)))
+                .collect(),
+            ..Default::default()
+        };
+        let db = temp_db("sidecar-loops", inner);
+
+        assert!(matches!(
+            db.attach_sidecar(a, a, false).await,
+            Err(HandlerError::UserError(UserError::SelfSidecar))
+        ));
+        assert_eq!(db.get_sidecar_attach(a).await.unwrap(), None);
+
+        db.attach_sidecar(a, b, false).await.unwrap();
+        assert!(matches!(
+            db.attach_sidecar(b, a, false).await,
+            Err(HandlerError::UserError(UserError::ChatAlreadyHasAttach(_)))
+        ));
+        // The original pair is left as it was, so it still detaches cleanly
+        assert_eq!(
+            db.get_sidecar_attach(b).await.unwrap(),
+            Some(SidecarAttach::is_sidecar(a, false))
+        );
+        db.detach_sidecar(b).await.unwrap();
+        assert_eq!(db.get_sidecar_attach(a).await.unwrap(), None);
+        assert_eq!(db.get_sidecar_attach(b).await.unwrap(), None);
+    }
+
+    #[test]
+    fn untouched_values_arent_dirty() {
+        let mut inner = large_db();
+
+        let mut txn = Txn::new(&mut inner);
+        let chat = txn.chat_mut(types::ChatId(42)).unwrap();
+        chat.delete_preview = !chat.delete_preview;
+        chat.delete_preview = !chat.delete_preview;
+        assert!(!txn.is_dirty());
+    }
+}
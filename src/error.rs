@@ -1,4 +1,4 @@
-use std::{io, result::Result as StdResult};
+use std::{fmt, io, path::PathBuf, process::ExitStatus, result::Result as StdResult};
 
 use crate::db;
 
@@ -9,7 +9,7 @@ pub type InitResult<T = ()> = StdResult<T, InitError>;
 pub type HandlerResult<T = ()> = StdResult<T, HandlerError>;
 pub type DbResult<T = ()> = StdResult<T, DbError>;
 
-#[derive(Debug, ThisError)]
+#[derive(ThisError)]
 pub enum InitError {
     #[error("{0}")]
     BotCommands(teloxide::RequestError),
@@ -17,6 +17,40 @@ pub enum InitError {
     DbLoad(#[from] DbError),
     #[error("Unable to detect bot name")]
     InvalidBotName,
+    #[error("Can't locate a data directory. Try setting RAMBOT_DATA_DIR")]
+    UnknownDataDir,
+    #[error(
+        "No whisper model found at {0}. Download a ggml model (e.g. from \
+        https://huggingface.co/ggerganov/whisper.cpp) and save it there"
+    )]
+    ModelMissing(PathBuf),
+    #[error("Couldn't run ffmpeg. Install it and make sure that it's on the PATH")]
+    FfmpegMissing,
+    #[error("RAMBOT_OWNER_ID should be a numeric user id. Found: {0:?}")]
+    InvalidOwnerId(String),
+    // The url itself is left out since it can have credentials in it
+    #[error("RAMBOT_PROXY_URL should be an http:// or https:// proxy url: {0}")]
+    InvalidProxyUrl(reqwest::Error),
+    #[error("Failed building the HTTP client: {0}")]
+    HttpClient(reqwest::Error),
+    // Same as the proxy the url is left out
+    #[error("RAMBOT_TRANSCRIPTION_API_URL should be an http:// or https:// url: {0}")]
+    InvalidTranscriptionApiUrl(String),
+    #[error("Fast mode uses the {0} model, so set {1} to the remote API's name for it")]
+    RemoteModelUnset(db::ModelSize, String),
+    #[cfg(any(feature = "healthcheck", feature = "metrics"))]
+    #[error("Failed binding {0}: {1}")]
+    HttpBind(&'static str, io::Error),
+    #[cfg(feature = "telegram-webhook")]
+    #[error("Failed setting up the telegram webhook: {0}")]
+    TelegramWebhook(teloxide::RequestError),
+}
+
+// Init errors bubble out of `main()` which prints them with `Debug`, so show the readable message
+impl fmt::Debug for InitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{self}")
+    }
 }
 
 #[derive(Debug, ThisError)]
@@ -29,10 +63,32 @@ pub enum HandlerError {
     Request(#[from] teloxide::RequestError),
     #[error("Encountered invalid UTF-8 text: {0}")]
     InvalidUtf8(#[from] std::str::Utf8Error),
+    #[error("Failed running ffmpeg (is it installed?): {0}")]
+    FfmpegSpawn(io::Error),
+    #[error("ffmpeg failed converting the audio ({status}): {stderr}")]
+    Ffmpeg { status: ExitStatus, stderr: String },
+    #[error("ffprobe couldn't find the audio's duration: {0}")]
+    Ffprobe(String),
+    #[error("Failed reading the converted audio: {0}")]
+    Wav(#[from] hound::Error),
+    #[cfg(feature = "summary")]
+    #[error("Failed getting a summary: {0}")]
+    Summarizer(#[from] reqwest::Error),
+    #[error("Failed getting a transcription from the API: {0}")]
+    TranscriptionApi(reqwest::Error),
+    #[error("Whisper error: {0}")]
+    Whisper(#[from] whisper_rs::WhisperError),
     #[error("The message task has stopped responding")]
     MessageHandleDied,
     #[error("The transcription worker died :c")]
     WorkerDied,
+    #[error("The transcription was cancelled")]
+    Cancelled,
+    #[error("Transcription timed out")]
+    TimedOut,
+    /// A failure from a job that was shared with other identical ones
+    #[error("{0}")]
+    SharedJob(String),
     #[error("The worker for sending new messages died :c")]
     SendMsgWorkerDied,
     #[error("A worker for updating an existing message died :c")]
@@ -78,14 +134,71 @@ pub enum UserError {
     NotReply,
     #[error("Your message should be a reply to a voice message")]
     ReplyNotVoice,
+    #[error("{0}")]
+    ReplyUnsupported(crate::media::MediaKind),
+    #[error("Telegram doesn't know of a file with that id")]
+    UnknownFileId,
+    #[error("Reply to the original voice message, not my transcript")]
+    ReplyToTranscript,
+    #[error("Give the user a name, like /adduser Jane")]
+    MissingUserName,
     #[error("I can't see the author of the message you're replying to")]
     ReplyUnknownAuthor,
+    #[error("The original author of that forward is hidden, so I can't check their trigger")]
+    ForwardedAuthorHidden,
     #[error("I can't transcribe as that user has their trigger set to {0}")]
     BadSummon(db::TranscribeTrigger),
     #[error("No chat found titled: {0:?}")]
     NoChatTitled(String),
-    #[error("Ambiguous request. Multiple chats were found with that title")]
+    #[error("No chat found with the username: @{0}")]
+    NoChatWithUsername(String),
+    #[error(
+        "Ambiguous request. Multiple chats were found with that title. Try its @username or \
+        chat id instead"
+    )]
     AmbiguousChatTitle,
+    #[error("A chat can't be its own sidecar")]
+    SelfSidecar,
+    #[error("Only the bot's owner can do that")]
+    NotAuthorized,
+    #[error("Only chat admins can do that")]
+    NotChatAdmin,
+    #[error("The {0} model isn't installed on this bot")]
+    ModelNotInstalled(crate::db::ModelSize),
+    #[error(
+        "That voice message is {:.1}MB, but bots can only download files up to 20MB",
+        f64::from(*.0) / (1024.0 * 1024.0)
+    )]
+    FileTooLarge(u32),
+    #[error("That voice message is too long. The limit here is {0}s")]
+    TooLong(u32),
+    #[error("That voice message is shorter than the {0}s minimum here")]
+    TooShort(u32),
+    #[error("Prompts can be at most {0} characters long")]
+    PromptTooLong(usize),
+    #[error("That's not a valid line template: {0}. Use {{ts}} for the timestamp and {{text}} for the text, like [{{ts}}] {{text}}")]
+    InvalidTemplate(String),
+    #[error("Too many voice messages are waiting to be transcribed right now. Try again in a bit")]
+    QueueFull,
+    #[error("No speech detected in that voice message")]
+    NoSpeechDetected,
+    #[error("Summaries aren't enabled on this bot")]
+    SummariesDisabled,
+    #[error("I don't have a transcription of that message on hand. Transcribe it again first")]
+    NoCachedTranscription,
+    #[error("This chat isn't keeping transcripts. An admin can turn it on with /setkeeptranscripts true")]
+    TranscriptsNotKept,
+    #[error("There's nothing to retry there. Reply to one of my error messages instead")]
+    NothingToRetry,
+    #[error("That's not one of my transcripts. Reply to one with /feedback instead")]
+    NotATranscript,
+    #[error("Add a note about what went wrong, like `/feedback it misheard the names`")]
+    EmptyFeedback,
+    #[error(
+        "Couldn't use {} for the self-test. It should be a short voice recording in OGG/Opus",
+        .0.display()
+    )]
+    InvalidSelfTestAudio(PathBuf),
 }
 
 #[derive(Debug, ThisError)]
@@ -98,6 +211,11 @@ pub enum DbError {
     FailedRead(io::Error),
     #[error("Failed writing the database. Error: {0}")]
     FailedWrite(io::Error),
+    #[error(
+        "The database at {0} can't be written to, so no settings would stick. Fix its \
+        permissions or point RAMBOT_DATA_DIR somewhere writable. Error: {1}"
+    )]
+    ReadOnly(PathBuf, io::Error),
     #[error("Failed deserializing the database. Error: {0}")]
     FailedDeserialize(ron::error::SpannedError),
     #[error("Failed serializing the database. Error: {0}")]
@@ -0,0 +1,132 @@
+//! Collects notes about bad transcriptions to tune prompts and models against later
+//!
+//! Like the kept transcripts this stays out of the db and just gets appended to as one RON-encoded
+//! entry per line. It's a single file for the whole bot since it's only meant for the owner
+
+use std::{
+    io,
+    path::PathBuf,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::HandlerResult;
+
+use serde::{Deserialize, Serialize};
+use tokio::{fs, io::AsyncWriteExt, sync::Mutex};
+
+#[derive(Clone)]
+pub struct Store {
+    path: PathBuf,
+    // Keeps concurrent appends from interleaving
+    write_lock: Arc<Mutex<()>>,
+}
+
+pub struct Feedback {
+    pub chat_id: i64,
+    /// The transcript that the feedback is about
+    pub transcript_msg_id: i32,
+    /// Who left the feedback
+    pub user_id: u64,
+    pub note: String,
+    pub transcript: String,
+    /// Only known while we still remember which voice message the transcript came from
+    pub voice_msg_id: Option<i32>,
+    pub voice_file_id: Option<String>,
+}
+
+/// A line in the file. The feedback with when it was saved
+#[derive(Deserialize, Serialize)]
+struct Record {
+    chat_id: i64,
+    transcript_msg_id: i32,
+    user_id: u64,
+    /// Seconds since the unix epoch
+    submitted_at: u64,
+    note: String,
+    transcript: String,
+    voice_msg_id: Option<i32>,
+    voice_file_id: Option<String>,
+}
+
+impl Store {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            write_lock: Default::default(),
+        }
+    }
+
+    pub async fn save(&self, feedback: Feedback) -> HandlerResult {
+        let Feedback {
+            chat_id,
+            transcript_msg_id,
+            user_id,
+            note,
+            transcript,
+            voice_msg_id,
+            voice_file_id,
+        } = feedback;
+        let submitted_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs());
+        let record = Record {
+            chat_id,
+            transcript_msg_id,
+            user_id,
+            submitted_at,
+            note,
+            transcript,
+            voice_msg_id,
+            voice_file_id,
+        };
+        // The default (non-pretty) config keeps it all on one line
+        let mut record = ron::to_string(&record).map_err(io::Error::other)?;
+        record.push('\n');
+
+        let _guard = self.write_lock.lock().await;
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await?;
+        file.write_all(record.as_bytes()).await?;
+        // tokio's files finish writing in the background otherwise
+        file.flush().await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn saving_stamps_the_submission_time() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Store::new(dir.path().join("feedback.ron"));
+        let feedback = |note: &str| Feedback {
+            chat_id: -100,
+            transcript_msg_id: 7,
+            user_id: 42,
+            note: note.to_owned(),
+            transcript: "Hello there".to_owned(),
+            voice_msg_id: None,
+            voice_file_id: None,
+        };
+        store.save(feedback("misheard")).await.unwrap();
+        store.save(feedback("again")).await.unwrap();
+
+        let contents = std::fs::read_to_string(&store.path).unwrap();
+        let records: Vec<Record> = contents
+            .lines()
+            .map(|line| ron::from_str(line).unwrap())
+            .collect();
+        let notes: Vec<_> = records.iter().map(|record| record.note.as_str()).collect();
+        assert_eq!(notes, ["misheard", "again"]);
+        assert!(records.iter().all(|record| record.submitted_at > 0));
+    }
+}
@@ -0,0 +1,68 @@
+//! Ways for whatever's running the bot to keep an eye on it
+//!
+//! A heartbeat with the transcriber pool's stats can get logged on an interval. With the
+//! `healthcheck` feature there's also a tiny HTTP server that answers `GET /healthz` with a 200
+//! while the workers are alive and a 503 once they aren't or one of them is stuck on a job, so that
+//! container orchestrators can restart a wedged bot. Both are off unless configured
+
+use std::time::Duration;
+
+use crate::transcriber;
+
+use tokio::time;
+
+/// Logs the pool's stats every `RAMBOT_HEARTBEAT_MINS` minutes. Unset or 0 means no heartbeat
+pub fn spawn_heartbeat(pool: transcriber::Pool) {
+    let Ok(mins) = std::env::var("RAMBOT_HEARTBEAT_MINS") else {
+        return;
+    };
+    let period = match mins.parse::<u64>() {
+        Ok(0) => return,
+        Ok(mins) => Duration::from_secs(mins * 60),
+        Err(e) => {
+            log::warn!("Ignoring invalid RAMBOT_HEARTBEAT_MINS {mins:?}: {e}");
+            return;
+        }
+    };
+
+    tokio::spawn(async move {
+        let mut interval = time::interval(period);
+        interval.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+        while !pool.is_shutting_down() {
+            interval.tick().await;
+            let transcriber::Stats {
+                busy_workers,
+                idle_workers,
+                queued_jobs,
+            } = pool.stats();
+            let health = if pool.is_healthy() {
+                "healthy"
+            } else {
+                "UNHEALTHY"
+            };
+            log::info!(
+                "Heartbeat: {health}, {busy_workers} busy and {idle_workers} idle workers, \
+                {queued_jobs} queued jobs"
+            );
+        }
+    });
+}
+
+/// Serves `/healthz` on `RAMBOT_HEALTHCHECK_PORT` when it's set
+#[cfg(feature = "healthcheck")]
+pub async fn spawn_server(pool: transcriber::Pool) -> crate::InitResult {
+    use crate::http::{self, Response};
+
+    http::spawn_server("RAMBOT_HEALTHCHECK_PORT", move |path| {
+        (path == "/healthz").then(|| {
+            if pool.is_healthy() {
+                Response::text("200 OK", "ok\n".to_owned())
+            } else {
+                let stuck = pool.stuck_workers();
+                let body = format!("unhealthy ({stuck} workers stuck on a job)\n");
+                Response::text("503 Service Unavailable", body)
+            }
+        })
+    })
+    .await
+}
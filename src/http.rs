@@ -0,0 +1,127 @@
+//! A bare-bones HTTP server for the operator-facing endpoints
+//!
+//! Health checkers and metrics scrapers only ever send simple `GET`s, so this reads the request
+//! line, hands the path off, and closes the connection. Pulling in a whole HTTP framework for
+//! that isn't worth it
+
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use crate::{InitError, InitResult};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    time,
+};
+
+/// Plenty for a request line from a health checker or scraper
+const MAX_REQUEST_BYTES: usize = 1024;
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub struct Response {
+    pub status: &'static str,
+    pub content_type: &'static str,
+    pub body: String,
+}
+
+impl Response {
+    pub fn text(status: &'static str, body: String) -> Self {
+        Self {
+            status,
+            content_type: "text/plain; charset=utf-8",
+            body,
+        }
+    }
+}
+
+/// Serves on the port from `port_var` when it's set. `route` gets the path of each `GET` and
+/// `None` turns into a 404
+pub async fn spawn_server<F>(port_var: &'static str, route: F) -> InitResult
+where
+    F: Fn(&str) -> Option<Response> + Send + Sync + 'static,
+{
+    let Ok(port) = std::env::var(port_var) else {
+        return Ok(());
+    };
+    let port: u16 = match port.parse() {
+        Ok(port) => port,
+        Err(e) => {
+            log::warn!("Ignoring invalid {port_var} {port:?}: {e}");
+            return Ok(());
+        }
+    };
+    let addr = SocketAddr::from((listen_ip(), port));
+    let listener = TcpListener::bind(addr)
+        .await
+        .map_err(|e| InitError::HttpBind(port_var, e))?;
+    log::info!("Serving {port_var} on {addr}");
+
+    let route = Arc::new(route);
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    let route = Arc::clone(&route);
+                    tokio::spawn(async move {
+                        if let Err(e) = respond(stream, &*route).await {
+                            log::debug!("Failed answering an HTTP request: {e}");
+                        }
+                    });
+                }
+                Err(e) => log::warn!("Failed accepting an HTTP connection: {e}"),
+            }
+        }
+    });
+
+    Ok(())
+}
+
+/// `RAMBOT_HTTP_LISTEN_ADDR` picks the interface to serve on. Defaults to localhost so that nothing
+/// gets exposed without asking for it (e.g. with `0.0.0.0` inside of a container)
+fn listen_ip() -> IpAddr {
+    let localhost = IpAddr::V4(Ipv4Addr::LOCALHOST);
+    let Ok(ip) = std::env::var("RAMBOT_HTTP_LISTEN_ADDR") else {
+        return localhost;
+    };
+    match ip.parse() {
+        Ok(ip) => ip,
+        Err(e) => {
+            log::warn!("Ignoring invalid RAMBOT_HTTP_LISTEN_ADDR {ip:?}: {e}");
+            localhost
+        }
+    }
+}
+
+async fn respond<F>(mut stream: TcpStream, route: &F) -> std::io::Result<()>
+where
+    F: Fn(&str) -> Option<Response>,
+{
+    // Only the request line matters, so there's no need to read the rest of the request
+    let mut buf = [0; MAX_REQUEST_BYTES];
+    let len = match time::timeout(READ_TIMEOUT, stream.read(&mut buf)).await {
+        Ok(len) => len?,
+        Err(_) => return Ok(()),
+    };
+    let request = String::from_utf8_lossy(&buf[..len]);
+    let request_line = request.lines().next().unwrap_or_default();
+    let mut parts = request_line.split_whitespace();
+    let resp = match (parts.next(), parts.next()) {
+        (Some("GET" | "HEAD"), Some(path)) => route(path),
+        _ => None,
+    }
+    .unwrap_or_else(|| Response::text("404 Not Found", "404 Not Found\n".to_owned()));
+
+    let head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        resp.status,
+        resp.content_type,
+        resp.body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(resp.body.as_bytes()).await?;
+    stream.shutdown().await
+}
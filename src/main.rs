@@ -1,45 +1,133 @@
 // TODO: Overall reorganization:
 // - Switch the whole `buf_messenger` naming and organization into a `LiveMessage` or something
 //   like that
-// - Split the transcription logic into a `PreviewMessage` and `LongMessage` that can be used
-//   separately (DMs default to just long, groupchats default to just preview)
-// - Add a little styling to the messages to make different parts more obvious
-// - Add a little scheduler before the transcriber (download file into a temp dir to hand to the
-//   transcriber. Auto cleans up the file and speeds up transcriptions by pre-downloading). This
-//   can be represnted by the job holding a temp dir
 // - Acquire a lockfile to start to ensure we're the only bot running?
 
 mod buf_messenger;
+mod cancel;
 mod command;
 mod db;
 mod error;
+mod feedback;
+mod health;
+#[cfg(any(feature = "healthcheck", feature = "metrics"))]
+mod http;
+mod media;
+#[cfg(feature = "metrics")]
+mod metrics;
+#[cfg(test)]
+mod mock_bot;
+mod origins;
+mod pending;
+mod retry;
+#[cfg(feature = "summary")]
+mod summary;
 mod telegram;
 mod transcriber;
+mod transcripts;
 mod utils;
+mod webhook;
 
-use std::{convert::Infallible, sync::OnceLock, time::Instant};
+use std::{
+    collections::{HashMap, HashSet},
+    convert::Infallible,
+    fmt,
+    io::Write,
+    path::PathBuf,
+    sync::{Arc, OnceLock},
+    time::{Duration, Instant},
+};
+use tokio::sync::Semaphore;
 
 use buf_messenger::UpdateMsgHandle;
 use db::TranscribeTrigger;
 pub use error::{HandlerError, HandlerResult, InitError, InitResult, UserError};
 
-use telegram::Message;
 use teloxide::{
     adaptors,
     dispatching::{Dispatcher, UpdateFilterExt},
     types,
     utils::command::{BotCommands, ParseError as CommandParseError},
 };
-use utils::Line;
+use utils::{escape_markdown_v2, Line};
 
 static BOT_NAME: OnceLock<String> = OnceLock::new();
 
+/// How long in-flight transcriptions get to finish up after a Ctrl-C
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(30);
+/// Whisper only looks at the last ~220 tokens of a prompt, so anything past this is wasted
+const MAX_PROMPT_CHARS: usize = 400;
+/// How much of the voice message `/quick` transcribes
+const QUICK_SECS: u32 = 60;
+
 #[derive(Clone)]
 struct State {
     transcriber_pool: transcriber::Pool,
     // TODO: move this into `telegram::Bot`
     send_msg_handle: buf_messenger::SendMsgHandle,
     db: db::Db,
+    cancellations: cancel::Registry,
+    retries: retry::Registry,
+    origins: origins::Registry,
+    transcripts: transcripts::Store,
+    feedback: feedback::Store,
+    pending: pending::Queue,
+    /// `None` when no webhook is configured
+    webhook: Option<webhook::Webhook>,
+    /// `None` when no summarizer is configured
+    #[cfg(feature = "summary")]
+    summaries: Option<summary::Summaries>,
+    owner_id: Option<types::UserId>,
+    /// `None` means that every chat is allowed
+    allowed_chats: Option<Arc<HashSet<types::ChatId>>>,
+    cutoffs: Cutoffs,
+    /// The longest voice message that gets transcribed unless a chat overrides it
+    max_duration_secs: Option<u32>,
+}
+
+impl State {
+    fn is_chat_allowed(&self, chat_id: types::ChatId) -> bool {
+        match &self.allowed_chats {
+            Some(allowed) => allowed.contains(&chat_id),
+            None => true,
+        }
+    }
+
+    fn is_owner(&self, user: &db::DbUser) -> bool {
+        self.owner_id == Some(user.id())
+    }
+
+    fn ensure_owner(&self, user: &db::DbUser) -> HandlerResult {
+        if self.is_owner(user) {
+            Ok(())
+        } else {
+            Err(UserError::NotAuthorized.into())
+        }
+    }
+
+    /// Managing users and sidecars is up to the owner when there is one. Without one it stays open
+    /// to every trusted user like it was before owners were a thing
+    fn ensure_manager(&self, user: &db::DbUser) -> HandlerResult {
+        match self.owner_id {
+            Some(_) => self.ensure_owner(user),
+            None => Ok(()),
+        }
+    }
+
+    /// Chat settings are up to the owner when there is one. Without one each chat's admins get to
+    /// manage their own chat
+    async fn ensure_chat_manager(
+        &self,
+        bot: &telegram::Bot,
+        chat_id: types::ChatId,
+        user: &db::DbUser,
+    ) -> HandlerResult {
+        match self.owner_id {
+            Some(_) => self.ensure_owner(user),
+            None if bot.is_chat_admin(chat_id, user.id()).await? => Ok(()),
+            None => Err(UserError::NotChatAdmin.into()),
+        }
+    }
 }
 
 #[tokio::main]
@@ -47,24 +135,63 @@ async fn main() -> InitResult {
     if let Err(e) = dotenvy::dotenv() {
         eprintln!(".env error: {e}");
     }
-    pretty_env_logger::init();
+    init_logging();
     log::info!("Logging started");
 
+    let data_dir = utils::data_dir().ok_or(InitError::UnknownDataDir)?;
+    log::info!("Using data dir {}", data_dir.display());
     let db = db::Db::load().await?;
+    let owner_id = match std::env::var("RAMBOT_OWNER_ID") {
+        Ok(id) => {
+            let parsed = id.parse().map_err(|_| InitError::InvalidOwnerId(id))?;
+            Some(types::UserId(parsed))
+        }
+        Err(_) => {
+            log::warn!(
+                "RAMBOT_OWNER_ID isn't set. Owner-only commands are disabled and any trusted user \
+                can manage users and sidecars"
+            );
+            None
+        }
+    };
 
-    let bot = telegram::Bot::from_env();
+    let allowed_chats = allowed_chats_from_env().map(Arc::new);
+
+    let bot = telegram::Bot::from_env()?;
     bot.set_my_commands(command::Command::bot_commands())
         .await
         .map_err(InitError::BotCommands)?;
-    let handler = types::Update::filter_message().endpoint(
-        |bot: adaptors::Throttle<teloxide::Bot>, state: State, msg: types::Message| async move {
-            handle_message(bot.into(), state, msg).await;
-            Ok::<_, Infallible>(())
-        },
-    );
+    let handler = teloxide::dptree::entry()
+        .branch(types::Update::filter_message().endpoint(
+            |bot: adaptors::Throttle<teloxide::Bot>, state: State, msg: types::Message| async move {
+                handle_message(bot.into(), state, msg).await;
+                Ok::<_, Infallible>(())
+            },
+        ))
+        .branch(types::Update::filter_edited_message().endpoint(
+            |bot: adaptors::Throttle<teloxide::Bot>, state: State, msg: types::Message| async move {
+                handle_edited_message(bot.into(), state, msg).await;
+                Ok::<_, Infallible>(())
+            },
+        ))
+        .branch(
+            types::Update::filter_callback_query().endpoint(
+                |bot: adaptors::Throttle<teloxide::Bot>,
+                 state: State,
+                 query: types::CallbackQuery| async move {
+                    handle_callback_query(bot.into(), state, query).await;
+                    Ok::<_, Infallible>(())
+                },
+            ),
+        );
 
-    let transcribers = transcriber::Pool::spawn(2).await;
-    let send_msg_handle = buf_messenger::init(bot.clone());
+    let transcribers = transcriber::Pool::spawn(2, transcriber::Config::from_env()).await?;
+    health::spawn_heartbeat(transcribers.clone());
+    #[cfg(feature = "healthcheck")]
+    health::spawn_server(transcribers.clone()).await?;
+    #[cfg(feature = "metrics")]
+    metrics::spawn_server(transcribers.clone()).await?;
+    let send_msg_handle = buf_messenger::init(bot.clone(), buf_messenger::Config::from_env());
     let name = bot
         .get_me()
         .await
@@ -73,30 +200,338 @@ async fn main() -> InitResult {
         .ok_or(InitError::InvalidBotName)?;
     BOT_NAME.get_or_init(|| name);
     let state = State {
-        transcriber_pool: transcribers,
+        transcriber_pool: transcribers.clone(),
         send_msg_handle,
         db,
+        cancellations: cancel::Registry::default(),
+        retries: retry::Registry::default(),
+        origins: origins::Registry::default(),
+        transcripts: transcripts::Store::new(data_dir.join("transcripts")),
+        feedback: feedback::Store::new(data_dir.join("feedback.ron")),
+        pending: pending::Queue::load(data_dir.join("pending.ron")).await,
+        webhook: webhook::Webhook::from_env(),
+        #[cfg(feature = "summary")]
+        summaries: summary::Summaries::from_env(),
+        owner_id,
+        allowed_chats,
+        cutoffs: Cutoffs::from_env(),
+        max_duration_secs: max_duration_from_env(),
     };
-    Dispatcher::builder(bot.0, handler)
+    resume_pending_jobs(&bot, &state).await;
+
+    let mut dispatcher = Dispatcher::builder(bot.0.clone(), handler)
         // The default distribution_function runs each chat sequentially. Run everything
         // concurrently instead. Embrace the async
         .distribution_function::<()>(|_| None)
         .dependencies(teloxide::dptree::deps![state])
-        .enable_ctrlc_handler()
-        .build()
-        .dispatch()
-        .await;
+        .build();
+
+    // Instead of the dispatcher's built-in Ctrl-C handler we stop taking new updates while also
+    // letting the transcribers drain, so that in-flight handlers can wrap up their messages
+    let shutdown_token = dispatcher.shutdown_token();
+    let pool = transcribers.clone();
+    tokio::spawn(async move {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            log::error!("Failed listening for Ctrl-C: {e}");
+            return;
+        }
+        log::info!("Received Ctrl-C. Shutting down");
+        let dispatcher_stopped = shutdown_token.shutdown();
+        transcribers.shutdown(SHUTDOWN_GRACE).await;
+        if let Ok(dispatcher_stopped) = dispatcher_stopped {
+            dispatcher_stopped.await;
+        }
+    });
+    // Long polling unless a telegram webhook is configured
+    #[cfg(feature = "telegram-webhook")]
+    if let Some(listener) = bot.webhook_listener().await? {
+        let error_handler =
+            teloxide::error_handlers::LoggingErrorHandler::with_custom_text("Webhook error");
+        dispatcher
+            .dispatch_with_listener(listener, error_handler)
+            .await;
+    } else {
+        dispatcher.dispatch().await;
+    }
+    #[cfg(not(feature = "telegram-webhook"))]
+    dispatcher.dispatch().await;
 
+    // Also covers the dispatcher stopping on its own. Jobs that outlive the grace period get
+    // aborted which stops whisper, so nothing's left on the blocking threads to hold up the runtime
+    pool.shutdown(SHUTDOWN_GRACE).await;
     Ok(())
 }
 
-const SHORT_MSG_CUTOFF_SECS: u32 = 45;
-const LONG_MSG_CHUNK_CUTOFF_SECS: u32 = 210;
+/// `RUST_LOG` picks the levels like usual, but falls back to `info` when it's unset.
+/// `RAMBOT_LOG_FORMAT=json` swaps the pretty output for one JSON object per line for log collectors
+fn init_logging() {
+    let format = std::env::var("RAMBOT_LOG_FORMAT").ok();
+    let json = format.as_deref() == Some("json");
+    let mut builder = if json {
+        let mut builder = env_logger::Builder::new();
+        builder.format(|buf, record| {
+            let entry = serde_json::json!({
+                "ts": buf.timestamp_millis().to_string(),
+                "level": record.level().as_str(),
+                "target": record.target(),
+                "msg": record.args().to_string(),
+            });
+            writeln!(buf, "{entry}")
+        });
+        builder
+    } else {
+        pretty_env_logger::formatted_builder()
+    };
+    builder.filter_level(log::LevelFilter::Info);
+    if let Ok(filters) = std::env::var("RUST_LOG") {
+        builder.parse_filters(&filters);
+    }
+    builder.init();
+
+    if let Some(format) = format.filter(|format| !matches!(format.as_str(), "json" | "pretty")) {
+        log::warn!("Ignoring invalid RAMBOT_LOG_FORMAT {format:?}. Expected json or pretty");
+    }
+}
+
+/// `RAMBOT_MAX_DURATION_SECS` where unset or 0 means there's no limit
+fn max_duration_from_env() -> Option<u32> {
+    let secs = std::env::var("RAMBOT_MAX_DURATION_SECS").ok()?;
+    match secs.parse() {
+        Ok(0) => None,
+        Ok(secs) => Some(secs),
+        Err(e) => {
+            log::warn!("Ignoring invalid RAMBOT_MAX_DURATION_SECS {secs:?}: {e}");
+            None
+        }
+    }
+}
+
+fn ensure_within_max_duration(duration_secs: u32, max_secs: Option<u32>) -> HandlerResult {
+    match max_secs {
+        Some(max_secs) if duration_secs > max_secs => Err(UserError::TooLong(max_secs).into()),
+        _ => Ok(()),
+    }
+}
+
+/// The chat's cap or otherwise the bot-wide one
+async fn max_duration(state: &State, chat_id: types::ChatId) -> HandlerResult<Option<u32>> {
+    let max_secs = state.db.get_max_duration(chat_id).await?;
+    Ok(max_secs.or(state.max_duration_secs))
+}
+
+/// Telegram's duration gets checked up front, but it can be way off. These get checked again
+/// against the decoded audio
+#[derive(Clone, Copy, Debug, Default)]
+struct DurationLimits {
+    min_secs: Option<u32>,
+    max_secs: Option<u32>,
+}
+
+impl DurationLimits {
+    fn check(self, duration_secs: u32) -> HandlerResult {
+        ensure_within_max_duration(duration_secs, self.max_secs)?;
+        match self.min_secs {
+            Some(min_secs) if duration_secs < min_secs => Err(UserError::TooShort(min_secs).into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// A comma separated list of chat ids from `RAMBOT_ALLOWED_CHATS`. Unset means every chat is fair
+/// game
+fn allowed_chats_from_env() -> Option<HashSet<types::ChatId>> {
+    let ids = std::env::var("RAMBOT_ALLOWED_CHATS").ok()?;
+    let allowed: HashSet<_> = ids
+        .split(',')
+        .map(str::trim)
+        .filter(|id| !id.is_empty())
+        .filter_map(|id| match id.parse() {
+            Ok(id) => Some(types::ChatId(id)),
+            Err(_) => {
+                log::warn!("Ignoring invalid chat id in RAMBOT_ALLOWED_CHATS: {id:?}");
+                None
+            }
+        })
+        .collect();
+    log::info!("Only handling messages in {} allowed chats", allowed.len());
+    Some(allowed)
+}
+
+const DEFAULT_PREVIEW_CUTOFF_SECS: u32 = 45;
+const DEFAULT_CHUNK_CUTOFF_SECS: u32 = 210;
+/// Over an hour of audio with the default chunk cutoff
+const DEFAULT_MAX_PARTS: usize = 20;
+
+/// How much of the audio goes in the preview and in each part of the long message
+#[derive(Clone, Copy, Debug)]
+struct Cutoffs {
+    preview_secs: u32,
+    chunk_secs: u32,
+    /// The long message stops growing past this many parts. `None` lets it grow forever
+    max_parts: Option<usize>,
+}
+
+impl Cutoffs {
+    fn from_env() -> Self {
+        let read_secs = |var: &str, default| match std::env::var(var) {
+            Ok(secs) => match secs.parse() {
+                Ok(secs) if secs > 0 => secs,
+                _ => {
+                    log::warn!("Ignoring invalid {var} {secs:?}. Expected a positive number");
+                    default
+                }
+            },
+            Err(_) => default,
+        };
+
+        // 0 turns the cap off
+        let max_parts = match std::env::var("RAMBOT_MAX_PARTS") {
+            Ok(parts) => match parts.parse() {
+                Ok(0) => None,
+                Ok(parts) => Some(parts),
+                Err(e) => {
+                    log::warn!("Ignoring invalid RAMBOT_MAX_PARTS {parts:?}: {e}");
+                    Some(DEFAULT_MAX_PARTS)
+                }
+            },
+            Err(_) => Some(DEFAULT_MAX_PARTS),
+        };
+
+        Self {
+            preview_secs: read_secs("RAMBOT_PREVIEW_CUTOFF_SECS", DEFAULT_PREVIEW_CUTOFF_SECS),
+            chunk_secs: read_secs("RAMBOT_CHUNK_CUTOFF_SECS", DEFAULT_CHUNK_CUTOFF_SECS),
+            max_parts,
+        }
+    }
+
+    fn num_parts(self, duration_secs: u32) -> usize {
+        usize::try_from(1 + duration_secs / self.chunk_secs).unwrap()
+    }
+
+    /// The start and end of the audio that a part of the long message covers in seconds
+    fn part_range(self, index: usize, duration_secs: u32) -> (u32, u32) {
+        let start_secs = u32::try_from(index)
+            .unwrap_or(u32::MAX)
+            .saturating_mul(self.chunk_secs);
+        let end_secs = start_secs
+            .saturating_add(self.chunk_secs)
+            .min(duration_secs)
+            .max(start_secs);
+        (start_secs, end_secs)
+    }
+
+    /// How many parts out of `num_parts` get shown and how many get left off
+    fn cap_parts(self, num_parts: usize) -> (usize, usize) {
+        let num_omitted = self
+            .max_parts
+            .map_or(0, |max_parts| num_parts.saturating_sub(max_parts));
+        (num_parts - num_omitted, num_omitted)
+    }
+}
+
+/// Transcription messages are all formatted with MarkdownV2
+const TRANSCRIPTION_PARSE_MODE: Option<types::ParseMode> = Some(types::ParseMode::MarkdownV2);
+
+/// Telegram's limit for a message's text
+const MAX_MSG_CHARS: usize = 4096;
+
+/// Renders the lines as one expandable blockquote that stays collapsed until it's tapped
+fn expandable_quote(lines: &[String]) -> String {
+    let rows: Vec<_> = lines
+        .iter()
+        .flat_map(|line| line.lines())
+        .map(|row| format!(">{row}"))
+        .collect();
+    format!("**{}||", rows.join("\n"))
+}
+
+/// The bold `[1/3]` marker that starts off each part of a long message followed by the stretch of
+/// audio it covers, like `03:30–07:00`
+fn part_marker(index: usize, num_parts: usize, (start_secs, end_secs): (u32, u32)) -> String {
+    format!(
+        "*\\[{}/{}\\]* {:02}:{:02}–{:02}:{:02}",
+        index + 1,
+        num_parts,
+        start_secs / 60,
+        start_secs % 60,
+        end_secs / 60,
+        end_secs % 60
+    )
+}
+
+// NOTE: Telegram only allows reacting with a fixed set of emoji, so no ✅
+const REACTION_QUEUED: &str = "👀";
+const REACTION_TRANSCRIBING: &str = "✍";
+const REACTION_DONE: &str = "👌";
 
 struct Transcription {
     transcription: Vec<Line>,
     status: Option<String>,
-    message: TranscriptionLong,
+    /// Only there when the chat's layout includes a preview
+    preview: Option<UpdateMsgHandle>,
+    /// Gets deleted instead of finished off once the sidecar has the full transcript
+    preview_is_transient: bool,
+    /// The long message's parts. Empty when the chat's layout doesn't include it
+    multipart: Vec<UpdateMsgHandle>,
+    /// What each part was last told to show, so parts that stopped changing get left alone
+    rendered_parts: Vec<String>,
+    /// How many parts the audio's duration calls for. Only the ones that lines reach get sent
+    expected_parts: usize,
+    duration_secs: u32,
+    /// Where the long message's parts go when the chat's layout includes it
+    long_msg_dest: Option<(types::ChatId, types::MessageId)>,
+    /// Only set when the long message shares a forum chat with the voice message
+    long_msg_topic: Option<i32>,
+    /// The long message is one collapsed quote for as long as it fits in a single message
+    collapse_long: bool,
+    send_msg_handle: buf_messenger::SendMsgHandle,
+    /// Soft-wraps lines to this many columns when set
+    wrap_width: Option<usize>,
+    line_template: Option<utils::LineTemplate>,
+    cutoffs: Cutoffs,
+    /// Only set when whisper had to detect the language itself
+    language: Option<transcriber::DetectedLanguage>,
+    /// Set when only this many seconds from the start are getting transcribed
+    partial_secs: Option<u32>,
+    /// Set when it's long enough to get transcribed with a faster model
+    fast_model: Option<db::ModelSize>,
+    job_id: cancel::JobId,
+    bot: telegram::Bot,
+    /// The voice message being transcribed
+    voice_msg: (types::ChatId, types::MessageId),
+}
+
+/// Which of the transcription messages get sent and where the long message goes
+#[derive(Debug, PartialEq)]
+struct MsgPlan {
+    preview: bool,
+    long: bool,
+    /// Where the long message goes instead of the voice message's chat
+    sidecar: Option<types::ChatId>,
+}
+
+impl MsgPlan {
+    fn new(layout: db::Layout, sidecar_attach: Option<db::SidecarAttach>) -> Self {
+        let sidecar =
+            sidecar_attach.filter(|attach| attach.self_kind == db::SidecarKind::HasSidecar);
+        match sidecar {
+            // Long-only sidecars leave the main chat alone no matter its layout
+            Some(attach) if !attach.preview_in_main => Self {
+                preview: false,
+                long: true,
+                sidecar: Some(attach.to),
+            },
+            // Otherwise the long message only gets moved out to the sidecar when there's a
+            // preview left behind in the original chat
+            sidecar => Self {
+                preview: layout.has_preview(),
+                long: layout.has_long(),
+                sidecar: sidecar
+                    .filter(|_| layout == db::Layout::Both)
+                    .map(|attach| attach.to),
+            },
+        }
+    }
 }
 
 impl Transcription {
@@ -104,41 +539,247 @@ impl Transcription {
         duration_secs: u32,
         status_text: S,
         bot: telegram::Bot,
-        send_msg_handle: buf_messenger::SendMsgHandle,
-        chat_id: types::ChatId,
-        msg_id: types::MessageId,
-        sidecar_id: Option<types::ChatId>,
+        state: &State,
+        voice_msg: &RelevantMeta,
+        job_id: cancel::JobId,
+        redo: Option<origins::Placement>,
     ) -> HandlerResult<Self> {
         let status_text = status_text.into();
-        let (long_msg_chat, long_msg_reply_to, maybe_sidecar) = match sidecar_id {
-            Some(sidecar_id) => {
-                let forwarded = bot.forward_message(sidecar_id, chat_id, msg_id).await?;
-                let forwarded_id = forwarded.id();
-                let preview = send_msg_handle.dispatch_send_msg(chat_id, msg_id, &status_text)?;
-                let with_sidecar = WithSidecar { preview, forwarded };
-                (sidecar_id, forwarded_id, Some(with_sidecar))
-            }
-            None => (chat_id, msg_id, None),
+        let mut redo = redo.unwrap_or_default();
+        let RelevantMeta {
+            id: msg_id,
+            chat_id,
+            topic,
+            ..
+        } = *voice_msg;
+        let send_msg_handle = state.send_msg_handle.with_log_prefix(&job_id.log_prefix());
+        let layout = state.db.get_chat_layout(chat_id).await?;
+        let sidecar_attach = state.db.get_sidecar_attach(chat_id).await?;
+        let plan = MsgPlan::new(layout, sidecar_attach);
+        let wrap_width = state.db.get_wrap_width(chat_id).await?;
+        let line_template = state.db.get_line_template(chat_id).await?;
+        // The cancel button lives on whichever message shows up in the original chat first
+        let mut cancel_button = Some(job_id.button());
+
+        let preview = if plan.preview {
+            let text = escape_markdown_v2(&status_text);
+            let preview = match redo.preview.take() {
+                Some(prev_id) => send_msg_handle.dispatch_adopt_msg(
+                    chat_id,
+                    msg_id,
+                    topic,
+                    prev_id,
+                    text,
+                    cancel_button.take(),
+                    TRANSCRIPTION_PARSE_MODE,
+                )?,
+                None => send_msg_handle.dispatch_send_msg(
+                    chat_id,
+                    msg_id,
+                    topic,
+                    text,
+                    cancel_button.take(),
+                    TRANSCRIPTION_PARSE_MODE,
+                )?,
+            };
+            Some(preview)
+        } else {
+            None
         };
+
+        let expected_parts = state.cutoffs.num_parts(duration_secs);
         let mut multipart = Vec::new();
-        let num_parts = 1 + duration_secs / LONG_MSG_CHUNK_CUTOFF_SECS;
-        for index in 0..num_parts {
-            let chunk = send_msg_handle.dispatch_send_msg(
-                long_msg_chat,
-                long_msg_reply_to,
-                &format!("[{}/{}] {}", index + 1, num_parts, status_text),
-            )?;
-            multipart.push(chunk);
+        let mut long_msg_dest = None;
+        let mut long_msg_topic = None;
+        let mut preview_is_transient = false;
+        let collapse_long = plan.long && state.db.collapses_long(chat_id).await?;
+        if plan.long {
+            let sidecar_id = plan.sidecar;
+            let long_msg_chat = sidecar_id.unwrap_or(chat_id);
+            // Redos keep their parts where they were as long as they'd still go to the same chat
+            let prev_dest = redo
+                .long_msg_dest
+                .filter(|&(prev_chat, _)| prev_chat == long_msg_chat);
+            let mut prev_parts = match prev_dest {
+                Some(_) => std::mem::take(&mut redo.multipart),
+                None => Vec::new(),
+            }
+            .into_iter();
+            let (long_msg_chat, long_msg_reply_to) = match (prev_dest, sidecar_id) {
+                (Some(prev_dest), _) => prev_dest,
+                (None, Some(sidecar_id)) => {
+                    let forwarded = bot.forward_message(sidecar_id, chat_id, msg_id).await?;
+                    (sidecar_id, forwarded.id())
+                }
+                (None, None) => (chat_id, msg_id),
+            };
+            preview_is_transient = preview.is_some()
+                && sidecar_id.is_some()
+                && state.db.deletes_preview(chat_id).await?;
+            long_msg_dest = Some((long_msg_chat, long_msg_reply_to));
+            // Sidecars get the forwarded voice message outside of any topic
+            long_msg_topic = topic.filter(|_| long_msg_chat == chat_id);
+            // Only the first part goes out up front so that long voice messages don't wait on a
+            // send per part before anything else can happen. `reflow_message()` sends the rest
+            // once lines reach them. Collapsed ones only ever start out as the one message
+            let num_parts = if collapse_long { 1 } else { expected_parts };
+            for index in 0..num_parts {
+                let text = if collapse_long {
+                    escape_markdown_v2(&status_text)
+                } else {
+                    format!(
+                        "{} {}",
+                        part_marker(
+                            index,
+                            expected_parts,
+                            state.cutoffs.part_range(index, duration_secs)
+                        ),
+                        escape_markdown_v2(&status_text)
+                    )
+                };
+                let chunk = match prev_parts.next() {
+                    Some(prev_id) => send_msg_handle.dispatch_adopt_msg(
+                        long_msg_chat,
+                        long_msg_reply_to,
+                        long_msg_topic,
+                        prev_id,
+                        text,
+                        cancel_button.take(),
+                        TRANSCRIPTION_PARSE_MODE,
+                    )?,
+                    None if index == 0 => send_msg_handle.dispatch_send_msg(
+                        long_msg_chat,
+                        long_msg_reply_to,
+                        long_msg_topic,
+                        text,
+                        cancel_button.take(),
+                        TRANSCRIPTION_PARSE_MODE,
+                    )?,
+                    None => break,
+                };
+                multipart.push(chunk);
+            }
+            redo.multipart.extend(prev_parts);
+        }
+
+        // Whatever the redo can't reuse would just be a stale transcript left hanging around
+        for (leftover_chat, leftover_id) in redo.bot_msgs(chat_id) {
+            if let Err(e) = bot.delete_message(leftover_chat, leftover_id).await {
+                log::warn!(
+                    "{} Failed deleting leftover message {leftover_id}: {e}",
+                    job_id.log_prefix()
+                );
+            }
         }
 
-        Ok(Self {
+        let transcription = Self {
             transcription: Vec::new(),
             status: Some(status_text),
-            message: TranscriptionLong {
-                multipart,
-                maybe_sidecar,
-            },
-        })
+            preview,
+            preview_is_transient,
+            multipart,
+            rendered_parts: Vec::new(),
+            expected_parts,
+            duration_secs,
+            long_msg_dest,
+            long_msg_topic,
+            collapse_long,
+            send_msg_handle,
+            wrap_width,
+            line_template,
+            cutoffs: state.cutoffs,
+            language: None,
+            partial_secs: None,
+            fast_model: None,
+            job_id,
+            bot,
+            voice_msg: (chat_id, msg_id),
+        };
+        transcription.react(Some(REACTION_QUEUED)).await;
+        Ok(transcription)
+    }
+
+    /// Reactions are just a nicety, so failing to set them doesn't fail the transcription
+    async fn react(&self, emoji: Option<&str>) {
+        let (chat_id, msg_id) = self.voice_msg;
+        if let Err(e) = self.bot.set_message_reaction(chat_id, msg_id, emoji).await {
+            log::warn!(
+                "{} Failed setting reaction on {msg_id}: {e}",
+                self.job_id.log_prefix()
+            );
+        }
+    }
+
+    fn remove_cancel_button(&mut self) {
+        if let Some(preview) = &mut self.preview {
+            preview.remove_markup();
+        }
+        for chunk in &mut self.multipart {
+            chunk.remove_markup();
+        }
+        // The button only goes away with the next edit, so every part needs one
+        self.rendered_parts.clear();
+    }
+
+    /// The plain transcribed text without any timestamps or formatting
+    fn full_text(&self) -> String {
+        let lines: Vec<_> = self
+            .transcription
+            .iter()
+            .map(|line| line.text.trim())
+            .collect();
+        lines.join("\n")
+    }
+
+    /// Waits for every pending edit to land, which settles which messages hold the transcript
+    async fn flush(&mut self) -> HandlerResult {
+        let flushing = self
+            .preview
+            .iter_mut()
+            .chain(&mut self.multipart)
+            .map(UpdateMsgHandle::flush);
+        futures::future::join_all(flushing)
+            .await
+            .into_iter()
+            .collect()
+    }
+
+    /// Every message that ended up holding part of the transcript
+    fn placement(&self) -> origins::Placement {
+        origins::Placement {
+            preview: self.preview.as_ref().and_then(UpdateMsgHandle::msg_id),
+            multipart: self
+                .multipart
+                .iter()
+                .filter_map(UpdateMsgHandle::msg_id)
+                .collect(),
+            long_msg_dest: self.long_msg_dest,
+            documents: HashMap::new(),
+        }
+    }
+
+    /// Clears the preview out of the original chat when the sidecar has everything anyways
+    async fn delete_transient_preview(&mut self) -> HandlerResult {
+        if !self.preview_is_transient {
+            return Ok(());
+        }
+        match self.preview.take() {
+            Some(preview) => preview.delete().await,
+            None => Ok(()),
+        }
+    }
+
+    /// Goes by the audio's real duration once it's known. Parts past what it turned out to need
+    /// get deleted
+    async fn fit_duration(&mut self, duration_secs: u32) -> HandlerResult {
+        self.expected_parts = self.cutoffs.num_parts(duration_secs);
+        self.duration_secs = duration_secs;
+        while self.multipart.len() > self.expected_parts {
+            let surplus = self.multipart.pop().expect("Longer than expected");
+            surplus.delete().await?;
+        }
+        self.rendered_parts.truncate(self.multipart.len());
+        Ok(())
     }
 
     async fn update_status(&mut self, new_status: Option<&str>) -> HandlerResult {
@@ -147,126 +788,350 @@ impl Transcription {
     }
 
     async fn push_line(&mut self, line: Line) -> HandlerResult {
-        self.transcription.push(line);
+        let is_absorbed = self
+            .transcription
+            .last_mut()
+            .is_some_and(|last| last.absorb_repeat(&line) || last.absorb_fragment(&line));
+        if !is_absorbed {
+            self.transcription.push(line);
+        }
         self.reflow_message().await
     }
 
     async fn reflow_message(&mut self) -> HandlerResult {
-        let long_msg = &mut self.message;
-        if self.transcription.is_empty() {
-            let status = self.status.as_deref().unwrap_or("");
-            if let Some(WithSidecar { preview, .. }) = &mut long_msg.maybe_sidecar {
-                let _ = preview.dispatch_edit_text(status);
+        let mut status = escape_markdown_v2(self.status.as_deref().unwrap_or(""));
+        if let Some(language) = &self.language {
+            if !status.is_empty() {
+                status.push('\n');
             }
-            let num_parts = long_msg.multipart.len();
-            for (i, chunk) in long_msg.multipart.iter_mut().enumerate() {
-                let msg_text = format!("[{}/{}] {}", i + 1, num_parts, status);
-                let _ = chunk.dispatch_edit_text(msg_text);
+            status.push_str(&language_label(language));
+        }
+        if let Some(secs) = self.partial_secs {
+            if !status.is_empty() {
+                status.push('\n');
             }
-
-            // TODO: this is bad code style, use a match
-            return Ok(());
+            let label = format!("Quick transcription of the first {secs}s only");
+            status.push_str(&format!("_{}_", escape_markdown_v2(&label)));
         }
-
-        let status = self.status.as_deref().unwrap_or("");
-        let preview: Vec<_> = self
-            .transcription
-            .iter()
-            .take_while(|line| line.end_secs < SHORT_MSG_CUTOFF_SECS)
-            .map(utils::Line::to_telegram_line)
-            .collect();
-        let preview_is_truncated = self.transcription.len() > preview.len();
-        let mut preview_text = format!("Preview:\n{}", preview.join("\n"));
-        if preview_is_truncated {
-            preview_text.push_str("\n...");
+        if let Some(model) = self.fast_model {
+            if !status.is_empty() {
+                status.push('\n');
+            }
+            let label = format!("Long voice message, so the faster {model} model was used");
+            status.push_str(&format!("_{}_", escape_markdown_v2(&label)));
         }
 
-        if let Some(WithSidecar { preview, .. }) = &mut long_msg.maybe_sidecar {
-            let _ = preview.dispatch_edit_text(format!("{status}\n{preview_text}").trim());
+        if let Some(preview) = &mut self.preview {
+            let preview_text = if self.transcription.is_empty() {
+                status.clone()
+            } else {
+                format!(
+                    "{status}\n{}",
+                    render_preview(
+                        &self.transcription,
+                        self.wrap_width,
+                        self.line_template.as_ref(),
+                        self.cutoffs.preview_secs
+                    )
+                )
+            };
+            let _ = preview.dispatch_edit_text(preview_text.trim());
         }
 
+        let Some((long_msg_chat, long_msg_reply_to)) = self.long_msg_dest else {
+            return Ok(());
+        };
+        if self.collapse_long {
+            let lines: Vec<_> = self
+                .transcription
+                .iter()
+                .map(|line| line.to_telegram_line(self.wrap_width, self.line_template.as_ref()))
+                .collect();
+            let text = if lines.is_empty() {
+                status.clone()
+            } else {
+                format!("{status}\n{}", expandable_quote(&lines))
+            };
+            // Otherwise it gets split into parts like usual with this message becoming the first
+            if text.trim().chars().count() <= MAX_MSG_CHARS {
+                return self.render_part(0, text.trim(), long_msg_chat, long_msg_reply_to);
+            }
+        }
         let mut lines_iter = self.transcription.iter().peekable();
-        let mut chunk_duration_limit = LONG_MSG_CHUNK_CUTOFF_SECS;
-        let num_chunks = long_msg.multipart.len();
-        for (i, chunk) in long_msg.multipart.iter_mut().enumerate() {
+        let mut chunk_duration_limit = self.cutoffs.chunk_secs;
+        let mut chunks = Vec::new();
+        for _ in 0..self.expected_parts.max(self.multipart.len()) {
             let mut chunk_lines = Vec::new();
             while lines_iter
                 .peek()
-                .map_or(false, |line| line.end_secs < chunk_duration_limit)
+                .is_some_and(|line| line.end_secs < chunk_duration_limit)
             {
                 let line = lines_iter.next().expect("Peeked");
-                chunk_lines.push(line.to_telegram_line());
+                chunk_lines
+                    .push(line.to_telegram_line(self.wrap_width, self.line_template.as_ref()));
             }
-            let _ = chunk.dispatch_edit_text(
-                format!(
-                    "[{}/{}] {}\n{}",
-                    i + 1,
+            chunks.push(chunk_lines);
+            chunk_duration_limit += self.cutoffs.chunk_secs;
+        }
+        // Parts only go out once lines reach them. Any empty ones before that still get sent to
+        // keep the parts in order
+        let num_sent = chunks
+            .iter()
+            .rposition(|chunk_lines| !chunk_lines.is_empty())
+            .map_or(0, |last| last + 1)
+            .max(self.multipart.len());
+        // Once it's done any parts that never got lines aren't coming
+        let num_chunks = if self.status.is_none() {
+            num_sent
+        } else {
+            chunks.len()
+        };
+        // Really long recordings would otherwise flood the chat with parts
+        let (num_chunks, num_omitted) = self.cutoffs.cap_parts(num_chunks);
+        let num_sent = num_sent.min(num_chunks);
+        self.rendered_parts.resize(num_sent, String::new());
+        for (i, chunk_lines) in chunks.into_iter().take(num_sent).enumerate() {
+            let mut text = format!(
+                "{} {}\n{}",
+                part_marker(
+                    i,
                     num_chunks,
-                    status,
-                    chunk_lines.join("\n")
-                )
-                .trim(),
+                    self.cutoffs.part_range(i, self.duration_secs)
+                ),
+                status,
+                chunk_lines.join("\n")
             );
-            chunk_duration_limit += LONG_MSG_CHUNK_CUTOFF_SECS;
+            if num_omitted > 0 && i + 1 == num_chunks {
+                let notice = format!("…(transcript truncated, {num_omitted} chunks omitted)");
+                text.push_str(&format!("\n_{}_", escape_markdown_v2(&notice)));
+            }
+            self.render_part(i, text.trim(), long_msg_chat, long_msg_reply_to)?;
         }
 
         Ok(())
     }
 
-    pub async fn close(self) -> HandlerResult {
-        let TranscriptionLong {
-            multipart,
-            maybe_sidecar,
-        } = self.message;
-        // TODO: closing all of these can be done concurrently
-        for part in multipart {
-            part.close().await?;
+    /// Sends or edits the long message's part unless it already shows the text
+    fn render_part(
+        &mut self,
+        index: usize,
+        text: &str,
+        long_msg_chat: types::ChatId,
+        long_msg_reply_to: types::MessageId,
+    ) -> HandlerResult {
+        if self.rendered_parts.len() <= index {
+            self.rendered_parts.resize(index + 1, String::new());
         }
-
-        if let Some(WithSidecar { preview, .. }) = maybe_sidecar {
-            preview.close().await?;
+        if text == self.rendered_parts[index] {
+            return Ok(());
+        }
+        text.clone_into(&mut self.rendered_parts[index]);
+        match self.multipart.get_mut(index) {
+            Some(chunk) => {
+                let _ = chunk.dispatch_edit_text(text);
+            }
+            None => {
+                let chunk = self.send_msg_handle.dispatch_send_msg(
+                    long_msg_chat,
+                    long_msg_reply_to,
+                    self.long_msg_topic,
+                    text,
+                    None,
+                    TRANSCRIPTION_PARSE_MODE,
+                )?;
+                self.multipart.push(chunk);
+            }
         }
-
         Ok(())
     }
+
+    pub async fn close(self) -> HandlerResult {
+        // `join_all()` over `try_join_all()` since one failed flush shouldn't stop the rest
+        let closing = self
+            .preview
+            .into_iter()
+            .chain(self.multipart)
+            .map(UpdateMsgHandle::close);
+        futures::future::join_all(closing)
+            .await
+            .into_iter()
+            .collect()
+    }
 }
 
-struct TranscriptionLong {
-    maybe_sidecar: Option<WithSidecar>,
-    multipart: Vec<UpdateMsgHandle>,
+/// Something like `Detected: Spanish` in italics, calling out when whisper wasn't too sure
+fn language_label(language: &transcriber::DetectedLanguage) -> String {
+    let mut chars = language.name.chars();
+    let name: String = chars
+        .next()
+        .map(|first| first.to_uppercase().chain(chars).collect())
+        .unwrap_or_default();
+    let label = if language.is_unsure() {
+        format!("Detected: {name} (unsure)")
+    } else {
+        format!("Detected: {name}")
+    };
+    format!("_{}_", escape_markdown_v2(&label))
+}
+
+/// The lines from the start of the transcription with a trailing `...` if there's more
+fn render_preview(
+    transcription: &[Line],
+    wrap_width: Option<usize>,
+    line_template: Option<&utils::LineTemplate>,
+    preview_cutoff_secs: u32,
+) -> String {
+    let preview: Vec<_> = transcription
+        .iter()
+        .take_while(|line| line.end_secs < preview_cutoff_secs)
+        .map(|line| line.to_telegram_line(wrap_width, line_template))
+        .collect();
+    let preview_is_truncated = transcription.len() > preview.len();
+    let mut preview_text = format!("*Preview:*\n{}", preview.join("\n"));
+    if preview_is_truncated {
+        preview_text.push_str("\n\\.\\.\\.");
+    }
+    preview_text
+}
+
+async fn handle_callback_query(bot: telegram::Bot, state: State, query: types::CallbackQuery) {
+    let Some(job_id) = query
+        .data
+        .as_deref()
+        .and_then(cancel::JobId::from_callback_data)
+    else {
+        log::debug!("Ignoring unknown callback query: {:?}", query.data);
+        return;
+    };
+
+    let answer = match state.cancellations.requester(job_id) {
+        None => "That transcription already finished",
+        Some(requester) if !can_cancel(&bot, &state, &query, requester).await => {
+            "Only whoever asked for this transcription can cancel it 🙅"
+        }
+        Some(_) if state.cancellations.cancel(job_id) => {
+            log::info!("User {} cancelled job {job_id}", query.from.id);
+            "Cancelling ✋🐏"
+        }
+        Some(_) => "That transcription already finished",
+    };
+    if let Err(e) = bot.answer_callback_query(query.id, answer).await {
+        log::warn!("Failed answering callback query: {e}");
+    }
 }
 
-struct WithSidecar {
-    forwarded: Message,
-    preview: UpdateMsgHandle,
+/// Besides whoever asked for the job, the owner and the chat's admins get to cancel it too
+async fn can_cancel(
+    bot: &telegram::Bot,
+    state: &State,
+    query: &types::CallbackQuery,
+    requester: types::UserId,
+) -> bool {
+    let tapper = query.from.id;
+    if tapper == requester || state.owner_id == Some(tapper) {
+        return true;
+    }
+    let Some(chat_id) = query.message.as_ref().map(|msg| msg.chat.id) else {
+        return false;
+    };
+    match bot.is_chat_admin(chat_id, tapper).await {
+        Ok(is_admin) => is_admin,
+        Err(e) => {
+            log::warn!("Failed checking if user {tapper} is an admin of chat {chat_id}: {e}");
+            false
+        }
+    }
 }
 
 async fn handle_message(bot: telegram::Bot, state: State, msg: types::Message) {
     let start = Instant::now();
 
-    let on_err_reply_to = Message::new(bot.clone(), &msg);
+    let on_err_reply_to = Reply::new(bot.clone(), msg.chat.id, msg.id, telegram::topic_of(&msg));
     let res = try_handle_message(bot, state, msg.clone()).await;
     log::info!("Handling message {} took {:?}", msg.id, start.elapsed());
     if let Err(err) = res {
         match err {
             HandlerError::Ignore => { /* do as it says */ }
             HandlerError::UserError(_) => {
-                let _ = on_err_reply_to.reply(err.to_string()).await;
+                let _ = on_err_reply_to.send(err.to_string()).await;
             }
             _ => {
                 log::warn!("Hit error: {err}");
                 let msg = format!("The bot hit an error while handling this message.\n{err}");
-                let _ = on_err_reply_to.reply(msg).await;
+                let _ = on_err_reply_to.send(msg).await;
             }
         }
     }
 }
 
+async fn handle_edited_message(bot: telegram::Bot, state: State, msg: types::Message) {
+    let on_err_reply_to = Reply::new(bot.clone(), msg.chat.id, msg.id, telegram::topic_of(&msg));
+    match try_handle_edited_message(bot, state, &msg).await {
+        Ok(()) | Err(HandlerError::Ignore) => {}
+        Err(err @ HandlerError::UserError(_)) => {
+            let _ = on_err_reply_to.send(err.to_string()).await;
+        }
+        Err(err) => log::warn!("Hit error while handling edited message {}: {err}", msg.id),
+    }
+}
+
+/// Redoes the transcript in place when a voice message that we transcribed gets its audio swapped
+/// out. Most edits leave the audio alone, so those get ignored
+async fn try_handle_edited_message(
+    bot: telegram::Bot,
+    state: State,
+    msg: &types::Message,
+) -> HandlerResult {
+    let voice = msg.voice().ok_or(HandlerError::Ignore)?;
+    let (origin, placement) = state
+        .origins
+        .transcript_of(msg.chat.id, msg.id)
+        .ok_or(HandlerError::Ignore)?;
+    if origin.voice.file.unique_id == voice.file.unique_id {
+        log::debug!(
+            "Ignoring edit that kept the audio of voice message {}",
+            msg.id
+        );
+        return Err(HandlerError::Ignore);
+    }
+
+    // Redone for whoever it was transcribed for the first time around
+    let sender = state
+        .db
+        .user(origin.requester_id)
+        .await
+        .ok_or(UserError::MissingUser(origin.requester_id))?;
+    log::info!(
+        "Voice message {} in chat {} got new audio. Redoing its transcript",
+        msg.id,
+        msg.chat.id
+    );
+    let opts = JobOpts {
+        redo: Some(placement),
+        ..JobOpts::new(origin.quick)
+    };
+    try_handle_voice_message(
+        bot,
+        state,
+        &origin.voice_msg,
+        voice.to_owned(),
+        sender,
+        opts,
+    )
+    .await
+}
+
 async fn try_handle_message(
     bot: telegram::Bot,
     state: State,
     msg: types::Message,
 ) -> HandlerResult {
+    // Chats outside of the allowlist don't even get remembered
+    if !state.is_chat_allowed(msg.chat.id) {
+        log::debug!("Ignoring message in non-allowed chat: {}", msg.chat.id);
+        return Err(HandlerError::Ignore);
+    }
+
     // New message means a potentially more up-to-date view of the world
     state.db.update_metadata(&msg).await?;
 
@@ -274,20 +1139,50 @@ async fn try_handle_message(
     let RelevantMsg { meta, kind } = (&msg).try_into()?;
 
     // We only interact with users that we know
-    let from = meta.from.clone();
-    let sender = state.db.user(from.id).await.expect("Already added");
+    let from = meta.from;
+    // `update_metadata()` adds the sender, but a missing user is better off as an error than a
+    // crash
+    let sender = state
+        .db
+        .user(from)
+        .await
+        .ok_or(UserError::MissingUser(from))?;
     if !sender.is_trusted().await {
-        log::debug!("Ignoring non-trusted user: {from:?}");
+        // Onboarding is the one thing that strangers get a reply to, so they know what to ask for
+        if let RelevantMsgKind::Command(RelevantCommand {
+            com: command::Command::Start,
+            ..
+        }) = kind
+        {
+            return send_welcome(bot, &meta, &sender).await;
+        }
+        log::debug!("Ignoring non-trusted user: {from}");
         return Err(HandlerError::Ignore);
     }
 
     match kind {
         RelevantMsgKind::Command(com) => try_handle_command(bot, state, &meta, com, sender).await,
         RelevantMsgKind::Voice(voice) => {
-            let trigger = sender.get_transcribe_trigger().await;
-            if trigger == TranscribeTrigger::Always {
-                try_handle_voice_message(bot, state, &meta, voice, sender).await?;
+            let trigger = sender.get_effective_trigger(meta.chat_id).await?;
+            if !trigger.is_automatic() {
+                return Ok(());
+            }
+            // Only auto-transcription gets skipped. Explicit summons go through regardless
+            let min_secs = state.db.get_min_duration(meta.chat_id).await?;
+            if let Some(min_secs) = min_secs {
+                if voice.duration < min_secs {
+                    log::debug!(
+                        "Skipping {}s voice message under {min_secs}s",
+                        voice.duration
+                    );
+                    return Ok(());
+                }
             }
+            let opts = JobOpts {
+                min_secs,
+                ..JobOpts::new(false)
+            };
+            try_handle_voice_message(bot, state, &meta, voice, sender, opts).await?;
             Ok(())
         }
     }
@@ -304,18 +1199,31 @@ impl TryFrom<&types::Message> for RelevantMsg {
     fn try_from(msg: &types::Message) -> Result<Self, Self::Error> {
         let id = msg.id;
         let chat_id = msg.chat.id;
-        let from = msg.from().ok_or(HandlerError::Ignore)?.to_owned();
-        let meta = RelevantMeta { id, chat_id, from };
+        let from = msg.from().ok_or(HandlerError::Ignore)?;
+        // Bots (including us when forwarding into a sidecar) never get to trigger anything, so the
+        // bot can't end up transcribing its own messages no matter how chats are set up
+        if from.is_bot {
+            return Err(HandlerError::Ignore);
+        }
+        let meta = RelevantMeta {
+            id,
+            chat_id,
+            from: from.id,
+            topic: telegram::topic_of(msg),
+        };
         let kind = msg.try_into()?;
 
         Ok(Self { meta, kind })
     }
 }
 
+#[derive(Clone)]
 struct RelevantMeta {
     id: types::MessageId,
     chat_id: types::ChatId,
-    from: types::User,
+    from: types::UserId,
+    /// The forum topic it's in. Anything we send about the message goes in there too
+    topic: Option<i32>,
 }
 
 enum RelevantMsgKind {
@@ -327,9 +1235,20 @@ impl TryFrom<&types::Message> for RelevantMsgKind {
     type Error = HandlerError;
 
     fn try_from(msg: &types::Message) -> Result<Self, Self::Error> {
+        Self::parse(msg, BOT_NAME.get().map(String::as_str))
+    }
+}
+
+impl RelevantMsgKind {
+    /// `bot_name` is only `None` while starting up before we've found out our own name
+    fn parse(msg: &types::Message, bot_name: Option<&str>) -> HandlerResult<Self> {
         if let Some(text) = msg.text() {
-            let bot_name = BOT_NAME.get().unwrap();
-            let com = match command::Command::parse(text, &bot_name) {
+            // Commands can't be told apart from ones meant for other bots without it
+            let Some(bot_name) = bot_name else {
+                log::debug!("Ignoring text message that arrived before knowing the bot's name");
+                return Err(HandlerError::Ignore);
+            };
+            let com = match command::Command::parse(text, bot_name) {
                 // Probably just a regular text, so ignore
                 Err(CommandParseError::UnknownCommand(_) | CommandParseError::WrongBotName(_)) => {
                     return Err(HandlerError::Ignore);
@@ -356,42 +1275,100 @@ struct RelevantParentMsg {
     // TODO: chat_id and id shouldn't be optional
     meta: Option<RelevantMeta>,
     voice: Option<types::Voice>,
+    text: Option<String>,
+    kind: media::MediaKind,
+    /// Who originally sent the message when it's a forward
+    original_author: Option<OriginalAuthor>,
+    /// Whether we sent it ourselves
+    is_ours: bool,
+}
+
+impl RelevantParentMsg {
+    /// The user that the message's content actually belongs to
+    fn author_id(&self) -> Result<types::UserId, UserError> {
+        match &self.original_author {
+            Some(OriginalAuthor::User(user_id)) => Ok(*user_id),
+            Some(OriginalAuthor::Hidden) => Err(UserError::ForwardedAuthorHidden),
+            None => self
+                .meta
+                .as_ref()
+                .map(|meta| meta.from)
+                .ok_or(UserError::ReplyUnknownAuthor),
+        }
+    }
 }
 
 impl From<&types::Message> for RelevantParentMsg {
     fn from(msg: &types::Message) -> Self {
         let id = msg.id;
         let chat_id = msg.chat.id;
-        let meta = msg.from().map(|from| {
-            let from = from.to_owned();
-            RelevantMeta { id, chat_id, from }
+        let meta = msg.from().map(|from| RelevantMeta {
+            id,
+            chat_id,
+            from: from.id,
+            topic: telegram::topic_of(msg),
         });
         let voice = msg.voice().map(ToOwned::to_owned);
-        RelevantParentMsg { meta, voice }
+        let text = msg.text().map(ToOwned::to_owned);
+        let original_author = msg.forward_from().map(|from| match from {
+            types::ForwardedFrom::User(user) => OriginalAuthor::User(user.id),
+            // Either the author hides themselves in forwards or it was sent on behalf of a chat
+            types::ForwardedFrom::SenderName(_) | types::ForwardedFrom::Chat(_) => {
+                OriginalAuthor::Hidden
+            }
+        });
+        let is_ours = msg
+            .from()
+            .and_then(|from| from.username.as_deref())
+            .is_some_and(|username| Some(username) == BOT_NAME.get().map(String::as_str));
+        RelevantParentMsg {
+            meta,
+            voice,
+            text,
+            kind: media::MediaKind::of(msg),
+            original_author,
+            is_ours,
+        }
     }
 }
 
+enum OriginalAuthor {
+    User(types::UserId),
+    Hidden,
+}
+
 struct Reply {
     bot: telegram::Bot,
     chat_id: types::ChatId,
     msg_id: types::MessageId,
+    topic: Option<i32>,
 }
 
 impl Reply {
-    fn new(bot: telegram::Bot, chat_id: types::ChatId, msg_id: types::MessageId) -> Self {
+    fn new(
+        bot: telegram::Bot,
+        chat_id: types::ChatId,
+        msg_id: types::MessageId,
+        topic: Option<i32>,
+    ) -> Self {
         Self {
             bot,
             chat_id,
             msg_id,
+            topic,
         }
     }
 
+    /// Skips the buffering since replies are one-offs. See `buf_messenger::send_now()`
     async fn send<S: Into<String>>(&self, text: S) -> HandlerResult<telegram::Message> {
-        let msg = self
-            .bot
-            .send_message(self.chat_id, self.msg_id, text.into())
-            .await?;
-        Ok(msg)
+        buf_messenger::send_now(
+            &self.bot,
+            self.chat_id,
+            self.msg_id,
+            self.topic,
+            text.into(),
+        )
+        .await
     }
 }
 
@@ -402,11 +1379,12 @@ async fn try_handle_command(
     RelevantCommand { com, reply_to }: RelevantCommand,
     sender: db::DbUser,
 ) -> HandlerResult {
-    let reply = Reply::new(bot.clone(), meta.chat_id, meta.id);
+    let reply = Reply::new(bot.clone(), meta.chat_id, meta.id, meta.topic);
 
     log::debug!("Running command: {com:?}");
     let db = &state.db;
     match com {
+        command::Command::Start => send_welcome(bot, meta, &sender).await,
         command::Command::Vroom => {
             let start = tokio::time::Instant::now();
             let msg = reply.send("Checking...").await?;
@@ -417,46 +1395,197 @@ async fn try_handle_command(
             .await?;
             Ok(())
         }
-        command::Command::Transcribe => {
-            // TODO: if it's a forward then check the trigger of the original author instead of the
-            // author of the forwarder
-            // Check the trigger of the sender
-            let parent_msg = reply_to.ok_or(UserError::ReplyNotVoice)?;
-            let parent_voice = parent_msg.voice.ok_or(UserError::ReplyNotVoice)?;
-            let parent_meta = parent_msg.meta.ok_or(UserError::ReplyUnknownAuthor)?;
-            let parent = &parent_meta.from;
-            let parent = db
-                .user(parent.id)
+        command::Command::Ping => {
+            let start = tokio::time::Instant::now();
+            bot.get_me().await?;
+            let api_rtt = start.elapsed();
+            let transcriber::Stats {
+                busy_workers,
+                idle_workers,
+                queued_jobs,
+            } = state.transcriber_pool.stats();
+            reply
+                .send(format!(
+                    "Pong 🏓🐏\n\
+                    Telegram API round-trip: {api_rtt:.01?}\n\
+                    Workers: {busy_workers} busy, {idle_workers} idle\n\
+                    Queued jobs: {queued_jobs}"
+                ))
+                .await?;
+            Ok(())
+        }
+        command::Command::Version => {
+            let version = env!("CARGO_PKG_VERSION");
+            let commit = option_env!("RAMBOT_GIT_COMMIT")
+                .map(|commit| format!(" ({commit})"))
+                .unwrap_or_default();
+            let ffmpeg = transcriber::ffmpeg_version()
                 .await
-                .ok_or(UserError::ReplyUnknownAuthor)?;
-            let trigger = parent.get_transcribe_trigger().await;
-            match trigger {
-                TranscribeTrigger::Never => Err(UserError::BadSummon(trigger).into()),
-                TranscribeTrigger::SummonBySelf => {
-                    if parent == sender {
-                        try_handle_voice_message(bot, state, &parent_meta, parent_voice, sender)
-                            .await
-                    } else {
-                        Err(UserError::BadSummon(trigger).into())
-                    }
-                }
-                TranscribeTrigger::SummonByAny | TranscribeTrigger::Always => {
-                    try_handle_voice_message(bot, state, &parent_meta, parent_voice, sender).await
-                }
+                .unwrap_or_else(|| "not found".to_owned());
+            reply
+                .send(format!(
+                    "Version 🏷️🐏\n\
+                    Rambot: {version}{commit}\n\
+                    whisper-rs: {}\n\
+                    Transcriber: {}\n\
+                    Your model: {}\n\
+                    ffmpeg: {ffmpeg}",
+                    option_env!("RAMBOT_WHISPER_RS_VERSION").unwrap_or("unknown"),
+                    state.transcriber_pool.backend(),
+                    sender.get_model().await,
+                ))
+                .await?;
+            Ok(())
+        }
+        command::Command::Transcribe | command::Command::Quick => {
+            let quick = matches!(com, command::Command::Quick);
+            // Check the trigger of whoever originally sent the voice message which is the original
+            // author for forwards
+            let mut parent_msg = reply_to.ok_or(UserError::ReplyNotVoice)?;
+            if parent_msg.voice.is_none() && parent_msg.is_ours {
+                // Replying to one of our transcripts means the voice message it belongs to
+                let origin = parent_msg
+                    .meta
+                    .as_ref()
+                    .and_then(|meta| state.origins.get(meta.chat_id, meta.id))
+                    .ok_or(UserError::ReplyToTranscript)?;
+                parent_msg = RelevantParentMsg {
+                    meta: Some(origin.voice_msg),
+                    voice: Some(origin.voice),
+                    text: None,
+                    kind: media::MediaKind::Voice,
+                    original_author: None,
+                    is_ours: false,
+                };
+            }
+            let author_id = parent_msg.author_id()?;
+            let parent_voice = parent_msg
+                .voice
+                .ok_or(UserError::ReplyUnsupported(parent_msg.kind))?;
+            let parent_meta = parent_msg.meta.ok_or(UserError::ReplyUnknownAuthor)?;
+            let parent = db
+                .user(author_id)
+                .await
+                .ok_or(UserError::ReplyUnknownAuthor)?;
+            let trigger = parent.get_effective_trigger(parent_meta.chat_id).await?;
+            if !trigger.allows_summon(parent == sender) {
+                return Err(UserError::BadSummon(trigger).into());
             }
+            let opts = JobOpts::new(quick);
+            try_handle_voice_message(bot, state, &parent_meta, parent_voice, sender, opts).await
         }
-        command::Command::AttachSidecar(title) => {
-            match *db.get_chat_ids_by_public_title(&title).await {
-                [] => Err(UserError::NoChatTitled(title).into()),
-                [sidecar] => {
-                    db.attach_sidecar(meta.chat_id, sidecar).await?;
-                    reply.send("Sidecar attached successfully 💪🐏").await?;
-                    Ok(())
-                }
-                [_, _, ..] => Err(UserError::AmbiguousChatTitle.into()),
+        command::Command::TranscribeId(command::FileId(file_id)) => {
+            state.ensure_owner(&sender)?;
+            let file = bot.get_file_meta(file_id).await?;
+            // Telegram only knows the duration for actual voice messages. Everything from the
+            // duration limits to the time limit and stats goes by it, so it can't be left at 0
+            let duration = transcriber::probe_duration(&bot, file.id.clone()).await?;
+            let voice = types::Voice {
+                file,
+                duration,
+                mime_type: None,
+            };
+            // The transcript replies to the command in place of a voice message
+            try_handle_voice_message(bot, state, meta, voice, sender, JobOpts::new(false)).await
+        }
+        command::Command::Summary => {
+            let parent_msg = reply_to.ok_or(UserError::ReplyNotVoice)?;
+            let parent_meta = parent_msg.meta.ok_or(UserError::ReplyNotVoice)?;
+            let summary = summarize(&state, &parent_meta).await?;
+            reply.send(format!("Summary 📝🐏\n{summary}")).await?;
+            Ok(())
+        }
+        command::Command::Retry => {
+            let parent_msg = reply_to.ok_or(UserError::NotReply)?;
+            let parent_meta = parent_msg.meta.ok_or(UserError::NothingToRetry)?;
+            // Retrying is summoning the bot all over again, so it's held to the same trigger
+            let author_id = state
+                .retries
+                .author(parent_meta.chat_id, parent_meta.id)
+                .ok_or(UserError::NothingToRetry)?;
+            let author = db
+                .user(author_id)
+                .await
+                .ok_or(UserError::ReplyUnknownAuthor)?;
+            let trigger = author.get_effective_trigger(parent_meta.chat_id).await?;
+            if !trigger.allows_summon(author == sender) {
+                return Err(UserError::BadSummon(trigger).into());
             }
+            let failed = state
+                .retries
+                .take(parent_meta.chat_id, parent_meta.id)
+                .ok_or(UserError::NothingToRetry)?;
+            log::info!(
+                "Retrying voice message {} (attempt {})",
+                failed.voice_msg.id,
+                failed.attempts + 1
+            );
+            let opts = JobOpts {
+                attempt: failed.attempts + 1,
+                ..JobOpts::new(failed.quick)
+            };
+            try_handle_voice_message(bot, state, &failed.voice_msg, failed.voice, sender, opts)
+                .await
+        }
+        command::Command::Feedback(note) => {
+            let parent_msg = reply_to.ok_or(UserError::NotReply)?;
+            let note = note.trim();
+            if note.is_empty() {
+                return Err(UserError::EmptyFeedback.into());
+            }
+            let (Some(parent_meta), Some(transcript), true) =
+                (parent_msg.meta, parent_msg.text, parent_msg.is_ours)
+            else {
+                return Err(UserError::NotATranscript.into());
+            };
+            let origin = state.origins.get(parent_meta.chat_id, parent_meta.id);
+            state
+                .feedback
+                .save(feedback::Feedback {
+                    chat_id: parent_meta.chat_id.0,
+                    transcript_msg_id: parent_meta.id.0,
+                    user_id: sender.id().0,
+                    note: note.to_owned(),
+                    transcript,
+                    voice_msg_id: origin.as_ref().map(|origin| origin.voice_msg.id.0),
+                    voice_file_id: origin.map(|origin| origin.voice.file.id),
+                })
+                .await?;
+            reply
+                .send("Thanks! Your feedback was recorded 📮🐏")
+                .await?;
+            Ok(())
+        }
+        command::Command::AttachSidecar(target) => {
+            // Sidecars get looked up across every chat the bot knows about
+            state.ensure_manager(&sender)?;
+            let sidecar = select_chat(db, target.chat).await?;
+            db.attach_sidecar(meta.chat_id, sidecar, target.preview_in_main)
+                .await?;
+            let text = if target.preview_in_main {
+                "Sidecar attached successfully 💪🐏"
+            } else {
+                "Sidecar attached successfully. Transcripts will only show up over there 💪🐏"
+            };
+            reply.send(text).await?;
+            Ok(())
+        }
+        command::Command::BecomeSidecar(target) => {
+            // Same as `/attachsidecar` from the other end
+            state.ensure_manager(&sender)?;
+            let main_chat = select_chat(db, target.chat).await?;
+            db.attach_sidecar(main_chat, meta.chat_id, target.preview_in_main)
+                .await?;
+            let text = if target.preview_in_main {
+                "This chat is now a sidecar 💪🐏"
+            } else {
+                "This chat is now a sidecar and the only place its transcripts show up 💪🐏"
+            };
+            reply.send(text).await?;
+            Ok(())
         }
         command::Command::DetachSidecar => {
+            state.ensure_manager(&sender)?;
             db.detach_sidecar(meta.chat_id).await?;
             reply.send("Sidecar detached 🫨").await?;
             Ok(())
@@ -465,9 +1594,10 @@ async fn try_handle_command(
             let trigger = sender.get_transcribe_trigger().await;
             reply
                 .send(&format!(
-                    "Your trigger is currently set to: {}\n{}",
+                    "Your trigger is currently set to: {}\n{}\n\nAccepted values for /settrigger: {}",
                     trigger,
-                    trigger.desc()
+                    trigger.desc(),
+                    TranscribeTrigger::accepted_values()
                 ))
                 .await?;
             Ok(())
@@ -477,74 +1607,2170 @@ async fn try_handle_command(
             reply.send("Trigger updated 🔫🐏").await?;
             Ok(())
         }
+        command::Command::SetTranslate(translate) => {
+            sender.set_translate(translate).await?;
+            let text = if translate {
+                "Voice messages you ask for will be translated to English 🌍🐏"
+            } else {
+                "Voice messages you ask for will be transcribed as-is 🐏"
+            };
+            reply.send(text).await?;
+            Ok(())
+        }
+        command::Command::OptOut => {
+            sender.set_opted_out(meta.chat_id, true).await?;
+            reply
+                .send("Your voice messages here won't be transcribed unless you ask 🙈🐏")
+                .await?;
+            Ok(())
+        }
+        command::Command::OptIn => {
+            sender.set_opted_out(meta.chat_id, false).await?;
+            reply
+                .send("Your voice messages here follow your and the chat's triggers again 🐏")
+                .await?;
+            Ok(())
+        }
+        command::Command::SetModel(model) => {
+            // Catch a missing model now instead of on the next voice message
+            state.transcriber_pool.check_model(model)?;
+            sender.set_model(model).await?;
+            reply
+                .send(format!("Now using the {model} model for you 🧠🐏"))
+                .await?;
+            Ok(())
+        }
+        command::Command::SetChatTrigger(trigger) => {
+            state
+                .ensure_chat_manager(&bot, meta.chat_id, &sender)
+                .await?;
+            db.set_chat_default_trigger(meta.chat_id, trigger).await?;
+            reply
+                .send(format!(
+                    "Chat trigger updated to {trigger} 🔫🐏\n\
+                    Everyone's trigger here is now at least {trigger}"
+                ))
+                .await?;
+            Ok(())
+        }
+        command::Command::SetLayout(layout) => {
+            state
+                .ensure_chat_manager(&bot, meta.chat_id, &sender)
+                .await?;
+            db.set_chat_layout(meta.chat_id, layout).await?;
+            reply
+                .send(format!(
+                    "Transcriptions here will use the {layout} layout now 📐🐏"
+                ))
+                .await?;
+            Ok(())
+        }
+        command::Command::SetKeepTranscripts(keep) => {
+            state
+                .ensure_chat_manager(&bot, meta.chat_id, &sender)
+                .await?;
+            db.set_keep_transcripts(meta.chat_id, keep).await?;
+            if keep {
+                reply
+                    .send("Transcripts here will be kept for /export from now on 🗄️🐏")
+                    .await?;
+            } else {
+                // Opting out also gets rid of everything that was kept so far
+                state.transcripts.delete(meta.chat_id).await?;
+                reply
+                    .send(
+                        "Transcripts here are no longer kept and the stored ones are deleted 🗑️🐏",
+                    )
+                    .await?;
+            }
+            Ok(())
+        }
+        command::Command::Export => {
+            if !db.keeps_transcripts(meta.chat_id).await? {
+                return Err(UserError::TranscriptsNotKept.into());
+            }
+            let kept = state.transcripts.load(meta.chat_id).await?;
+            if kept.is_empty() {
+                reply
+                    .send("No transcripts have been kept here yet 🫙🐏")
+                    .await?;
+                return Ok(());
+            }
+            let text = transcripts::export_text(&kept);
+            bot.send_document(
+                meta.chat_id,
+                meta.id,
+                meta.topic,
+                format!("transcripts-{}.txt", meta.chat_id),
+                text.into_bytes(),
+            )
+            .await?;
+            Ok(())
+        }
+        command::Command::SetDeletePreview(delete) => {
+            state
+                .ensure_chat_manager(&bot, meta.chat_id, &sender)
+                .await?;
+            db.set_delete_preview(meta.chat_id, delete).await?;
+            let text = if delete {
+                "Previews here will get cleaned up once the sidecar has the full transcript 🧹🐏"
+            } else {
+                "Previews here will stick around now 📌🐏"
+            };
+            reply.send(text).await?;
+            Ok(())
+        }
+        command::Command::SetCollapsed(collapse) => {
+            state
+                .ensure_chat_manager(&bot, meta.chat_id, &sender)
+                .await?;
+            db.set_collapse_long(meta.chat_id, collapse).await?;
+            let text = if collapse {
+                "Long transcripts here will be one collapsed message while they fit 🪗🐏"
+            } else {
+                "Long transcripts here will be split into parts again 📚🐏"
+            };
+            reply.send(text).await?;
+            Ok(())
+        }
+        command::Command::SetJsonl(attach) => {
+            state
+                .ensure_chat_manager(&bot, meta.chat_id, &sender)
+                .await?;
+            db.set_attach_jsonl(meta.chat_id, attach).await?;
+            let text = if attach {
+                "Transcriptions here will come with a .jsonl attached 🧾🐏"
+            } else {
+                "Transcriptions here won't come with a .jsonl anymore 🧹🐏"
+            };
+            reply.send(text).await?;
+            Ok(())
+        }
+        command::Command::SetSubtitles(subtitles) => {
+            state
+                .ensure_chat_manager(&bot, meta.chat_id, &sender)
+                .await?;
+            db.set_subtitles(meta.chat_id, subtitles).await?;
+            let text = match subtitles {
+                db::Subtitles::Off => {
+                    "Transcriptions here won't come with subtitles anymore 🧹🐏".to_owned()
+                }
+                format => format!("Transcriptions here will come with .{format} subtitles 🎬🐏"),
+            };
+            reply.send(text).await?;
+            Ok(())
+        }
+        command::Command::SetWrap(width) => {
+            state
+                .ensure_chat_manager(&bot, meta.chat_id, &sender)
+                .await?;
+            // Zero is how you turn it back off
+            let width = (width != 0).then_some(width);
+            db.set_wrap_width(meta.chat_id, width).await?;
+            let text = match width {
+                Some(width) => {
+                    format!("Transcription lines here will wrap at {width} columns 📏🐏")
+                }
+                None => "Transcription lines here won't be wrapped anymore 📜🐏".to_owned(),
+            };
+            reply.send(text).await?;
+            Ok(())
+        }
+        command::Command::SetPrompt(prompt) => {
+            state
+                .ensure_chat_manager(&bot, meta.chat_id, &sender)
+                .await?;
+            // Whisper takes the prompt as a C string
+            let prompt = prompt.replace('\0', "");
+            let prompt = prompt.trim();
+            if prompt.chars().count() > MAX_PROMPT_CHARS {
+                return Err(UserError::PromptTooLong(MAX_PROMPT_CHARS).into());
+            }
+            // Empty is how you clear it
+            let prompt = (!prompt.is_empty()).then(|| prompt.to_owned());
+            let text = if prompt.is_some() {
+                "Transcriptions here will keep that vocabulary in mind now 📖🐏"
+            } else {
+                "Transcriptions here won't use a prompt anymore 🧹🐏"
+            };
+            db.set_prompt(meta.chat_id, prompt).await?;
+            reply.send(text).await?;
+            Ok(())
+        }
+        command::Command::SetTemplate(template) => {
+            state
+                .ensure_chat_manager(&bot, meta.chat_id, &sender)
+                .await?;
+            let template = template.trim();
+            // Empty goes back to the default
+            let text = if template.is_empty() {
+                db.set_line_template(meta.chat_id, None).await?;
+                "Transcription lines here are back to the usual layout 📜🐏"
+            } else {
+                template.parse::<utils::LineTemplate>()?;
+                db.set_line_template(meta.chat_id, Some(template.to_owned()))
+                    .await?;
+                "Transcription lines here will use that template now 🖋️🐏"
+            };
+            reply.send(text).await?;
+            Ok(())
+        }
+        command::Command::SetMaxDuration(secs) => {
+            state.ensure_owner(&sender)?;
+            // Zero goes back to the bot's default
+            let max_secs = (secs != 0).then_some(secs);
+            db.set_max_duration(meta.chat_id, max_secs).await?;
+            let text = match max_secs.or(state.max_duration_secs) {
+                Some(secs) => format!("Voice messages here can be up to {secs}s long now ⏱️🐏"),
+                None => "Voice messages here can be any length now ⏱️🐏".to_owned(),
+            };
+            reply.send(text).await?;
+            Ok(())
+        }
+        command::Command::SetMinDuration(secs) => {
+            state
+                .ensure_chat_manager(&bot, meta.chat_id, &sender)
+                .await?;
+            // Zero is how you turn it back off
+            let min_secs = (secs != 0).then_some(secs);
+            db.set_min_duration(meta.chat_id, min_secs).await?;
+            let text = match min_secs {
+                Some(secs) => format!(
+                    "Voice messages here under {secs}s won't be transcribed automatically now ⏱️🐏"
+                ),
+                None => {
+                    "Voice messages here get transcribed regardless of length now ⏱️🐏".to_owned()
+                }
+            };
+            reply.send(text).await?;
+            Ok(())
+        }
         command::Command::AddUser(name) => {
+            state.ensure_manager(&sender)?;
             let parent_msg = reply_to.ok_or(UserError::NotReply)?;
             let meta = parent_msg.meta.ok_or(UserError::ReplyUnknownAuthor)?;
-            db.add_trusted_user(meta.from.id, name.clone()).await?;
+            let name = normalize_user_name(&name)?;
+            db.add_trusted_user(meta.from, name.clone()).await?;
             reply.send(&format!("Added user {name} 🫡")).await?;
             Ok(())
         }
+        command::Command::Stats => {
+            let stats = sender.get_stats().await;
+            reply.send(format_stats("Your", stats)).await?;
+            Ok(())
+        }
+        command::Command::WhoAmI => {
+            let yes_no = |b| if b { "yes" } else { "no" };
+            let trigger = sender.get_transcribe_trigger().await;
+            let effective_trigger = sender.get_effective_trigger(meta.chat_id).await?;
+            let text = format!(
+                "Your settings 🪪🐏\n\
+                Id: {}\n\
+                Trusted: {}\n\
+                Owner: {}\n\
+                Trigger: {trigger} ({effective_trigger} in this chat)\n\
+                Opted out here: {}\n\
+                Translate: {}\n\
+                Model: {}",
+                sender.id(),
+                yes_no(sender.is_trusted().await),
+                yes_no(state.is_owner(&sender)),
+                yes_no(sender.is_opted_out(meta.chat_id).await),
+                yes_no(sender.get_translate().await),
+                sender.get_model().await,
+            );
+            reply.send(text).await?;
+            Ok(())
+        }
+        command::Command::AllStats => {
+            state.ensure_owner(&sender)?;
+            let stats = db.total_stats().await;
+            reply.send(format_stats("Everyone's", stats)).await?;
+            Ok(())
+        }
+        command::Command::SelfTest => {
+            state.ensure_owner(&sender)?;
+            let timings = run_self_test(bot, &state, meta).await?;
+            reply
+                .send(format!("Self-test passed ✅🐏\n{timings}"))
+                .await?;
+            Ok(())
+        }
+        command::Command::Config => {
+            state.ensure_owner(&sender)?;
+            reply.send(format_config(&state)).await?;
+            Ok(())
+        }
+    }
+}
+
+/// `RAMBOT_SELF_TEST_AUDIO` or else `self-test.ogg` in the data dir
+fn self_test_audio_path() -> HandlerResult<PathBuf> {
+    match std::env::var_os("RAMBOT_SELF_TEST_AUDIO") {
+        Some(path) if !path.is_empty() => Ok(PathBuf::from(path)),
+        _ => utils::data_dir()
+            .map(|dir| dir.join("self-test.ogg"))
+            .ok_or(HandlerError::UnknownDataDir),
+    }
+}
+
+/// Runs a sample through the whole pipeline from uploading it as a voice message to transcribing
+/// it with the default model. Nothing gets recorded in anyone's stats
+async fn run_self_test(
+    bot: telegram::Bot,
+    state: &State,
+    meta: &RelevantMeta,
+) -> HandlerResult<StageTimings> {
+    let path = self_test_audio_path()?;
+    let audio = tokio::fs::read(&path)
+        .await
+        .map_err(|_| UserError::InvalidSelfTestAudio(path.clone()))?;
+    let upload_start = Instant::now();
+    let (sample_msg, voice) = bot
+        .send_voice(
+            meta.chat_id,
+            meta.id,
+            meta.topic,
+            "self-test.ogg".to_owned(),
+            audio,
+        )
+        .await?
+        .ok_or(UserError::InvalidSelfTestAudio(path))?;
+    let upload = upload_start.elapsed();
+
+    let sample_meta = RelevantMeta {
+        id: sample_msg.id(),
+        ..meta.clone()
+    };
+    let mut cancel_handle = state.cancellations.register(meta.from);
+    log::info!(
+        "{} Running self-test in chat {}",
+        cancel_handle.id().log_prefix(),
+        meta.chat_id
+    );
+    let mut bot_msg = Transcription::start(
+        voice.duration,
+        "Queued...",
+        bot.clone(),
+        state,
+        &sample_meta,
+        cancel_handle.id(),
+        None,
+    )
+    .await?;
+    let pool = &state.transcriber_pool;
+    let res = tokio::select! {
+        res = run_transcription(
+            bot,
+            pool,
+            &mut bot_msg,
+            voice.file.id,
+            voice.duration,
+            transcriber::Settings::default(),
+            DurationLimits::default(),
+        ) => res,
+        () = pool.stopped() => Err(HandlerError::WorkerDied),
+        () = cancel_handle.cancelled() => Err(HandlerError::Cancelled),
+    };
+
+    bot_msg.remove_cancel_button();
+    let status = match &res {
+        Ok(_) => None,
+        Err(_) => Some("Self-test failed"),
+    };
+    let _ = bot_msg.update_status(status).await;
+    bot_msg.react(res.is_ok().then_some(REACTION_DONE)).await;
+    bot_msg.close().await?;
+
+    res.map(|timings| StageTimings { upload, ..timings })
+}
+
+#[cfg(feature = "summary")]
+async fn summarize(state: &State, voice_msg: &RelevantMeta) -> HandlerResult<String> {
+    let summaries = state
+        .summaries
+        .as_ref()
+        .ok_or(UserError::SummariesDisabled)?;
+    summaries.summarize(voice_msg.chat_id, voice_msg.id).await
+}
+
+#[cfg(not(feature = "summary"))]
+async fn summarize(_: &State, _: &RelevantMeta) -> HandlerResult<String> {
+    Err(UserError::SummariesDisabled.into())
+}
+
+/// Everything that got picked up from the env. Secrets like the token and proxy url only get
+/// reported as being set or not
+fn format_config(state: &State) -> String {
+    let on_off = |b| if b { "on" } else { "off" };
+    let transcriber::Config {
+        vad,
+        timeout_factor,
+        no_speech_threshold,
+        threads,
+        beam_size,
+        language,
+        word_timestamps,
+        fast_mode,
+        circuit_breaker,
+    } = state.transcriber_pool.config();
+    let Cutoffs {
+        preview_secs,
+        chunk_secs,
+        max_parts,
+    } = state.cutoffs;
+    let default_model = match transcriber::model_path(db::ModelSize::Default) {
+        Ok(path) => path.display().to_string(),
+        Err(e) => e.to_string(),
+    };
+    let features: Vec<_> = [
+        ("summary", cfg!(feature = "summary")),
+        ("healthcheck", cfg!(feature = "healthcheck")),
+        ("metrics", cfg!(feature = "metrics")),
+        ("telegram-webhook", cfg!(feature = "telegram-webhook")),
+    ]
+    .into_iter()
+    .filter_map(|(name, enabled)| enabled.then_some(name))
+    .collect();
+    #[cfg(feature = "summary")]
+    let summaries = state.summaries.is_some();
+    #[cfg(not(feature = "summary"))]
+    let summaries = false;
+
+    format!(
+        "Config ⚙️🐏\n\
+        Data dir: {}\n\
+        Temp dir: {}\n\
+        Transcriber: {}\n\
+        Default model: {default_model}\n\
+        Workers: {}\n\
+        Threads per worker: {threads}\n\
+        Beam size: {}\n\
+        Language: {language}\n\
+        Word timestamps: {}\n\
+        Fast mode: {}\n\
+        Circuit breaker: {}\n\
+        Silence trimming: {}\n\
+        No speech threshold: {no_speech_threshold}\n\
+        Timeout factor: {timeout_factor}x\n\
+        Edit debounce: {:?}\n\
+        Preview cutoff: {preview_secs}s\n\
+        Chunk cutoff: {chunk_secs}s\n\
+        Max parts: {}\n\
+        Max duration: {}\n\
+        Allowed chats: {}\n\
+        Proxy: {}\n\
+        Webhook: {}\n\
+        Summaries: {}\n\
+        Features: {}",
+        utils::data_dir().map_or_else(|| "unknown".to_owned(), |dir| dir.display().to_string()),
+        utils::tmp_dir().display(),
+        state.transcriber_pool.backend(),
+        state.transcriber_pool.num_workers(),
+        beam_size.map_or_else(|| "greedy".to_owned(), |size| size.to_string()),
+        on_off(word_timestamps),
+        fast_mode.map_or_else(
+            || "off".to_owned(),
+            |fast| format!("{} model past {}s", fast.model, fast.after_secs)
+        ),
+        circuit_breaker.map_or_else(
+            || "off".to_owned(),
+            |breaker| format!(
+                "pause {:?} after {} failures",
+                breaker.pause, breaker.after_failures
+            )
+        ),
+        vad.aggressiveness.map_or_else(
+            || "off".to_owned(),
+            |level| format!("{level:?}").to_lowercase()
+        ),
+        state.send_msg_handle.config().edit_debounce,
+        max_parts.map_or_else(|| "none".to_owned(), |parts| parts.to_string()),
+        state
+            .max_duration_secs
+            .map_or_else(|| "none".to_owned(), |secs| format!("{secs}s")),
+        state
+            .allowed_chats
+            .as_ref()
+            .map_or_else(|| "all".to_owned(), |chats| chats.len().to_string()),
+        on_off(std::env::var_os("RAMBOT_PROXY_URL").is_some()),
+        on_off(state.webhook.is_some()),
+        on_off(summaries),
+        if features.is_empty() {
+            "none".to_owned()
+        } else {
+            features.join(", ")
+        },
+    )
+}
+
+fn format_stats(whose: &str, stats: db::Stats) -> String {
+    let db::Stats {
+        transcribe_count,
+        total_secs,
+    } = stats;
+    format!(
+        "{whose} stats 📊🐏\n\
+        Voice messages transcribed: {transcribe_count}\n\
+        Audio processed: {:02}:{:02}:{:02}",
+        total_secs / 3600,
+        total_secs / 60 % 60,
+        total_secs % 60
+    )
+}
+
+/// Everything about a transcription job besides the voice message and who asked for it
+struct JobOpts {
+    /// Starts at 1
+    attempt: u8,
+    /// Only the start gets transcribed
+    quick: bool,
+    /// An earlier transcript of the voice message to redo in place instead of sending a new one
+    redo: Option<origins::Placement>,
+    /// The chat's minimum duration when it's getting transcribed automatically
+    min_secs: Option<u32>,
+}
+
+impl JobOpts {
+    fn new(quick: bool) -> Self {
+        Self {
+            attempt: 1,
+            quick,
+            redo: None,
+            min_secs: None,
+        }
     }
 }
 
+/// Stray whitespace from the command would otherwise stick around in the name forever
+fn normalize_user_name(name: &str) -> Result<String, UserError> {
+    let name = name.trim();
+    if name.is_empty() {
+        return Err(UserError::MissingUserName);
+    }
+    Ok(name.to_owned())
+}
+
+/// Only happens in private chats since `/start` is how telegram kicks off a DM with a bot
+async fn send_welcome(
+    bot: telegram::Bot,
+    meta: &RelevantMeta,
+    sender: &db::DbUser,
+) -> HandlerResult {
+    if !meta.chat_id.is_user() {
+        return Err(HandlerError::Ignore);
+    }
+    let text = if sender.is_trusted().await {
+        let trigger = sender.get_transcribe_trigger().await;
+        format!(
+            "Hi there 👋🐏\n\
+            I transcribe voice messages and you're all set to use me.\n\n\
+            Your trigger is {trigger}: {}\n\
+            Change it with /settrigger ({}) or reply to any voice message with /transcribe",
+            trigger.desc(),
+            TranscribeTrigger::accepted_values(),
+        )
+    } else {
+        format!(
+            "Hi there 👋🐏\n\
+            I transcribe voice messages, but only for people I know.\n\n\
+            To get added, ask my owner to reply to one of your messages in a chat we share with \
+            /adduser followed by your name. Your id is {}",
+            sender.id()
+        )
+    };
+    Reply::new(bot, meta.chat_id, meta.id, meta.topic)
+        .send(text)
+        .await?;
+    Ok(())
+}
+
+/// Finds a chat across every chat the bot knows about
+async fn select_chat(db: &db::Db, selector: command::ChatSelector) -> HandlerResult<types::ChatId> {
+    let chat_id = match selector {
+        command::ChatSelector::Id(id) => id,
+        command::ChatSelector::Username(username) => db
+            .get_chat_id_by_username(&username)
+            .await
+            .ok_or(UserError::NoChatWithUsername(username))?,
+        command::ChatSelector::Title(title) => match *db.get_chat_ids_by_public_title(&title).await
+        {
+            [] => return Err(UserError::NoChatTitled(title).into()),
+            [chat_id] => chat_id,
+            [_, _, ..] => return Err(UserError::AmbiguousChatTitle.into()),
+        },
+    };
+    Ok(chat_id)
+}
+
 async fn try_handle_voice_message(
     bot: telegram::Bot,
     state: State,
     meta: &RelevantMeta,
     voice: types::Voice,
     sender: db::DbUser,
+    opts: JobOpts,
 ) -> HandlerResult {
-    // TODO: Refactor to avoid `.unwrap()`
-    let maybe_sidecar_id = match state.db.get_sidecar_attach(meta.chat_id).await.unwrap() {
-        // Sidecar chats are ignored
-        Some(a) if a.self_kind == db::SidecarKind::IsSidecar => {
+    let JobOpts { attempt, quick, .. } = opts;
+    // Checked before anything gets queued up since these can tie up a worker for ages. Quick
+    // transcriptions only ever take a bite out of the start, so they're fine regardless
+    if !quick {
+        let max_secs = max_duration(&state, meta.chat_id).await?;
+        ensure_within_max_duration(voice.duration, max_secs)?;
+    }
+
+    state
+        .pending
+        .insert(pending::PendingJob {
+            chat_id: meta.chat_id,
+            voice_msg_id: meta.id.0,
+            author_id: meta.from,
+            requester_id: sender.id(),
+            voice_file_id: voice.file.id.clone(),
+            voice_file_unique_id: voice.file.unique_id.clone(),
+            voice_file_size: voice.file.size,
+            duration_secs: voice.duration,
+            attempt,
+            quick,
+            topic: meta.topic,
+        })
+        .await;
+    let res = transcribe_voice_message(bot, state.clone(), meta, voice, sender, opts).await;
+    // Jobs that got cut off by a shutdown stick around to get picked back up on the next start
+    if !state.transcriber_pool.is_shutting_down() {
+        state.pending.remove(meta.chat_id, meta.id).await;
+    }
+    res
+}
+
+/// Picks the jobs that got cut off by the last shutdown back up
+///
+/// Only as many get fed in at a time as there are workers, so that a big backlog neither overflows
+/// the queue nor crowds out new voice messages
+async fn resume_pending_jobs(
+    bot: &telegram::Bot,
+    state: &State,
+) -> Vec<tokio::task::JoinHandle<()>> {
+    let interrupted = state.pending.jobs().await;
+    if !interrupted.is_empty() {
+        log::info!("Resuming {} interrupted transcriptions", interrupted.len());
+    }
+    let resuming = Arc::new(Semaphore::new(state.transcriber_pool.num_workers().into()));
+    let mut handles = Vec::new();
+    for job in interrupted {
+        let (chat_id, voice_msg_id) = (job.chat_id, types::MessageId(job.voice_msg_id));
+        // The job itself could be what keeps taking the bot down
+        if job.attempt >= retry::MAX_ATTEMPTS {
+            log::warn!(
+                "Giving up on {voice_msg_id} in chat {chat_id} after {} attempts",
+                job.attempt
+            );
+            state.pending.remove(chat_id, voice_msg_id).await;
+            continue;
+        }
+        let (bot, state, resuming) = (bot.clone(), state.clone(), Arc::clone(&resuming));
+        handles.push(tokio::spawn(async move {
+            let _permit = resuming.acquire().await;
+            // Most likely the voice message got deleted in the meantime. Nothing to do but move on
+            if let Err(e) = resume_pending_job(bot, state.clone(), job).await {
+                log::warn!("Failed resuming {voice_msg_id} in chat {chat_id}: {e}");
+                state.pending.remove(chat_id, voice_msg_id).await;
+            }
+        }));
+    }
+    handles
+}
+
+async fn resume_pending_job(
+    bot: telegram::Bot,
+    state: State,
+    job: pending::PendingJob,
+) -> HandlerResult {
+    let meta = RelevantMeta {
+        id: types::MessageId(job.voice_msg_id),
+        chat_id: job.chat_id,
+        from: job.author_id,
+        topic: job.topic,
+    };
+    let voice = types::Voice {
+        file: types::FileMeta {
+            id: job.voice_file_id,
+            unique_id: job.voice_file_unique_id,
+            size: job.voice_file_size,
+        },
+        duration: job.duration_secs,
+        mime_type: None,
+    };
+    let sender = state
+        .db
+        .user(job.requester_id)
+        .await
+        .ok_or(UserError::MissingUser(job.requester_id))?;
+    let opts = JobOpts {
+        attempt: job.attempt + 1,
+        ..JobOpts::new(job.quick)
+    };
+    try_handle_voice_message(bot, state, &meta, voice, sender, opts).await
+}
+
+async fn transcribe_voice_message(
+    bot: telegram::Bot,
+    state: State,
+    meta: &RelevantMeta,
+    voice: types::Voice,
+    sender: db::DbUser,
+    opts: JobOpts,
+) -> HandlerResult {
+    let JobOpts {
+        attempt,
+        quick,
+        redo,
+        min_secs,
+    } = opts;
+    // Sidecar chats are ignored
+    if let Some(attach) = state.db.get_sidecar_attach(meta.chat_id).await? {
+        if attach.self_kind == db::SidecarKind::IsSidecar {
             log::debug!("Ignoring sidecar chat voice message");
             return Err(HandlerError::Ignore);
         }
-        Some(a) => Some(a.to),
-        None => None,
-    };
+    }
 
     let voice_file_id = &voice.file.id;
-    let voice_msg_duration_secs = voice.duration;
+    // Anything short enough gets transcribed in full either way
+    let partial_secs = (quick && voice.duration > QUICK_SECS).then_some(QUICK_SECS);
+    let voice_msg_duration_secs = partial_secs.unwrap_or(voice.duration);
+    // Catch it before it takes up a spot in the queue
+    if voice.file.size > telegram::MAX_DOWNLOAD_BYTES {
+        return Err(UserError::FileTooLarge(voice.file.size).into());
+    }
+
+    // A redo swaps the earlier transcript's attachments out in place instead of piling on more
+    let is_redo = redo.is_some();
+    let mut prev_documents = redo
+        .as_ref()
+        .map(|placement| placement.documents.clone())
+        .unwrap_or_default();
 
-    // TODO: refactor this so that the initial message doesn't send more than one, and then after
-    // the download finishes it sends the rest
     // Send our initial reply
+    let mut cancel_handle = state.cancellations.register(sender.id());
+    log::info!(
+        "{} Accepted voice message {} in chat {} ({voice_msg_duration_secs}s, attempt {attempt})",
+        cancel_handle.id().log_prefix(),
+        meta.id,
+        meta.chat_id
+    );
     let mut bot_msg = Transcription::start(
         voice_msg_duration_secs,
         "Queued...",
         bot.clone(),
-        state.send_msg_handle,
-        meta.chat_id,
-        meta.id,
-        maybe_sidecar_id,
+        &state,
+        meta,
+        cancel_handle.id(),
+        redo,
     )
     .await?;
+    bot_msg.partial_secs = partial_secs;
 
-    let job = state
-        .transcriber_pool
-        .submit_job(
+    // Whoever asked for the transcription is the one that's going to be reading it
+    let mut settings = transcriber::Settings {
+        translate: sender.get_translate().await,
+        model: sender.get_model().await,
+        // The vocabulary belongs to the chat though
+        prompt: state.db.get_prompt(meta.chat_id).await?,
+        // Telegram's duration can be off, so the cap applies regardless
+        max_secs: quick.then_some(QUICK_SECS),
+        greedy: false,
+    };
+    let limits = DurationLimits {
+        min_secs,
+        max_secs: if quick {
+            None
+        } else {
+            max_duration(&state, meta.chat_id).await?
+        },
+    };
+    let fast_mode = state.transcriber_pool.config().fast_mode;
+    if let Some(fast_mode) = fast_mode.filter(|fast| fast.applies_to(voice_msg_duration_secs)) {
+        fast_mode.speed_up(&mut settings);
+        bot_msg.fast_model = Some(fast_mode.model);
+    }
+    let pool = &state.transcriber_pool;
+    let res = tokio::select! {
+        res = run_transcription(
             bot.clone(),
+            pool,
+            &mut bot_msg,
             voice_file_id.to_owned(),
             voice_msg_duration_secs,
-        )
-        .await;
+            settings,
+            limits,
+        ) => res,
+        // A job that outlived the shutdown grace period is never going to report back
+        () = pool.stopped() => Err(HandlerError::WorkerDied),
+        () = cancel_handle.cancelled() => Err(HandlerError::Cancelled),
+    };
+
+    // Whatever happened there's nothing left to cancel now
+    bot_msg.remove_cancel_button();
+    if res.is_err() {
+        bot_msg.react(None).await;
+    }
+    let log_prefix = cancel_handle.id().log_prefix();
+    // The transcription itself is out of our hands by now, so each step after it gets its own
+    // shot instead of one hiccup skipping the rest along with the stats and closing the messages
+    let warn_on_err = |step: &str, res: HandlerResult| {
+        if let Err(e) = res {
+            log::warn!("{log_prefix} Failed to {step}: {e}");
+        }
+    };
+    match res {
+        Ok(timings) => {
+            log::debug!("{log_prefix} Finished in {timings:?}");
+            warn_on_err("clear the status", bot_msg.update_status(None).await);
+            warn_on_err(
+                "delete the preview",
+                bot_msg.delete_transient_preview().await,
+            );
+            bot_msg.react(Some(REACTION_DONE)).await;
+            warn_on_err("flush the transcription", bot_msg.flush().await);
+            let mut placement = bot_msg.placement();
+            let save = async {
+                if state.db.keeps_transcripts(meta.chat_id).await? {
+                    state
+                        .transcripts
+                        .save(meta.chat_id, meta.id, &bot_msg.transcription)
+                        .await?;
+                }
+                Ok(())
+            };
+            warn_on_err("save the transcript", save.await);
+            let attach_jsonl = async {
+                if state.db.attaches_jsonl(meta.chat_id).await? {
+                    let file_name = format!("transcription-{}.jsonl", meta.id);
+                    let contents = utils::lines_to_jsonl(&bot_msg.transcription).into_bytes();
+                    let prev = prev_documents.remove(&file_name);
+                    let msg_id = attach_document(&bot, meta, prev, &file_name, contents).await?;
+                    placement.documents.insert(file_name, msg_id);
+                }
+                Ok(())
+            };
+            warn_on_err("attach the jsonl", attach_jsonl.await);
+            let attach_subtitles = async {
+                let format = state.db.get_subtitles(meta.chat_id).await?;
+                let subtitles = match format {
+                    db::Subtitles::Off => None,
+                    db::Subtitles::Srt => Some(utils::lines_to_srt(&bot_msg.transcription)),
+                    db::Subtitles::Vtt => Some(utils::lines_to_vtt(&bot_msg.transcription)),
+                };
+                if let Some(subtitles) = subtitles {
+                    let file_name = format!("transcription-{}.{format}", meta.id);
+                    let prev = prev_documents.remove(&file_name);
+                    let contents = subtitles.into_bytes();
+                    let msg_id = attach_document(&bot, meta, prev, &file_name, contents).await?;
+                    placement.documents.insert(file_name, msg_id);
+                }
+                Ok(())
+            };
+            warn_on_err("attach the subtitles", attach_subtitles.await);
+            // Whatever the chat doesn't get attached anymore would just be stale
+            for (_, msg_id) in prev_documents {
+                let res = bot.delete_message(meta.chat_id, msg_id).await;
+                warn_on_err("delete a stale attachment", res);
+            }
+            let origin = origins::Origin {
+                voice_msg: meta.clone(),
+                voice: voice.clone(),
+                requester_id: sender.id(),
+                quick,
+            };
+            state.origins.insert(origin, placement);
+            #[cfg(feature = "summary")]
+            if let Some(summaries) = &state.summaries {
+                summaries.cache_transcription(meta.chat_id, meta.id, bot_msg.full_text());
+            }
+            if let Some(webhook) = &state.webhook {
+                webhook.send(webhook::Payload {
+                    chat_id: meta.chat_id.0,
+                    message_id: meta.id.0,
+                    user_id: meta.from.0,
+                    text: bot_msg.full_text(),
+                    lines: bot_msg.transcription.clone(),
+                    edited: is_redo,
+                });
+            }
+            // It's the same voice message as far as the stats are concerned
+            let author = if is_redo {
+                None
+            } else {
+                state.db.user(meta.from).await
+            };
+            if let Some(author) = author {
+                // Telegram's duration can be way off, so it goes by the decoded audio
+                warn_on_err(
+                    "record the stats",
+                    author.record_transcription(bot_msg.duration_secs).await,
+                );
+            }
+        }
+        Err(HandlerError::Cancelled) => warn_on_err(
+            "update the status",
+            bot_msg.update_status(Some("Cancelled ✋")).await,
+        ),
+        Err(HandlerError::UserError(UserError::NoSpeechDetected)) => warn_on_err(
+            "update the status",
+            bot_msg.update_status(Some("No speech detected 🔇")).await,
+        ),
+        Err(HandlerError::UserError(e @ (UserError::TooShort(_) | UserError::TooLong(_)))) => {
+            warn_on_err(
+                "update the status",
+                bot_msg.update_status(Some(&format!("{e} 📏"))).await,
+            );
+        }
+        Err(HandlerError::TimedOut) => warn_on_err(
+            "update the status",
+            bot_msg
+                .update_status(Some("Transcription timed out ⏰"))
+                .await,
+        ),
+        // Leave the user with something actionable instead of a status that never changes
+        Err(_) if pool.is_shutting_down() => warn_on_err(
+            "update the status",
+            bot_msg
+                .update_status(Some("Interrupted — this will pick back up after a restart"))
+                .await,
+        ),
+        Err(e) => {
+            let _ = bot_msg.update_status(Some("Failed")).await;
+            let _ = bot_msg.close().await;
+            log::warn!("{log_prefix} Transcription failed on attempt {attempt}: {e}");
+            let failed = retry::FailedJob {
+                voice_msg: meta.clone(),
+                voice,
+                attempts: attempt,
+                quick,
+            };
+            let mut text = format!("The bot hit an error while transcribing this message.\n{e}");
+            if failed.can_retry() {
+                text.push_str("\nReply to this with /retry to give it another shot 🔁");
+            }
+            let error_msg = Reply::new(bot, meta.chat_id, meta.id, meta.topic)
+                .send(text)
+                .await?;
+            if failed.can_retry() {
+                state.retries.insert(meta.chat_id, error_msg.id(), failed);
+            }
+            // Already reported above
+            return Err(HandlerError::Ignore);
+        }
+    }
+    bot_msg.close().await?;
+
+    Ok(())
+}
+
+/// Replaces `prev` with the new contents when there's one to replace or sends a new document
+async fn attach_document(
+    bot: &telegram::Bot,
+    meta: &RelevantMeta,
+    prev: Option<types::MessageId>,
+    file_name: &str,
+    contents: Vec<u8>,
+) -> HandlerResult<types::MessageId> {
+    let file_name = file_name.to_owned();
+    if let Some(msg_id) = prev {
+        bot.edit_document(meta.chat_id, msg_id, file_name, contents)
+            .await?;
+        return Ok(msg_id);
+    }
+    let msg = bot
+        .send_document(meta.chat_id, meta.id, meta.topic, file_name, contents)
+        .await?;
+    Ok(msg.id())
+}
+
+/// How long each stage of a transcription took
+#[derive(Debug, Default)]
+struct StageTimings {
+    /// Only gets filled in by the self-test
+    upload: Duration,
+    queued: Duration,
+    /// Includes converting the audio
+    download: Duration,
+    waiting: Duration,
+    /// Includes loading the model
+    transcription: Duration,
+}
+
+impl fmt::Display for StageTimings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Self {
+            upload,
+            queued,
+            download,
+            waiting,
+            transcription,
+        } = self;
+        writeln!(f, "Upload: {upload:.01?}")?;
+        writeln!(f, "Queued: {queued:.01?}")?;
+        writeln!(f, "Download and convert: {download:.01?}")?;
+        writeln!(f, "Waiting for a transcriber: {waiting:.01?}")?;
+        write!(f, "Load model and transcribe: {transcription:.01?}")
+    }
+}
+
+async fn run_transcription(
+    bot: telegram::Bot,
+    pool: &transcriber::Pool,
+    bot_msg: &mut Transcription,
+    voice_file_id: String,
+    voice_msg_duration_secs: u32,
+    settings: transcriber::Settings,
+    limits: DurationLimits,
+) -> HandlerResult<StageTimings> {
+    let mut stage_start = Instant::now();
+    let mut finish_stage = || {
+        let elapsed = stage_start.elapsed();
+        stage_start = Instant::now();
+        elapsed
+    };
+    let mut timings = StageTimings::default();
+
+    let translate = settings.translate;
+    let job = pool.submit_job(
+        bot_msg.job_id,
+        bot,
+        voice_file_id,
+        voice_msg_duration_secs,
+        settings,
+    )?;
 
     let download_started = job.await.map_err(HandlerError::worker_died)?;
+    timings.queued = finish_stage();
     let _ = bot_msg.update_status(Some("Downloading...")).await;
-    let downloading = download_started
+    let downloaded = download_started
         .await
         .map_err(HandlerError::worker_died)??;
-    let mut transcribing = downloading.await.map_err(HandlerError::worker_died)??;
-    let _ = bot_msg.update_status(Some("Transcribing...")).await;
+    timings.download = finish_stage();
+    limits.check(downloaded.duration_secs)?;
+    bot_msg.fit_duration(downloaded.duration_secs).await?;
+    let _ = bot_msg
+        .update_status(Some("Waiting for a free transcriber..."))
+        .await;
+    let mut transcribing = downloaded.next.await.map_err(HandlerError::worker_died)??;
+    timings.waiting = finish_stage();
+    let status = if translate {
+        "Translating..."
+    } else {
+        "Transcribing..."
+    };
+    let _ = bot_msg.update_status(Some(status)).await;
+    bot_msg.react(Some(REACTION_TRANSCRIBING)).await;
 
     while let Some(line) = transcribing.next().await? {
+        if let Some(language) = transcribing.take_detected_language() {
+            bot_msg.language = Some(language);
+        }
         let _ = bot_msg.push_line(line).await;
     }
+    timings.transcription = finish_stage();
 
-    bot_msg.update_status(None).await?;
-    bot_msg.close().await?;
+    Ok(timings)
+}
 
-    Ok(())
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mock_bot::{wav, MockBot};
+
+    const AUTHOR: types::UserId = types::UserId(42);
+    const AUTHOR_CHAT: types::ChatId = types::ChatId(42);
+
+    /// A fresh state that transcribes with `backend` and keeps its files in a temp dir
+    async fn test_state(name: &str, bot: &MockBot, backend: transcriber::MockBackend) -> State {
+        let dir = std::env::temp_dir().join(format!("rambot-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let send_config = buf_messenger::Config {
+            edit_debounce: Duration::ZERO,
+        };
+        State {
+            transcriber_pool: transcriber::Pool::with_mock(backend),
+            send_msg_handle: buf_messenger::spawn(bot.bot(), send_config),
+            db: db::Db::in_dir(&dir),
+            cancellations: Default::default(),
+            retries: Default::default(),
+            origins: Default::default(),
+            transcripts: transcripts::Store::new(dir.join("transcripts")),
+            feedback: feedback::Store::new(dir.join("feedback.ron")),
+            pending: pending::Queue::load(dir.join("pending.ron")).await,
+            webhook: None,
+            #[cfg(feature = "summary")]
+            summaries: None,
+            owner_id: None,
+            allowed_chats: None,
+            cutoffs: Cutoffs::from_env(),
+            max_duration_secs: None,
+        }
+    }
+
+    /// Trusts the author and has all of their voice messages transcribed
+    async fn trust_author(state: &State) {
+        state
+            .db
+            .add_trusted_user(AUTHOR, "Author".to_owned())
+            .await
+            .unwrap();
+        let author = state.db.user(AUTHOR).await.unwrap();
+        author
+            .set_transcribe_trigger(TranscribeTrigger::Always)
+            .await
+            .unwrap();
+    }
+
+    /// A voice message that the author sent in their private chat with the bot
+    fn voice_msg(msg_id: i32, file_id: &str, duration: u32) -> types::Message {
+        serde_json::from_value(serde_json::json!({
+            "message_id": msg_id,
+            "date": 1_700_000_000,
+            "chat": { "id": AUTHOR_CHAT.0, "type": "private", "first_name": "Author" },
+            "from": { "id": AUTHOR.0, "is_bot": false, "first_name": "Author" },
+            "voice": {
+                "file_id": file_id,
+                "file_unique_id": format!("{file_id}-unique"),
+                "file_size": 1024,
+                "duration": duration,
+                "mime_type": "audio/ogg"
+            }
+        }))
+        .unwrap()
+    }
+
+    fn greeting_backend() -> transcriber::MockBackend {
+        transcriber::MockBackend {
+            segments: vec![(0, " Hello there."), (300, " General Kenobi.")],
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn voice_messages_get_transcribed_start_to_finish() {
+        let mock = MockBot::spawn();
+        mock.add_file("voice", wav(5));
+        let state = test_state("start-to-finish", &mock, greeting_backend()).await;
+        trust_author(&state).await;
+
+        try_handle_message(mock.bot(), state.clone(), voice_msg(7, "voice", 5))
+            .await
+            .unwrap();
+
+        let sends = mock.calls_to("sendMessage");
+        assert_eq!(sends.len(), 1);
+        assert_eq!(sends[0]["reply_to_message_id"], 7);
+        assert!(sends[0]["text"].as_str().unwrap().contains("Queued"));
+        let reply_id = i32::try_from(sends[0]["sent_id"].as_i64().unwrap()).unwrap();
+        let text = mock.text_of(reply_id).unwrap();
+        assert!(text.contains("Hello there") && text.contains("General Kenobi"));
+        assert!(!text.contains("Transcribing"));
+        // Ends on the done reaction
+        let reactions = mock.calls_to("setMessageReaction");
+        assert_eq!(
+            reactions.last().unwrap()["reaction"][0]["emoji"],
+            REACTION_DONE
+        );
+        let author = state.db.user(AUTHOR).await.unwrap();
+        assert_eq!(author.get_stats().await.transcribe_count, 1);
+        assert!(state.pending.jobs().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn only_the_requester_admins_and_owner_can_cancel() {
+        const GROUP: types::ChatId = types::ChatId(-1004);
+        let (other, admin) = (types::UserId(43), types::UserId(44));
+        let mock = MockBot::spawn();
+        mock.add_admin(GROUP, admin);
+        let state = test_state("cancel-permissions", &mock, greeting_backend()).await;
+        trust_author(&state).await;
+        state
+            .db
+            .add_trusted_user(other, "Other".to_owned())
+            .await
+            .unwrap();
+        let tap = |from: types::UserId, job_id: cancel::JobId| {
+            let query: types::CallbackQuery = serde_json::from_value(serde_json::json!({
+                "id": "query",
+                "from": { "id": from.0, "is_bot": false, "first_name": "Tapper" },
+                "chat_instance": "instance",
+                "data": job_id.callback_data(),
+                "message": {
+                    "message_id": 1000,
+                    "date": 0,
+                    "chat": { "id": GROUP.0, "type": "group", "title": "Group" },
+                    "text": "Queued..."
+                }
+            }))
+            .unwrap();
+            handle_callback_query(mock.bot(), state.clone(), query)
+        };
+        let last_answer = || {
+            let answers = mock.calls_to("answerCallbackQuery");
+            answers.last().unwrap()["text"].as_str().unwrap().to_owned()
+        };
+
+        // Someone else who the bot trusts still doesn't get to cancel it
+        let mut handle = state.cancellations.register(AUTHOR);
+        tap(other, handle.id()).await;
+        assert!(last_answer().contains("Only whoever asked"));
+        assert_eq!(state.cancellations.requester(handle.id()), Some(AUTHOR));
+
+        tap(AUTHOR, handle.id()).await;
+        assert!(last_answer().contains("Cancelling"));
+        handle.cancelled().await;
+
+        // The chat's admins can step in too
+        let mut handle = state.cancellations.register(AUTHOR);
+        tap(admin, handle.id()).await;
+        assert!(last_answer().contains("Cancelling"));
+        handle.cancelled().await;
+    }
+
+    #[tokio::test]
+    async fn voice_messages_in_brand_new_chats_get_transcribed() {
+        let mock = MockBot::spawn();
+        mock.add_file("voice", wav(5));
+        let state = test_state("brand-new-chat", &mock, greeting_backend()).await;
+        trust_author(&state).await;
+        let new_chat = types::ChatId(-1003);
+        assert!(state.db.get_sidecar_attach(new_chat).await.is_err());
+
+        // The very first thing that the bot ever sees from the chat
+        let mut msg = voice_msg(7, "voice", 5);
+        msg.chat = serde_json::from_value(serde_json::json!({
+            "id": new_chat.0,
+            "type": "group",
+            "title": "New"
+        }))
+        .unwrap();
+        try_handle_message(mock.bot(), state.clone(), msg)
+            .await
+            .unwrap();
+
+        assert!(state.db.get_sidecar_attach(new_chat).await.is_ok());
+        let sends = mock.calls_to("sendMessage");
+        assert_eq!(sends[0]["chat_id"], new_chat.0);
+        let reply_id = i32::try_from(sends[0]["sent_id"].as_i64().unwrap()).unwrap();
+        assert!(mock.text_of(reply_id).unwrap().contains("General Kenobi"));
+    }
+
+    #[tokio::test]
+    async fn long_messages_only_send_their_first_part_up_front() {
+        let mock = MockBot::spawn();
+        mock.add_file("voice", wav(10));
+        let backend = transcriber::MockBackend {
+            delay: Duration::from_millis(300),
+            segments: vec![(0, " Start."), (500, " Halfway.")],
+            ..Default::default()
+        };
+        let seen = Arc::clone(&backend.seen);
+        let mut state = test_state("long-up-front", &mock, backend).await;
+        // A part per second makes for 11 of them
+        state.cutoffs = Cutoffs {
+            preview_secs: DEFAULT_PREVIEW_CUTOFF_SECS,
+            chunk_secs: 1,
+            max_parts: None,
+        };
+        let voice = voice_msg(7, "voice", 10);
+        state.db.update_metadata(&voice).await.unwrap();
+        trust_author(&state).await;
+        state
+            .db
+            .set_chat_layout(AUTHOR_CHAT, db::Layout::Long)
+            .await
+            .unwrap();
+
+        let handler = tokio::spawn(try_handle_message(mock.bot(), state.clone(), voice));
+        while seen.lock().unwrap().is_none() {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        // Leaves any sends that were already on their way time to land
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(mock.calls_to("sendMessage").len(), 1);
+
+        handler.await.unwrap().unwrap();
+        // The rest only went out once lines reached them. The last line ends 6s in, so the parts
+        // up through the 7th got sent with the empty ones before it keeping the parts in order
+        let sends = mock.calls_to("sendMessage");
+        assert_eq!(sends.len(), 7);
+        let last_id = i32::try_from(sends[6]["sent_id"].as_i64().unwrap()).unwrap();
+        assert!(mock.text_of(last_id).unwrap().contains("Halfway"));
+    }
+
+    #[tokio::test]
+    async fn failed_attachments_dont_skip_the_rest() {
+        let mock = MockBot::spawn();
+        mock.add_file("voice", wav(5));
+        let state = test_state("failed-attachments", &mock, greeting_backend()).await;
+        let voice = voice_msg(7, "voice", 5);
+        state.db.update_metadata(&voice).await.unwrap();
+        trust_author(&state).await;
+        state.db.set_attach_jsonl(AUTHOR_CHAT, true).await.unwrap();
+        state
+            .db
+            .set_subtitles(AUTHOR_CHAT, db::Subtitles::Srt)
+            .await
+            .unwrap();
+        mock.fail("sendDocument");
+
+        try_handle_message(mock.bot(), state.clone(), voice)
+            .await
+            .unwrap();
+
+        // Both attachments still got their shot
+        assert_eq!(mock.calls_to("sendDocument").len(), 2);
+        let sends = mock.calls_to("sendMessage");
+        let reply_id = i32::try_from(sends[0]["sent_id"].as_i64().unwrap()).unwrap();
+        let text = mock.text_of(reply_id).unwrap();
+        assert!(text.contains("General Kenobi") && !text.contains("Transcribing"));
+        let author = state.db.user(AUTHOR).await.unwrap();
+        assert_eq!(author.get_stats().await.transcribe_count, 1);
+    }
+
+    #[tokio::test]
+    async fn resuming_feeds_jobs_in_without_overflowing_the_queue() {
+        let mock = MockBot::spawn();
+        let state = test_state("resume", &mock, greeting_backend()).await;
+        state
+            .db
+            .update_metadata(&voice_msg(1, "voice", 1))
+            .await
+            .unwrap();
+        trust_author(&state).await;
+        let pending = |msg_id: i32, attempt| pending::PendingJob {
+            chat_id: AUTHOR_CHAT,
+            voice_msg_id: msg_id,
+            author_id: AUTHOR,
+            requester_id: AUTHOR,
+            voice_file_id: format!("voice-{msg_id}"),
+            voice_file_unique_id: format!("voice-{msg_id}-unique"),
+            voice_file_size: 1024,
+            duration_secs: 1,
+            attempt,
+            quick: false,
+            topic: None,
+        };
+        // More than the queue holds at once
+        let num_jobs = 40;
+        for msg_id in 1..=num_jobs {
+            mock.add_file(&format!("voice-{msg_id}"), wav(1));
+            state.pending.insert(pending(msg_id, 1)).await;
+        }
+        // One that took the bot down every time it got picked up
+        let crashing = num_jobs + 1;
+        state
+            .pending
+            .insert(pending(crashing, retry::MAX_ATTEMPTS))
+            .await;
+
+        for handle in resume_pending_jobs(&mock.bot(), &state).await {
+            handle.await.unwrap();
+        }
+
+        assert!(state.pending.jobs().await.is_empty());
+        let author = state.db.user(AUTHOR).await.unwrap();
+        assert_eq!(author.get_stats().await.transcribe_count, num_jobs as u64);
+        let replied_to: HashSet<_> = mock
+            .calls_to("sendMessage")
+            .iter()
+            .map(|send| send["reply_to_message_id"].as_i64().unwrap())
+            .collect();
+        assert_eq!(replied_to.len(), num_jobs as usize);
+        assert!(!replied_to.contains(&i64::from(crashing)));
+    }
+
+    #[tokio::test]
+    async fn retrying_is_held_to_the_authors_trigger() {
+        let mock = MockBot::spawn();
+        mock.add_file("voice", wav(5));
+        let state = test_state("retry-trigger", &mock, greeting_backend()).await;
+        let voice = voice_msg(7, "voice", 5);
+        state.db.update_metadata(&voice).await.unwrap();
+        trust_author(&state).await;
+        let author = state.db.user(AUTHOR).await.unwrap();
+        author
+            .set_transcribe_trigger(TranscribeTrigger::SummonBySelf)
+            .await
+            .unwrap();
+        let other_id = types::UserId(43);
+        state
+            .db
+            .add_trusted_user(other_id, "Other".to_owned())
+            .await
+            .unwrap();
+        let voice_meta = RelevantMeta {
+            id: types::MessageId(7),
+            chat_id: AUTHOR_CHAT,
+            from: AUTHOR,
+            topic: None,
+        };
+        let error_meta = RelevantMeta {
+            id: types::MessageId(8),
+            ..voice_meta.clone()
+        };
+        state.retries.insert(
+            AUTHOR_CHAT,
+            error_meta.id,
+            retry::FailedJob {
+                voice_msg: voice_meta,
+                voice: voice.voice().unwrap().clone(),
+                attempts: 1,
+                quick: false,
+            },
+        );
+        let retry_from = |from| {
+            let meta = RelevantMeta {
+                id: types::MessageId(9),
+                from,
+                ..error_meta.clone()
+            };
+            let com = RelevantCommand {
+                com: command::Command::Retry,
+                reply_to: Some(RelevantParentMsg {
+                    meta: Some(error_meta.clone()),
+                    voice: None,
+                    text: None,
+                    kind: media::MediaKind::Text,
+                    original_author: None,
+                    is_ours: true,
+                }),
+            };
+            (meta, com)
+        };
+
+        // Only the author can summon the bot on their voice messages, so only they can retry
+        let (meta, com) = retry_from(other_id);
+        let other = state.db.user(other_id).await.unwrap();
+        let res = try_handle_command(mock.bot(), state.clone(), &meta, com, other).await;
+        assert!(matches!(
+            res,
+            Err(HandlerError::UserError(UserError::BadSummon(_)))
+        ));
+        assert!(mock.calls_to("sendMessage").is_empty());
+
+        // and the failure is still there for them to retry
+        let (meta, com) = retry_from(AUTHOR);
+        try_handle_command(mock.bot(), state.clone(), &meta, com, author)
+            .await
+            .unwrap();
+        let sends = mock.calls_to("sendMessage");
+        assert_eq!(sends.len(), 1);
+        assert_eq!(sends[0]["reply_to_message_id"], 7);
+    }
+
+    #[tokio::test]
+    async fn edited_audio_redoes_the_transcript_in_place() {
+        let mock = MockBot::spawn();
+        mock.add_file("voice", wav(5));
+        mock.add_file("voice-edited", wav(5));
+        let state = test_state("redo", &mock, greeting_backend()).await;
+        let voice = voice_msg(7, "voice", 5);
+        state.db.update_metadata(&voice).await.unwrap();
+        trust_author(&state).await;
+        state.db.set_attach_jsonl(AUTHOR_CHAT, true).await.unwrap();
+        try_handle_message(mock.bot(), state.clone(), voice)
+            .await
+            .unwrap();
+        let document_id = mock.calls_to("sendDocument")[0]["sent_id"].clone();
+
+        // An edit that leaves the audio alone is a no-op
+        let same_audio = voice_msg(7, "voice", 5);
+        let res = try_handle_edited_message(mock.bot(), state.clone(), &same_audio).await;
+        assert!(matches!(res, Err(HandlerError::Ignore)));
+        let edited = voice_msg(7, "voice-edited", 5);
+        try_handle_edited_message(mock.bot(), state.clone(), &edited)
+            .await
+            .unwrap();
+
+        // Everything got updated in place instead of sent all over again
+        assert_eq!(mock.calls_to("sendMessage").len(), 1);
+        assert_eq!(mock.calls_to("sendDocument").len(), 1);
+        let replaced = mock.calls_to("editMessageMedia");
+        assert_eq!(replaced.len(), 1);
+        assert_eq!(replaced[0]["message_id"], document_id);
+        let author = state.db.user(AUTHOR).await.unwrap();
+        assert_eq!(author.get_stats().await.transcribe_count, 1);
+        let (origin, _) = state
+            .origins
+            .transcript_of(AUTHOR_CHAT, types::MessageId(7))
+            .unwrap();
+        assert_eq!(origin.voice.file.id, "voice-edited");
+    }
+
+    #[tokio::test]
+    async fn adding_users_is_up_to_the_owner_when_there_is_one() {
+        let mock = MockBot::spawn();
+        let mut state = test_state("add-user", &mock, greeting_backend()).await;
+        state
+            .db
+            .update_metadata(&voice_msg(1, "voice", 1))
+            .await
+            .unwrap();
+        trust_author(&state).await;
+        let newcomer = |id| RelevantParentMsg {
+            meta: Some(RelevantMeta {
+                id: types::MessageId(1),
+                chat_id: AUTHOR_CHAT,
+                from: types::UserId(id),
+                topic: None,
+            }),
+            voice: None,
+            text: None,
+            kind: media::MediaKind::Text,
+            original_author: None,
+            is_ours: false,
+        };
+        let meta = RelevantMeta {
+            id: types::MessageId(2),
+            chat_id: AUTHOR_CHAT,
+            from: AUTHOR,
+            topic: None,
+        };
+        let add_user = |state: &State, id, name: &str| {
+            let com = RelevantCommand {
+                com: command::Command::AddUser(name.to_owned()),
+                reply_to: Some(newcomer(id)),
+            };
+            let (bot, state, meta) = (mock.bot(), state.clone(), meta.clone());
+            async move {
+                let author = state.db.user(AUTHOR).await.unwrap();
+                try_handle_command(bot, state, &meta, com, author).await
+            }
+        };
+
+        // Without an owner any trusted user can still add others
+        add_user(&state, 43, "Newcomer").await.unwrap();
+        assert!(state.db.user(types::UserId(43)).await.is_some());
+
+        // Once there's an owner it's only up to them
+        state.owner_id = Some(types::UserId(1));
+        let res = add_user(&state, 44, "Stranger").await;
+        assert!(matches!(
+            res,
+            Err(HandlerError::UserError(UserError::NotAuthorized))
+        ));
+        state.owner_id = Some(AUTHOR);
+        add_user(&state, 44, "Stranger").await.unwrap();
+    }
+
+    /// Who a gated command is left up to
+    #[derive(Clone, Copy, Debug)]
+    enum Gate {
+        Owner,
+        /// The owner when there is one, otherwise any trusted user
+        Manager,
+        /// The owner when there is one, otherwise the chat's admins
+        Chat,
+    }
+
+    #[tokio::test]
+    async fn gated_commands_check_who_is_asking() {
+        const GROUP: types::ChatId = types::ChatId(-1005);
+        const OTHER_GROUP: types::ChatId = types::ChatId(-1006);
+        let (trusted, admin, stranger) = (types::UserId(43), types::UserId(44), types::UserId(45));
+        BOT_NAME.get_or_init(|| mock_bot::BOT_NAME.to_owned());
+        let mock = MockBot::spawn();
+        mock.add_file("document", wav(1));
+        mock.add_admin(GROUP, admin);
+        let mut state = test_state("gated-commands", &mock, greeting_backend()).await;
+        trust_author(&state).await;
+        for (user, name) in [(trusted, "Trusted"), (admin, "Admin")] {
+            state
+                .db
+                .add_trusted_user(user, name.to_owned())
+                .await
+                .unwrap();
+        }
+        let command = |state: &State, from: types::UserId, chat_id: types::ChatId, text: &str| {
+            let chat = serde_json::json!({ "id": chat_id.0, "type": "group", "title": "Group" });
+            let msg: types::Message = serde_json::from_value(serde_json::json!({
+                "message_id": 8,
+                "date": 1_700_000_000,
+                "chat": chat,
+                "from": { "id": from.0, "is_bot": false, "first_name": "Sender" },
+                "text": text,
+                "reply_to_message": {
+                    "message_id": 7,
+                    "date": 1_700_000_000,
+                    "chat": chat,
+                    "from": { "id": 46, "is_bot": false, "first_name": "Newcomer" },
+                    "text": "Hi"
+                }
+            }))
+            .unwrap();
+            try_handle_message(mock.bot(), state.clone(), msg)
+        };
+        // The sidecar has to be a chat that the bot already knows about
+        command(&state, AUTHOR, OTHER_GROUP, "Hi")
+            .await
+            .unwrap_err();
+
+        let commands = [
+            ("/transcribeid document", Gate::Owner),
+            ("/setmaxduration 60", Gate::Owner),
+            ("/allstats", Gate::Owner),
+            ("/config", Gate::Owner),
+            ("/attachsidecar -1006", Gate::Manager),
+            ("/detachsidecar", Gate::Manager),
+            ("/becomesidecar -1006", Gate::Manager),
+            ("/detachsidecar", Gate::Manager),
+            ("/adduser Newcomer", Gate::Manager),
+            ("/setchattrigger always", Gate::Chat),
+            ("/setlayout both", Gate::Chat),
+            ("/setkeeptranscripts true", Gate::Chat),
+            ("/setdeletepreview true", Gate::Chat),
+            ("/setcollapsed true", Gate::Chat),
+            ("/setjsonl true", Gate::Chat),
+            ("/setsubtitles srt", Gate::Chat),
+            ("/setwrap 40", Gate::Chat),
+            ("/setprompt Rambot", Gate::Chat),
+            ("/settemplate {ts} {text}", Gate::Chat),
+            ("/setminduration 2", Gate::Chat),
+        ];
+        let is_refused = |res: &HandlerResult| {
+            matches!(
+                res,
+                Err(HandlerError::UserError(
+                    UserError::NotAuthorized | UserError::NotChatAdmin
+                ))
+            )
+        };
+
+        // With an owner everything gated is theirs alone, even over the chat's admins
+        state.owner_id = Some(AUTHOR);
+        for (text, gate) in commands {
+            let res = command(&state, stranger, GROUP, text).await;
+            assert!(matches!(res, Err(HandlerError::Ignore)), "{text}: {res:?}");
+            for user in [trusted, admin] {
+                let res = command(&state, user, GROUP, text).await;
+                assert!(
+                    matches!(res, Err(HandlerError::UserError(UserError::NotAuthorized))),
+                    "{text} by {user}: {res:?}"
+                );
+            }
+            let res = command(&state, AUTHOR, GROUP, text).await;
+            assert!(res.is_ok(), "{text} ({gate:?}): {res:?}");
+        }
+        // The self-test gets past the check, but there's no sample audio to run it with
+        let res = command(&state, trusted, GROUP, "/selftest").await;
+        assert!(is_refused(&res), "{res:?}");
+        let res = command(&state, AUTHOR, GROUP, "/selftest").await;
+        assert!(res.is_err() && !is_refused(&res), "{res:?}");
+
+        // Without one it falls back to trusted users and chat admins
+        state.owner_id = None;
+        for (text, gate) in commands {
+            let res = command(&state, stranger, GROUP, text).await;
+            assert!(matches!(res, Err(HandlerError::Ignore)), "{text}: {res:?}");
+            match gate {
+                Gate::Owner => {
+                    let res = command(&state, admin, GROUP, text).await;
+                    assert!(
+                        matches!(res, Err(HandlerError::UserError(UserError::NotAuthorized))),
+                        "{text}: {res:?}"
+                    );
+                }
+                Gate::Manager => {
+                    let res = command(&state, trusted, GROUP, text).await;
+                    assert!(res.is_ok(), "{text}: {res:?}");
+                }
+                Gate::Chat => {
+                    let res = command(&state, trusted, GROUP, text).await;
+                    assert!(
+                        matches!(res, Err(HandlerError::UserError(UserError::NotChatAdmin))),
+                        "{text}: {res:?}"
+                    );
+                    let res = command(&state, admin, GROUP, text).await;
+                    assert!(res.is_ok(), "{text}: {res:?}");
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn disallowed_chats_get_ignored_outright() {
+        let mock = MockBot::spawn();
+        mock.add_file("voice", wav(5));
+        let mut state = test_state("allowed-chats", &mock, greeting_backend()).await;
+        trust_author(&state).await;
+        state.owner_id = Some(AUTHOR);
+        state.allowed_chats = Some(Arc::new(HashSet::from([types::ChatId(-1)])));
+
+        // Not even the owner gets an exception
+        let res = try_handle_message(mock.bot(), state.clone(), voice_msg(7, "voice", 5)).await;
+        assert!(matches!(res, Err(HandlerError::Ignore)));
+        assert!(mock.calls().is_empty());
+        // and the chat never made it into the db
+        assert!(state.db.attaches_jsonl(AUTHOR_CHAT).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn decoded_duration_wins_over_telegrams() {
+        let mock = MockBot::spawn();
+        mock.add_file("voice", wav(3));
+        let state = test_state("decoded-duration", &mock, greeting_backend()).await;
+        trust_author(&state).await;
+
+        // Claims to be way longer than it actually is
+        try_handle_message(mock.bot(), state.clone(), voice_msg(7, "voice", 20))
+            .await
+            .unwrap();
+        let author = state.db.user(AUTHOR).await.unwrap();
+        assert_eq!(author.get_stats().await.total_secs, 3);
+
+        // And it gets held to the chat's minimum too
+        state
+            .db
+            .set_min_duration(AUTHOR_CHAT, Some(10))
+            .await
+            .unwrap();
+        try_handle_message(mock.bot(), state.clone(), voice_msg(8, "voice", 20))
+            .await
+            .unwrap();
+        let sends = mock.calls_to("sendMessage");
+        let reply_id = i32::try_from(sends.last().unwrap()["sent_id"].as_i64().unwrap()).unwrap();
+        let text = mock.text_of(reply_id).unwrap();
+        assert!(text.contains("shorter than the 10s minimum"), "{text}");
+        assert_eq!(author.get_stats().await.transcribe_count, 1);
+    }
+
+    #[tokio::test]
+    async fn voice_messages_right_at_the_minimum_get_transcribed() {
+        let mock = MockBot::spawn();
+        mock.add_file("short", wav(4));
+        mock.add_file("exact", wav(5));
+        let state = test_state("min-duration-boundary", &mock, greeting_backend()).await;
+        trust_author(&state).await;
+        state
+            .db
+            .update_metadata(&voice_msg(6, "short", 4))
+            .await
+            .unwrap();
+        state
+            .db
+            .set_min_duration(AUTHOR_CHAT, Some(5))
+            .await
+            .unwrap();
+
+        // A second under gets skipped without a word
+        try_handle_message(mock.bot(), state.clone(), voice_msg(7, "short", 4))
+            .await
+            .unwrap();
+        assert!(mock.calls_to("sendMessage").is_empty());
+
+        // While exactly at the minimum still counts
+        try_handle_message(mock.bot(), state.clone(), voice_msg(8, "exact", 5))
+            .await
+            .unwrap();
+        let sends = mock.calls_to("sendMessage");
+        assert_eq!(sends.len(), 1);
+        assert_eq!(sends[0]["reply_to_message_id"], 8);
+        let author = state.db.user(AUTHOR).await.unwrap();
+        assert_eq!(author.get_stats().await.transcribe_count, 1);
+
+        // Same goes for the decoded duration
+        let limits = DurationLimits {
+            min_secs: Some(5),
+            max_secs: None,
+        };
+        assert!(matches!(
+            limits.check(4),
+            Err(HandlerError::UserError(UserError::TooShort(5)))
+        ));
+        assert!(limits.check(5).is_ok());
+    }
+
+    #[tokio::test]
+    async fn parts_past_the_decoded_duration_get_deleted() {
+        let mock = MockBot::spawn();
+        let mut state = test_state("surplus-parts", &mock, greeting_backend()).await;
+        state.cutoffs = Cutoffs {
+            preview_secs: DEFAULT_PREVIEW_CUTOFF_SECS,
+            chunk_secs: 1,
+            max_parts: None,
+        };
+        let voice = voice_msg(7, "voice", 10);
+        state.db.update_metadata(&voice).await.unwrap();
+        state
+            .db
+            .set_chat_layout(AUTHOR_CHAT, db::Layout::Long)
+            .await
+            .unwrap();
+        let RelevantMsg { meta, .. } = (&voice).try_into().unwrap();
+        // A redo whose earlier transcript went by a longer claimed duration
+        let redo = origins::Placement {
+            multipart: vec![
+                types::MessageId(100),
+                types::MessageId(101),
+                types::MessageId(102),
+            ],
+            long_msg_dest: Some((AUTHOR_CHAT, meta.id)),
+            ..Default::default()
+        };
+        let job_id = state.cancellations.register(AUTHOR).id();
+        let mut bot_msg = Transcription::start(
+            10,
+            "Queued...",
+            mock.bot(),
+            &state,
+            &meta,
+            job_id,
+            Some(redo),
+        )
+        .await
+        .unwrap();
+        assert_eq!(bot_msg.multipart.len(), 3);
+
+        // 1s only calls for two parts
+        bot_msg.fit_duration(1).await.unwrap();
+        assert_eq!(bot_msg.multipart.len(), 2);
+        let deleted = mock.calls_to("deleteMessage");
+        assert_eq!(deleted.len(), 1);
+        assert_eq!(deleted[0]["message_id"], 102);
+    }
+
+    #[tokio::test]
+    async fn transcribing_by_file_id_goes_by_the_probed_duration() {
+        let mock = MockBot::spawn();
+        mock.add_file("document", wav(5));
+        let mut state = test_state("transcribe-id", &mock, greeting_backend()).await;
+        state.owner_id = Some(AUTHOR);
+        // Sets up the chat
+        let voice = voice_msg(7, "voice", 5);
+        state.db.update_metadata(&voice).await.unwrap();
+        trust_author(&state).await;
+        let meta = RelevantMeta {
+            id: types::MessageId(8),
+            chat_id: AUTHOR_CHAT,
+            from: AUTHOR,
+            topic: None,
+        };
+        let transcribe_id = || RelevantCommand {
+            com: command::Command::TranscribeId(command::FileId("document".to_owned())),
+            reply_to: None,
+        };
+
+        // Telegram never says how long a plain file is, but it still gets held to the limit
+        state.max_duration_secs = Some(3);
+        let owner = state.db.user(AUTHOR).await.unwrap();
+        let res =
+            try_handle_command(mock.bot(), state.clone(), &meta, transcribe_id(), owner).await;
+        assert!(matches!(
+            res,
+            Err(HandlerError::UserError(UserError::TooLong(3)))
+        ));
+
+        state.max_duration_secs = None;
+        let owner = state.db.user(AUTHOR).await.unwrap();
+        try_handle_command(mock.bot(), state.clone(), &meta, transcribe_id(), owner)
+            .await
+            .unwrap();
+        let owner = state.db.user(AUTHOR).await.unwrap();
+        assert_eq!(owner.get_stats().await.total_secs, 5);
+    }
+
+    #[tokio::test]
+    async fn shutting_down_leaves_a_final_status() {
+        let mock = MockBot::spawn();
+        mock.add_file("voice", wav(5));
+        let backend = transcriber::MockBackend {
+            delay: Duration::from_secs(60),
+            ..greeting_backend()
+        };
+        let (seen, aborted) = (Arc::clone(&backend.seen), Arc::clone(&backend.aborted));
+        let state = test_state("shutdown", &mock, backend).await;
+        trust_author(&state).await;
+
+        let handler = tokio::spawn(try_handle_message(
+            mock.bot(),
+            state.clone(),
+            voice_msg(7, "voice", 5),
+        ));
+        while seen.lock().unwrap().is_none() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        state
+            .transcriber_pool
+            .shutdown(Duration::from_millis(50))
+            .await;
+        let res = tokio::time::timeout(Duration::from_secs(5), handler).await;
+        res.expect("The handler wrapped up").unwrap().unwrap();
+
+        let reply_id = mock.calls_to("sendMessage")[0]["sent_id"].as_i64().unwrap();
+        let text = mock.text_of(reply_id.try_into().unwrap()).unwrap();
+        assert!(text.contains("Interrupted"), "{text}");
+        // The backend got stopped instead of being left running in the background
+        assert!(transcriber::noticed_abort(&aborted).await);
+        // and the job sticks around for the next start
+        assert_eq!(state.pending.jobs().await.len(), 1);
+    }
+
+    #[test]
+    fn collapsed_lines_share_one_quote() {
+        let lines = ["`00:00` Hi".to_owned(), "`00:04` wrapped\nline".to_owned()];
+        assert_eq!(
+            expandable_quote(&lines),
+            "**>`00:00` Hi\n>`00:04` wrapped\n>line||"
+        );
+    }
+
+    #[test]
+    fn parts_cover_their_stretch_of_the_audio() {
+        let cutoffs = Cutoffs {
+            preview_secs: DEFAULT_PREVIEW_CUTOFF_SECS,
+            chunk_secs: 240,
+            max_parts: None,
+        };
+        assert_eq!(cutoffs.part_range(0, 500), (0, 240));
+        assert_eq!(cutoffs.part_range(1, 500), (240, 480));
+        // The last part stops where the audio does
+        assert_eq!(cutoffs.part_range(2, 500), (480, 500));
+        assert_eq!(part_marker(1, 3, (240, 480)), "*\\[2/3\\]* 04:00–08:00");
+    }
+
+    #[test]
+    fn long_messages_stop_at_the_max_parts() {
+        let mut cutoffs = Cutoffs {
+            preview_secs: DEFAULT_PREVIEW_CUTOFF_SECS,
+            chunk_secs: DEFAULT_CHUNK_CUTOFF_SECS,
+            max_parts: Some(3),
+        };
+        assert_eq!(cutoffs.cap_parts(2), (2, 0));
+        assert_eq!(cutoffs.cap_parts(3), (3, 0));
+        assert_eq!(cutoffs.cap_parts(35), (3, 32));
+        cutoffs.max_parts = None;
+        assert_eq!(cutoffs.cap_parts(35), (35, 0));
+    }
+
+    #[test]
+    fn max_duration_boundary() {
+        assert!(ensure_within_max_duration(60, Some(60)).is_ok());
+        assert!(matches!(
+            ensure_within_max_duration(61, Some(60)),
+            Err(HandlerError::UserError(UserError::TooLong(60)))
+        ));
+        assert!(ensure_within_max_duration(u32::MAX, None).is_ok());
+    }
+
+    #[test]
+    fn sidecars_get_the_long_message() {
+        let (main_chat, sidecar) = (types::ChatId(1), types::ChatId(2));
+        let with_preview = db::SidecarAttach::has_sidecar(sidecar, true);
+        assert_eq!(
+            MsgPlan::new(db::Layout::Both, Some(with_preview.clone())),
+            MsgPlan {
+                preview: true,
+                long: true,
+                sidecar: Some(sidecar),
+            }
+        );
+        // Without a preview in the main chat the long message stays there
+        assert_eq!(
+            MsgPlan::new(db::Layout::Long, Some(with_preview)),
+            MsgPlan {
+                preview: false,
+                long: true,
+                sidecar: None,
+            }
+        );
+
+        let long_only = db::SidecarAttach::has_sidecar(sidecar, false);
+        for layout in [db::Layout::Preview, db::Layout::Long, db::Layout::Both] {
+            assert_eq!(
+                MsgPlan::new(layout, Some(long_only.clone())),
+                MsgPlan {
+                    preview: false,
+                    long: true,
+                    sidecar: Some(sidecar),
+                }
+            );
+        }
+        // The sidecar's own voice messages stay put
+        let is_sidecar = db::SidecarAttach::is_sidecar(main_chat, false);
+        assert_eq!(
+            MsgPlan::new(db::Layout::Both, Some(is_sidecar)),
+            MsgPlan {
+                preview: true,
+                long: true,
+                sidecar: None,
+            }
+        );
+    }
+
+    #[test]
+    fn user_names_get_trimmed() {
+        assert_eq!(normalize_user_name(" name ").unwrap(), "name");
+        assert_eq!(normalize_user_name("\tJane Doe\n").unwrap(), "Jane Doe");
+        assert!(matches!(
+            normalize_user_name("   "),
+            Err(UserError::MissingUserName)
+        ));
+    }
+
+    #[test]
+    fn commands_get_ignored_until_the_name_is_known() {
+        let msg: types::Message = serde_json::from_value(serde_json::json!({
+            "message_id": 8,
+            "date": 1_700_000_000,
+            "chat": { "id": 42, "type": "private", "first_name": "Author" },
+            "from": { "id": 42, "is_bot": false, "first_name": "Author" },
+            "text": "/ping",
+            "entities": [{ "type": "bot_command", "offset": 0, "length": 5 }]
+        }))
+        .unwrap();
+        assert!(matches!(
+            RelevantMsgKind::parse(&msg, None),
+            Err(HandlerError::Ignore)
+        ));
+        assert!(matches!(
+            RelevantMsgKind::parse(&msg, Some("rambot")),
+            Ok(RelevantMsgKind::Command(RelevantCommand {
+                com: command::Command::Ping,
+                ..
+            }))
+        ));
+        // Voice messages don't need the name at all
+        assert!(matches!(
+            RelevantMsgKind::parse(&forwarded_voice(false), None),
+            Ok(RelevantMsgKind::Voice(_))
+        ));
+    }
+
+    /// What the bot's own forward of a voice message into a sidecar chat looks like
+    fn forwarded_voice(from_is_bot: bool) -> types::Message {
+        serde_json::from_value(serde_json::json!({
+            "message_id": 7,
+            "date": 1_700_000_000,
+            "chat": { "id": -1002, "type": "supergroup", "title": "Sidecar" },
+            "from": { "id": 99, "is_bot": from_is_bot, "first_name": "rambot" },
+            "forward_from": { "id": 42, "is_bot": false, "first_name": "Author" },
+            "forward_date": 1_699_999_000,
+            "voice": {
+                "file_id": "voice",
+                "file_unique_id": "voice-unique",
+                "file_size": 1024,
+                "duration": 5,
+                "mime_type": "audio/ogg"
+            }
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn bots_own_forwards_get_ignored() {
+        let forward = forwarded_voice(true);
+        assert!(matches!(
+            RelevantMsg::try_from(&forward),
+            Err(HandlerError::Ignore)
+        ));
+        // The same forward from a person is still fair game
+        let forward = forwarded_voice(false);
+        assert!(matches!(
+            RelevantMsg::try_from(&forward),
+            Ok(RelevantMsg {
+                kind: RelevantMsgKind::Voice(_),
+                ..
+            })
+        ));
+    }
+
+    /// A voice message that `forwarder` forwarded into the author's chat with however telegram
+    /// described where it came from
+    fn forward_from(forwarder: u64, origin: serde_json::Value) -> types::Message {
+        let mut msg = serde_json::json!({
+            "message_id": 9,
+            "date": 1_700_000_000,
+            "chat": { "id": AUTHOR_CHAT.0, "type": "private", "first_name": "Author" },
+            "from": { "id": forwarder, "is_bot": false, "first_name": "Forwarder" },
+            "forward_date": 1_699_999_000,
+            "voice": {
+                "file_id": "voice",
+                "file_unique_id": "voice-unique",
+                "file_size": 1024,
+                "duration": 5,
+                "mime_type": "audio/ogg"
+            }
+        });
+        msg.as_object_mut()
+            .unwrap()
+            .extend(origin.as_object().unwrap().clone());
+        serde_json::from_value(msg).unwrap()
+    }
+
+    #[test]
+    fn forwards_belong_to_their_original_author() {
+        let user = |id: u64| serde_json::json!({ "id": id, "is_bot": false, "first_name": "User" });
+
+        // Forwarding your own voice message keeps it yours
+        let own = forward_from(42, serde_json::json!({ "forward_from": user(42) }));
+        assert!(matches!(
+            RelevantParentMsg::from(&own).author_id(),
+            Ok(AUTHOR)
+        ));
+
+        // Someone else's is theirs and not the forwarder's
+        let other = forward_from(42, serde_json::json!({ "forward_from": user(7) }));
+        assert!(matches!(
+            RelevantParentMsg::from(&other).author_id(),
+            Ok(types::UserId(7))
+        ));
+
+        // Authors that hide themselves in forwards can't have their trigger checked
+        let hidden = forward_from(42, serde_json::json!({ "forward_sender_name": "Anon" }));
+        assert!(matches!(
+            RelevantParentMsg::from(&hidden).author_id(),
+            Err(UserError::ForwardedAuthorHidden)
+        ));
+
+        // Not being a forward at all goes by the sender
+        let plain = voice_msg(7, "voice", 5);
+        assert!(matches!(
+            RelevantParentMsg::from(&plain).author_id(),
+            Ok(AUTHOR)
+        ));
+    }
+
+    #[tokio::test]
+    async fn forwarding_into_a_sidecar_submits_nothing() {
+        let mock = MockBot::spawn();
+        mock.add_file("voice", wav(5));
+        let state = test_state("sidecar-forward", &mock, greeting_backend()).await;
+        let forward = forwarded_voice(true);
+        state
+            .db
+            .update_metadata(&voice_msg(1, "voice", 5))
+            .await
+            .unwrap();
+        state.db.update_metadata(&forward).await.unwrap();
+        state
+            .db
+            .attach_sidecar(AUTHOR_CHAT, forward.chat.id, false)
+            .await
+            .unwrap();
+        // Even a bot that would otherwise transcribe everything it sends gets ignored
+        let bot_id = types::UserId(mock_bot::BOT_ID);
+        state
+            .db
+            .add_trusted_user(bot_id, "rambot".to_owned())
+            .await
+            .unwrap();
+        let bot_user = state.db.user(bot_id).await.unwrap();
+        bot_user
+            .set_transcribe_trigger(TranscribeTrigger::Always)
+            .await
+            .unwrap();
+
+        let res = try_handle_message(mock.bot(), state.clone(), forward).await;
+        assert!(matches!(res, Err(HandlerError::Ignore)));
+        assert!(mock.calls_to("getFile").is_empty());
+        assert!(mock.calls_to("sendMessage").is_empty());
+        assert_eq!(state.transcriber_pool.stats().queued_jobs, 0);
+        assert!(state.pending.jobs().await.is_empty());
+    }
 }
@@ -0,0 +1,108 @@
+//! Figures out what kind of media a message holds
+//!
+//! Only voice messages can be transcribed, but people reply to all sorts of things with
+//! `/transcribe`. Knowing what they actually replied to lets us tell them why it didn't work instead
+//! of a blanket "that's not a voice message"
+
+use std::fmt;
+
+use teloxide::types;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MediaKind {
+    Voice,
+    VideoNote,
+    Video,
+    Audio,
+    Photo,
+    Sticker,
+    Animation,
+    Document,
+    Text,
+    Other,
+}
+
+impl MediaKind {
+    pub fn of(msg: &types::Message) -> Self {
+        if msg.voice().is_some() {
+            Self::Voice
+        } else if msg.video_note().is_some() {
+            Self::VideoNote
+        } else if msg.video().is_some() {
+            Self::Video
+        } else if msg.audio().is_some() {
+            Self::Audio
+        } else if msg.photo().is_some() {
+            Self::Photo
+        } else if msg.sticker().is_some() {
+            Self::Sticker
+        } else if msg.animation().is_some() {
+            Self::Animation
+        } else if msg.document().is_some() {
+            Self::Document
+        } else if msg.text().is_some() {
+            Self::Text
+        } else {
+            Self::Other
+        }
+    }
+}
+
+/// Explains why the media can't be transcribed
+impl fmt::Display for MediaKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let hint = match self {
+            Self::Voice => "That's a voice message, so I should be able to transcribe it",
+            Self::VideoNote => {
+                "That's a video note. I can only transcribe voice messages, not video notes (yet)"
+            }
+            Self::Video => "That's a video. I can only transcribe voice messages",
+            Self::Audio => {
+                "That's an audio file. I can only transcribe voice messages, so try sending it as \
+                one instead"
+            }
+            Self::Photo => "That's a photo, so there's nothing for me to transcribe",
+            Self::Sticker => "That's a sticker, so there's nothing for me to transcribe",
+            Self::Animation => "That's a GIF, so there's nothing for me to transcribe",
+            Self::Document => "That's a file. I can only transcribe voice messages",
+            Self::Text => "That's already text, so there's nothing for me to transcribe",
+            Self::Other => "Your message should be a reply to a voice message",
+        };
+        f.write_str(hint)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn msg_with(media: serde_json::Value) -> types::Message {
+        let mut msg = serde_json::json!({
+            "message_id": 3,
+            "date": 1_700_000_000,
+            "chat": { "id": 42, "type": "private", "first_name": "Author" },
+            "from": { "id": 42, "is_bot": false, "first_name": "Author" },
+        });
+        msg.as_object_mut()
+            .unwrap()
+            .extend(media.as_object().unwrap().clone());
+        serde_json::from_value(msg).unwrap()
+    }
+
+    #[test]
+    fn replies_get_told_apart_by_media() {
+        let file = serde_json::json!({ "file_id": "file", "file_unique_id": "file" });
+        let doc = msg_with(serde_json::json!({ "document": file }));
+        assert_eq!(MediaKind::of(&doc), MediaKind::Document);
+        let photo = msg_with(serde_json::json!({
+            "photo": [{ "file_id": "pic", "file_unique_id": "pic", "width": 90, "height": 90 }],
+        }));
+        assert_eq!(MediaKind::of(&photo), MediaKind::Photo);
+        let video_note = msg_with(serde_json::json!({
+            "video_note": { "file_id": "vid", "file_unique_id": "vid", "length": 240, "duration": 3 },
+        }));
+        assert_eq!(MediaKind::of(&video_note), MediaKind::VideoNote);
+        let text = msg_with(serde_json::json!({ "text": "hi" }));
+        assert_eq!(MediaKind::of(&text), MediaKind::Text);
+    }
+}
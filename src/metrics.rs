@@ -0,0 +1,196 @@
+//! Counters for keeping tabs on how transcriptions are going
+//!
+//! Everything's a plain atomic so that recording is cheap enough for the hot path and never
+//! blocks. They get served in Prometheus' text format at `/metrics` on `RAMBOT_METRICS_PORT`. The
+//! whole module only exists with the `metrics` feature, so builds without it don't pay for
+//! recording numbers that no one can scrape
+
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+pub static JOBS_SUBMITTED: Counter = Counter::new();
+/// Turned away because the queue was full
+pub static JOBS_REJECTED: Counter = Counter::new();
+pub static JOBS_COMPLETED: Counter = Counter::new();
+pub static JOBS_FAILED: Counter = Counter::new();
+pub static AUDIO_SECS: Counter = Counter::new();
+pub static TRANSCRIPTION_DURATION: Histogram = Histogram::new();
+pub static MESSAGE_EDITS: Counter = Counter::new();
+pub static MESSAGE_EDIT_FAILURES: Counter = Counter::new();
+
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    const fn new() -> Self {
+        Self(AtomicU64::new(0))
+    }
+
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Upper bounds in seconds. Transcriptions range from a few seconds up to several minutes
+const BUCKETS: [f64; 8] = [1.0, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0, 600.0];
+
+pub struct Histogram {
+    /// Cumulative like Prometheus expects, so each observation bumps every bucket it fits in
+    buckets: [AtomicU64; BUCKETS.len()],
+    count: AtomicU64,
+    sum_millis: AtomicU64,
+}
+
+impl Histogram {
+    const fn new() -> Self {
+        Self {
+            buckets: [const { AtomicU64::new(0) }; BUCKETS.len()],
+            count: AtomicU64::new(0),
+            sum_millis: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, duration: Duration) {
+        let secs = duration.as_secs_f64();
+        for (bucket, bound) in self.buckets.iter().zip(BUCKETS) {
+            if secs <= bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        let millis = u64::try_from(duration.as_millis()).unwrap_or(u64::MAX);
+        self.sum_millis.fetch_add(millis, Ordering::Relaxed);
+    }
+}
+
+pub use server::spawn_server;
+
+mod server {
+    use std::fmt::Write;
+
+    use super::*;
+    use crate::{
+        http::{self, Response},
+        transcriber, InitResult,
+    };
+
+    /// Serves `/metrics` on `RAMBOT_METRICS_PORT` when it's set
+    pub async fn spawn_server(pool: transcriber::Pool) -> InitResult {
+        http::spawn_server("RAMBOT_METRICS_PORT", move |path| {
+            (path == "/metrics").then(|| Response {
+                status: "200 OK",
+                content_type: "text/plain; version=0.0.4",
+                body: render(pool.stats()),
+            })
+        })
+        .await
+    }
+
+    fn render(stats: transcriber::Stats) -> String {
+        let mut out = String::new();
+        let counters = [
+            (
+                "rambot_jobs_submitted_total",
+                "Jobs submitted to the transcriber pool",
+                &JOBS_SUBMITTED,
+            ),
+            (
+                "rambot_jobs_rejected_total",
+                "Jobs turned away by a full queue",
+                &JOBS_REJECTED,
+            ),
+            (
+                "rambot_jobs_completed_total",
+                "Transcriptions that finished",
+                &JOBS_COMPLETED,
+            ),
+            (
+                "rambot_jobs_failed_total",
+                "Transcriptions that errored or timed out",
+                &JOBS_FAILED,
+            ),
+            (
+                "rambot_audio_seconds_total",
+                "Seconds of audio transcribed",
+                &AUDIO_SECS,
+            ),
+            (
+                "rambot_message_edits_total",
+                "Edits made to transcription messages",
+                &MESSAGE_EDITS,
+            ),
+            (
+                "rambot_message_edit_failures_total",
+                "Edits to transcription messages that failed",
+                &MESSAGE_EDIT_FAILURES,
+            ),
+        ];
+        for (name, help, counter) in counters {
+            let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} counter");
+            let _ = writeln!(out, "{name} {}", counter.get());
+        }
+
+        let gauges = [
+            (
+                "rambot_queue_depth",
+                "Jobs waiting on a transcriber",
+                stats.queued_jobs,
+            ),
+            (
+                "rambot_busy_workers",
+                "Transcribers working on a job",
+                stats.busy_workers,
+            ),
+        ];
+        for (name, help, value) in gauges {
+            let _ = writeln!(
+                out,
+                "# HELP {name} {help}\n# TYPE {name} gauge\n{name} {value}"
+            );
+        }
+
+        let name = "rambot_transcription_duration_seconds";
+        let hist = &TRANSCRIPTION_DURATION;
+        let _ = writeln!(out, "# HELP {name} Time spent transcribing each job");
+        let _ = writeln!(out, "# TYPE {name} histogram");
+        for (bucket, bound) in hist.buckets.iter().zip(BUCKETS) {
+            let count = bucket.load(Ordering::Relaxed);
+            let _ = writeln!(out, "{name}_bucket{{le=\"{bound}\"}} {count}");
+        }
+        let count = hist.count.load(Ordering::Relaxed);
+        let sum = hist.sum_millis.load(Ordering::Relaxed) as f64 / 1000.0;
+        let _ = writeln!(out, "{name}_bucket{{le=\"+Inf\"}} {count}");
+        let _ = writeln!(out, "{name}_sum {sum}\n{name}_count {count}");
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histogram_buckets_are_cumulative() {
+        let hist = Histogram::new();
+        hist.observe(Duration::from_secs(7));
+        hist.observe(Duration::from_secs(45));
+
+        let buckets: Vec<_> = hist
+            .buckets
+            .iter()
+            .map(|bucket| bucket.load(Ordering::Relaxed))
+            .collect();
+        assert_eq!(buckets, [0, 0, 1, 1, 2, 2, 2, 2]);
+        assert_eq!(hist.count.load(Ordering::Relaxed), 2);
+        assert_eq!(hist.sum_millis.load(Ordering::Relaxed), 52_000);
+    }
+}
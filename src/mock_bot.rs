@@ -0,0 +1,290 @@
+//! Stands in for telegram's bot API so that handlers can be tested from start to finish
+//!
+//! Every request that the bot makes gets recorded and answered with just enough for the handlers
+//! to keep going. Files added with [`MockBot::add_file()`] can be downloaded like the real thing
+
+use std::{
+    collections::{HashMap, HashSet},
+    convert::Infallible,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicI32, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use crate::{telegram, transcriber};
+
+use hyper::{
+    header::CONTENT_TYPE,
+    service::{make_service_fn, service_fn},
+    Body, Request, Response, Server, StatusCode,
+};
+use serde_json::{json, Value};
+use teloxide::{adaptors::throttle::Limits, types};
+
+const TOKEN: &str = "1234:mock";
+pub const BOT_ID: u64 = 99;
+pub const BOT_NAME: &str = "rambot";
+
+#[derive(Clone, Debug)]
+pub struct Call {
+    pub method: String,
+    /// The request's payload. Uploads come through as `{"file_name": .., "contents": ..}`
+    pub params: Value,
+}
+
+#[derive(Clone, Default)]
+struct Shared {
+    calls: Arc<Mutex<Vec<Call>>>,
+    files: Arc<Mutex<HashMap<String, Vec<u8>>>>,
+    failing: Arc<Mutex<HashSet<String>>>,
+    /// `(chat_id, user_id)`s that `getChatMember` reports as the chat's creator
+    admins: Arc<Mutex<HashSet<(i64, u64)>>>,
+    /// How long downloading a file takes
+    file_delay: Arc<Mutex<Duration>>,
+    last_msg_id: Arc<AtomicI32>,
+}
+
+pub struct MockBot {
+    shared: Shared,
+    bot: telegram::Bot,
+}
+
+impl MockBot {
+    pub fn spawn() -> Self {
+        let shared = Shared {
+            // Well clear of the ids that tests give their own messages
+            last_msg_id: Arc::new(AtomicI32::new(1000)),
+            ..Default::default()
+        };
+        let server_shared = shared.clone();
+        let make_svc = make_service_fn(move |_| {
+            let shared = server_shared.clone();
+            async move { Ok::<_, Infallible>(service_fn(move |req| respond(shared.clone(), req))) }
+        });
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_svc);
+        let url = format!("http://{}/", server.local_addr()).parse().unwrap();
+        tokio::spawn(server);
+
+        // The real limits would have tests waiting around between sends
+        let limits = Limits {
+            messages_per_sec_chat: 1_000,
+            messages_per_min_chat: 1_000,
+            messages_per_min_channel: 1_000,
+            messages_per_sec_overall: 1_000,
+        };
+        let bot = teloxide::Bot::new(TOKEN).set_api_url(url);
+        Self {
+            shared,
+            bot: telegram::Bot::with_limits(bot, limits),
+        }
+    }
+
+    pub fn bot(&self) -> telegram::Bot {
+        self.bot.clone()
+    }
+
+    pub fn add_file(&self, file_id: &str, contents: Vec<u8>) {
+        let mut files = self.shared.files.lock().unwrap();
+        files.insert(file_id.to_owned(), contents);
+    }
+
+    /// Slows down every file download from here on like a real connection would
+    pub fn delay_files(&self, delay: Duration) {
+        *self.shared.file_delay.lock().unwrap() = delay;
+    }
+
+    pub fn add_admin(&self, chat_id: types::ChatId, user_id: types::UserId) {
+        let mut admins = self.shared.admins.lock().unwrap();
+        admins.insert((chat_id.0, user_id.0));
+    }
+
+    /// Every call to `method` from here on gets an error back (after still getting recorded)
+    pub fn fail(&self, method: &str) {
+        self.shared
+            .failing
+            .lock()
+            .unwrap()
+            .insert(method.to_owned());
+    }
+
+    pub fn calls(&self) -> Vec<Call> {
+        self.shared.calls.lock().unwrap().clone()
+    }
+
+    /// The params of every call to `method` in the order they were made
+    pub fn calls_to(&self, method: &str) -> Vec<Value> {
+        self.calls()
+            .into_iter()
+            .filter(|call| call.method == method)
+            .map(|call| call.params)
+            .collect()
+    }
+
+    /// The last text that the message got sent or edited to have
+    pub fn text_of(&self, msg_id: i32) -> Option<String> {
+        self.calls().into_iter().rev().find_map(|call| {
+            let id_key = match call.method.as_str() {
+                "sendMessage" => "sent_id",
+                "editMessageText" => "message_id",
+                _ => return None,
+            };
+            let text = call.params["text"].as_str().map(ToOwned::to_owned);
+            (call.params[id_key] == msg_id).then_some(text)?
+        })
+    }
+}
+
+async fn respond(shared: Shared, req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    let path = req.uri().path().to_owned();
+    if let Some(file_path) = path.strip_prefix(&format!("/file/bot{TOKEN}/")) {
+        let delay = *shared.file_delay.lock().unwrap();
+        tokio::time::sleep(delay).await;
+        let contents = shared.files.lock().unwrap().get(file_path).cloned();
+        let resp = match contents {
+            Some(contents) => Response::new(Body::from(contents)),
+            None => {
+                let mut resp = Response::new(Body::empty());
+                *resp.status_mut() = StatusCode::NOT_FOUND;
+                resp
+            }
+        };
+        return Ok(resp);
+    }
+
+    // teloxide capitalizes the method names while telegram's docs don't
+    let method = path.rsplit('/').next().unwrap_or_default();
+    let mut chars = method.chars();
+    let method: String = chars
+        .next()
+        .map(|first| first.to_ascii_lowercase())
+        .into_iter()
+        .chain(chars)
+        .collect();
+    let is_multipart = req
+        .headers()
+        .get(CONTENT_TYPE)
+        .is_some_and(|content_type| content_type.as_bytes().starts_with(b"multipart/"));
+    let body = hyper::body::to_bytes(req.into_body())
+        .await
+        .unwrap_or_default();
+    let mut params = if is_multipart {
+        multipart_fields(&body)
+    } else {
+        serde_json::from_slice(&body).unwrap_or(Value::Null)
+    };
+
+    let resp = match shared.answer(&method, &mut params) {
+        Ok(result) => json!({ "ok": true, "result": result }),
+        Err(description) => json!({ "ok": false, "error_code": 400, "description": description }),
+    };
+    shared.calls.lock().unwrap().push(Call { method, params });
+    let resp = Response::builder()
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(resp.to_string()))
+        .unwrap();
+    Ok(resp)
+}
+
+impl Shared {
+    /// Sent messages get the id that they were given tacked onto their params as `sent_id`
+    fn answer(&self, method: &str, params: &mut Value) -> Result<Value, &'static str> {
+        if self.failing.lock().unwrap().contains(method) {
+            return Err("Bad Request: failing on purpose");
+        }
+        let result = match method {
+            "getMe" => json!({
+                "id": BOT_ID,
+                "is_bot": true,
+                "first_name": BOT_NAME,
+                "username": BOT_NAME,
+            }),
+            "getFile" => {
+                let file_id = params["file_id"].as_str().unwrap_or_default();
+                let files = self.files.lock().unwrap();
+                let contents = files.get(file_id).ok_or("Bad Request: invalid file_id")?;
+                json!({
+                    "file_id": file_id,
+                    "file_unique_id": file_id,
+                    "file_size": contents.len(),
+                    "file_path": file_id,
+                })
+            }
+            "sendMessage" | "sendDocument" | "sendVoice" | "forwardMessage" => {
+                let id = self.last_msg_id.fetch_add(1, Ordering::Relaxed) + 1;
+                params["sent_id"] = id.into();
+                message(id, params)
+            }
+            "getChatMember" => {
+                let chat_id = params["chat_id"].as_i64().unwrap_or_default();
+                let user_id = params["user_id"].as_u64().unwrap_or_default();
+                let is_admin = self.admins.lock().unwrap().contains(&(chat_id, user_id));
+                let user = json!({ "id": user_id, "is_bot": false, "first_name": "Member" });
+                if is_admin {
+                    json!({ "user": user, "status": "creator", "is_anonymous": false })
+                } else {
+                    json!({ "user": user, "status": "member" })
+                }
+            }
+            "editMessageText" | "editMessageReplyMarkup" | "editMessageMedia" => {
+                let id = params["message_id"].as_i64().unwrap_or_default();
+                message(i32::try_from(id).unwrap(), params)
+            }
+            _ => json!(true),
+        };
+        Ok(result)
+    }
+}
+
+/// Audio that's already in whisper's format, so it doesn't need ffmpeg to decode
+pub fn wav(secs: usize) -> Vec<u8> {
+    let spec = hound::WavSpec {
+        channels: 1,
+        sample_rate: transcriber::vad::SAMPLE_RATE as u32,
+        bits_per_sample: 16,
+        sample_format: hound::SampleFormat::Int,
+    };
+    let mut wav = std::io::Cursor::new(Vec::new());
+    let mut writer = hound::WavWriter::new(&mut wav, spec).unwrap();
+    for i in 0..secs * transcriber::vad::SAMPLE_RATE {
+        let sample = (i as f32 / 10.0).sin() * f32::from(i16::MAX / 2);
+        writer.write_sample(sample as i16).unwrap();
+    }
+    writer.finalize().unwrap();
+    wav.into_inner()
+}
+
+fn message(id: i32, params: &Value) -> Value {
+    json!({
+        "message_id": id,
+        "date": 0,
+        "chat": { "id": params["chat_id"], "type": "private", "first_name": "Chat" },
+        "text": params["text"].as_str().unwrap_or_default(),
+    })
+}
+
+/// Good enough for the uploads that teloxide sends. Fields that hold JSON get parsed
+fn multipart_fields(body: &[u8]) -> Value {
+    let body = String::from_utf8_lossy(body);
+    let mut fields = serde_json::Map::new();
+    for part in body.split("\r\n--") {
+        let Some((head, value)) = part.split_once("\r\n\r\n") else {
+            continue;
+        };
+        let attr = |name: &str| {
+            let (_, rest) = head.split_once(&format!(" {name}=\""))?;
+            rest.split('"').next()
+        };
+        let Some(name) = attr("name") else {
+            continue;
+        };
+        let value = match attr("filename") {
+            Some(file_name) => json!({ "file_name": file_name, "contents": value }),
+            None => serde_json::from_str(value).unwrap_or_else(|_| value.into()),
+        };
+        fields.insert(name.to_owned(), value);
+    }
+    fields.into()
+}
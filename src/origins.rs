@@ -0,0 +1,103 @@
+//! Remembers which voice message each of our transcripts belongs to
+//!
+//! People tend to reply to the transcript instead of the voice message when they want it redone
+//! (e.g. with `/quick` or after changing their settings). Keying the voice message by the bot's
+//! own messages lets those replies find their way back to the original. Going the other way
+//! lets a transcript get redone in place when its voice message gets swapped out by an edit
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::RelevantMeta;
+
+use teloxide::types;
+
+/// How long a transcript can still be replied to
+const ORIGIN_WINDOW: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Clone)]
+pub struct Origin {
+    pub voice_msg: RelevantMeta,
+    pub voice: types::Voice,
+    /// Whose settings it was transcribed with
+    pub requester_id: types::UserId,
+    pub quick: bool,
+}
+
+/// Where a voice message's transcript ended up
+#[derive(Clone, Debug, Default)]
+pub struct Placement {
+    /// Always in the voice message's chat
+    pub preview: Option<types::MessageId>,
+    pub multipart: Vec<types::MessageId>,
+    /// The chat that the long message's parts are in and the message that they reply to
+    pub long_msg_dest: Option<(types::ChatId, types::MessageId)>,
+    /// Attachments like subtitles keyed by their file name. Always in the voice message's chat
+    pub documents: HashMap<String, types::MessageId>,
+}
+
+impl Placement {
+    pub fn bot_msgs(&self, voice_chat_id: types::ChatId) -> Vec<BotMsgKey> {
+        let preview = self.preview.map(|id| (voice_chat_id, id));
+        let multipart = self
+            .long_msg_dest
+            .iter()
+            .flat_map(|&(chat_id, _)| self.multipart.iter().map(move |&id| (chat_id, id)));
+        preview.into_iter().chain(multipart).collect()
+    }
+}
+
+type BotMsgKey = (types::ChatId, types::MessageId);
+type VoiceMsgKey = (types::ChatId, types::MessageId);
+
+#[derive(Default)]
+struct Inner {
+    transcripts: HashMap<VoiceMsgKey, (Instant, Origin, Placement)>,
+    by_bot_msg: HashMap<BotMsgKey, VoiceMsgKey>,
+}
+
+#[derive(Clone, Default)]
+pub struct Registry {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl Registry {
+    /// Every part of a transcript points back to the same voice message, even when it lives over
+    /// in a sidecar chat. Replaces whatever the voice message had before
+    pub fn insert(&self, origin: Origin, placement: Placement) {
+        let mut inner = self.inner.lock().unwrap();
+        let Inner {
+            transcripts,
+            by_bot_msg,
+        } = &mut *inner;
+        transcripts.retain(|_, (sent_at, _, _)| sent_at.elapsed() < ORIGIN_WINDOW);
+        by_bot_msg.retain(|_, voice_key| transcripts.contains_key(voice_key));
+
+        let voice_key = (origin.voice_msg.chat_id, origin.voice_msg.id);
+        for bot_msg in placement.bot_msgs(voice_key.0) {
+            by_bot_msg.insert(bot_msg, voice_key);
+        }
+        transcripts.insert(voice_key, (Instant::now(), origin, placement));
+    }
+
+    pub fn get(&self, chat_id: types::ChatId, bot_msg_id: types::MessageId) -> Option<Origin> {
+        let inner = self.inner.lock().unwrap();
+        let voice_key = inner.by_bot_msg.get(&(chat_id, bot_msg_id))?;
+        let (sent_at, origin, _) = inner.transcripts.get(voice_key)?;
+        (sent_at.elapsed() < ORIGIN_WINDOW).then(|| origin.clone())
+    }
+
+    /// The voice message's latest transcript
+    pub fn transcript_of(
+        &self,
+        chat_id: types::ChatId,
+        voice_msg_id: types::MessageId,
+    ) -> Option<(Origin, Placement)> {
+        let inner = self.inner.lock().unwrap();
+        let (sent_at, origin, placement) = inner.transcripts.get(&(chat_id, voice_msg_id))?;
+        (sent_at.elapsed() < ORIGIN_WINDOW).then(|| (origin.clone(), placement.clone()))
+    }
+}
@@ -0,0 +1,104 @@
+//! Remembers accepted voice messages until they're done so that a restart doesn't lose them
+//!
+//! Jobs get written out as soon as they're accepted and dropped once they've finished one way or
+//! another. Anything still around on startup got cut off by a shutdown and gets picked back up.
+//! There's only ever a handful of these, so the whole file just gets rewritten on every change
+//! like the db
+
+use std::{io, path::PathBuf, sync::Arc};
+
+use serde::{Deserialize, Serialize};
+use teloxide::types;
+use tokio::{fs, sync::Mutex};
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PendingJob {
+    pub chat_id: types::ChatId,
+    pub voice_msg_id: i32,
+    /// Who sent the voice message
+    pub author_id: types::UserId,
+    /// Who asked for the transcription which decides the settings that get used
+    pub requester_id: types::UserId,
+    pub voice_file_id: String,
+    pub voice_file_unique_id: String,
+    pub voice_file_size: u32,
+    pub duration_secs: u32,
+    pub attempt: u8,
+    /// Only the start of it gets transcribed
+    #[serde(default)]
+    pub quick: bool,
+    /// The forum topic that the voice message is in
+    #[serde(default)]
+    pub topic: Option<i32>,
+}
+
+impl PendingJob {
+    fn key(&self) -> (types::ChatId, i32) {
+        (self.chat_id, self.voice_msg_id)
+    }
+}
+
+#[derive(Clone)]
+pub struct Queue {
+    path: PathBuf,
+    jobs: Arc<Mutex<Vec<PendingJob>>>,
+}
+
+impl Queue {
+    /// A missing or unreadable file just means there's nothing to resume
+    pub async fn load(path: PathBuf) -> Self {
+        let jobs = match fs::read_to_string(&path).await {
+            Ok(contents) => ron::from_str(&contents).unwrap_or_else(|e| {
+                log::warn!(
+                    "Ignoring unreadable pending jobs at {}: {e}",
+                    path.display()
+                );
+                Vec::new()
+            }),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => {
+                log::warn!("Failed reading pending jobs at {}: {e}", path.display());
+                Vec::new()
+            }
+        };
+
+        Self {
+            path,
+            jobs: Arc::new(Mutex::new(jobs)),
+        }
+    }
+
+    pub async fn jobs(&self) -> Vec<PendingJob> {
+        self.jobs.lock().await.clone()
+    }
+
+    pub async fn insert(&self, job: PendingJob) {
+        let mut jobs = self.jobs.lock().await;
+        jobs.retain(|pending| pending.key() != job.key());
+        jobs.push(job);
+        self.dump(&jobs).await;
+    }
+
+    pub async fn remove(&self, chat_id: types::ChatId, voice_msg_id: types::MessageId) {
+        let mut jobs = self.jobs.lock().await;
+        let prev_len = jobs.len();
+        jobs.retain(|pending| pending.key() != (chat_id, voice_msg_id.0));
+        if jobs.len() != prev_len {
+            self.dump(&jobs).await;
+        }
+    }
+
+    /// Failing to persist only matters if we restart, so it doesn't fail the job
+    async fn dump(&self, jobs: &[PendingJob]) {
+        let res = async {
+            let contents = ron::to_string(jobs).map_err(io::Error::other)?;
+            if let Some(parent) = self.path.parent() {
+                fs::create_dir_all(parent).await?;
+            }
+            fs::write(&self.path, contents).await
+        };
+        if let Err(e) = res.await {
+            log::warn!("Failed saving pending jobs: {e}");
+        }
+    }
+}
@@ -0,0 +1,74 @@
+//! Remembers failed transcriptions for a little while so that they can be retried
+//!
+//! Failures get keyed by the bot's error message, so replying to that message with `/retry` is
+//! enough to find the original voice message again. This is handy for forwards where resending
+//! isn't really an option
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::RelevantMeta;
+
+use teloxide::types;
+
+/// How long a failure sticks around to be retried
+const RETRY_WINDOW: Duration = Duration::from_secs(60 * 60);
+/// Keeps a voice message that fails every time from getting retried forever
+pub const MAX_ATTEMPTS: u8 = 3;
+
+pub struct FailedJob {
+    pub voice_msg: RelevantMeta,
+    pub voice: types::Voice,
+    /// How many times transcribing this has been attempted so far
+    pub attempts: u8,
+    pub quick: bool,
+}
+
+impl FailedJob {
+    pub fn can_retry(&self) -> bool {
+        self.attempts < MAX_ATTEMPTS
+    }
+}
+
+type ErrorMsgKey = (types::ChatId, types::MessageId);
+
+#[derive(Clone, Default)]
+pub struct Registry {
+    failed: Arc<Mutex<HashMap<ErrorMsgKey, (Instant, FailedJob)>>>,
+}
+
+impl Registry {
+    pub fn insert(&self, chat_id: types::ChatId, error_msg_id: types::MessageId, job: FailedJob) {
+        let mut failed = self.failed.lock().unwrap();
+        failed.retain(|_, (failed_at, _)| failed_at.elapsed() < RETRY_WINDOW);
+        failed.insert((chat_id, error_msg_id), (Instant::now(), job));
+    }
+
+    /// Who sent the failed job's voice message, which decides who's allowed to retry it
+    pub fn author(
+        &self,
+        chat_id: types::ChatId,
+        error_msg_id: types::MessageId,
+    ) -> Option<types::UserId> {
+        let failed = self.failed.lock().unwrap();
+        let (_, job) = failed.get(&(chat_id, error_msg_id))?;
+        Some(job.voice_msg.from)
+    }
+
+    /// Takes the failed job so that it can only get retried once from the same error message
+    pub fn take(
+        &self,
+        chat_id: types::ChatId,
+        error_msg_id: types::MessageId,
+    ) -> Option<FailedJob> {
+        let (failed_at, job) = self
+            .failed
+            .lock()
+            .unwrap()
+            .remove(&(chat_id, error_msg_id))?;
+        (failed_at.elapsed() < RETRY_WINDOW).then_some(job)
+    }
+}
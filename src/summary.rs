@@ -0,0 +1,195 @@
+//! Condenses finished transcriptions into a few bullet points
+//!
+//! Transcription text gets cached per voice message as it finishes, so `/summary` never has to
+//! re-transcribe anything. The actual summarizing is handed off to a [`Summarizer`] which keeps
+//! the backend swappable. The only one so far talks to an OpenAI-compatible chat completions API
+//! which covers both hosted APIs and local servers like llama.cpp or ollama
+
+use std::{
+    collections::{HashMap, VecDeque},
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+};
+
+use crate::{HandlerResult, UserError};
+
+use serde::{Deserialize, Serialize};
+use teloxide::types;
+
+/// Plenty for recent messages while keeping memory use in check for long ones
+const CACHE_CAPACITY: usize = 256;
+const PROMPT: &str = "You summarize transcriptions of voice messages. Reply with a few short \
+    bullet points covering the main points. Reply in the language of the transcription and don't \
+    add anything that wasn't said";
+
+pub type SummaryFut<'a> = Pin<Box<dyn Future<Output = HandlerResult<String>> + Send + 'a>>;
+
+pub trait Summarizer: Send + Sync {
+    fn summarize<'a>(&'a self, transcription: &'a str) -> SummaryFut<'a>;
+}
+
+type VoiceMsgKey = (types::ChatId, types::MessageId);
+
+#[derive(Clone)]
+pub struct Summaries {
+    summarizer: Arc<dyn Summarizer>,
+    transcriptions: Arc<Mutex<Cache>>,
+}
+
+impl Summaries {
+    /// `None` when no summarizer is configured
+    pub fn from_env() -> Option<Self> {
+        let summarizer = ChatCompletions::from_env()?;
+        Some(Self::new(summarizer))
+    }
+
+    pub fn new<S: Summarizer + 'static>(summarizer: S) -> Self {
+        Self {
+            summarizer: Arc::new(summarizer),
+            transcriptions: Default::default(),
+        }
+    }
+
+    pub fn cache_transcription(
+        &self,
+        chat_id: types::ChatId,
+        voice_msg_id: types::MessageId,
+        text: String,
+    ) {
+        self.transcriptions
+            .lock()
+            .unwrap()
+            .insert((chat_id, voice_msg_id), text);
+    }
+
+    pub async fn summarize(
+        &self,
+        chat_id: types::ChatId,
+        voice_msg_id: types::MessageId,
+    ) -> HandlerResult<String> {
+        let text = self
+            .transcriptions
+            .lock()
+            .unwrap()
+            .get(&(chat_id, voice_msg_id))
+            .ok_or(UserError::NoCachedTranscription)?;
+        self.summarizer.summarize(&text).await
+    }
+}
+
+/// Drops the oldest transcriptions once it's full
+#[derive(Default)]
+struct Cache {
+    texts: HashMap<VoiceMsgKey, Arc<str>>,
+    order: VecDeque<VoiceMsgKey>,
+}
+
+impl Cache {
+    fn insert(&mut self, key: VoiceMsgKey, text: String) {
+        if self.texts.insert(key, text.into()).is_none() {
+            self.order.push_back(key);
+        }
+        while self.order.len() > CACHE_CAPACITY {
+            if let Some(oldest) = self.order.pop_front() {
+                self.texts.remove(&oldest);
+            }
+        }
+    }
+
+    fn get(&self, key: &VoiceMsgKey) -> Option<Arc<str>> {
+        self.texts.get(key).cloned()
+    }
+}
+
+/// Summarizes with any OpenAI-compatible `/chat/completions` endpoint
+pub struct ChatCompletions {
+    client: reqwest::Client,
+    url: String,
+    model: String,
+    api_key: Option<String>,
+}
+
+impl ChatCompletions {
+    /// Configured through `RAMBOT_SUMMARY_API_URL`, `RAMBOT_SUMMARY_MODEL`, and optionally
+    /// `RAMBOT_SUMMARY_API_KEY`
+    pub fn from_env() -> Option<Self> {
+        let (Ok(url), Ok(model)) = (
+            std::env::var("RAMBOT_SUMMARY_API_URL"),
+            std::env::var("RAMBOT_SUMMARY_MODEL"),
+        ) else {
+            log::info!("RAMBOT_SUMMARY_API_URL or RAMBOT_SUMMARY_MODEL isn't set. /summary is off");
+            return None;
+        };
+        let api_key = std::env::var("RAMBOT_SUMMARY_API_KEY").ok();
+
+        Some(Self {
+            client: reqwest::Client::new(),
+            url,
+            model,
+            api_key,
+        })
+    }
+
+    async fn request_summary(&self, transcription: &str) -> HandlerResult<String> {
+        let body = ChatRequest {
+            model: &self.model,
+            messages: [
+                ChatMessage {
+                    role: "system",
+                    content: PROMPT,
+                },
+                ChatMessage {
+                    role: "user",
+                    content: transcription,
+                },
+            ],
+        };
+        let mut req = self.client.post(&self.url).json(&body);
+        if let Some(api_key) = &self.api_key {
+            req = req.bearer_auth(api_key);
+        }
+        let resp: ChatResponse = req.send().await?.error_for_status()?.json().await?;
+
+        let summary = resp
+            .choices
+            .into_iter()
+            .next()
+            .map(|choice| choice.message.content)
+            .unwrap_or_default();
+        Ok(summary.trim().to_owned())
+    }
+}
+
+impl Summarizer for ChatCompletions {
+    fn summarize<'a>(&'a self, transcription: &'a str) -> SummaryFut<'a> {
+        Box::pin(self.request_summary(transcription))
+    }
+}
+
+#[derive(Serialize)]
+struct ChatRequest<'a> {
+    model: &'a str,
+    messages: [ChatMessage<'a>; 2],
+}
+
+#[derive(Serialize)]
+struct ChatMessage<'a> {
+    role: &'a str,
+    content: &'a str,
+}
+
+#[derive(Deserialize)]
+struct ChatResponse {
+    choices: Vec<ChatChoice>,
+}
+
+#[derive(Deserialize)]
+struct ChatChoice {
+    message: ChatChoiceMessage,
+}
+
+#[derive(Deserialize)]
+struct ChatChoiceMessage {
+    content: String,
+}
@@ -1,29 +1,85 @@
 //! Telegram has a big API surface area. These are the parts we care about
 
-use std::path::Path;
+use std::{collections::BTreeMap, future::Future, path::Path, sync::Mutex, time::Duration};
 
-use crate::HandlerResult;
+use crate::{HandlerResult, InitError, InitResult, UserError};
 
+use serde::Serialize;
+#[cfg(feature = "telegram-webhook")]
+use teloxide::update_listeners::{webhooks, UpdateListener};
 use teloxide::{
-    adaptors,
+    adaptors::{self, throttle::Limits},
     net::Download,
-    payloads,
-    requests::{HasPayload, Requester, RequesterExt},
-    types,
+    requests::{HasPayload, JsonRequest, Payload, Requester, RequesterExt},
+    types, ApiError, RequestError,
 };
+use tokio::{io::AsyncWriteExt, time::Instant};
 
+/// Bots can't download files any bigger than this
+pub const MAX_DOWNLOAD_BYTES: u32 = 20 * 1024 * 1024;
+
+/// When each chat's next reaction can go out. See [`Bot::set_message_reaction()`]
+static NEXT_REACTION: Mutex<BTreeMap<types::ChatId, Instant>> = Mutex::new(BTreeMap::new());
+
+/// The second field is the per-chat rate that the throttling adaptor got set up with, so that
+/// reactions can be held to it too. See [`Bot::set_message_reaction()`]
 #[derive(Clone)]
-pub struct Bot(pub adaptors::Throttle<teloxide::Bot>);
+pub struct Bot(pub adaptors::Throttle<teloxide::Bot>, u32);
 
+/// Assumes the default limits like [`Bot::from_env()`] sets up
 impl From<adaptors::Throttle<teloxide::Bot>> for Bot {
     fn from(bot: adaptors::Throttle<teloxide::Bot>) -> Self {
-        Self(bot)
+        Self(bot, Limits::default().messages_per_sec_chat)
+    }
+}
+
+/// The forum topic that the message was sent in. Replies have to name it explicitly to be sure
+/// that they land in the same topic instead of the general one
+///
+/// Only topics count. Plain supergroups hand out thread ids for reply chains too, but those can't
+/// be sent to
+pub fn topic_of(msg: &types::Message) -> Option<i32> {
+    match &msg.kind {
+        types::MessageKind::Common(common) if common.is_topic_message => msg.thread_id,
+        _ => None,
     }
 }
 
 impl Bot {
-    pub fn from_env() -> Self {
-        Self(teloxide::Bot::from_env().throttle(Default::default()))
+    /// Reads the token from `TELOXIDE_TOKEN` and goes through the proxy at `RAMBOT_PROXY_URL` when
+    /// it's set. Otherwise the usual `HTTPS_PROXY` and friends are still respected
+    pub fn from_env() -> InitResult<Self> {
+        let mut client = teloxide::net::default_reqwest_settings();
+        if let Ok(url) = std::env::var("RAMBOT_PROXY_URL") {
+            let proxy = reqwest::Proxy::all(url).map_err(InitError::InvalidProxyUrl)?;
+            log::info!("Reaching telegram through the proxy from RAMBOT_PROXY_URL");
+            client = client.proxy(proxy);
+        }
+        let client = client.build().map_err(InitError::HttpClient)?;
+
+        let bot = teloxide::Bot::from_env_with_client(client);
+        Ok(Self::with_limits(bot, Limits::default()))
+    }
+
+    pub fn with_limits(bot: teloxide::Bot, limits: Limits) -> Self {
+        Self(bot.throttle(limits), limits.messages_per_sec_chat)
+    }
+
+    /// Serves a webhook for telegram to send updates to when it's configured with both
+    /// `RAMBOT_TELEGRAM_WEBHOOK_LISTEN` (the local address to listen on) and
+    /// `RAMBOT_TELEGRAM_WEBHOOK_URL` (the public url that reaches it). `None` means long polling
+    #[cfg(feature = "telegram-webhook")]
+    pub async fn webhook_listener(
+        &self,
+    ) -> InitResult<Option<impl UpdateListener<Err = std::convert::Infallible>>> {
+        let Some(options) = webhook_options_from_env() else {
+            return Ok(None);
+        };
+        log::info!("Receiving updates through the webhook at {}", options.url);
+        let listener = webhooks::axum(self.0.clone(), options)
+            .await
+            .map_err(InitError::TelegramWebhook)?;
+        Ok(Some(listener))
     }
 
     pub async fn get_me(&self) -> Result<types::Me, teloxide::RequestError> {
@@ -40,17 +96,26 @@ impl Bot {
         Ok(())
     }
 
-    pub async fn send_message<S: Into<String>>(
+    /// Sends a message with an optional inline keyboard
+    ///
+    /// NOTE: The text has to already be escaped for the `parse_mode` when one is passed
+    pub async fn send_message_with_markup<S: Into<String>>(
         &self,
         chat_id: types::ChatId,
         reply_to: types::MessageId,
+        topic: Option<i32>,
         text: S,
+        markup: Option<types::InlineKeyboardMarkup>,
+        parse_mode: Option<types::ParseMode>,
     ) -> HandlerResult<Message> {
         let text = text.into();
         log::debug!("Sending reply to message {reply_to} text:\n{text}");
-        let mut pending_msg = self.0.send_message(chat_id.clone(), text);
+        let mut pending_msg = self.0.send_message(chat_id, text);
         let payload = pending_msg.payload_mut();
         payload.reply_to_message_id = Some(reply_to);
+        payload.message_thread_id = topic;
+        payload.reply_markup = markup.map(Into::into);
+        payload.parse_mode = parse_mode;
         let msg = pending_msg.await?;
 
         Ok(Message {
@@ -60,14 +125,195 @@ impl Bot {
         })
     }
 
+    pub async fn send_document(
+        &self,
+        chat_id: types::ChatId,
+        reply_to: types::MessageId,
+        topic: Option<i32>,
+        file_name: String,
+        contents: Vec<u8>,
+    ) -> HandlerResult<Message> {
+        log::debug!(
+            "Sending document {file_name} ({} bytes) in reply to {reply_to}",
+            contents.len()
+        );
+        let document = types::InputFile::memory(contents).file_name(file_name);
+        let mut pending_msg = self.0.send_document(chat_id, document);
+        let payload = pending_msg.payload_mut();
+        payload.reply_to_message_id = Some(reply_to);
+        payload.message_thread_id = topic;
+        let msg = pending_msg.await?;
+
+        Ok(Message {
+            bot: self.0.clone(),
+            msg_id: msg.id,
+            chat_id,
+        })
+    }
+
+    /// Swaps out the file of a document that we sent earlier
+    pub async fn edit_document(
+        &self,
+        chat_id: types::ChatId,
+        msg_id: types::MessageId,
+        file_name: String,
+        contents: Vec<u8>,
+    ) -> HandlerResult {
+        log::debug!(
+            "Replacing document {msg_id} in {chat_id} with {file_name} ({} bytes)",
+            contents.len()
+        );
+        let document = types::InputFile::memory(contents).file_name(file_name);
+        let media = types::InputMedia::Document(types::InputMediaDocument::new(document));
+        self.0.edit_message_media(chat_id, msg_id, media).await?;
+        Ok(())
+    }
+
+    /// `None` when telegram didn't take the audio as a voice message
+    pub async fn send_voice(
+        &self,
+        chat_id: types::ChatId,
+        reply_to: types::MessageId,
+        topic: Option<i32>,
+        file_name: String,
+        contents: Vec<u8>,
+    ) -> HandlerResult<Option<(Message, types::Voice)>> {
+        log::debug!(
+            "Sending voice {file_name} ({} bytes) in reply to {reply_to}",
+            contents.len()
+        );
+        let voice = types::InputFile::memory(contents).file_name(file_name);
+        let mut pending_msg = self.0.send_voice(chat_id, voice);
+        let payload = pending_msg.payload_mut();
+        payload.reply_to_message_id = Some(reply_to);
+        payload.message_thread_id = topic;
+        let msg = pending_msg.await?;
+
+        Ok(msg
+            .voice()
+            .cloned()
+            .map(|voice| (Message::new(self.clone(), &msg), voice)))
+    }
+
+    /// Checks that the file exists along with how big it is without downloading it
+    pub async fn get_file_meta(&self, file_id: String) -> HandlerResult<types::FileMeta> {
+        match self.0.get_file(file_id).await {
+            Ok(file) => Ok(file.meta),
+            Err(RequestError::Api(ApiError::WrongFileId | ApiError::FileIdInvalid)) => {
+                Err(UserError::UnknownFileId.into())
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
     pub async fn download_file(&self, output_path: &Path, file_id: String) -> HandlerResult {
-        log::debug!("Downloading file {} to {}", file_id, output_path.display());
         let file_meta = self.0.get_file(file_id).await?;
+        log::debug!(
+            "Downloading file {} ({} bytes) to {}",
+            file_meta.meta.id,
+            file_meta.meta.size,
+            output_path.display()
+        );
+        // Trying anyways just fails with a vague error
+        if file_meta.meta.size > MAX_DOWNLOAD_BYTES {
+            return Err(UserError::FileTooLarge(file_meta.meta.size).into());
+        }
         let mut file = tokio::fs::File::create(output_path).await?;
         self.0.download_file(&file_meta.path, &mut file).await?;
+        // Tokio finishes writes in the background, so reading the file right after could come up
+        // short otherwise
+        file.flush().await?;
         Ok(())
     }
 
+    pub async fn is_chat_admin(
+        &self,
+        chat_id: types::ChatId,
+        user_id: types::UserId,
+    ) -> HandlerResult<bool> {
+        // Private chats don't have admins, but it's their chat
+        if chat_id.is_user() {
+            return Ok(true);
+        }
+
+        log::debug!("Checking if user {user_id} is an admin of chat {chat_id}");
+        let member = self.0.get_chat_member(chat_id, user_id).await?;
+        Ok(member.is_privileged())
+    }
+
+    pub async fn answer_callback_query<S: Into<String>>(
+        &self,
+        query_id: String,
+        text: S,
+    ) -> HandlerResult {
+        let text = text.into();
+        log::debug!("Answering callback query {query_id} with: {text}");
+        let mut pending_answer = self.0.answer_callback_query(query_id);
+        pending_answer.payload_mut().text = Some(text);
+        pending_answer.await?;
+        Ok(())
+    }
+
+    /// Replaces the bot's reaction on a message. `None` clears it
+    ///
+    /// Chats that don't allow the reaction are silently skipped since reactions are just a nicety
+    pub async fn set_message_reaction(
+        &self,
+        chat_id: types::ChatId,
+        msg_id: types::MessageId,
+        emoji: Option<&str>,
+    ) -> HandlerResult {
+        log::debug!("Setting reaction {emoji:?} on message {msg_id} in {chat_id}");
+        let payload = SetMessageReaction {
+            chat_id,
+            message_id: msg_id.0,
+            reaction: emoji
+                .map(|emoji| ReactionType {
+                    kind: "emoji",
+                    emoji: emoji.to_owned(),
+                })
+                .into_iter()
+                .collect(),
+        };
+        // teloxide doesn't know about reactions yet and its throttling adaptor can only queue the
+        // requests that it knows about, so reactions get held to the adaptor's limits by hand
+        let slot = reserve_reaction_slot(chat_id, Duration::from_secs(1) / self.1.max(1));
+        tokio::time::sleep_until(slot).await;
+        let request = || JsonRequest::new(self.0.inner().clone(), payload.clone());
+        let mut res = request().await;
+        // Same as the adaptor, a flood wait gets waited out and retried
+        if let Err(RequestError::RetryAfter(wait)) = res {
+            log::warn!("Holding off on reactions in {chat_id} for {wait:?}");
+            tokio::time::sleep(wait).await;
+            res = request().await;
+        }
+        match res {
+            Ok(types::True) => Ok(()),
+            Err(RequestError::Api(ApiError::Unknown(reason))) if reason.contains("REACTION") => {
+                log::debug!("Reactions aren't allowed in chat {chat_id}: {reason}");
+                Ok(())
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// A message that's already gone counts as deleted
+    pub async fn delete_message(
+        &self,
+        chat_id: types::ChatId,
+        msg_id: types::MessageId,
+    ) -> HandlerResult {
+        log::debug!("Deleting message {msg_id} in {chat_id}");
+        match self.0.delete_message(chat_id, msg_id).await {
+            Ok(_) => Ok(()),
+            Err(RequestError::Api(ApiError::MessageToDeleteNotFound)) => {
+                log::debug!("Message {msg_id} in {chat_id} was already deleted");
+                Ok(())
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
     pub async fn forward_message(
         &self,
         to_chat_id: types::ChatId,
@@ -104,6 +350,19 @@ impl Message {
     }
 
     pub async fn edit_text<S: Into<String>>(&self, text: S) -> HandlerResult {
+        self.edit_text_with_markup(text, None, None).await
+    }
+
+    /// Edits the text of the message
+    ///
+    /// NOTE: Telegram drops any existing inline keyboard that isn't passed along with the edit, and
+    /// the text has to already be escaped for the `parse_mode` when one is passed
+    pub async fn edit_text_with_markup<S: Into<String>>(
+        &self,
+        text: S,
+        markup: Option<types::InlineKeyboardMarkup>,
+        parse_mode: Option<types::ParseMode>,
+    ) -> HandlerResult {
         let text = text.into();
         log::debug!(
             "Editing message {} len {} snippet:\n{}",
@@ -115,17 +374,192 @@ impl Message {
                 text.to_owned()
             }
         );
-        self.bot
-            .edit_message_text(self.chat_id, self.msg_id, text)
-            .await?;
+        let mut pending_edit = self.bot.edit_message_text(self.chat_id, self.msg_id, text);
+        let payload = pending_edit.payload_mut();
+        payload.reply_markup = markup;
+        payload.parse_mode = parse_mode;
+        pending_edit.await?;
         Ok(())
     }
 
-    pub async fn reply<S: Into<String>>(&self, text: S) -> HandlerResult {
-        let bot_ext = Bot::from(self.bot.clone());
-        bot_ext
-            .send_message(self.chat_id, self.msg_id, text.into())
-            .await?;
-        Ok(())
+    pub async fn delete(&self) -> HandlerResult {
+        Bot::from(self.bot.clone())
+            .delete_message(self.chat_id, self.msg_id)
+            .await
+    }
+}
+
+/// The slice of the API that live-updating messages go through so that tests can swap in a fake
+pub trait Api: Clone + Send + Sync + 'static {
+    type Message: EditMessage;
+
+    fn send_message_with_markup(
+        &self,
+        chat_id: types::ChatId,
+        reply_to: types::MessageId,
+        topic: Option<i32>,
+        text: String,
+        markup: Option<types::InlineKeyboardMarkup>,
+        parse_mode: Option<types::ParseMode>,
+    ) -> impl Future<Output = HandlerResult<Self::Message>> + Send;
+
+    /// A handle to one of our messages that's already out there
+    fn existing_message(&self, chat_id: types::ChatId, msg_id: types::MessageId) -> Self::Message;
+}
+
+pub trait EditMessage: Send + Sync + 'static {
+    fn id(&self) -> types::MessageId;
+
+    fn edit_text_with_markup(
+        &self,
+        text: String,
+        markup: Option<types::InlineKeyboardMarkup>,
+        parse_mode: Option<types::ParseMode>,
+    ) -> impl Future<Output = HandlerResult> + Send;
+
+    fn delete(&self) -> impl Future<Output = HandlerResult> + Send;
+}
+
+impl Api for Bot {
+    type Message = Message;
+
+    fn send_message_with_markup(
+        &self,
+        chat_id: types::ChatId,
+        reply_to: types::MessageId,
+        topic: Option<i32>,
+        text: String,
+        markup: Option<types::InlineKeyboardMarkup>,
+        parse_mode: Option<types::ParseMode>,
+    ) -> impl Future<Output = HandlerResult<Self::Message>> + Send {
+        Bot::send_message_with_markup(self, chat_id, reply_to, topic, text, markup, parse_mode)
+    }
+
+    fn existing_message(&self, chat_id: types::ChatId, msg_id: types::MessageId) -> Self::Message {
+        Message {
+            bot: self.0.clone(),
+            msg_id,
+            chat_id,
+        }
+    }
+}
+
+impl EditMessage for Message {
+    fn id(&self) -> types::MessageId {
+        Message::id(self)
+    }
+
+    fn edit_text_with_markup(
+        &self,
+        text: String,
+        markup: Option<types::InlineKeyboardMarkup>,
+        parse_mode: Option<types::ParseMode>,
+    ) -> impl Future<Output = HandlerResult> + Send {
+        Message::edit_text_with_markup(self, text, markup, parse_mode)
+    }
+
+    fn delete(&self) -> impl Future<Output = HandlerResult> + Send {
+        Message::delete(self)
+    }
+}
+
+/// Spaces each chat's reactions out by `gap` and hands back when this one can go out
+fn reserve_reaction_slot(chat_id: types::ChatId, gap: Duration) -> Instant {
+    let now = Instant::now();
+    let mut next = NEXT_REACTION.lock().unwrap();
+    next.retain(|_, at| *at > now);
+    let slot = next.get(&chat_id).copied().unwrap_or(now);
+    next.insert(chat_id, slot + gap);
+    slot
+}
+
+/// `setMessageReaction` which isn't supported by our version of teloxide
+#[derive(Clone, Serialize)]
+struct SetMessageReaction {
+    chat_id: types::ChatId,
+    message_id: i32,
+    reaction: Vec<ReactionType>,
+}
+
+#[derive(Clone, Serialize)]
+struct ReactionType {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    emoji: String,
+}
+
+impl Payload for SetMessageReaction {
+    type Output = types::True;
+
+    const NAME: &'static str = "SetMessageReaction";
+}
+
+#[cfg(feature = "telegram-webhook")]
+fn webhook_options_from_env() -> Option<webhooks::Options> {
+    let listen = std::env::var("RAMBOT_TELEGRAM_WEBHOOK_LISTEN").ok();
+    let url = std::env::var("RAMBOT_TELEGRAM_WEBHOOK_URL").ok();
+    let (listen, url) = match (listen, url) {
+        (Some(listen), Some(url)) => (listen, url),
+        (None, None) => return None,
+        _ => {
+            log::warn!(
+                "Both RAMBOT_TELEGRAM_WEBHOOK_LISTEN and RAMBOT_TELEGRAM_WEBHOOK_URL need to \
+                be set for the webhook. Long polling instead"
+            );
+            return None;
+        }
+    };
+    let listen = match listen.parse() {
+        Ok(listen) => listen,
+        Err(e) => {
+            log::warn!("Ignoring invalid RAMBOT_TELEGRAM_WEBHOOK_LISTEN {listen:?}: {e}");
+            return None;
+        }
+    };
+    let url = match reqwest::Url::parse(&url) {
+        Ok(url) => url,
+        Err(e) => {
+            log::warn!("Ignoring invalid RAMBOT_TELEGRAM_WEBHOOK_URL: {e}");
+            return None;
+        }
+    };
+
+    Some(webhooks::Options::new(listen, url))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn supergroup_msg(is_forum: bool) -> types::Message {
+        serde_json::from_value(serde_json::json!({
+            "message_id": 5,
+            "message_thread_id": 4,
+            "is_topic_message": is_forum,
+            "date": 1_700_000_000,
+            "chat": { "id": -100, "type": "supergroup", "title": "Group", "is_forum": is_forum },
+            "from": { "id": 42, "is_bot": false, "first_name": "Author" },
+            "text": "hi",
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn reactions_get_spaced_out_per_chat() {
+        let gap = Duration::from_secs(60);
+        let (chat, other_chat) = (types::ChatId(-1_001), types::ChatId(-1_002));
+        let first = reserve_reaction_slot(chat, gap);
+        let second = reserve_reaction_slot(chat, gap);
+        assert_eq!(second - first, gap);
+        // Other chats don't wait on it
+        let other = reserve_reaction_slot(other_chat, gap);
+        assert!(other < second);
+    }
+
+    #[test]
+    fn only_forum_topics_get_sent_to() {
+        assert_eq!(topic_of(&supergroup_msg(true)), Some(4));
+        // A reply chain in a plain supergroup
+        assert_eq!(topic_of(&supergroup_msg(false)), None);
     }
 }
@@ -0,0 +1,179 @@
+//! The step that actually turns audio into text
+//!
+//! Everything around it is shared between backends. Downloading, decoding, trimming silence,
+//! timing out, and streaming lines back to the message all happen the same way no matter which
+//! [`Backend`] ends up doing the transcribing
+
+use std::{future::Future, pin::Pin, sync::Arc};
+
+use super::state_machine::Update;
+use crate::{cancel::JobId, db::ModelSize, HandlerError, HandlerResult};
+
+use tokio::sync::{mpsc, watch};
+
+pub type BackendFut<'a> = Pin<Box<dyn Future<Output = HandlerResult> + Send + 'a>>;
+
+/// Transcribes a job's audio while streaming updates back as it goes
+///
+/// Lines should be sent as soon as they're ready. The end of the transcription gets signalled by
+/// the returned future resolving. Backends should also keep an eye on the job's [`Abort`] and wrap
+/// up early once it's set
+pub trait Backend: Send + Sync {
+    fn transcribe(&self, job: Job) -> BackendFut<'_>;
+
+    /// A short human-readable summary of what's doing the transcribing
+    fn describe(&self) -> String;
+
+    /// Errors when jobs can't be transcribed with `model`, so that it gets caught before a voice
+    /// message needs it
+    fn check_model(&self, model: ModelSize) -> HandlerResult;
+}
+
+pub struct Job {
+    /// Shows up in all of the job's logs to tie them together
+    pub job_id: JobId,
+    /// 16kHz mono samples
+    pub audio: Vec<f32>,
+    /// Where `audio` starts in the original audio after trimming off any leading silence
+    pub offset_centis: i64,
+    pub settings: Settings,
+    pub updates: mpsc::Sender<HandlerResult<Update>>,
+    pub abort: Abort,
+}
+
+/// Gets set once no one wants the job's result anymore, either because it got cancelled or it ran
+/// out of time
+///
+/// Dropping the backend's future isn't enough on its own since whisper runs on a blocking thread
+/// that would keep going in the background
+#[derive(Clone)]
+pub struct Abort(Arc<watch::Sender<bool>>);
+
+impl Default for Abort {
+    fn default() -> Self {
+        Self(Arc::new(watch::channel(false).0))
+    }
+}
+
+impl Abort {
+    pub fn abort(&self) {
+        self.0.send_replace(true);
+    }
+
+    pub fn is_aborted(&self) -> bool {
+        *self.0.borrow()
+    }
+
+    /// An error to bail with once the job got aborted
+    pub fn check(&self) -> HandlerResult {
+        if self.is_aborted() {
+            Err(HandlerError::Cancelled)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Resolves once the job gets aborted
+    pub async fn aborted(&self) {
+        let mut rx = self.0.subscribe();
+        // The sender lives as long as `self`, so this can only resolve by getting aborted
+        let _ = rx.wait_for(|&aborted| aborted).await;
+    }
+}
+
+/// How the requester wants their audio transcribed
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Settings {
+    /// Translate the speech to English instead of transcribing it as-is
+    pub translate: bool,
+    pub model: ModelSize,
+    /// Primes the transcription with names and jargon so that they get spelled right
+    pub prompt: Option<String>,
+    /// Only the start of the audio up to this many seconds gets transcribed when set
+    pub max_secs: Option<u32>,
+    /// Decode greedily even when beam search is configured
+    pub greedy: bool,
+}
+
+#[cfg(test)]
+pub use mock::{noticed_abort, MockBackend};
+
+#[cfg(test)]
+mod mock {
+    use super::*;
+    use crate::utils::SegmentCallbackData;
+
+    use std::{
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Mutex,
+        },
+        time::Duration,
+    };
+
+    use tokio::time;
+
+    /// Replays canned segments after a delay instead of running a model
+    #[derive(Default)]
+    pub struct MockBackend {
+        pub delay: Duration,
+        pub segments: Vec<(i64, &'static str)>,
+        /// The settings of the last job it was handed
+        pub seen: Arc<Mutex<Option<Settings>>>,
+        /// Set once it noticed that its job got aborted
+        pub aborted: Arc<AtomicBool>,
+    }
+
+    /// Whether a [`MockBackend`] noticed its job getting aborted within a second. It keeps watch
+    /// from its own task, so it can take a moment to catch up
+    pub async fn noticed_abort(aborted: &AtomicBool) -> bool {
+        let noticed = async {
+            while !aborted.load(Ordering::SeqCst) {
+                time::sleep(Duration::from_millis(5)).await;
+            }
+        };
+        time::timeout(Duration::from_secs(1), noticed).await.is_ok()
+    }
+
+    impl Backend for MockBackend {
+        fn describe(&self) -> String {
+            "mock".to_owned()
+        }
+
+        fn check_model(&self, _: ModelSize) -> HandlerResult {
+            Ok(())
+        }
+
+        fn transcribe(&self, job: Job) -> BackendFut<'_> {
+            Box::pin(async move {
+                *self.seen.lock().unwrap() = Some(job.settings.clone());
+                // Keeps an eye out from outside of this future like whisper's blocking thread
+                // would
+                let (abort, aborted) = (job.abort.clone(), Arc::clone(&self.aborted));
+                tokio::spawn(async move {
+                    abort.aborted().await;
+                    aborted.store(true, Ordering::SeqCst);
+                });
+                tokio::select! {
+                    () = time::sleep(self.delay) => {}
+                    () = job.abort.aborted() => return Err(HandlerError::Cancelled),
+                }
+                for &(start_centis, text) in &self.segments {
+                    let segment = SegmentCallbackData {
+                        start_timestamp: start_centis + job.offset_centis,
+                        end_timestamp: start_centis + job.offset_centis + 100,
+                        text: text.to_owned(),
+                        confidence: 1.0,
+                        language: None,
+                        words: None,
+                    };
+                    job.updates
+                        .send(Ok(segment.into()))
+                        .await
+                        .map_err(HandlerError::worker_died)?;
+                }
+                Ok(())
+            })
+        }
+    }
+}
@@ -1,70 +1,966 @@
+//! A pool of transcription workers fed by a prefetching download stage
+//!
+//! Downloading and converting the audio happens in its own task ahead of the workers. That way
+//! a queued job's download and ffmpeg conversion overlap with the job that's still being
+//! transcribed, and the worker can start on the next job as soon as it frees up instead of
+//! sitting idle while fetching audio. The time spent preparing each job gets logged so the
+//! overlap can be checked against what the workers spend transcribing
+//!
+//! The transcribing itself is handed off to a [`Backend`]. Jobs run through the local whisper
+//! models unless `RAMBOT_TRANSCRIPTION_API_URL` points at a remote API to use instead
+
+mod backend;
+mod remote;
+mod shared;
 mod state_machine;
-pub use state_machine::DownloadStarted;
-use state_machine::{JobFut, JobMeta};
+pub mod vad;
+mod whisper;
+use backend::Backend;
+pub use backend::Settings;
+#[cfg(test)]
+pub use backend::{noticed_abort, MockBackend};
+use remote::Remote;
+pub use state_machine::{ffmpeg_version, probe_duration, DetectedLanguage, DownloadStarted};
+use state_machine::{DownloadDied, DownloadingFut, JobFut, JobMeta};
+use whisper::Whisper;
+
+use std::{
+    collections::HashMap,
+    fmt,
+    future::Future,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+#[cfg(feature = "metrics")]
+use crate::metrics;
+use crate::{
+    cancel::JobId, db::ModelSize, telegram::Bot, utils, HandlerError, HandlerResult, InitError,
+    InitResult, UserError,
+};
+
+use tokio::{
+    sync::{oneshot, watch, Mutex},
+    task::JoinSet,
+    time,
+};
+
+/// Past this many waiting jobs new ones get turned away instead of piling up
+const MAX_QUEUED_JOBS: usize = 32;
+const DEFAULT_TIMEOUT_FACTOR: u32 = 10;
+/// Matches the cutoff that whisper itself uses for skipping silent windows
+const DEFAULT_NO_SPEECH_THRESHOLD: f32 = 0.6;
+/// whisper.cpp's own default. Past this there are diminishing returns, and each worker gets its
+/// own set of threads
+const MAX_DEFAULT_THREADS: u16 = 4;
+/// Whisper's own default
+const DEFAULT_LANGUAGE: &str = "en";
+const DEFAULT_FAST_MODE_MODEL: ModelSize = ModelSize::Tiny;
+const DEFAULT_BREAKER_FAILURES: u32 = 5;
+const DEFAULT_BREAKER_PAUSE: Duration = Duration::from_secs(60);
+
+/// Which language the speech gets transcribed as
+#[derive(Clone, Copy, Debug)]
+pub enum Language {
+    /// Let whisper figure it out from the start of the audio
+    Detect,
+    /// Detect it again for every window of audio so that speakers can switch between languages.
+    /// Each line gets tagged with its language. This is slower and windows get cut at fixed
+    /// points, so words that straddle a boundary can get mangled
+    Mixed,
+    /// A whisper language code like `en` or `es`
+    Fixed(&'static str),
+}
+
+/// Matches what `RAMBOT_LANGUAGE` accepts
+impl fmt::Display for Language {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Detect => f.write_str("auto"),
+            Self::Mixed => f.write_str("mixed"),
+            Self::Fixed(lang) => f.write_str(lang),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Config {
+    pub vad: vad::Config,
+    /// Jobs get abandoned after running for this many times the voice message's duration
+    pub timeout_factor: u32,
+    /// Jobs bail before the full transcription when the odds of there being no speech are higher
+    /// than this
+    pub no_speech_threshold: f32,
+    /// CPU threads each transcription gets to use
+    pub threads: u16,
+    /// Beam search tracks this many candidate transcriptions at once which is more accurate, but
+    /// roughly that many times slower than the default greedy decoding. `None` decodes greedily
+    pub beam_size: Option<u16>,
+    pub language: Language,
+    /// Captures when each word was said at the cost of a slower transcription
+    pub word_timestamps: bool,
+    /// `None` transcribes everything the same no matter how long it is
+    pub fast_mode: Option<FastMode>,
+    /// `None` lets workers keep failing jobs for as long as they keep coming
+    pub circuit_breaker: Option<CircuitBreaker>,
+}
+
+/// Pauses a worker (or the downloader) once its jobs keep failing back to back, so that something that's broken for
+/// every job (like a corrupt model) doesn't burn through the whole queue failing everything
+#[derive(Clone, Copy, Debug)]
+pub struct CircuitBreaker {
+    pub after_failures: u32,
+    pub pause: Duration,
+}
+
+impl CircuitBreaker {
+    /// Set up with `RAMBOT_BREAKER_FAILURES` (0 for off) and `RAMBOT_BREAKER_PAUSE_SECS`
+    fn from_env() -> Option<Self> {
+        let after_failures = match std::env::var("RAMBOT_BREAKER_FAILURES") {
+            Ok(failures) => match failures.parse() {
+                Ok(0) => return None,
+                Ok(failures) => failures,
+                Err(e) => {
+                    log::warn!("Ignoring invalid RAMBOT_BREAKER_FAILURES {failures:?}: {e}");
+                    DEFAULT_BREAKER_FAILURES
+                }
+            },
+            Err(_) => DEFAULT_BREAKER_FAILURES,
+        };
+        let pause = match std::env::var("RAMBOT_BREAKER_PAUSE_SECS") {
+            Ok(secs) => match secs.parse() {
+                Ok(secs) if secs > 0 => Duration::from_secs(secs),
+                _ => {
+                    log::warn!("Ignoring invalid RAMBOT_BREAKER_PAUSE_SECS {secs:?}");
+                    DEFAULT_BREAKER_PAUSE
+                }
+            },
+            Err(_) => DEFAULT_BREAKER_PAUSE,
+        };
+
+        Some(Self {
+            after_failures,
+            pause,
+        })
+    }
+}
 
-use crate::telegram::Bot;
+/// Tracks a single worker's (or the downloader's) failures in a row
+struct FailureStreak {
+    breaker: Option<CircuitBreaker>,
+    failures: u32,
+}
+
+impl FailureStreak {
+    fn new(breaker: Option<CircuitBreaker>) -> Self {
+        Self {
+            breaker,
+            failures: 0,
+        }
+    }
+
+    /// How long the worker should pause for once the breaker trips
+    fn record(&mut self, succeeded: bool) -> Option<Duration> {
+        if succeeded {
+            self.failures = 0;
+            return None;
+        }
+        self.failures += 1;
+        let breaker = self.breaker?;
+        (self.failures >= breaker.after_failures).then(|| {
+            self.failures = 0;
+            breaker.pause
+        })
+    }
+}
+
+/// Trades accuracy for speed on long voice messages so that they don't tie up a worker for ages
+#[derive(Clone, Copy, Debug)]
+pub struct FastMode {
+    /// Voice messages longer than this get the fast treatment
+    pub after_secs: u32,
+    /// Used in place of the requester's model
+    pub model: ModelSize,
+}
+
+impl FastMode {
+    /// Opted into with `RAMBOT_FAST_MODE_AFTER_SECS` (0 for off) and `RAMBOT_FAST_MODE_MODEL`
+    /// picks the model, falling back to the tiny one
+    fn from_env() -> Option<Self> {
+        let after_secs = std::env::var("RAMBOT_FAST_MODE_AFTER_SECS").ok()?;
+        let after_secs = match after_secs.parse() {
+            Ok(0) => return None,
+            Ok(secs) => secs,
+            Err(e) => {
+                log::warn!("Ignoring invalid RAMBOT_FAST_MODE_AFTER_SECS {after_secs:?}: {e}");
+                return None;
+            }
+        };
+        let model = match std::env::var("RAMBOT_FAST_MODE_MODEL") {
+            Ok(model) => match model.parse() {
+                Ok(model) => model,
+                Err(e) => {
+                    log::warn!("Ignoring invalid RAMBOT_FAST_MODE_MODEL {model:?}: {e}");
+                    DEFAULT_FAST_MODE_MODEL
+                }
+            },
+            Err(_) => DEFAULT_FAST_MODE_MODEL,
+        };
+
+        Some(Self { after_secs, model })
+    }
+
+    pub fn applies_to(self, duration_secs: u32) -> bool {
+        duration_secs > self.after_secs
+    }
+
+    /// Swaps in the faster model and skips beam search
+    pub fn speed_up(self, settings: &mut Settings) {
+        settings.model = self.model;
+        settings.greedy = true;
+    }
+}
 
-use tokio::{sync::oneshot, task::JoinSet};
+impl Config {
+    pub fn from_env() -> Self {
+        let timeout_factor = match std::env::var("RAMBOT_TIMEOUT_FACTOR") {
+            Ok(factor) => match factor.parse() {
+                Ok(factor) if factor > 0 => factor,
+                _ => {
+                    log::warn!("Ignoring invalid RAMBOT_TIMEOUT_FACTOR {factor:?}");
+                    DEFAULT_TIMEOUT_FACTOR
+                }
+            },
+            Err(_) => DEFAULT_TIMEOUT_FACTOR,
+        };
+        let no_speech_threshold = match std::env::var("RAMBOT_NO_SPEECH_THRESHOLD") {
+            Ok(threshold) => match threshold.parse() {
+                Ok(threshold) if (0.0..=1.0).contains(&threshold) => threshold,
+                _ => {
+                    log::warn!(
+                        "Ignoring invalid RAMBOT_NO_SPEECH_THRESHOLD {threshold:?}. Expected 0.0-1.0"
+                    );
+                    DEFAULT_NO_SPEECH_THRESHOLD
+                }
+            },
+            Err(_) => DEFAULT_NO_SPEECH_THRESHOLD,
+        };
+        let default_threads = std::thread::available_parallelism().map_or(1, |n| {
+            u16::try_from(n.get()).map_or(MAX_DEFAULT_THREADS, |n| n.min(MAX_DEFAULT_THREADS))
+        });
+        let threads = match std::env::var("RAMBOT_WHISPER_THREADS") {
+            Ok(threads) => match threads.parse() {
+                Ok(threads) if threads > 0 => threads,
+                _ => {
+                    log::warn!("Ignoring invalid RAMBOT_WHISPER_THREADS {threads:?}");
+                    default_threads
+                }
+            },
+            Err(_) => default_threads,
+        };
+        // 0 is the same as leaving it unset
+        let beam_size = match std::env::var("RAMBOT_BEAM_SIZE") {
+            Ok(size) => match size.parse() {
+                Ok(0) => None,
+                Ok(size) => Some(size),
+                Err(e) => {
+                    log::warn!("Ignoring invalid RAMBOT_BEAM_SIZE {size:?}: {e}");
+                    None
+                }
+            },
+            Err(_) => None,
+        };
+
+        let word_timestamps = match std::env::var("RAMBOT_WORD_TIMESTAMPS") {
+            Ok(enabled) => enabled.parse().unwrap_or_else(|_| {
+                log::warn!(
+                    "Ignoring invalid RAMBOT_WORD_TIMESTAMPS {enabled:?}. Expected true/false"
+                );
+                false
+            }),
+            Err(_) => false,
+        };
+
+        let language = match std::env::var("RAMBOT_LANGUAGE") {
+            Ok(lang) if lang == "auto" => Language::Detect,
+            Ok(lang) if lang == "mixed" => Language::Mixed,
+            Ok(lang) => match whisper_rs::get_lang_id(&lang).and_then(whisper_rs::get_lang_str) {
+                Some(lang) => Language::Fixed(lang),
+                None => {
+                    log::warn!(
+                        "Ignoring invalid RAMBOT_LANGUAGE {lang:?}. Expected a whisper language \
+                        code, auto, or mixed"
+                    );
+                    Language::Fixed(DEFAULT_LANGUAGE)
+                }
+            },
+            Err(_) => Language::Fixed(DEFAULT_LANGUAGE),
+        };
+
+        Self {
+            vad: vad::Config::from_env(),
+            timeout_factor,
+            no_speech_threshold,
+            threads,
+            beam_size,
+            language,
+            word_timestamps,
+            fast_mode: FastMode::from_env(),
+            circuit_breaker: CircuitBreaker::from_env(),
+        }
+    }
+}
+
+/// Every job's audio gets converted with ffmpeg no matter the backend, so catch it missing now
+/// instead of on the first voice message. Only a warning unless `RAMBOT_REQUIRE_FFMPEG=true`
+async fn check_ffmpeg() -> InitResult {
+    let required = match std::env::var("RAMBOT_REQUIRE_FFMPEG") {
+        Ok(required) => required.parse().unwrap_or_else(|_| {
+            log::warn!("Ignoring invalid RAMBOT_REQUIRE_FFMPEG {required:?}. Expected true/false");
+            false
+        }),
+        Err(_) => false,
+    };
+    match ffmpeg_version().await {
+        Some(version) => log::info!("Found {version}"),
+        None if required => return Err(InitError::FfmpegMissing),
+        None => log::warn!(
+            "Couldn't run ffmpeg. Voice messages will fail to transcribe until it's installed and \
+            on the PATH"
+        ),
+    }
+    Ok(())
+}
+
+fn expected_model_path(model: ModelSize) -> Option<PathBuf> {
+    utils::data_dir().map(|dir| dir.join(model.file_name()))
+}
+
+/// Catches the models that jobs need missing at startup instead of on the first voice message
+fn check_models(data_dir: &Path, config: Config) -> InitResult {
+    // Every job falls back to the default model, and long voice messages get switched over to the
+    // fast model
+    let fast_model = config.fast_mode.map(|fast_mode| fast_mode.model);
+    for model in std::iter::once(ModelSize::Default).chain(fast_model) {
+        let path = data_dir.join(model.file_name());
+        if !path.is_file() {
+            return Err(InitError::ModelMissing(path));
+        }
+    }
+    Ok(())
+}
+
+/// Where the model lives on disk, erroring when it hasn't been installed
+pub fn model_path(model: ModelSize) -> HandlerResult<PathBuf> {
+    let path = expected_model_path(model).ok_or(HandlerError::UnknownDataDir)?;
+    if path.is_file() {
+        Ok(path)
+    } else {
+        Err(UserError::ModelNotInstalled(model).into())
+    }
+}
 
 #[derive(Clone)]
-pub struct Pool(async_channel::Sender<JobFut>);
+pub struct Pool {
+    job_tx: async_channel::Sender<JobFut>,
+    // Only held onto so that queued jobs can be drained on shutdown
+    job_rx: async_channel::Receiver<JobFut>,
+    ready_rx: async_channel::Receiver<DownloadingFut>,
+    workers: Arc<Mutex<JoinSet<()>>>,
+    num_workers: u8,
+    num_busy: Arc<AtomicUsize>,
+    /// The downloader plus the workers that haven't exited or panicked
+    num_alive: Arc<AtomicUsize>,
+    lifecycle: Arc<watch::Sender<Lifecycle>>,
+    busy_until: BusyUntil,
+    backend_desc: Arc<str>,
+    backend: Arc<dyn Backend>,
+    config: Config,
+    in_flight: shared::InFlight,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Stats {
+    pub busy_workers: usize,
+    pub idle_workers: usize,
+    pub queued_jobs: usize,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Lifecycle {
+    Running,
+    /// No new jobs are accepted, but in-flight jobs are given a chance to finish
+    Draining,
+    Stopped,
+}
 
 impl Pool {
-    pub async fn spawn(num_workers: u8) -> Self {
+    pub async fn spawn(num_workers: u8, config: Config) -> InitResult<Self> {
+        check_ffmpeg().await?;
+        let backend: Arc<dyn Backend> = match Remote::from_env(config)? {
+            Some(remote) => {
+                log::info!("Transcribing with the remote API");
+                // Long voice messages get switched over to the fast model, so it needs a name too
+                if let Some(fast_mode) = config.fast_mode {
+                    if remote.check_model(fast_mode.model).is_err() {
+                        let var = remote::model_var(fast_mode.model);
+                        return Err(InitError::RemoteModelUnset(fast_mode.model, var));
+                    }
+                }
+                Arc::new(remote)
+            }
+            None => {
+                let data_dir = utils::data_dir().ok_or(InitError::UnknownDataDir)?;
+                check_models(&data_dir, config)?;
+                Arc::new(Whisper::new(config))
+            }
+        };
+        Ok(Self::with_backend(num_workers, config, backend))
+    }
+
+    /// A single worker that transcribes with a mock backend instead of an actual model
+    #[cfg(test)]
+    pub fn with_mock(backend: MockBackend) -> Self {
+        Self::with_backend(1, Config::from_env(), Arc::new(backend))
+    }
+
+    fn with_backend(num_workers: u8, config: Config, backend: Arc<dyn Backend>) -> Self {
         // TODO: switch this to NonZeroU8?
         assert!(num_workers != 0);
+
+        let backend_desc = backend.describe().into();
         let mut transcribers = JoinSet::new();
-        let (tx_workers, rx_workers) = async_channel::bounded(32);
+        let (job_tx, job_rx) = async_channel::bounded(MAX_QUEUED_JOBS);
+        // Only prefetch a little ahead of the workers to avoid piling up decoded audio in memory
+        let (ready_tx, ready_rx) = async_channel::bounded(num_workers.into());
+        let (lifecycle, _) = watch::channel(Lifecycle::Running);
+        let num_busy = Arc::new(AtomicUsize::new(0));
+        let num_alive = Arc::new(AtomicUsize::new(0));
+        let busy_until = BusyUntil::default();
+        transcribers.spawn(AliveGuard::wrap(
+            &num_alive,
+            run_downloader(
+                job_rx.clone(),
+                ready_tx,
+                lifecycle.subscribe(),
+                config.circuit_breaker,
+            ),
+        ));
         for i in 0..num_workers {
-            transcribers.spawn(run_worker(rx_workers.clone(), i));
+            transcribers.spawn(AliveGuard::wrap(
+                &num_alive,
+                run_worker(
+                    ready_rx.clone(),
+                    lifecycle.subscribe(),
+                    Arc::clone(&num_busy),
+                    busy_until.clone(),
+                    config,
+                    Arc::clone(&backend),
+                    i,
+                ),
+            ));
+        }
+
+        Self {
+            job_tx,
+            job_rx,
+            ready_rx,
+            workers: Arc::new(Mutex::new(transcribers)),
+            num_workers,
+            num_busy,
+            num_alive,
+            lifecycle: Arc::new(lifecycle),
+            busy_until,
+            backend_desc,
+            backend,
+            in_flight: Default::default(),
+            config,
         }
+    }
+
+    /// What the workers transcribe with
+    pub fn backend(&self) -> &str {
+        &self.backend_desc
+    }
+
+    /// Errors when the backend can't transcribe with `model`
+    pub fn check_model(&self, model: ModelSize) -> HandlerResult {
+        self.backend.check_model(model)
+    }
+
+    pub fn num_workers(&self) -> u8 {
+        self.num_workers
+    }
+
+    pub fn config(&self) -> Config {
+        self.config
+    }
+
+    /// Running with the downloader and every worker still around, none of them stuck on a job
+    pub fn is_healthy(&self) -> bool {
+        !self.is_shutting_down()
+            && self.num_alive.load(Ordering::Relaxed) == usize::from(self.num_workers) + 1
+            && self.stuck_workers() == 0
+    }
 
-        // NOTE: Keep all the transcribers running in the background
-        transcribers.detach_all();
+    /// Workers that have been on the same job for longer than it could possibly take
+    pub fn stuck_workers(&self) -> usize {
+        let now = Instant::now();
+        let busy_until = self.busy_until.lock().unwrap();
+        busy_until.values().filter(|&&until| until < now).count()
+    }
 
-        Self(tx_workers)
+    pub fn stats(&self) -> Stats {
+        let busy_workers = self.num_busy.load(Ordering::Relaxed);
+        Stats {
+            busy_workers,
+            idle_workers: usize::from(self.num_workers).saturating_sub(busy_workers),
+            queued_jobs: self.job_tx.len() + self.ready_rx.len(),
+        }
     }
 
-    #[must_use]
-    pub async fn submit_job(
+    /// Never waits on a full queue so that a transcription backlog can't hold up the handler
+    ///
+    /// Identical jobs that are already in flight get joined instead of running all over again
+    pub fn submit_job(
         &self,
+        job_id: JobId,
         bot: Bot,
         voice_file_id: String,
         voice_msg_duration_secs: u32,
-    ) -> oneshot::Receiver<DownloadStarted> {
+        settings: Settings,
+    ) -> HandlerResult<oneshot::Receiver<DownloadStarted>> {
+        let key = shared::Key {
+            voice_file_id: voice_file_id.clone(),
+            settings: settings.clone(),
+        };
+        self.in_flight.join_or_start(key, job_id, || {
+            self.start_job(
+                job_id,
+                bot,
+                voice_file_id,
+                voice_msg_duration_secs,
+                settings,
+            )
+        })
+    }
+
+    fn start_job(
+        &self,
+        job_id: JobId,
+        bot: Bot,
+        voice_file_id: String,
+        voice_msg_duration_secs: u32,
+        settings: Settings,
+    ) -> HandlerResult<oneshot::Receiver<DownloadStarted>> {
         let (msg_handle, job_handle) = oneshot::channel();
-        log::info!("Starting transcribe task for {voice_file_id}");
-        let _ = self
-            .0
-            .send(JobFut {
-                next: msg_handle,
-                meta: JobMeta {
-                    bot,
-                    voice_file_id,
-                    voice_msg_duration_secs,
-                },
-            })
+        let log_prefix = job_id.log_prefix();
+        log::info!("{log_prefix} Starting transcribe task for {voice_file_id}");
+        let sent = self.job_tx.try_send(JobFut {
+            next: msg_handle,
+            meta: JobMeta {
+                job_id,
+                bot,
+                voice_file_id,
+                voice_msg_duration_secs,
+                settings,
+            },
+        });
+        match sent {
+            Ok(()) => {
+                #[cfg(feature = "metrics")]
+                metrics::JOBS_SUBMITTED.inc();
+                Ok(job_handle)
+            }
+            Err(async_channel::TrySendError::Full(_)) => {
+                #[cfg(feature = "metrics")]
+                metrics::JOBS_REJECTED.inc();
+                log::warn!("{log_prefix} Rejecting job since the queue is full");
+                Err(UserError::QueueFull.into())
+            }
+            // The pool's shutting down and the job would never get picked up
+            Err(async_channel::TrySendError::Closed(_)) => Err(HandlerError::WorkerDied),
+        }
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        *self.lifecycle.borrow() != Lifecycle::Running
+    }
+
+    /// Resolves once the pool has fully stopped and no more work will be completed
+    pub async fn stopped(&self) {
+        let mut lifecycle = self.lifecycle.subscribe();
+        let _ = lifecycle
+            .wait_for(|state| *state == Lifecycle::Stopped)
             .await;
+    }
 
-        job_handle
+    /// Stops accepting new jobs and waits up to `grace` for the in-flight ones to finish
+    ///
+    /// Queued jobs that never got picked up are dropped which lets their handlers know that they
+    /// won't be finished
+    pub async fn shutdown(&self, grace: Duration) {
+        log::info!("Shutting down the transcriber pool. Waiting up to {grace:?} for running jobs");
+        self.job_tx.close();
+        self.ready_rx.close();
+        self.lifecycle.send_replace(Lifecycle::Draining);
+        while let Ok(job) = self.job_rx.try_recv() {
+            log::info!("{} Dropping queued job", job.meta.job_id.log_prefix());
+        }
+        while let Ok(job) = self.ready_rx.try_recv() {
+            log::info!("{} Dropping prefetched job", job.meta.job_id.log_prefix());
+        }
+
+        let mut workers = self.workers.lock().await;
+        let drain_workers = async { while workers.join_next().await.is_some() {} };
+        if time::timeout(grace, drain_workers).await.is_err() {
+            log::warn!("Transcription workers didn't finish in time. Abandoning them");
+            workers.abort_all();
+        }
+
+        self.lifecycle.send_replace(Lifecycle::Stopped);
+    }
+}
+
+/// Counts a task as alive until it's done one way or another, which includes panicking
+struct AliveGuard(Arc<AtomicUsize>);
+
+impl AliveGuard {
+    fn wrap<F: Future<Output = ()>>(
+        num_alive: &Arc<AtomicUsize>,
+        task: F,
+    ) -> impl Future<Output = ()> {
+        num_alive.fetch_add(1, Ordering::Relaxed);
+        let guard = Self(Arc::clone(num_alive));
+        async move {
+            let _guard = guard;
+            task.await;
+        }
     }
 }
 
+impl Drop for AliveGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+async fn run_downloader(
+    rx: async_channel::Receiver<JobFut>,
+    ready_tx: async_channel::Sender<DownloadingFut>,
+    mut lifecycle: watch::Receiver<Lifecycle>,
+    breaker: Option<CircuitBreaker>,
+) {
+    let mut streak = FailureStreak::new(breaker);
+    loop {
+        let job = tokio::select! {
+            biased;
+            _ = lifecycle.wait_for(|state| *state != Lifecycle::Running) => break,
+            maybe_job = rx.recv() => match maybe_job {
+                Ok(job) => job,
+                Err(_) => break,
+            },
+        };
+
+        let job_id = job.meta.job_id;
+        let log_prefix = job_id.log_prefix();
+        let start = Instant::now();
+        let downloaded = match prefetch_audio(job).await {
+            Ok(downloaded) => downloaded,
+            Err(died) => {
+                log::warn!("{log_prefix} Downloading died. Oh well");
+                // Only failures that are on us say anything about downloading being broken
+                if let Some(pause) = streak.record(died != DownloadDied::Broken) {
+                    log::error!(
+                        "The downloader failed {} jobs in a row. Pausing it for {pause:?} since \
+                        something is likely broken for every job (ffmpeg missing, telegram being \
+                        unreachable, etc.)",
+                        breaker.map_or(0, |breaker| breaker.after_failures)
+                    );
+                    tokio::select! {
+                        biased;
+                        _ = lifecycle.wait_for(|state| *state != Lifecycle::Running) => break,
+                        _ = time::sleep(pause) => log::info!("The downloader is taking jobs again"),
+                    }
+                }
+                continue;
+            }
+        };
+        streak.record(true);
+        log::info!("{log_prefix} Audio ready in {:?}", start.elapsed());
+
+        // Waits for a free spot when the workers are all busy which is what keeps us from running
+        // too far ahead
+        if ready_tx.send(downloaded).await.is_err() {
+            log::info!("{log_prefix} Dropping prefetched job since the workers are gone");
+        }
+    }
+
+    log::info!("Downloader shut down");
+}
+
+async fn prefetch_audio(job: JobFut) -> Result<DownloadingFut, DownloadDied> {
+    let started = job.start_download().ok_or(DownloadDied::Abandoned)?;
+    started.finish_download().await
+}
+
+/// When each busy worker's job should be done by at the latest, keyed by the worker's id
+type BusyUntil = Arc<std::sync::Mutex<HashMap<u8, Instant>>>;
+
 // TODO: keep the model around and use a timeout
-async fn run_worker(rx: async_channel::Receiver<JobFut>, id: u8) {
-    while let Ok(job) = rx.recv().await {
-        log::info!("Worker {} got work {}", id, job.meta.voice_file_id);
-        if run_transcription_process(job).await.is_none() {
-            log::warn!("Transcription job died. Oh well");
+async fn run_worker(
+    rx: async_channel::Receiver<DownloadingFut>,
+    mut lifecycle: watch::Receiver<Lifecycle>,
+    num_busy: Arc<AtomicUsize>,
+    busy_until: BusyUntil,
+    config: Config,
+    backend: Arc<dyn Backend>,
+    id: u8,
+) {
+    let mut streak = FailureStreak::new(config.circuit_breaker);
+    loop {
+        let job = tokio::select! {
+            // Prefer noticing a shutdown over picking up more work
+            biased;
+            _ = lifecycle.wait_for(|state| *state != Lifecycle::Running) => break,
+            maybe_job = rx.recv() => match maybe_job {
+                Ok(job) => job,
+                Err(_) => break,
+            },
+        };
+
+        let job_id = job.meta.job_id;
+        let log_prefix = job_id.log_prefix();
+        log::info!(
+            "{log_prefix} Worker {id} got work ({}s)",
+            job.meta.voice_msg_duration_secs
+        );
+        num_busy.fetch_add(1, Ordering::Relaxed);
+        let succeeded = match job.start_transcription(config, Arc::clone(&backend)) {
+            Some(transcribing) => {
+                let until = Instant::now() + transcribing.max_busy();
+                busy_until.lock().unwrap().insert(id, until);
+                let succeeded = transcribing.finish_transcription().await;
+                busy_until.lock().unwrap().remove(&id);
+                succeeded
+            }
+            None => {
+                log::warn!("{log_prefix} Transcription job died. Oh well");
+                // Nothing to do with the backend's health
+                true
+            }
+        };
+        num_busy.fetch_sub(1, Ordering::Relaxed);
+
+        if let Some(pause) = streak.record(succeeded) {
+            log::error!(
+                "Worker {id} failed {} jobs in a row. Pausing it for {pause:?} since something is \
+                likely broken for every job (a corrupt model, a flaky remote API, etc.)",
+                config
+                    .circuit_breaker
+                    .map_or(0, |breaker| breaker.after_failures)
+            );
+            tokio::select! {
+                biased;
+                _ = lifecycle.wait_for(|state| *state != Lifecycle::Running) => break,
+                _ = time::sleep(pause) => log::info!("Worker {id} is taking jobs again"),
+            }
         }
     }
+
+    log::info!("Worker {id} shut down");
 }
 
-async fn run_transcription_process(job: JobFut) -> Option<()> {
-    job.start_download()?
-        .finish_download()
-        .await?
-        .start_transcription()?
-        .finish_transcription()
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_bot::{wav, MockBot};
+
+    #[test]
+    fn missing_models_fail_at_startup() {
+        // An empty data dir is the same as a fresh install that skipped downloading the model
+        let data_dir = std::env::temp_dir().join(format!("rambot-no-model-{}", std::process::id()));
+        std::fs::create_dir_all(&data_dir).unwrap();
+        let default_model = data_dir.join(ModelSize::Default.file_name());
+        let fast_model = data_dir.join(ModelSize::Tiny.file_name());
+        let config = Config {
+            fast_mode: Some(FastMode {
+                after_secs: 60,
+                model: ModelSize::Tiny,
+            }),
+            ..Config::from_env()
+        };
+
+        let missing = check_models(&data_dir, config);
+        assert!(matches!(missing, Err(InitError::ModelMissing(path)) if path == default_model));
+        // The fast model is needed just as much
+        std::fs::write(&default_model, b"model").unwrap();
+        let missing = check_models(&data_dir, config);
+        assert!(matches!(missing, Err(InitError::ModelMissing(path)) if path == fast_model));
+        std::fs::write(&fast_model, b"model").unwrap();
+        assert!(check_models(&data_dir, config).is_ok());
+
+        std::fs::remove_dir_all(&data_dir).unwrap();
+    }
+
+    #[test]
+    fn breaker_trips_after_consecutive_failures() {
+        let breaker = CircuitBreaker {
+            after_failures: 3,
+            pause: Duration::from_secs(60),
+        };
+        let mut streak = FailureStreak::new(Some(breaker));
+
+        // A success in the middle of a streak starts the count over
+        assert_eq!(streak.record(false), None);
+        assert_eq!(streak.record(false), None);
+        assert_eq!(streak.record(true), None);
+        assert_eq!(streak.record(false), None);
+        assert_eq!(streak.record(false), None);
+        assert_eq!(streak.record(false), Some(breaker.pause));
+        // And it takes a whole new streak to trip again after a pause
+        assert_eq!(streak.record(false), None);
+
+        let mut disabled = FailureStreak::new(None);
+        assert!((0..100).all(|_| disabled.record(false).is_none()));
+    }
+
+    #[tokio::test]
+    async fn downloader_pauses_once_downloads_keep_failing() {
+        let mock = MockBot::spawn();
+        let (job_tx, job_rx) = async_channel::unbounded();
+        let (ready_tx, _ready_rx) = async_channel::unbounded();
+        let (_lifecycle, lifecycle_rx) = watch::channel(Lifecycle::Running);
+        let breaker = CircuitBreaker {
+            after_failures: 2,
+            pause: Duration::from_secs(60),
+        };
+        tokio::spawn(run_downloader(
+            job_rx,
+            ready_tx,
+            lifecycle_rx,
+            Some(breaker),
+        ));
+
+        let mut started = Vec::new();
+        for job_id in 0..3 {
+            let (next, started_rx) = oneshot::channel();
+            let meta = JobMeta {
+                job_id: job_id.to_string().parse().unwrap(),
+                bot: mock.bot(),
+                // Telegram not knowing the file is on us rather than the user
+                voice_file_id: "missing".to_owned(),
+                voice_msg_duration_secs: 1,
+                settings: Settings::default(),
+            };
+            job_tx.send(JobFut { next, meta }).await.unwrap();
+            started.push(started_rx);
+        }
+
+        let mut started = started.into_iter();
+        for started_rx in started.by_ref().take(2) {
+            let downloaded = started_rx.await.unwrap().await.unwrap();
+            assert!(downloaded.is_err());
+        }
+        // The third job waits out the pause instead of failing right along with the rest
+        let third = time::timeout(Duration::from_millis(200), started.next().unwrap()).await;
+        assert!(third.is_err());
+    }
+
+    #[tokio::test]
+    async fn prefetching_overlaps_downloads_with_transcribing() {
+        const DELAY: Duration = Duration::from_millis(500);
+
+        let mock = MockBot::spawn();
+        mock.add_file("first", wav(1));
+        mock.add_file("second", wav(1));
+        mock.delay_files(DELAY);
+        let backend = MockBackend {
+            delay: DELAY,
+            ..Default::default()
+        };
+        let pool = Pool::with_mock(backend);
+
+        let run_job = |job_id: u64, file_id: &str| {
+            let job = pool.submit_job(
+                job_id.to_string().parse().unwrap(),
+                mock.bot(),
+                file_id.to_owned(),
+                1,
+                Settings::default(),
+            );
+            async move {
+                let started = job.unwrap().await.unwrap();
+                let downloaded = started.await.unwrap().unwrap();
+                let mut transcribing = downloaded.next.await.unwrap().unwrap();
+                while transcribing.next().await.unwrap().is_some() {}
+            }
+        };
+        let start = Instant::now();
+        tokio::join!(run_job(1, "first"), run_job(2, "second"));
+        let elapsed = start.elapsed();
+
+        // One after the other would be two downloads and two transcriptions back to back. The
+        // second download happening while the first job transcribes saves one of those
+        assert!(elapsed >= DELAY * 3, "{elapsed:?}");
+        assert!(elapsed < DELAY * 4 - DELAY / 2, "{elapsed:?}");
+    }
+
+    #[tokio::test]
+    async fn full_queues_turn_away_new_jobs() {
+        let mock = MockBot::spawn();
+        mock.add_file("0", wav(1));
+        // Keeps the downloader stuck on the first job so that nothing else leaves the queue
+        mock.delay_files(Duration::from_secs(60));
+        let pool = Pool::with_mock(MockBackend::default());
+        let submit = |job_id: usize| {
+            pool.submit_job(
+                job_id.to_string().parse().unwrap(),
+                mock.bot(),
+                job_id.to_string(),
+                1,
+                Settings::default(),
+            )
+        };
+
+        let mut jobs = vec![submit(0).unwrap()];
+        time::timeout(Duration::from_secs(5), async {
+            while !pool.job_tx.is_empty() {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
         .await
+        .unwrap();
+
+        for job_id in 1..=MAX_QUEUED_JOBS {
+            jobs.push(submit(job_id).unwrap());
+        }
+        // One past a full queue gets turned away instead of waiting for room
+        assert!(matches!(
+            submit(MAX_QUEUED_JOBS + 1),
+            Err(HandlerError::UserError(UserError::QueueFull))
+        ));
+        assert_eq!(pool.job_tx.len(), MAX_QUEUED_JOBS);
+    }
+
+    #[tokio::test]
+    async fn workers_stuck_on_a_job_are_unhealthy() {
+        let pool = Pool::with_mock(MockBackend::default());
+        time::sleep(Duration::from_millis(50)).await;
+        assert!(pool.is_healthy());
+
+        // Busy on a job that should've wrapped up by now
+        let past = Instant::now() - Duration::from_secs(1);
+        pool.busy_until.lock().unwrap().insert(0, past);
+        assert_eq!(pool.stuck_workers(), 1);
+        assert!(!pool.is_healthy());
+
+        let future = Instant::now() + Duration::from_secs(60);
+        pool.busy_until.lock().unwrap().insert(0, future);
+        assert!(pool.is_healthy());
+    }
 }
@@ -0,0 +1,358 @@
+//! Transcribes with any OpenAI-compatible speech-to-text API
+//!
+//! This covers hosted APIs along with local servers that mimic them. The whole clip gets uploaded
+//! at once, so lines all show up together at the end instead of streaming in
+
+use std::{collections::HashMap, io};
+
+use super::{
+    backend::{Backend, BackendFut, Job},
+    state_machine::Update,
+    vad, Config, DetectedLanguage, Language,
+};
+use crate::{
+    db::ModelSize, utils::SegmentCallbackData, HandlerError, HandlerResult, InitError, InitResult,
+    UserError,
+};
+
+use reqwest::multipart;
+use serde::Deserialize;
+
+const DEFAULT_MODEL: &str = "whisper-1";
+
+pub struct Remote {
+    client: reqwest::Client,
+    url: String,
+    /// The API's own name for each of the model sizes that it has
+    models: HashMap<ModelSize, String>,
+    api_key: Option<String>,
+    language: Language,
+    no_speech_threshold: f32,
+}
+
+impl Remote {
+    /// Configured through `RAMBOT_TRANSCRIPTION_API_URL` along with optionally
+    /// `RAMBOT_TRANSCRIPTION_MODEL` and `RAMBOT_TRANSCRIPTION_API_KEY`. `None` when the url isn't
+    /// set which leaves transcribing to the local models
+    ///
+    /// The other model sizes only work once they're given the API's name for them with
+    /// `RAMBOT_TRANSCRIPTION_MODEL_<SIZE>` (e.g. `RAMBOT_TRANSCRIPTION_MODEL_TINY`)
+    pub fn from_env(config: Config) -> InitResult<Option<Self>> {
+        let Ok(url) = std::env::var("RAMBOT_TRANSCRIPTION_API_URL") else {
+            return Ok(None);
+        };
+        // Catch a typo now instead of on every voice message
+        check_url(&url)?;
+        let mut models = HashMap::new();
+        for size in [
+            ModelSize::Default,
+            ModelSize::Tiny,
+            ModelSize::Base,
+            ModelSize::Small,
+            ModelSize::Medium,
+        ] {
+            if let Ok(name) = std::env::var(model_var(size)) {
+                models.insert(size, name);
+            }
+        }
+        models
+            .entry(ModelSize::Default)
+            .or_insert_with(|| DEFAULT_MODEL.to_owned());
+        let api_key = std::env::var("RAMBOT_TRANSCRIPTION_API_KEY").ok();
+
+        Ok(Some(Self {
+            client: reqwest::Client::new(),
+            url: url.trim_end_matches('/').to_owned(),
+            models,
+            api_key,
+            language: config.language,
+            no_speech_threshold: config.no_speech_threshold,
+        }))
+    }
+
+    /// Sizes that don't have a name for the API can't be transcribed with instead of quietly
+    /// getting the default model
+    fn model_for(&self, size: ModelSize) -> HandlerResult<&str> {
+        self.models
+            .get(&size)
+            .map(String::as_str)
+            .ok_or_else(|| UserError::ModelNotInstalled(size).into())
+    }
+
+    async fn request_transcription(&self, job: Job) -> HandlerResult {
+        let Job {
+            job_id,
+            audio,
+            offset_centis,
+            settings,
+            updates,
+            abort,
+        } = job;
+
+        let model = self.model_for(settings.model)?;
+        let wav = encode_wav(&audio)?;
+        let file = multipart::Part::bytes(wav)
+            .file_name("voice.wav")
+            .mime_str("audio/wav")
+            .expect("The mime type is valid");
+        let mut form = multipart::Form::new()
+            .part("file", file)
+            .text("model", model.to_owned())
+            .text("response_format", "verbose_json");
+        if let Some(prompt) = settings.prompt {
+            form = form.text("prompt", prompt);
+        }
+        // Translations are always to English, so the endpoint doesn't take a language
+        let endpoint = if settings.translate {
+            "translations"
+        } else {
+            if let Language::Fixed(lang) = self.language {
+                form = form.text("language", lang);
+            }
+            "transcriptions"
+        };
+
+        let mut req = self
+            .client
+            .post(format!("{}/audio/{endpoint}", self.url))
+            .multipart(form);
+        if let Some(api_key) = &self.api_key {
+            req = req.bearer_auth(api_key);
+        }
+        let request = async { req.send().await?.error_for_status()?.json().await };
+        let resp: VerboseResponse = tokio::select! {
+            resp = request => resp.map_err(HandlerError::TranscriptionApi)?,
+            // Dropping the request hangs up on the server, so it can stop working on it too
+            () = abort.aborted() => return Err(HandlerError::Cancelled),
+        };
+
+        if resp
+            .segments
+            .iter()
+            .all(|segment| segment.no_speech_prob > self.no_speech_threshold)
+        {
+            return Err(UserError::NoSpeechDetected.into());
+        }
+        if let (Language::Detect | Language::Mixed, Some(detected)) = (self.language, resp.language)
+        {
+            // The API names the language, but doesn't say how sure it is. It also only ever
+            // detects one for the whole clip, so mixed languages get treated the same as auto
+            match whisper_rs::get_lang_id(&detected).and_then(whisper_rs::get_lang_str_full) {
+                Some(name) => {
+                    let detected = DetectedLanguage {
+                        name,
+                        probability: 1.0,
+                    };
+                    let _ = updates.send(Ok(Update::Language(detected))).await;
+                }
+                None => log::debug!(
+                    "{} Unknown detected language {detected:?}",
+                    job_id.log_prefix()
+                ),
+            }
+        }
+        for segment in resp.segments {
+            let segment = SegmentCallbackData {
+                start_timestamp: (segment.start * 100.0) as i64 + offset_centis,
+                end_timestamp: (segment.end * 100.0) as i64 + offset_centis,
+                text: segment.text,
+                confidence: segment.avg_logprob.exp(),
+                language: None,
+                words: None,
+            };
+            if updates.send(Ok(segment.into())).await.is_err() {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Backend for Remote {
+    fn transcribe(&self, job: Job) -> BackendFut<'_> {
+        Box::pin(self.request_transcription(job))
+    }
+
+    fn describe(&self) -> String {
+        format!("remote API ({})", self.models[&ModelSize::Default])
+    }
+
+    fn check_model(&self, model: ModelSize) -> HandlerResult {
+        self.model_for(model).map(drop)
+    }
+}
+
+fn check_url(url: &str) -> InitResult {
+    match reqwest::Url::parse(url) {
+        Ok(parsed) if matches!(parsed.scheme(), "http" | "https") => Ok(()),
+        Ok(parsed) => Err(InitError::InvalidTranscriptionApiUrl(format!(
+            "unsupported scheme {:?}",
+            parsed.scheme()
+        ))),
+        Err(e) => Err(InitError::InvalidTranscriptionApiUrl(e.to_string())),
+    }
+}
+
+/// Where the API's name for the model size comes from
+pub fn model_var(size: ModelSize) -> String {
+    match size {
+        ModelSize::Default => "RAMBOT_TRANSCRIPTION_MODEL".to_owned(),
+        sized => format!(
+            "RAMBOT_TRANSCRIPTION_MODEL_{}",
+            sized.as_str().to_uppercase()
+        ),
+    }
+}
+
+/// 16-bit PCM keeps the upload at half the size of the float samples
+fn encode_wav(audio: &[f32]) -> HandlerResult<Vec<u8>> {
+    let spec = hound::WavSpec {
+        channels: 1,
+        sample_rate: vad::SAMPLE_RATE as u32,
+        bits_per_sample: 16,
+        sample_format: hound::SampleFormat::Int,
+    };
+    let mut wav = io::Cursor::new(Vec::new());
+    let mut writer = hound::WavWriter::new(&mut wav, spec)?;
+    for sample in audio {
+        writer.write_sample((sample.clamp(-1.0, 1.0) * f32::from(i16::MAX)) as i16)?;
+    }
+    writer.finalize()?;
+    Ok(wav.into_inner())
+}
+
+#[derive(Deserialize)]
+struct VerboseResponse {
+    language: Option<String>,
+    #[serde(default)]
+    segments: Vec<Segment>,
+}
+
+#[derive(Deserialize)]
+struct Segment {
+    /// Seconds
+    start: f64,
+    end: f64,
+    text: String,
+    avg_logprob: f32,
+    no_speech_prob: f32,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        convert::Infallible,
+        net::SocketAddr,
+        sync::{Arc, Mutex},
+    };
+
+    use super::*;
+    use crate::transcriber::backend::{Abort, Settings};
+
+    use hyper::{
+        service::{make_service_fn, service_fn},
+        Body, Response, Server,
+    };
+    use tokio::sync::mpsc;
+
+    /// Answers every request with a single segment and hangs onto the last request's body
+    fn spawn_api() -> (String, Arc<Mutex<String>>) {
+        let last_body = Arc::new(Mutex::new(String::new()));
+        let server_body = Arc::clone(&last_body);
+        let make_svc = make_service_fn(move |_| {
+            let last_body = Arc::clone(&server_body);
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: hyper::Request<Body>| {
+                    let last_body = Arc::clone(&last_body);
+                    async move {
+                        let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                        *last_body.lock().unwrap() = String::from_utf8_lossy(&body).into_owned();
+                        let resp = serde_json::json!({
+                            "segments": [{
+                                "start": 0.0,
+                                "end": 1.0,
+                                "text": " Hi",
+                                "avg_logprob": -0.1,
+                                "no_speech_prob": 0.0,
+                            }],
+                        });
+                        Ok::<_, Infallible>(Response::new(Body::from(resp.to_string())))
+                    }
+                }))
+            }
+        });
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_svc);
+        let url = format!("http://{}/v1", server.local_addr());
+        tokio::spawn(server);
+        (url, last_body)
+    }
+
+    fn remote(url: String, models: &[(ModelSize, &str)]) -> Remote {
+        let models = models
+            .iter()
+            .map(|&(size, name)| (size, name.to_owned()))
+            .collect();
+        Remote {
+            client: reqwest::Client::new(),
+            url,
+            models,
+            api_key: None,
+            language: Language::Detect,
+            no_speech_threshold: 0.6,
+        }
+    }
+
+    async fn transcribe_with(remote: &Remote, model: ModelSize) -> HandlerResult {
+        let (updates, _updates_rx) = mpsc::channel(16);
+        let job = Job {
+            job_id: "1".parse().unwrap(),
+            audio: vec![0.0; vad::SAMPLE_RATE],
+            offset_centis: 0,
+            settings: Settings {
+                model,
+                ..Default::default()
+            },
+            updates,
+            abort: Abort::default(),
+        };
+        remote.transcribe(job).await
+    }
+
+    #[tokio::test]
+    async fn model_sizes_map_to_the_apis_names() {
+        let (url, last_body) = spawn_api();
+        let remote = remote(
+            url,
+            &[
+                (ModelSize::Default, "whisper-1"),
+                (ModelSize::Tiny, "whisper-tiny"),
+            ],
+        );
+
+        transcribe_with(&remote, ModelSize::Tiny).await.unwrap();
+        assert!(last_body.lock().unwrap().contains("whisper-tiny"));
+        transcribe_with(&remote, ModelSize::Default).await.unwrap();
+        assert!(last_body.lock().unwrap().contains("whisper-1"));
+
+        // A size without a name gets turned away instead of quietly using the default
+        last_body.lock().unwrap().clear();
+        let res = transcribe_with(&remote, ModelSize::Medium).await;
+        assert!(matches!(
+            res,
+            Err(HandlerError::UserError(UserError::ModelNotInstalled(
+                ModelSize::Medium
+            )))
+        ));
+        assert!(last_body.lock().unwrap().is_empty());
+        assert!(remote.check_model(ModelSize::Medium).is_err());
+    }
+
+    #[test]
+    fn api_urls_get_checked() {
+        assert!(check_url("https://api.openai.com/v1").is_ok());
+        assert!(check_url("http://localhost:8080/v1").is_ok());
+        assert!(check_url("api.openai.com/v1").is_err());
+        assert!(check_url("ftp://example.com").is_err());
+    }
+}
@@ -0,0 +1,473 @@
+//! Lets identical jobs share a single run through the pool
+//!
+//! Two people asking for the same voice message at the same time shouldn't cost two workers.
+//! Every job's progress gets relayed out to everyone waiting on it, and anyone that joins partway
+//! through gets caught up on everything they missed before following along live. Relaying never
+//! waits on a waiter, so one that's slow to read can't hold up the job or anyone else on it
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use super::{
+    backend::Settings,
+    state_machine::{Downloaded, Transcribing, Update},
+    DownloadStarted,
+};
+use crate::{cancel::JobId, HandlerError, HandlerResult, UserError};
+
+use tokio::sync::{mpsc, oneshot};
+
+/// Jobs only get shared when they'd turn out exactly the same
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Key {
+    pub voice_file_id: String,
+    pub settings: Settings,
+}
+
+type JobHandle = oneshot::Receiver<DownloadStarted>;
+type Joins = mpsc::UnboundedSender<oneshot::Sender<DownloadStarted>>;
+
+#[derive(Clone, Default)]
+pub struct InFlight {
+    jobs: Arc<Mutex<HashMap<Key, (JobId, Joins)>>>,
+}
+
+impl InFlight {
+    /// Joins an identical job that's already in flight or otherwise starts a new one with `start`
+    pub fn join_or_start(
+        &self,
+        key: Key,
+        job_id: JobId,
+        start: impl FnOnce() -> HandlerResult<JobHandle>,
+    ) -> HandlerResult<JobHandle> {
+        let mut jobs = self.jobs.lock().unwrap();
+        if let Some((shared_id, joins)) = jobs.get(&key) {
+            let (tx, rx) = oneshot::channel();
+            if joins.send(tx).is_ok() {
+                log::info!(
+                    "{} Sharing job {shared_id} for the same voice message",
+                    job_id.log_prefix()
+                );
+                return Ok(rx);
+            }
+        }
+
+        let job = start()?;
+        let (first_tx, first_rx) = oneshot::channel();
+        let (joins_tx, joins_rx) = mpsc::unbounded_channel();
+        jobs.insert(key.clone(), (job_id, joins_tx));
+        tokio::spawn(relay(self.clone(), key, job, first_tx, joins_rx));
+        Ok(first_rx)
+    }
+
+    /// Stops taking new joins when there aren't any waiting. Otherwise they get handed back to be
+    /// caught up
+    fn close(
+        &self,
+        key: &Key,
+        joins: &mut mpsc::UnboundedReceiver<oneshot::Sender<DownloadStarted>>,
+        force: bool,
+    ) -> Vec<Subscriber> {
+        let mut jobs = self.jobs.lock().unwrap();
+        let mut pending = Vec::new();
+        while let Ok(join) = joins.try_recv() {
+            pending.push(Subscriber::Queued(join));
+        }
+        if force || pending.is_empty() {
+            jobs.remove(key);
+        }
+        pending
+    }
+}
+
+/// What happened to the job so far. Gets replayed to anyone that joins late
+enum Event {
+    DownloadStarted,
+    Downloaded { duration_secs: u32 },
+    TranscriptionStarted,
+    Update(Update),
+    Failed(HandlerError),
+}
+
+/// Where the real job is at
+enum Source {
+    Queued(JobHandle),
+    Downloading(DownloadStarted),
+    Waiting(oneshot::Receiver<HandlerResult<Transcribing>>),
+    Transcribing(Transcribing),
+    Done,
+}
+
+impl Source {
+    /// Cancel-safe since the source only moves on once the stage actually finished
+    async fn next(&mut self) -> Option<Event> {
+        let (event, next) = match self {
+            Self::Queued(rx) => match rx.await {
+                Ok(started) => (Event::DownloadStarted, Self::Downloading(started)),
+                Err(_) => (Event::Failed(HandlerError::WorkerDied), Self::Done),
+            },
+            Self::Downloading(rx) => match rx.await {
+                Ok(Ok(Downloaded {
+                    duration_secs,
+                    next,
+                })) => (Event::Downloaded { duration_secs }, Self::Waiting(next)),
+                Ok(Err(e)) => (Event::Failed(e), Self::Done),
+                Err(_) => (Event::Failed(HandlerError::WorkerDied), Self::Done),
+            },
+            Self::Waiting(rx) => match rx.await {
+                Ok(Ok(transcribing)) => (
+                    Event::TranscriptionStarted,
+                    Self::Transcribing(transcribing),
+                ),
+                Ok(Err(e)) => (Event::Failed(e), Self::Done),
+                Err(_) => (Event::Failed(HandlerError::WorkerDied), Self::Done),
+            },
+            Self::Transcribing(transcribing) => match transcribing.next_update().await {
+                Some(Ok(Update::Eof)) => (Event::Update(Update::Eof), Self::Done),
+                Some(Ok(update)) => return Some(Event::Update(update)),
+                Some(Err(e)) => (Event::Failed(e), Self::Done),
+                None => (Event::Failed(HandlerError::WorkerDied), Self::Done),
+            },
+            Self::Done => return None,
+        };
+        *self = next;
+        Some(event)
+    }
+}
+
+/// Where one of the job's waiters is at
+enum Subscriber {
+    Queued(oneshot::Sender<DownloadStarted>),
+    Downloading(oneshot::Sender<HandlerResult<Downloaded>>),
+    Waiting(oneshot::Sender<HandlerResult<Transcribing>>),
+    Transcribing(mpsc::UnboundedSender<HandlerResult<Update>>),
+}
+
+impl Subscriber {
+    /// `None` once the subscriber is all done or stopped listening
+    fn advance(self, event: &Event) -> Option<Self> {
+        match (self, event) {
+            (Self::Queued(tx), Event::DownloadStarted) => {
+                let (next_tx, next_rx) = oneshot::channel();
+                tx.send(next_rx).ok()?;
+                Some(Self::Downloading(next_tx))
+            }
+            (Self::Downloading(tx), &Event::Downloaded { duration_secs }) => {
+                let (next_tx, next_rx) = oneshot::channel();
+                tx.send(Ok(Downloaded {
+                    duration_secs,
+                    next: next_rx,
+                }))
+                .ok()?;
+                Some(Self::Waiting(next_tx))
+            }
+            (Self::Waiting(tx), Event::TranscriptionStarted) => {
+                let (updates_tx, updates_rx) = mpsc::unbounded_channel();
+                tx.send(Ok(Transcribing::shared(updates_rx))).ok()?;
+                Some(Self::Transcribing(updates_tx))
+            }
+            (Self::Transcribing(tx), Event::Update(update)) => {
+                tx.send(Ok(update.clone())).ok()?;
+                (*update != Update::Eof).then_some(Self::Transcribing(tx))
+            }
+            (subscriber, Event::Failed(e)) => {
+                subscriber.fail(copy_error(e));
+                None
+            }
+            // Events always come in the same order, so this never happens
+            (_, _) => None,
+        }
+    }
+
+    /// Resolves once the subscriber stopped listening
+    async fn closed(&mut self) {
+        match self {
+            Self::Queued(tx) => tx.closed().await,
+            Self::Downloading(tx) => tx.closed().await,
+            Self::Waiting(tx) => tx.closed().await,
+            Self::Transcribing(tx) => tx.closed().await,
+        }
+    }
+
+    fn fail(self, e: HandlerError) {
+        let _ = match self {
+            Self::Queued(tx) => {
+                // There's no way to fail before the download starts, so fail the download
+                let (next_tx, next_rx) = oneshot::channel();
+                let _ = tx.send(next_rx);
+                next_tx.send(Err(e)).map_err(drop)
+            }
+            Self::Downloading(tx) => tx.send(Err(e)).map_err(drop),
+            Self::Waiting(tx) => tx.send(Err(e)).map_err(drop),
+            Self::Transcribing(tx) => tx.send(Err(e)).map_err(drop),
+        };
+    }
+
+    fn catch_up(mut self, log: &[Event]) -> Option<Self> {
+        for event in log {
+            self = self.advance(event)?;
+        }
+        Some(self)
+    }
+}
+
+fn advance_all(subscribers: Vec<Subscriber>, event: &Event) -> Vec<Subscriber> {
+    subscribers
+        .into_iter()
+        .filter_map(|subscriber| subscriber.advance(event))
+        .collect()
+}
+
+async fn all_closed(subscribers: &mut [Subscriber]) {
+    futures::future::join_all(subscribers.iter_mut().map(Subscriber::closed)).await;
+}
+
+async fn relay(
+    in_flight: InFlight,
+    key: Key,
+    job: JobHandle,
+    first: oneshot::Sender<DownloadStarted>,
+    mut joins: mpsc::UnboundedReceiver<oneshot::Sender<DownloadStarted>>,
+) {
+    let mut source = Source::Queued(job);
+    let mut subscribers = vec![Subscriber::Queued(first)];
+    let mut log = Vec::new();
+    loop {
+        tokio::select! {
+            event = source.next() => {
+                let Some(event) = event else { break };
+                subscribers = advance_all(subscribers, &event);
+                log.push(event);
+            }
+            Some(join) = joins.recv() => {
+                subscribers.extend(Subscriber::Queued(join).catch_up(&log));
+            }
+            // Otherwise a cancelled job would only notice on its next update which could be a
+            // long ways off
+            () = all_closed(&mut subscribers) => subscribers.clear(),
+        }
+
+        // Dropping the source lets the job know that no one's listening anymore
+        if subscribers.is_empty() {
+            let pending = in_flight.close(&key, &mut joins, false);
+            if pending.is_empty() {
+                return;
+            }
+            for subscriber in pending {
+                subscribers.extend(subscriber.catch_up(&log));
+            }
+        }
+    }
+
+    // Anyone that joined right as the job finished still gets the whole thing
+    for subscriber in in_flight.close(&key, &mut joins, true) {
+        subscriber.catch_up(&log);
+    }
+}
+
+/// Errors can't be cloned, so the ones that get special handling are rebuilt and the rest keep
+/// their message
+fn copy_error(e: &HandlerError) -> HandlerError {
+    match e {
+        HandlerError::WorkerDied => HandlerError::WorkerDied,
+        HandlerError::Cancelled => HandlerError::Cancelled,
+        HandlerError::TimedOut => HandlerError::TimedOut,
+        HandlerError::UserError(UserError::NoSpeechDetected) => UserError::NoSpeechDetected.into(),
+        HandlerError::UserError(UserError::ModelNotInstalled(model)) => {
+            UserError::ModelNotInstalled(*model).into()
+        }
+        other => HandlerError::SharedJob(other.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::SegmentCallbackData;
+
+    use std::time::Duration;
+
+    fn key() -> Key {
+        Key {
+            voice_file_id: "voice".to_owned(),
+            settings: Settings::default(),
+        }
+    }
+
+    /// Follows a job all the way through like a handler would
+    async fn lines_of(job: JobHandle) -> HandlerResult<(u32, Vec<String>)> {
+        let downloaded = job.await.unwrap().await.unwrap()?;
+        let mut transcribing = downloaded.next.await.unwrap()?;
+        let mut lines = Vec::new();
+        while let Some(line) = transcribing.next().await? {
+            lines.push(line.text);
+        }
+        Ok((downloaded.duration_secs, lines))
+    }
+
+    #[tokio::test]
+    async fn identical_jobs_share_one_run() {
+        let in_flight = InFlight::default();
+        let mut starts = 0;
+        let (real_tx, real_rx) = oneshot::channel();
+        let mut real_rx = Some(real_rx);
+        let mut submit = |job_id: &str| {
+            in_flight.join_or_start(key(), job_id.parse().unwrap(), || {
+                starts += 1;
+                Ok(real_rx.take().unwrap())
+            })
+        };
+        let first = tokio::spawn(lines_of(submit("1").unwrap()));
+
+        // Play the worker's side of things
+        let (download_tx, download_rx) = oneshot::channel();
+        real_tx.send(download_rx).map_err(drop).unwrap();
+        let (waiting_tx, waiting_rx) = oneshot::channel();
+        download_tx
+            .send(Ok(Downloaded {
+                duration_secs: 5,
+                next: waiting_rx,
+            }))
+            .map_err(drop)
+            .unwrap();
+        let (updates_tx, updates_rx) = mpsc::channel(16);
+        let line = |text: &str| {
+            let segment = SegmentCallbackData {
+                start_timestamp: 0,
+                end_timestamp: 100,
+                text: text.to_owned(),
+                confidence: 1.0,
+                language: None,
+                words: None,
+            };
+            Ok(Update::from(segment))
+        };
+        updates_tx.send(line("Hello")).await.unwrap();
+        waiting_tx
+            .send(Ok(Transcribing::new(updates_rx)))
+            .map_err(drop)
+            .unwrap();
+
+        // Joining partway through still gets everything from the start
+        let second = tokio::spawn(lines_of(submit("2").unwrap()));
+        updates_tx.send(line("there")).await.unwrap();
+        updates_tx.send(Ok(Update::Eof)).await.unwrap();
+
+        let expected = (5, vec!["Hello".to_owned(), "there".to_owned()]);
+        assert_eq!(first.await.unwrap().unwrap(), expected);
+        assert_eq!(second.await.unwrap().unwrap(), expected);
+        assert_eq!(starts, 1);
+    }
+
+    #[tokio::test]
+    async fn slow_waiters_dont_hold_up_the_rest() {
+        let in_flight = InFlight::default();
+        let (real_tx, real_rx) = oneshot::channel();
+        let slow = in_flight
+            .join_or_start(key(), "1".parse().unwrap(), || Ok(real_rx))
+            .unwrap();
+        let fast = in_flight
+            .join_or_start(key(), "2".parse().unwrap(), || unreachable!())
+            .unwrap();
+
+        let (download_tx, download_rx) = oneshot::channel();
+        real_tx.send(download_rx).map_err(drop).unwrap();
+        let (waiting_tx, waiting_rx) = oneshot::channel();
+        download_tx
+            .send(Ok(Downloaded {
+                duration_secs: 5,
+                next: waiting_rx,
+            }))
+            .map_err(drop)
+            .unwrap();
+        let (updates_tx, updates_rx) = mpsc::channel(16);
+        waiting_tx
+            .send(Ok(Transcribing::new(updates_rx)))
+            .map_err(drop)
+            .unwrap();
+        // Gets as far as transcribing and then never reads a thing
+        let downloaded = slow.await.unwrap().await.unwrap().unwrap();
+        let _stalled = downloaded.next.await.unwrap().unwrap();
+        let fast = tokio::spawn(lines_of(fast));
+
+        // Way more than fits in a bounded channel
+        let worker = async {
+            for i in 0..100 {
+                let segment = SegmentCallbackData {
+                    start_timestamp: i * 100,
+                    end_timestamp: i * 100 + 100,
+                    text: format!("Line {i}"),
+                    confidence: 1.0,
+                    language: None,
+                    words: None,
+                };
+                updates_tx.send(Ok(segment.into())).await.unwrap();
+            }
+            updates_tx.send(Ok(Update::Eof)).await.unwrap();
+        };
+        let finished = tokio::time::timeout(Duration::from_secs(1), worker).await;
+        assert!(finished.is_ok());
+        let (_, lines) = fast.await.unwrap().unwrap();
+        assert_eq!(lines.len(), 100);
+    }
+
+    #[tokio::test]
+    async fn leaving_hangs_up_on_the_worker() {
+        let in_flight = InFlight::default();
+        let (real_tx, real_rx) = oneshot::channel();
+        let job = in_flight
+            .join_or_start(key(), "1".parse().unwrap(), || Ok(real_rx))
+            .unwrap();
+
+        let (download_tx, download_rx) = oneshot::channel();
+        real_tx.send(download_rx).map_err(drop).unwrap();
+        let (waiting_tx, waiting_rx) = oneshot::channel();
+        download_tx
+            .send(Ok(Downloaded {
+                duration_secs: 5,
+                next: waiting_rx,
+            }))
+            .map_err(drop)
+            .unwrap();
+        let (updates_tx, updates_rx) = mpsc::channel(16);
+        waiting_tx
+            .send(Ok(Transcribing::new(updates_rx)))
+            .map_err(drop)
+            .unwrap();
+        let downloaded = job.await.unwrap().await.unwrap().unwrap();
+        let transcribing = downloaded.next.await.unwrap().unwrap();
+
+        // The worker hears about it without having to send anything first
+        drop(transcribing);
+        let closed = tokio::time::timeout(Duration::from_secs(1), updates_tx.closed()).await;
+        assert!(closed.is_ok());
+    }
+
+    #[tokio::test]
+    async fn failures_reach_every_waiter() {
+        let in_flight = InFlight::default();
+        let (real_tx, real_rx) = oneshot::channel();
+        let first = in_flight
+            .join_or_start(key(), "1".parse().unwrap(), || Ok(real_rx))
+            .unwrap();
+        let second = in_flight
+            .join_or_start(key(), "2".parse().unwrap(), || unreachable!())
+            .unwrap();
+
+        let (download_tx, download_rx) = oneshot::channel();
+        real_tx.send(download_rx).map_err(drop).unwrap();
+        download_tx
+            .send(Err(UserError::NoSpeechDetected.into()))
+            .map_err(drop)
+            .unwrap();
+
+        for job in [first, second] {
+            assert!(matches!(
+                lines_of(job).await,
+                Err(HandlerError::UserError(UserError::NoSpeechDetected))
+            ));
+        }
+    }
+}
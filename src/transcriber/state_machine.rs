@@ -4,15 +4,37 @@
 //! state machine where the *Fut side automatically emits updates to the non-*Fut side that expand
 //! out to follow the state machine's flow
 
-use std::{process::Stdio, sync::Arc};
+use std::{process::Stdio, sync::Arc, time::Duration};
 
-use crate::{telegram::Bot, utils::SegmentCallbackData, HandlerError, HandlerResult, Line};
+use super::{
+    backend::{Abort, Backend, Job, Settings},
+    vad, Config,
+};
+#[cfg(feature = "metrics")]
+use crate::metrics;
+use crate::{
+    cancel::JobId,
+    telegram::Bot,
+    utils::{self, SegmentCallbackData},
+    HandlerError, HandlerResult, Line,
+};
 
+use tempfile::TempDir;
 use tokio::{
-    runtime::Handle,
-    sync::{mpsc, oneshot, Mutex},
+    fs,
+    process::Command,
+    sync::{mpsc, oneshot},
+    time,
 };
-use whisper_rs::{FullParams, WhisperContext, WhisperContextParameters};
+
+/// Short messages still need time to load the model, so they get at least this long
+const MIN_TIME_LIMIT: Duration = Duration::from_secs(60);
+/// Telegram rounds durations to the second, so a little drift is expected
+const DURATION_TOLERANCE_SECS: u32 = 2;
+/// Detected languages less likely than this get flagged as a guess
+const UNSURE_LANGUAGE_PROB: f32 = 0.5;
+/// How long an aborted backend gets to wrap up before it's left to finish in the background
+const ABORT_GRACE: Duration = Duration::from_secs(10);
 
 // TODO: provide some kind of constructor
 // TODO: wrap non-fut so that we can expose a meaningful error directly?
@@ -23,9 +45,12 @@ pub struct JobFut {
 }
 
 pub struct JobMeta {
+    /// Shows up in all of the job's logs to tie them together
+    pub job_id: JobId,
     pub bot: Bot,
     pub voice_file_id: String,
     pub voice_msg_duration_secs: u32,
+    pub settings: Settings,
 }
 
 impl JobFut {
@@ -37,157 +62,459 @@ impl JobFut {
     }
 }
 
-pub type DownloadStarted = oneshot::Receiver<HandlerResult<Downloading>>;
+pub type DownloadStarted = oneshot::Receiver<HandlerResult<Downloaded>>;
+
+/// The audio's actual duration which can differ from what telegram claimed
+pub struct Downloaded {
+    pub duration_secs: u32,
+    pub next: Downloading,
+}
+
+/// Why a job never made it to a worker
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DownloadDied {
+    /// No one was waiting on the job anymore
+    Abandoned,
+    /// Down to what the user sent, like a file that's too big
+    UserError,
+    /// Down to us, like ffmpeg missing or telegram being unreachable
+    Broken,
+}
 
 #[must_use]
 pub struct DownloadStartedFut {
-    next: oneshot::Sender<HandlerResult<Downloading>>,
+    next: oneshot::Sender<HandlerResult<Downloaded>>,
     meta: JobMeta,
 }
 
 impl DownloadStartedFut {
-    pub async fn finish_download(self) -> Option<DownloadingFut> {
-        let Self {
-            next,
-            meta: JobMeta {
-                bot, voice_file_id, ..
-            },
-        } = self;
+    pub async fn finish_download(self) -> Result<DownloadingFut, DownloadDied> {
+        let Self { next, mut meta } = self;
         let (tx, rx) = oneshot::channel();
 
-        // TODO: tempdir here to download into
-        let ogg_file = tempfile::Builder::new()
-            .prefix("rambot")
-            .suffix(".ogg")
-            .tempfile()
-            .unwrap();
-        let ogg_path = ogg_file.path();
-
-        if let Err(e) = bot.download_file(ogg_path, voice_file_id).await {
-            next.send(Err(e.into())).ok()?;
-            None
-        } else {
-            // TODO: switch to symphonia once they have an opus decoder
-            let wav_file = tempfile::Builder::new()
-                .prefix("rambot")
-                .suffix(".wav")
-                .tempfile()
-                .unwrap();
-            let wav_path = wav_file.path();
-            #[rustfmt::skip]
-            std::process::Command::new("ffmpeg")
-                .arg("-i").arg(ogg_path)
-                // Convert to i16 LE samples because that's what the example used
-                .arg("-acodec").arg("pcm_s16le")
-                // 16kHz
-                .arg("-ar").arg("16000")
-                // Skip confirmation
-                .arg("-y")
-                .arg(wav_path)
-                .stdout(Stdio::null())
-                .stderr(Stdio::null())
-                .status()
-                .unwrap();
-
-            let wav_reader = hound::WavReader::open(wav_path).unwrap();
-            let int_audio: Vec<_> = wav_reader
-                .into_samples::<i16>()
-                .map(|x| x.unwrap())
-                .collect();
-            let mut float_audio = vec![0.0; int_audio.len()];
-            whisper_rs::convert_integer_to_float_audio(&int_audio, &mut float_audio).ok()?;
-
-            next.send(Ok(rx)).ok()?;
-            Some(DownloadingFut {
-                next: tx,
-                audio_data: float_audio,
-            })
+        match download_audio(&meta.bot, meta.voice_file_id.clone()).await {
+            Ok((workdir, mut audio_data)) => {
+                let decoded_secs =
+                    u32::try_from(audio_data.len() / vad::SAMPLE_RATE).unwrap_or(u32::MAX);
+                // Telegram's duration is sometimes way off or even zero, so the decoded audio wins
+                if decoded_secs.abs_diff(meta.voice_msg_duration_secs) > DURATION_TOLERANCE_SECS {
+                    log::warn!(
+                        "{} Voice message claimed to be {}s, but decoded to {decoded_secs}s",
+                        meta.job_id.log_prefix(),
+                        meta.voice_msg_duration_secs
+                    );
+                    meta.voice_msg_duration_secs = decoded_secs;
+                }
+                if let Some(max_secs) = meta.settings.max_secs {
+                    audio_data.truncate(max_secs as usize * vad::SAMPLE_RATE);
+                    meta.voice_msg_duration_secs = meta.voice_msg_duration_secs.min(max_secs);
+                }
+                let downloaded = Downloaded {
+                    duration_secs: meta.voice_msg_duration_secs,
+                    next: rx,
+                };
+                next.send(Ok(downloaded))
+                    .map_err(|_| DownloadDied::Abandoned)?;
+                Ok(DownloadingFut {
+                    next: tx,
+                    meta,
+                    _workdir: workdir,
+                    audio_data,
+                })
+            }
+            Err(e) => {
+                #[cfg(feature = "metrics")]
+                metrics::JOBS_FAILED.inc();
+                log::warn!("{} Failed preparing audio: {e}", meta.job_id.log_prefix());
+                let died = if matches!(e, HandlerError::UserError(_)) {
+                    DownloadDied::UserError
+                } else {
+                    DownloadDied::Broken
+                };
+                // The handler might have stopped listening already
+                let _ = next.send(Err(e));
+                Err(died)
+            }
+        }
+    }
+}
+
+/// The first line of `ffmpeg -version` or `None` when it can't be run
+pub async fn ffmpeg_version() -> Option<String> {
+    let output = Command::new("ffmpeg")
+        .arg("-version")
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .await
+        .ok()?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    output
+        .status
+        .success()
+        .then(|| stdout.lines().next().unwrap_or_default().trim().to_owned())
+}
+
+/// A fresh temp dir that gets cleaned up when dropped
+async fn workdir() -> HandlerResult<TempDir> {
+    let tmp_dir = utils::tmp_dir();
+    // A custom temp dir might not exist yet
+    fs::create_dir_all(&tmp_dir).await?;
+    let workdir = tempfile::Builder::new()
+        .prefix("rambot")
+        .tempdir_in(tmp_dir)?;
+    Ok(workdir)
+}
+
+/// How long the file's audio runs for in seconds
+///
+/// Telegram only knows the duration of actual voice messages, so anything else has to get
+/// downloaded and probed to find out
+pub async fn probe_duration(bot: &Bot, file_id: String) -> HandlerResult<u32> {
+    let workdir = workdir().await?;
+    let path = workdir.path().join("audio");
+    bot.download_file(&path, file_id).await?;
+    if let Ok(wav_reader) = hound::WavReader::open(&path) {
+        return Ok(wav_reader.duration() / wav_reader.spec().sample_rate);
+    }
+
+    #[rustfmt::skip]
+    let output = Command::new("ffprobe")
+        .arg("-v").arg("error")
+        // Just the container's duration in seconds without any labels
+        .arg("-show_entries").arg("format=duration")
+        .arg("-of").arg("csv=p=0")
+        .arg(&path)
+        .stdin(Stdio::null())
+        .stderr(Stdio::piped())
+        .output()
+        .await
+        .map_err(HandlerError::FfmpegSpawn)?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    match stdout.trim().parse::<f64>() {
+        Ok(secs) if output.status.success() => Ok(secs.round() as u32),
+        _ => {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            let stderr = stderr.trim().lines().last().unwrap_or_default();
+            Err(HandlerError::Ffprobe(stderr.to_owned()))
         }
     }
 }
 
+/// Downloads and decodes the audio into a fresh temp dir that gets cleaned up when dropped
+async fn download_audio(bot: &Bot, voice_file_id: String) -> HandlerResult<(TempDir, Vec<f32>)> {
+    let workdir = workdir().await?;
+    let ogg_path = workdir.path().join("voice.ogg");
+    bot.download_file(&ogg_path, voice_file_id).await?;
+    // Audio that's already how whisper wants it doesn't need converting
+    if let Ok(wav_reader) = hound::WavReader::open(&ogg_path) {
+        if is_whisper_ready(wav_reader.spec()) {
+            return Ok((workdir, read_wav(wav_reader)?));
+        }
+    }
+
+    // TODO: switch to symphonia once they have an opus decoder. As of symphonia 0.6 there's still
+    // no native one, only an adapter that links against libopus, which would just trade ffmpeg
+    // for a C toolchain requirement at build time
+    let wav_path = workdir.path().join("voice.wav");
+    #[rustfmt::skip]
+    let output = Command::new("ffmpeg")
+        .arg("-i").arg(ogg_path)
+        // Convert to i16 LE samples because that's what the example used
+        .arg("-acodec").arg("pcm_s16le")
+        // 16kHz
+        .arg("-ar").arg("16000")
+        // Skip confirmation
+        .arg("-y")
+        .arg(&wav_path)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .output()
+        .await
+        .map_err(HandlerError::FfmpegSpawn)?;
+    if !output.status.success() {
+        // ffmpeg opens with a big banner, but the actual error is the last thing it logs
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let stderr = stderr.trim().lines().last().unwrap_or_default().to_owned();
+        return Err(HandlerError::Ffmpeg {
+            status: output.status,
+            stderr,
+        });
+    }
+
+    let wav_reader = hound::WavReader::open(&wav_path)?;
+    Ok((workdir, read_wav(wav_reader)?))
+}
+
+/// 16kHz mono with 16-bit samples which matches what ffmpeg gets asked for
+fn is_whisper_ready(spec: hound::WavSpec) -> bool {
+    spec.channels == 1
+        && spec.sample_rate as usize == vad::SAMPLE_RATE
+        && spec.bits_per_sample == 16
+        && spec.sample_format == hound::SampleFormat::Int
+}
+
+fn read_wav<R: std::io::Read>(wav_reader: hound::WavReader<R>) -> HandlerResult<Vec<f32>> {
+    let int_audio = wav_reader
+        .into_samples::<i16>()
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut float_audio = vec![0.0; int_audio.len()];
+    whisper_rs::convert_integer_to_float_audio(&int_audio, &mut float_audio)?;
+    Ok(float_audio)
+}
+
 // TODO: rename all `Downloading` -> `Downloaded`
 pub type Downloading = oneshot::Receiver<HandlerResult<Transcribing>>;
 
 #[must_use]
 pub struct DownloadingFut {
     next: oneshot::Sender<HandlerResult<Transcribing>>,
+    pub meta: JobMeta,
+    // Holds onto the downloaded files until the job is done with them
+    _workdir: TempDir,
     audio_data: Vec<f32>,
 }
 
 impl DownloadingFut {
-    pub fn start_transcription(self) -> Option<TranscribingFut> {
-        let Self { next, audio_data } = self;
+    pub fn start_transcription(
+        self,
+        config: Config,
+        backend: Arc<dyn Backend>,
+    ) -> Option<TranscribingFut> {
+        // The decoded audio is all we need from here on, so the downloaded files get cleaned up
+        let Self {
+            next,
+            meta,
+            _workdir: _,
+            audio_data,
+        } = self;
+        let vad::Trimmed {
+            audio: audio_data,
+            offset_centis,
+        } = vad::trim_silence(config.vad, audio_data);
+        let time_limit = MIN_TIME_LIMIT
+            .max(Duration::from_secs(meta.voice_msg_duration_secs.into()) * config.timeout_factor);
         let (msg_handle, transcriber_handle) = mpsc::channel(16);
-        let shared_transcription = Arc::default();
-        next.send(Ok(Transcribing {
-            finished: false,
-            transcriber_handle,
-            shared_transcription: Arc::clone(&shared_transcription),
-        }))
-        .ok()?;
+        next.send(Ok(Transcribing::new(transcriber_handle))).ok()?;
         Some(TranscribingFut {
+            job_id: meta.job_id,
             msg_handle,
-            shared_transcription,
             audio_data,
+            offset_centis,
+            time_limit,
+            settings: meta.settings,
+            backend,
         })
     }
 }
 
+/// Workers hand over their updates through a bounded channel to keep the backend from running too
+/// far ahead. Shared jobs relay them on to every waiter unbounded, so that one slow waiter can't
+/// hold up the rest
+enum UpdatesRx {
+    Worker(mpsc::Receiver<HandlerResult<Update>>),
+    Shared(mpsc::UnboundedReceiver<HandlerResult<Update>>),
+}
+
+impl UpdatesRx {
+    async fn recv(&mut self) -> Option<HandlerResult<Update>> {
+        match self {
+            Self::Worker(rx) => rx.recv().await,
+            Self::Shared(rx) => rx.recv().await,
+        }
+    }
+}
+
 #[must_use]
 pub struct Transcribing {
-    finished: bool,
-    transcriber_handle: mpsc::Receiver<HandlerResult<Update>>,
-    shared_transcription: Arc<Mutex<String>>,
+    transcriber_handle: UpdatesRx,
+    detected_language: Option<DetectedLanguage>,
 }
 
 impl Transcribing {
+    pub(super) fn new(transcriber_handle: mpsc::Receiver<HandlerResult<Update>>) -> Self {
+        Self {
+            transcriber_handle: UpdatesRx::Worker(transcriber_handle),
+            detected_language: None,
+        }
+    }
+
+    pub(super) fn shared(
+        transcriber_handle: mpsc::UnboundedReceiver<HandlerResult<Update>>,
+    ) -> Self {
+        Self {
+            transcriber_handle: UpdatesRx::Shared(transcriber_handle),
+            detected_language: None,
+        }
+    }
+
+    /// The raw updates for passing along to someone else
+    pub(super) async fn next_update(&mut self) -> Option<HandlerResult<Update>> {
+        self.transcriber_handle.recv().await
+    }
+
     pub async fn next(&mut self) -> HandlerResult<Option<Line>> {
-        let maybe_update = self
-            .transcriber_handle
-            .recv()
-            .await
-            .ok_or(HandlerError::WorkerDied)?;
-
-        maybe_update.map(|update| match update {
-            Update::Line(line) => Some(line),
-            Update::Eof => None,
-        })
+        loop {
+            let update = self
+                .transcriber_handle
+                .recv()
+                .await
+                .ok_or(HandlerError::WorkerDied)??;
+
+            match update {
+                Update::Language(language) => self.detected_language = Some(language),
+                Update::Line(line) => break Ok(Some(line)),
+                Update::Eof => break Ok(None),
+            }
+        }
+    }
+
+    /// Only shows up once when whisper detected the language itself
+    pub fn take_detected_language(&mut self) -> Option<DetectedLanguage> {
+        self.detected_language.take()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct DetectedLanguage {
+    /// The full lowercase name like `spanish`
+    pub name: &'static str,
+    pub probability: f32,
+}
+
+impl DetectedLanguage {
+    pub fn is_unsure(&self) -> bool {
+        self.probability < UNSURE_LANGUAGE_PROB
     }
 }
 
-#[derive(Clone)]
 pub struct TranscribingFut {
+    job_id: JobId,
     msg_handle: mpsc::Sender<HandlerResult<Update>>,
-    shared_transcription: Arc<Mutex<String>>,
     audio_data: Vec<f32>,
+    /// Where `audio_data` starts in the original audio after trimming off any leading silence
+    offset_centis: i64,
+    time_limit: Duration,
+    settings: Settings,
+    backend: Arc<dyn Backend>,
 }
 
 impl TranscribingFut {
-    pub async fn finish_transcription(self) -> Option<()> {
-        let fut = self.clone();
-        let res = match tokio::task::spawn_blocking(move || run_sync_process(fut)).await {
-            Ok(Ok(())) => Ok(()),
+    /// Past this the job has kept its worker busy for longer than it ever should have. It's the
+    /// time limit plus the grace period that an aborted backend gets to wrap up
+    pub fn max_busy(&self) -> Duration {
+        self.time_limit + ABORT_GRACE
+    }
+
+    /// Whether the backend held up. Jobs that fail for reasons that are up to the user (like
+    /// there not being any speech) still count, and so do ones that ran out of time since long
+    /// voice messages can do that no matter how healthy the backend is
+    pub async fn finish_transcription(self) -> bool {
+        let Self {
+            job_id,
+            msg_handle,
+            audio_data,
+            offset_centis,
+            time_limit,
+            settings,
+            backend,
+        } = self;
+        let log_prefix = job_id.log_prefix();
+        #[cfg(feature = "metrics")]
+        let audio_secs = (audio_data.len() / vad::SAMPLE_RATE) as u64;
+        let abort = Abort::default();
+        // Also stops the backend when the worker gets torn down partway through
+        let mut abort_on_drop = AbortOnDrop(Some(abort.clone()));
+        let job = Job {
+            job_id,
+            audio: audio_data,
+            offset_centis,
+            settings,
+            updates: msg_handle.clone(),
+            abort: abort.clone(),
+        };
+        #[cfg(feature = "metrics")]
+        let start = time::Instant::now();
+        let mut transcription = backend.transcribe(job);
+        let stopped = tokio::select! {
+            res = &mut transcription => Ok(res),
+            () = time::sleep(time_limit) => Err(HandlerError::TimedOut),
+            // Everyone waiting on the job left, so there's no point in finishing it
+            () = msg_handle.closed() => Err(HandlerError::Cancelled),
+        };
+        if stopped.is_ok() {
+            abort_on_drop.disarm();
+        }
+        let res = match stopped {
+            Ok(Ok(())) => {
+                #[cfg(feature = "metrics")]
+                {
+                    metrics::JOBS_COMPLETED.inc();
+                    metrics::AUDIO_SECS.add(audio_secs);
+                    metrics::TRANSCRIPTION_DURATION.observe(start.elapsed());
+                }
+                // The job finished even if no one's listening anymore
+                let _ = msg_handle.send(Ok(Update::Eof)).await;
+                Ok(())
+            }
             Ok(Err(err)) => {
-                log::warn!("Sync process wrapper returned an error: {err}");
+                log::warn!("{log_prefix} Transcription backend returned an error: {err}");
                 Err(err)
             }
-            Err(err) => {
-                log::warn!("Sync process wrapper died unexpectedly: {err}");
-                Err(HandlerError::WorkerDied)
+            Err(reason) => {
+                if let HandlerError::TimedOut = reason {
+                    log::warn!("{log_prefix} Transcription ran over {time_limit:?}. Stopping it");
+                } else {
+                    log::info!("{log_prefix} No one's waiting on the transcription. Stopping it");
+                }
+                abort.abort();
+                // Waiting on the backend keeps the worker from picking up more work while the
+                // aborted job is still hogging the cpu
+                if time::timeout(ABORT_GRACE, transcription).await.is_err() {
+                    log::warn!("{log_prefix} Backend didn't stop within {ABORT_GRACE:?}");
+                }
+                Err(reason)
             }
         };
 
-        if let Err(e) = res {
-            self.msg_handle.send(Err(e)).await.ok()?;
+        match res {
+            Ok(()) => true,
+            // There's no one left to tell and it's not the backend's fault
+            Err(HandlerError::Cancelled) => true,
+            Err(e) => {
+                #[cfg(feature = "metrics")]
+                metrics::JOBS_FAILED.inc();
+                let succeeded = matches!(e, HandlerError::UserError(_) | HandlerError::TimedOut);
+                // The handler might have stopped listening already
+                let _ = msg_handle.send(Err(e)).await;
+                succeeded
+            }
         }
+    }
+}
 
-        Some(())
+/// Aborts the job when the transcription's future gets dropped before the backend is done
+struct AbortOnDrop(Option<Abort>);
+
+impl AbortOnDrop {
+    fn disarm(&mut self) {
+        self.0 = None;
+    }
+}
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        if let Some(abort) = &self.0 {
+            abort.abort();
+        }
     }
 }
 
-#[derive(Clone, Debug, PartialEq, Eq)]
-enum Update {
+#[derive(Clone, Debug, PartialEq)]
+pub enum Update {
+    /// Sent before any lines when the language gets detected
+    Language(DetectedLanguage),
     Line(Line),
     Eof,
 }
@@ -204,47 +531,129 @@ impl From<SegmentCallbackData> for Update {
     }
 }
 
-fn run_sync_process(fut: TranscribingFut) -> HandlerResult {
-    let TranscribingFut {
-        shared_transcription,
-        msg_handle,
-        audio_data,
-    } = fut;
-
-    let model_path = dirs::data_dir().unwrap().join("rambot").join("model.bin");
-    let params = WhisperContextParameters::new();
-    let ctx = WhisperContext::new_with_params(model_path.to_str().unwrap(), params).unwrap();
-    let mut state = ctx.create_state().unwrap();
-    let mut params = FullParams::new(Default::default());
-    params.set_no_context(true);
-    // TODO: This callback segfaults... Need to minimize and report the issue upstream
-    // let (tx, _) = tokio::sync::mpsc::unbounded_channel::<()>();
-    // let msg_handle2 = msg_handle.clone();
-    // params.set_progress_callback_safe(move |_| {
-    //     Handle::current().block_on(async {
-    //         let _ = tx.send(());
-    //     });
-    // });
-
-    // Actually run the model on the audio file
-    state.full(params, &audio_data).unwrap();
-
-    Handle::current().block_on(async {
-        let n_segments = state.full_n_segments().unwrap();
-        for i in 0..n_segments {
-            let start_timestamp = state.full_get_segment_t0(i).unwrap();
-            let end_timestamp = state.full_get_segment_t1(i).unwrap();
-            let text = state.full_get_segment_text(i).unwrap();
-            let segment = SegmentCallbackData {
-                segment: i,
-                start_timestamp,
-                end_timestamp,
-                text,
-            };
-            let _ = msg_handle.send(Ok(segment.into())).await;
-        }
-        let _ = msg_handle.send(Ok(Update::Eof)).await;
-    });
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transcriber::backend::{noticed_abort, MockBackend};
+
+    fn start(
+        backend: MockBackend,
+        settings: Settings,
+        time_limit: Duration,
+    ) -> (Transcribing, TranscribingFut) {
+        let (msg_handle, transcriber_handle) = mpsc::channel(16);
+        let transcribing = Transcribing::new(transcriber_handle);
+        let fut = TranscribingFut {
+            job_id: "1".parse().unwrap(),
+            msg_handle,
+            audio_data: Vec::new(),
+            offset_centis: 200,
+            time_limit,
+            settings,
+            backend: Arc::new(backend),
+        };
+        (transcribing, fut)
+    }
+
+    #[tokio::test]
+    async fn backend_lines_stream_through() {
+        let backend = MockBackend {
+            delay: Duration::ZERO,
+            segments: vec![(0, " Hello"), (300, " there")],
+            ..Default::default()
+        };
+        let (mut transcribing, fut) = start(backend, Settings::default(), Duration::from_secs(5));
+        tokio::spawn(fut.finish_transcription());
+
+        let first = transcribing.next().await.unwrap().unwrap();
+        assert_eq!((first.start_secs, first.text.as_str()), (2, "Hello"));
+        let second = transcribing.next().await.unwrap().unwrap();
+        assert_eq!((second.start_secs, second.text.as_str()), (5, "there"));
+        assert!(transcribing.next().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn timestamps_follow_the_untrimmed_audio() {
+        let mut audio = vec![0.0; 3 * vad::SAMPLE_RATE];
+        audio.extend((0..2 * vad::SAMPLE_RATE).map(|i| (i as f32 / 10.0).sin() * 0.5));
+        let config = vad::Config {
+            aggressiveness: Some(vad::Aggressiveness::Medium),
+        };
+        // 3s of silence minus the 0.3s of padding that the trimming leaves behind
+        let trimmed = vad::trim_silence(config, audio);
+        assert_eq!(trimmed.offset_centis, 270);
+
+        // The backend only ever sees the trimmed audio, so its timestamps start from there
+        let backend = MockBackend {
+            segments: vec![(0, " Speech"), (100, " starts")],
+            ..Default::default()
+        };
+        let (mut transcribing, mut fut) =
+            start(backend, Settings::default(), Duration::from_secs(5));
+        fut.audio_data = trimmed.audio;
+        fut.offset_centis = trimmed.offset_centis;
+        tokio::spawn(fut.finish_transcription());
 
-    Ok(())
+        let first = transcribing.next().await.unwrap().unwrap();
+        let second = transcribing.next().await.unwrap().unwrap();
+        assert_eq!((first.start_secs, second.start_secs), (2, 3));
+    }
+
+    #[tokio::test]
+    async fn slow_backend_times_out() {
+        let backend = MockBackend {
+            delay: Duration::from_secs(10),
+            segments: vec![(0, "Too late")],
+            ..Default::default()
+        };
+        let aborted = Arc::clone(&backend.aborted);
+        let settings = Settings::default();
+        let (mut transcribing, fut) = start(backend, settings, Duration::from_millis(50));
+        let finished = tokio::spawn(fut.finish_transcription());
+
+        assert!(matches!(
+            transcribing.next().await,
+            Err(HandlerError::TimedOut)
+        ));
+        // Doesn't count against the worker's circuit breaker
+        assert!(finished.await.unwrap());
+        // and doesn't keep running in the background
+        assert!(noticed_abort(&aborted).await);
+    }
+
+    #[tokio::test]
+    async fn leaving_aborts_the_backend() {
+        let backend = MockBackend {
+            delay: Duration::from_secs(10),
+            ..Default::default()
+        };
+        let aborted = Arc::clone(&backend.aborted);
+        let (transcribing, fut) = start(backend, Settings::default(), Duration::from_secs(60));
+        let finished = tokio::spawn(fut.finish_transcription());
+
+        drop(transcribing);
+        let finished = time::timeout(Duration::from_secs(1), finished).await;
+        // Doesn't count against the worker's circuit breaker either
+        assert!(finished.unwrap().unwrap());
+        assert!(noticed_abort(&aborted).await);
+    }
+
+    #[tokio::test]
+    async fn prompt_reaches_the_backend() {
+        let backend = MockBackend::default();
+        let seen = Arc::clone(&backend.seen);
+        let settings = Settings {
+            prompt: Some("Rambot, Telegram, whisper.cpp".to_owned()),
+            ..Default::default()
+        };
+        let (mut transcribing, fut) = start(backend, settings, Duration::from_secs(5));
+        tokio::spawn(fut.finish_transcription());
+
+        assert!(transcribing.next().await.unwrap().is_none());
+        let seen = seen.lock().unwrap().take().unwrap();
+        assert_eq!(
+            seen.prompt.as_deref(),
+            Some("Rambot, Telegram, whisper.cpp")
+        );
+    }
 }
@@ -0,0 +1,147 @@
+//! A tiny energy-based voice activity detector for trimming silence off the ends of voice messages
+//!
+//! Whisper both wastes time on long stretches of silence and likes to hallucinate text into them,
+//! so we chop off the quiet bits at the start and end before handing the audio over. Only the
+//! ends get trimmed which keeps things simple when mapping timestamps back onto the original audio
+
+use std::ops::Range;
+
+/// 16kHz audio, so this is 30ms frames
+const FRAME_LEN: usize = 480;
+/// Keep a bit of audio around the detected speech so that we don't clip soft onsets and endings
+const PADDING_FRAMES: usize = 10;
+pub const SAMPLE_RATE: usize = 16_000;
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Config {
+    /// `None` leaves the audio untouched
+    pub aggressiveness: Option<Aggressiveness>,
+}
+
+impl Config {
+    pub fn from_env() -> Self {
+        let aggressiveness = match std::env::var("RAMBOT_VAD_AGGRESSIVENESS") {
+            Ok(level) => match level.parse() {
+                Ok(0) => None,
+                Ok(1) => Some(Aggressiveness::Low),
+                Ok(2) => Some(Aggressiveness::Medium),
+                Ok(3) => Some(Aggressiveness::High),
+                Ok(_) | Err(_) => {
+                    log::warn!(
+                        "Ignoring invalid RAMBOT_VAD_AGGRESSIVENESS {level:?}. Expected 0-3"
+                    );
+                    None
+                }
+            },
+            Err(_) => None,
+        };
+
+        Self { aggressiveness }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Aggressiveness {
+    Low,
+    Medium,
+    High,
+}
+
+impl Aggressiveness {
+    /// Frames quieter than this fraction of the loudest frame's energy count as silence
+    fn threshold(self) -> f32 {
+        match self {
+            Self::Low => 0.01,
+            Self::Medium => 0.03,
+            Self::High => 0.08,
+        }
+    }
+}
+
+/// Audio with the silence trimmed off along with where it started in the original audio
+pub struct Trimmed {
+    pub audio: Vec<f32>,
+    /// Offset of the trimmed audio from the start of the original audio in centi-seconds (matching
+    /// whisper's segment timestamps)
+    pub offset_centis: i64,
+}
+
+pub fn trim_silence(config: Config, audio: Vec<f32>) -> Trimmed {
+    let untouched = |audio| Trimmed {
+        audio,
+        offset_centis: 0,
+    };
+    let Some(aggressiveness) = config.aggressiveness else {
+        return untouched(audio);
+    };
+    let Some(voiced) = voiced_range(&audio, aggressiveness) else {
+        // Either it's all silence or there's nothing there at all. Let whisper sort it out
+        return untouched(audio);
+    };
+
+    let offset_centis = (voiced.start * 100 / SAMPLE_RATE) as i64;
+    log::debug!(
+        "Trimmed {} of {} samples of silence",
+        audio.len() - voiced.len(),
+        audio.len()
+    );
+    Trimmed {
+        audio: audio[voiced].to_owned(),
+        offset_centis,
+    }
+}
+
+/// The sample range spanning from the first to the last voiced frame (with padding)
+fn voiced_range(audio: &[f32], aggressiveness: Aggressiveness) -> Option<Range<usize>> {
+    let energies: Vec<f32> = audio
+        .chunks(FRAME_LEN)
+        .map(|frame| frame.iter().map(|sample| sample * sample).sum::<f32>() / frame.len() as f32)
+        .collect();
+    let peak = energies.iter().copied().fold(0.0, f32::max);
+    if peak <= 0.0 {
+        return None;
+    }
+
+    let cutoff = peak * aggressiveness.threshold();
+    let first = energies.iter().position(|&energy| energy >= cutoff)?;
+    let last = energies.iter().rposition(|&energy| energy >= cutoff)?;
+    let start = first.saturating_sub(PADDING_FRAMES) * FRAME_LEN;
+    let end = ((last + 1 + PADDING_FRAMES) * FRAME_LEN).min(audio.len());
+    Some(start..end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `silent_secs` of silence followed by `voiced_secs` of a loud tone
+    fn silence_then_tone(silent_secs: usize, voiced_secs: usize) -> Vec<f32> {
+        let mut audio = vec![0.0; silent_secs * SAMPLE_RATE];
+        audio.extend((0..voiced_secs * SAMPLE_RATE).map(|i| (i as f32 / 10.0).sin() * 0.5));
+        audio
+    }
+
+    #[test]
+    fn trimmed_prefix_becomes_the_offset() {
+        let config = Config {
+            aggressiveness: Some(Aggressiveness::Medium),
+        };
+        let Trimmed {
+            audio,
+            offset_centis,
+        } = trim_silence(config, silence_then_tone(3, 2));
+
+        // The padding keeps a little of the silence before the tone
+        let padding_centis = (PADDING_FRAMES * FRAME_LEN * 100 / SAMPLE_RATE) as i64;
+        assert_eq!(offset_centis, 300 - padding_centis);
+        assert_eq!(audio.len(), (2 * SAMPLE_RATE) + PADDING_FRAMES * FRAME_LEN);
+    }
+
+    #[test]
+    fn untrimmed_audio_has_no_offset() {
+        let audio = silence_then_tone(3, 2);
+        let trimmed = trim_silence(Config::default(), audio.clone());
+        assert_eq!(trimmed.offset_centis, 0);
+        assert_eq!(trimmed.audio, audio);
+    }
+}
@@ -0,0 +1,384 @@
+//! Transcribes locally with whisper.cpp
+
+use std::{
+    ffi::{c_int, c_void, CStr},
+    ops::Range,
+    panic::{self, AssertUnwindSafe},
+};
+
+use super::{
+    backend::{Abort, Backend, BackendFut, Job, Settings},
+    state_machine::Update,
+    vad, Config, DetectedLanguage, Language,
+};
+use crate::{
+    db::ModelSize,
+    utils::{self, SegmentCallbackData, Word},
+    HandlerError, HandlerResult, UserError,
+};
+
+use tokio::sync::mpsc;
+use whisper_rs::{
+    whisper_rs_sys, FullParams, SamplingStrategy, WhisperContext, WhisperContextParameters,
+    WhisperState, WhisperSysContext, WhisperSysState, WhisperToken,
+};
+
+/// Whisper works on 30 second windows, so that's the most that a single detection covers
+const MIXED_WINDOW_SAMPLES: usize = 30 * vad::SAMPLE_RATE;
+const MIN_MIXED_WINDOW_SAMPLES: usize = 5 * vad::SAMPLE_RATE;
+
+pub struct Whisper {
+    config: Config,
+}
+
+impl Whisper {
+    pub fn new(config: Config) -> Self {
+        Self { config }
+    }
+}
+
+impl Backend for Whisper {
+    fn describe(&self) -> String {
+        "local whisper.cpp".to_owned()
+    }
+
+    fn check_model(&self, model: ModelSize) -> HandlerResult {
+        super::model_path(model).map(drop)
+    }
+
+    fn transcribe(&self, job: Job) -> BackendFut<'_> {
+        let config = self.config;
+        Box::pin(async move {
+            // Dropping this doesn't stop the blocking thread. That's left to the job's abort which
+            // whisper checks in on while it runs
+            tokio::task::spawn_blocking(move || run_sync_process(config, job))
+                .await
+                .map_err(HandlerError::worker_died)?
+        })
+    }
+}
+
+fn run_sync_process(config: Config, job: Job) -> HandlerResult {
+    let Job {
+        job_id,
+        audio,
+        offset_centis,
+        settings,
+        updates,
+        abort,
+    } = job;
+    let log_prefix = job_id.log_prefix();
+
+    let model_path = super::model_path(settings.model)?;
+    let params = WhisperContextParameters::new();
+    let ctx = WhisperContext::new_with_params(model_path.to_str().unwrap(), params)?;
+    let mut state = ctx.create_state().unwrap();
+
+    // A single encoder pass over the start of the audio is a lot cheaper than a full
+    // transcription, so check that there's actually something to transcribe first
+    let no_speech_prob = no_speech_prob(&ctx, &mut state, &audio, config.threads.into())?;
+    log::debug!("{log_prefix} No speech probability: {no_speech_prob:.02}");
+    if no_speech_prob > config.no_speech_threshold {
+        return Err(UserError::NoSpeechDetected.into());
+    }
+    // Only the full transcription can be interrupted, so check in between the other steps
+    abort.check()?;
+    // The mel spectrogram is already there from the no speech check, so this is pretty cheap.
+    // Passing the result along also keeps `state.full()` from detecting it all over again
+    let language = match config.language {
+        Language::Fixed(lang) => lang,
+        Language::Detect => {
+            let (lang, detected) = detect_language(&state, config.threads.into())?;
+            log::debug!("{log_prefix} Detected language: {detected:?}");
+            let _ = updates.blocking_send(Ok(Update::Language(detected)));
+            lang
+        }
+        Language::Mixed => {
+            for window in mixed_windows(audio.len()) {
+                abort.check()?;
+                let window_audio = &audio[window.clone()];
+                state.pcm_to_mel(window_audio, config.threads.into())?;
+                let (lang, detected) = detect_language(&state, config.threads.into())?;
+                log::debug!("{log_prefix} Window {window:?} detected as {detected:?}");
+                let sink = SegmentSink {
+                    updates: updates.clone(),
+                    offset_centis: offset_centis + samples_to_centis(window.start),
+                    token_eot: ctx.token_eot(),
+                    language: Some(lang),
+                    word_timestamps: config.word_timestamps,
+                };
+                run_full(
+                    &mut state,
+                    config,
+                    &settings,
+                    lang,
+                    &sink,
+                    &abort,
+                    window_audio,
+                )?;
+            }
+            return abort.check();
+        }
+    };
+    abort.check()?;
+
+    let sink = SegmentSink {
+        updates,
+        offset_centis,
+        token_eot: ctx.token_eot(),
+        language: None,
+        word_timestamps: config.word_timestamps,
+    };
+    run_full(
+        &mut state, config, &settings, language, &sink, &abort, &audio,
+    )?;
+    abort.check()
+}
+
+/// Actually runs the model on the audio. Lines get streamed out through the sink as they're
+/// finished
+fn run_full(
+    state: &mut WhisperState,
+    config: Config,
+    settings: &Settings,
+    language: &str,
+    // Has to outlive `state.full()` since whisper holds a pointer to it the whole time
+    sink: &SegmentSink,
+    // Same deal as with `sink`
+    abort: &Abort,
+    audio: &[f32],
+) -> HandlerResult {
+    let mut params = full_params(config, settings);
+    params.set_language(Some(language));
+    if let Some(prompt) = &settings.prompt {
+        params.set_initial_prompt(prompt);
+    }
+    // NOTE: whisper-rs' `*_callback_safe()` setters hand whisper a pointer to the closure before
+    // moving it into a box which leaves the pointer dangling. That's what was segfaulting the old
+    // progress callback, so we set the raw callback up ourselves instead
+    // SAFETY: `sink` and `abort` outlive `params` which only get used for the `state.full()` call
+    // below and the callbacks only read from the state that they're handed
+    unsafe {
+        params.set_new_segment_callback(Some(on_new_segments));
+        params.set_new_segment_callback_user_data(sink as *const SegmentSink as *mut c_void);
+        params.set_abort_callback(Some(should_abort));
+        params.set_abort_callback_user_data(abort as *const Abort as *mut c_void);
+    }
+
+    let res = state.full(params, audio);
+    // Whisper bails with a generic error when it gets aborted
+    abort.check()?;
+    res?;
+    Ok(())
+}
+
+/// Splits the audio into whisper-sized windows for detecting the language of each one
+///
+/// A short leftover at the end gets folded into the window before it since there's too little
+/// audio there to reliably detect anything
+fn mixed_windows(len: usize) -> Vec<Range<usize>> {
+    let mut windows: Vec<_> = (0..len)
+        .step_by(MIXED_WINDOW_SAMPLES)
+        .map(|start| start..len.min(start + MIXED_WINDOW_SAMPLES))
+        .collect();
+    if let [.., prev, last] = windows.as_slice() {
+        if last.len() < MIN_MIXED_WINDOW_SAMPLES {
+            let merged = prev.start..last.end;
+            windows.truncate(windows.len() - 2);
+            windows.push(merged);
+        }
+    }
+    windows
+}
+
+fn samples_to_centis(samples: usize) -> i64 {
+    i64::try_from(samples * 100 / vad::SAMPLE_RATE).unwrap_or(i64::MAX)
+}
+
+/// Whisper's params minus the segment callback which has to be set up by the caller
+fn full_params<'a, 'b>(config: Config, settings: &Settings) -> FullParams<'a, 'b> {
+    let strategy = match config.beam_size.filter(|_| !settings.greedy) {
+        Some(beam_size) => SamplingStrategy::BeamSearch {
+            beam_size: beam_size.into(),
+            // Ignored by whisper.cpp, so this just leaves it at its default
+            patience: -1.0,
+        },
+        None => SamplingStrategy::Greedy { best_of: 1 },
+    };
+    let mut params = FullParams::new(strategy);
+    params.set_n_threads(config.threads.into());
+    params.set_no_context(true);
+    params.set_translate(settings.translate);
+    params.set_token_timestamps(config.word_timestamps);
+    params
+}
+
+/// Everything the new segment callback needs to pass segments along
+struct SegmentSink {
+    updates: mpsc::Sender<HandlerResult<Update>>,
+    /// Where the audio starts in the original audio after trimming off any leading silence
+    offset_centis: i64,
+    token_eot: WhisperToken,
+    /// Tags every line when the language can change throughout the audio
+    language: Option<&'static str>,
+    /// Whether the tokens' timestamps are worth reading
+    word_timestamps: bool,
+}
+
+/// Called by whisper from within `state.full()` each time it finishes new segments
+unsafe extern "C" fn on_new_segments(
+    ctx: *mut WhisperSysContext,
+    state: *mut WhisperSysState,
+    n_new: c_int,
+    user_data: *mut c_void,
+) {
+    // Unwinding across the FFI boundary is undefined behavior, so a panic only costs the segments
+    let forwarded = panic::catch_unwind(AssertUnwindSafe(|| {
+        forward_new_segments(ctx, state, n_new, user_data);
+    }));
+    if forwarded.is_err() {
+        log::error!("Panicked while forwarding new segments. Dropping them");
+    }
+}
+
+unsafe fn forward_new_segments(
+    ctx: *mut WhisperSysContext,
+    state: *mut WhisperSysState,
+    n_new: c_int,
+    user_data: *mut c_void,
+) {
+    let sink = &*(user_data as *const SegmentSink);
+    let n_segments = whisper_rs_sys::whisper_full_n_segments_from_state(state);
+    for i in (n_segments - n_new).max(0)..n_segments {
+        let start_timestamp = whisper_rs_sys::whisper_full_get_segment_t0_from_state(state, i);
+        let end_timestamp = whisper_rs_sys::whisper_full_get_segment_t1_from_state(state, i);
+        let text = whisper_rs_sys::whisper_full_get_segment_text_from_state(state, i);
+        // Panicking across the FFI boundary would abort, so skip anything unexpected instead
+        if text.is_null() {
+            continue;
+        }
+        let text = CStr::from_ptr(text).to_string_lossy().into_owned();
+        let segment = SegmentCallbackData {
+            start_timestamp: start_timestamp + sink.offset_centis,
+            end_timestamp: end_timestamp + sink.offset_centis,
+            text,
+            confidence: segment_confidence(state, i, sink.token_eot),
+            language: sink.language,
+            words: sink
+                .word_timestamps
+                .then(|| segment_words(ctx, state, i, sink)),
+        };
+        // We're on a blocking thread outside of the runtime, so no need to go through a handle
+        let _ = sink.updates.blocking_send(Ok(segment.into()));
+    }
+}
+
+/// Polled by whisper from within `state.full()` to see if it should stop early
+unsafe extern "C" fn should_abort(user_data: *mut c_void) -> bool {
+    let abort = &*(user_data as *const Abort);
+    abort.is_aborted()
+}
+
+/// Average probability across the segment's text tokens
+unsafe fn segment_confidence(
+    state: *mut WhisperSysState,
+    segment: c_int,
+    token_eot: WhisperToken,
+) -> f32 {
+    let n_tokens = whisper_rs_sys::whisper_full_n_tokens_from_state(state, segment);
+    let probs: Vec<_> = (0..n_tokens)
+        // Timestamps and other special tokens don't say anything about the text
+        .filter(|&i| {
+            whisper_rs_sys::whisper_full_get_token_id_from_state(state, segment, i) < token_eot
+        })
+        .map(|i| whisper_rs_sys::whisper_full_get_token_p_from_state(state, segment, i))
+        .collect();
+    if probs.is_empty() {
+        1.0
+    } else {
+        probs.iter().sum::<f32>() / probs.len() as f32
+    }
+}
+
+/// When each of the segment's words was said
+unsafe fn segment_words(
+    ctx: *mut WhisperSysContext,
+    state: *mut WhisperSysState,
+    segment: c_int,
+    sink: &SegmentSink,
+) -> Vec<Word> {
+    let n_tokens = whisper_rs_sys::whisper_full_n_tokens_from_state(state, segment);
+    let tokens = (0..n_tokens).filter_map(|i| {
+        let data = whisper_rs_sys::whisper_full_get_token_data_from_state(state, segment, i);
+        if data.id >= sink.token_eot {
+            return None;
+        }
+        let text = whisper_rs_sys::whisper_full_get_token_text_from_state(ctx, state, segment, i);
+        if text.is_null() {
+            return None;
+        }
+        Some(Word {
+            start_centis: data.t0 + sink.offset_centis,
+            end_centis: data.t1 + sink.offset_centis,
+            text: CStr::from_ptr(text).to_string_lossy().into_owned(),
+        })
+    });
+    utils::tokens_to_words(tokens)
+}
+
+/// The most likely language for the start of the audio as its code along with its details
+fn detect_language(
+    state: &WhisperState,
+    threads: usize,
+) -> HandlerResult<(&'static str, DetectedLanguage)> {
+    let probs = state.lang_detect(0, threads)?;
+    let (id, probability) = probs
+        .into_iter()
+        .enumerate()
+        .max_by(|(_, a), (_, b)| a.total_cmp(b))
+        .expect("There's always at least one language");
+    let id = i32::try_from(id).unwrap();
+    let lang = whisper_rs::get_lang_str(id).expect("Ids come from whisper");
+    let name = whisper_rs::get_lang_str_full(id).expect("Ids come from whisper");
+    Ok((lang, DetectedLanguage { name, probability }))
+}
+
+/// The odds of the first window of audio being without speech
+///
+/// This mirrors how upstream whisper gets its `no_speech_prob`. It's the probability of the
+/// no-speech token being predicted right after the start-of-transcript token
+fn no_speech_prob(
+    ctx: &WhisperContext,
+    state: &mut WhisperState,
+    audio: &[f32],
+    threads: usize,
+) -> HandlerResult<f32> {
+    state.pcm_to_mel(audio, threads)?;
+    state.encode(0, threads)?;
+    state.decode(&[ctx.token_sot()], 0, threads)?;
+
+    let logits = state.get_logits()?;
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let total: f32 = logits.iter().map(|logit| (logit - max).exp()).sum();
+    let no_speech = logits[usize::try_from(ctx.token_nosp()).unwrap()];
+    Ok((no_speech - max).exp() / total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mixed_windows_cover_the_audio() {
+        let secs = |secs: usize| secs * vad::SAMPLE_RATE;
+        assert!(mixed_windows(0).is_empty());
+        let short = mixed_windows(secs(3));
+        assert_eq!((short.len(), &short[0]), (1, &(0..secs(3))));
+        assert_eq!(
+            mixed_windows(secs(70)),
+            [0..secs(30), secs(30)..secs(60), secs(60)..secs(70)]
+        );
+        // The short tail gets folded into the last full window
+        assert_eq!(mixed_windows(secs(62)), [0..secs(30), secs(30)..secs(62)]);
+    }
+}
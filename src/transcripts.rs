@@ -0,0 +1,179 @@
+//! Long-term storage for finished transcriptions so that chats can export them later
+//!
+//! This is kept apart from the db since that's just for settings and gets rewritten on every
+//! change. Instead each chat gets its own append-only file with one RON-encoded transcript per
+//! line. Nothing gets stored unless the chat has opted in
+
+use std::{
+    io,
+    path::PathBuf,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{HandlerResult, Line};
+
+use serde::{Deserialize, Serialize};
+use teloxide::types;
+use tokio::{fs, io::AsyncWriteExt, sync::Mutex};
+
+#[derive(Clone)]
+pub struct Store {
+    dir: PathBuf,
+    // Keeps concurrent appends from interleaving
+    write_lock: Arc<Mutex<()>>,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct Transcript {
+    pub voice_msg_id: i32,
+    /// Seconds since the unix epoch
+    pub transcribed_at: u64,
+    pub lines: Vec<StoredLine>,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct StoredLine {
+    pub start_secs: u32,
+    pub end_secs: u32,
+    pub text: String,
+}
+
+impl From<&Line> for StoredLine {
+    fn from(line: &Line) -> Self {
+        Self {
+            start_secs: line.start_secs,
+            end_secs: line.end_secs,
+            text: line.text.trim().to_owned(),
+        }
+    }
+}
+
+impl Store {
+    pub fn new(dir: PathBuf) -> Self {
+        Self {
+            dir,
+            write_lock: Default::default(),
+        }
+    }
+
+    fn chat_path(&self, chat_id: types::ChatId) -> PathBuf {
+        self.dir.join(format!("{chat_id}.ron"))
+    }
+
+    pub async fn save(
+        &self,
+        chat_id: types::ChatId,
+        voice_msg_id: types::MessageId,
+        lines: &[Line],
+    ) -> HandlerResult {
+        let transcribed_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs());
+        let transcript = Transcript {
+            voice_msg_id: voice_msg_id.0,
+            transcribed_at,
+            lines: lines.iter().map(Into::into).collect(),
+        };
+        // The default (non-pretty) config keeps it all on one line
+        let mut record = ron::to_string(&transcript).map_err(io::Error::other)?;
+        record.push('\n');
+
+        let _guard = self.write_lock.lock().await;
+        fs::create_dir_all(&self.dir).await?;
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.chat_path(chat_id))
+            .await?;
+        file.write_all(record.as_bytes()).await?;
+        Ok(())
+    }
+
+    /// All of the chat's stored transcripts from oldest to newest
+    pub async fn load(&self, chat_id: types::ChatId) -> HandlerResult<Vec<Transcript>> {
+        let contents = match fs::read_to_string(self.chat_path(chat_id)).await {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut transcripts = Vec::new();
+        for record in contents.lines().filter(|line| !line.trim().is_empty()) {
+            match ron::from_str(record) {
+                Ok(transcript) => transcripts.push(transcript),
+                // A partially written line shouldn't make the rest unreadable
+                Err(e) => log::warn!("Skipping unreadable transcript for chat {chat_id}: {e}"),
+            }
+        }
+        Ok(transcripts)
+    }
+
+    pub async fn delete(&self, chat_id: types::ChatId) -> HandlerResult {
+        let _guard = self.write_lock.lock().await;
+        match fs::remove_file(self.chat_path(chat_id)).await {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Renders the transcripts as a plain text document
+pub fn export_text(transcripts: &[Transcript]) -> String {
+    let mut text = String::new();
+    for transcript in transcripts {
+        text.push_str(&format!(
+            "Voice message {} (transcribed at {} UTC)\n",
+            transcript.voice_msg_id,
+            format_unix_time(transcript.transcribed_at)
+        ));
+        for line in &transcript.lines {
+            text.push_str(&format!(
+                "{:02}:{:02} {}\n",
+                line.start_secs / 60,
+                line.start_secs % 60,
+                line.text
+            ));
+        }
+        text.push('\n');
+    }
+    text
+}
+
+/// `YYYY-MM-DD HH:MM:SS` without pulling in a whole date crate
+fn format_unix_time(secs: u64) -> String {
+    let days = secs / 86_400;
+    let secs_of_day = secs % 86_400;
+    // Howard Hinnant's civil-from-days algorithm
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{year:04}-{month:02}-{day:02} {:02}:{:02}:{:02}",
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unix_times_format_as_dates() {
+        assert_eq!(format_unix_time(0), "1970-01-01 00:00:00");
+        // Leap days and the years that skip them
+        assert_eq!(format_unix_time(1_709_210_096), "2024-02-29 12:34:56");
+        assert_eq!(format_unix_time(4_107_542_400), "2100-03-01 00:00:00");
+        // Rolling over into a new year
+        assert_eq!(format_unix_time(946_684_799), "1999-12-31 23:59:59");
+        assert_eq!(format_unix_time(946_684_800), "2000-01-01 00:00:00");
+    }
+}
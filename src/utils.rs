@@ -1,43 +1,901 @@
-#[derive(Clone, Debug, PartialEq, Eq)]
+use std::{path::PathBuf, str::FromStr, sync::OnceLock};
+
+use crate::error::UserError;
+
+use serde::Serialize;
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
+
+const DEFAULT_LOW_CONFIDENCE_THRESHOLD: f32 = 0.5;
+const DEFAULT_LOW_CONFIDENCE_MARKER: &str = "⚠️";
+/// Merged lines never span longer than this so that the timestamps stay useful
+const MERGE_MAX_SECS: u32 = 10;
+pub const MAX_TEMPLATE_CHARS: usize = 100;
+
+static LINE_STYLE: OnceLock<LineStyle> = OnceLock::new();
+
+/// Where the bot keeps its database and models
+///
+/// `RAMBOT_DATA_DIR` takes priority (handy for pointing containers at a volume) and otherwise it's
+/// the `rambot` dir within the user's data dir
+pub fn data_dir() -> Option<PathBuf> {
+    match std::env::var_os("RAMBOT_DATA_DIR") {
+        Some(dir) if !dir.is_empty() => Some(PathBuf::from(dir)),
+        _ => dirs::data_dir().map(|dir| dir.join("rambot")),
+    }
+}
+
+/// Where voice messages get downloaded and converted
+///
+/// `RAMBOT_TMP_DIR` takes priority (handy when `/tmp` is a small tmpfs that long conversions would
+/// fill up) and otherwise it's the system's temp dir
+pub fn tmp_dir() -> PathBuf {
+    match std::env::var_os("RAMBOT_TMP_DIR") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => std::env::temp_dir(),
+    }
+}
+
+/// How lines get flagged when whisper wasn't too sure about them and when they get collapsed as
+/// repeats or merged together
+#[derive(Debug)]
+pub struct LineStyle {
+    pub low_confidence_threshold: f32,
+    /// `None` skips flagging lines entirely
+    pub low_confidence_marker: Option<String>,
+    /// Consecutive lines at least this similar get collapsed into one. Off (`None`) unless
+    /// configured since it can eat legitimately repeated speech. Around 0.9 catches whisper's usual
+    /// loops
+    pub repeat_similarity: Option<f32>,
+    /// Fragments get merged into the line before them up to this many chars. `None` keeps every
+    /// fragment as its own line
+    pub merge_max_chars: Option<usize>,
+}
+
+impl LineStyle {
+    pub fn from_env() -> Self {
+        let low_confidence_threshold = match std::env::var("RAMBOT_LOW_CONFIDENCE_THRESHOLD") {
+            Ok(threshold) => match threshold.parse() {
+                Ok(threshold) if (0.0..=1.0).contains(&threshold) => threshold,
+                _ => {
+                    log::warn!(
+                        "Ignoring invalid RAMBOT_LOW_CONFIDENCE_THRESHOLD {threshold:?}. \
+                        Expected 0.0-1.0"
+                    );
+                    DEFAULT_LOW_CONFIDENCE_THRESHOLD
+                }
+            },
+            Err(_) => DEFAULT_LOW_CONFIDENCE_THRESHOLD,
+        };
+        // Setting it to nothing is how you opt out of the marker
+        let low_confidence_marker = match std::env::var("RAMBOT_LOW_CONFIDENCE_MARKER") {
+            Ok(marker) if marker.trim().is_empty() => None,
+            Ok(marker) => Some(marker),
+            Err(_) => Some(DEFAULT_LOW_CONFIDENCE_MARKER.to_owned()),
+        };
+        let repeat_similarity = match std::env::var("RAMBOT_REPEAT_SIMILARITY") {
+            Ok(similarity) if similarity == "off" => None,
+            Ok(similarity) => match similarity.parse() {
+                Ok(similarity) if (0.0..=1.0).contains(&similarity) => Some(similarity),
+                _ => {
+                    log::warn!(
+                        "Ignoring invalid RAMBOT_REPEAT_SIMILARITY {similarity:?}. \
+                        Expected 0.0-1.0 or off"
+                    );
+                    None
+                }
+            },
+            Err(_) => None,
+        };
+        let merge_max_chars = match std::env::var("RAMBOT_MERGE_MAX_CHARS") {
+            Ok(max) => match max.parse() {
+                Ok(0) => None,
+                Ok(max) => Some(max),
+                Err(e) => {
+                    log::warn!("Ignoring invalid RAMBOT_MERGE_MAX_CHARS {max:?}: {e}");
+                    None
+                }
+            },
+            Err(_) => None,
+        };
+
+        Self {
+            low_confidence_threshold,
+            low_confidence_marker,
+            repeat_similarity,
+            merge_max_chars,
+        }
+    }
+
+    pub fn get() -> &'static Self {
+        LINE_STYLE.get_or_init(Self::from_env)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
 pub struct Line {
     pub start_secs: u32,
     pub end_secs: u32,
     pub text: String,
+    /// Average probability of the line's tokens
+    #[serde(skip)]
+    pub confidence: f32,
+    /// How many repeats of this line got collapsed into it
+    #[serde(skip)]
+    pub repeats: u32,
+    /// The whisper language code like `hi` when lines can differ in language
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub language: Option<&'static str>,
+    /// Only captured when word timestamps are turned on
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub words: Option<Vec<Word>>,
+}
+
+/// A single word along with when it was said in centiseconds
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Word {
+    pub start_centis: i64,
+    pub end_centis: i64,
+    pub text: String,
+}
+
+/// Glues whisper's tokens back together into words
+///
+/// Tokens are often just a piece of a word. The ones that start a new word lead with a space, so
+/// everything else gets tacked onto the word before it
+pub fn tokens_to_words(tokens: impl IntoIterator<Item = Word>) -> Vec<Word> {
+    let mut words: Vec<Word> = Vec::new();
+    for token in tokens {
+        match words.last_mut() {
+            Some(word) if !token.text.starts_with(' ') => {
+                word.text.push_str(&token.text);
+                word.end_centis = token.end_centis;
+            }
+            _ => words.push(Word {
+                text: token.text.trim_start().to_owned(),
+                ..token
+            }),
+        }
+    }
+    words.retain(|word| !word.text.is_empty());
+    words
+}
+
+/// One JSON object per line for anything that wants to consume transcriptions programmatically
+pub fn lines_to_jsonl(lines: &[Line]) -> String {
+    let mut jsonl = String::new();
+    for line in lines {
+        let object = serde_json::to_string(line).expect("Lines are always valid JSON");
+        jsonl.push_str(&object);
+        jsonl.push('\n');
+    }
+    jsonl
+}
+
+pub fn lines_to_srt(lines: &[Line]) -> String {
+    let cues: Vec<_> = lines
+        .iter()
+        .enumerate()
+        .map(|(i, line)| line.to_srt_cue(i + 1))
+        .collect();
+    cues.join("\n")
+}
+
+pub fn lines_to_vtt(lines: &[Line]) -> String {
+    let mut vtt = "WEBVTT\n".to_owned();
+    for line in lines {
+        let (start, end) = line.cue_span();
+        vtt.push_str(&format!(
+            "\n{} --> {}\n{}\n",
+            subtitle_timestamp(start, '.'),
+            subtitle_timestamp(end, '.'),
+            line.text
+        ));
+    }
+    vtt
+}
+
+/// `HH:MM:SS<sep>mmm` where SRT separates the millis with `,` and VTT with `.`
+fn subtitle_timestamp(secs: u32, millis_sep: char) -> String {
+    format!(
+        "{:02}:{:02}:{:02}{millis_sep}000",
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
 }
 
 impl Line {
-    pub fn to_telegram_line(&self) -> String {
+    /// A numbered SRT cue (SRT numbering starts at 1)
+    pub fn to_srt_cue(&self, index: usize) -> String {
+        let (start, end) = self.cue_span();
         format!(
-            "{:02}:{:02} {}",
-            self.start_secs / 60,
-            self.start_secs % 60,
+            "{index}\n{} --> {}\n{}\n",
+            subtitle_timestamp(start, ','),
+            subtitle_timestamp(end, ','),
             self.text
         )
     }
+
+    /// Collapses `next` into this line if it's just a repeat of it
+    ///
+    /// Whisper loves to hallucinate the same phrase over and over on silence and noise ("Thanks for
+    /// watching" x30), so runs of near-identical lines get squashed down to one. Only once
+    /// `RAMBOT_REPEAT_SIMILARITY` opts into it
+    pub fn absorb_repeat(&mut self, next: &Line) -> bool {
+        LineStyle::get()
+            .repeat_similarity
+            .is_some_and(|threshold| self.absorb_similar(next, threshold))
+    }
+
+    fn absorb_similar(&mut self, next: &Line, threshold: f32) -> bool {
+        if similarity(&self.text, &next.text) < threshold {
+            return false;
+        }
+
+        self.end_secs = next.end_secs;
+        self.repeats += 1;
+        true
+    }
+
+    /// Merges `next` onto the end of this line if this one doesn't end a sentence yet
+    ///
+    /// Whisper sometimes splits speech into a pile of one or two word segments that each get their
+    /// own timestamp. These get glued back together until there's a sentence boundary or we hit
+    /// the char or duration budget
+    pub fn absorb_fragment(&mut self, next: &Line) -> bool {
+        LineStyle::get()
+            .merge_max_chars
+            .is_some_and(|max_chars| self.absorb_fragment_within(next, max_chars))
+    }
+
+    fn absorb_fragment_within(&mut self, next: &Line, max_chars: usize) -> bool {
+        // Collapsed repeats render with a count that would be wrong for the merged text
+        if self.repeats > 0
+            || next.repeats > 0
+            || self.language != next.language
+            || ends_sentence(&self.text)
+        {
+            return false;
+        }
+        let merged_len = self.text.chars().count() + 1 + next.text.chars().count();
+        if merged_len > max_chars || next.end_secs.saturating_sub(self.start_secs) > MERGE_MAX_SECS
+        {
+            return false;
+        }
+
+        self.text.push(' ');
+        self.text.push_str(&next.text);
+        self.end_secs = next.end_secs;
+        if let (Some(words), Some(next_words)) = (&mut self.words, &next.words) {
+            words.extend_from_slice(next_words);
+        }
+        // Keep it flagged if any part of it was shaky
+        self.confidence = self.confidence.min(next.confidence);
+        true
+    }
+
+    /// Lines only have second precision, so short lines can start and end on the same second.
+    /// Players tend to skip cues that don't last any time at all
+    fn cue_span(&self) -> (u32, u32) {
+        (self.start_secs, self.end_secs.max(self.start_secs + 1))
+    }
+
+    /// Renders the line as MarkdownV2 with a monospace timestamp, laid out by the `template` when
+    /// there is one
+    ///
+    /// With a `wrap_width` the text gets soft-wrapped to rows of at most that many columns with
+    /// only the first row getting the timestamp. The first row is that much shorter to leave room
+    /// for it
+    pub fn to_telegram_line(
+        &self,
+        wrap_width: Option<usize>,
+        template: Option<&LineTemplate>,
+    ) -> String {
+        let timestamp = format!("{:02}:{:02}", self.start_secs / 60, self.start_secs % 60);
+        let language = self.language.map(|language| format!("[{language}]"));
+        let text = match wrap_width {
+            Some(width) => {
+                let ts_indent = match template {
+                    Some(template) => template.text_indent(text_width(&timestamp)),
+                    // The timestamp and the space after it
+                    None => text_width(&timestamp) + 1,
+                };
+                let lang_indent = language.as_deref().map_or(0, |tag| text_width(tag) + 1);
+                let rows: Vec<_> = wrap_text(&self.text, width, ts_indent + lang_indent)
+                    .iter()
+                    .map(|row| escape_markdown_v2(row))
+                    .collect();
+                rows.join("\n")
+            }
+            None => escape_markdown_v2(&self.text),
+        };
+        let timestamp = format!("`{timestamp}`");
+        let mut line = String::new();
+        if let Some(language) = &language {
+            line.push_str(&format!("_{}_ ", escape_markdown_v2(language)));
+        }
+        line.push_str(&text);
+        if self.repeats > 0 {
+            line.push_str(&format!(" _\\(repeated {}x\\)_", self.repeats + 1));
+        }
+        let style = LineStyle::get();
+        if let Some(marker) = &style.low_confidence_marker {
+            if self.confidence < style.low_confidence_threshold {
+                line.push(' ');
+                line.push_str(&escape_markdown_v2(marker));
+            }
+        }
+        match template {
+            Some(template) => template.render(&timestamp, &line),
+            None => format!("{timestamp} {line}"),
+        }
+    }
+}
+
+/// A layout for transcription lines where `{ts}` is the timestamp and `{text}` is everything else
+#[derive(Clone, Debug, PartialEq)]
+pub struct LineTemplate(Vec<TemplatePart>);
+
+#[derive(Clone, Debug, PartialEq)]
+enum TemplatePart {
+    Literal(String),
+    Timestamp,
+    Text,
+}
+
+impl LineTemplate {
+    /// Both get escaped for MarkdownV2 already
+    fn render(&self, timestamp: &str, text: &str) -> String {
+        self.0
+            .iter()
+            .map(|part| match part {
+                TemplatePart::Literal(literal) => escape_markdown_v2(literal),
+                TemplatePart::Timestamp => timestamp.to_owned(),
+                TemplatePart::Text => text.to_owned(),
+            })
+            .collect()
+    }
+
+    /// How many columns come before the text on its row given how wide the timestamp is
+    fn text_indent(&self, timestamp_width: usize) -> usize {
+        self.0
+            .iter()
+            .take_while(|part| **part != TemplatePart::Text)
+            .fold(0, |indent, part| match part {
+                TemplatePart::Literal(literal) => match literal.rsplit_once('\n') {
+                    // The text starts out on a fresh row
+                    Some((_, last_row)) => text_width(last_row),
+                    None => indent + text_width(literal),
+                },
+                TemplatePart::Timestamp => indent + timestamp_width,
+                TemplatePart::Text => indent,
+            })
+    }
+}
+
+impl FromStr for LineTemplate {
+    type Err = UserError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.chars().count() > MAX_TEMPLATE_CHARS {
+            return Err(UserError::InvalidTemplate(format!(
+                "it can be at most {MAX_TEMPLATE_CHARS} characters long"
+            )));
+        }
+
+        let mut parts = Vec::new();
+        let mut rest = s;
+        while let Some(start) = rest.find(['{', '}']) {
+            if start > 0 {
+                parts.push(TemplatePart::Literal(rest[..start].to_owned()));
+            }
+            rest = &rest[start..];
+            let part = if let Some(after) = rest.strip_prefix("{ts}") {
+                rest = after;
+                TemplatePart::Timestamp
+            } else if let Some(after) = rest.strip_prefix("{text}") {
+                rest = after;
+                TemplatePart::Text
+            } else {
+                return Err(UserError::InvalidTemplate(
+                    "the only placeholders are {ts} and {text}".to_owned(),
+                ));
+            };
+            parts.push(part);
+        }
+        if !rest.is_empty() {
+            parts.push(TemplatePart::Literal(rest.to_owned()));
+        }
+
+        if !parts.contains(&TemplatePart::Text) {
+            return Err(UserError::InvalidTemplate(
+                "it needs a {text} placeholder".to_owned(),
+            ));
+        }
+        Ok(Self(parts))
+    }
+}
+
+/// Covers the usual western and CJK sentence enders
+fn ends_sentence(text: &str) -> bool {
+    text.trim_end_matches(['"', '\'', ')', '»', '”'])
+        .ends_with(['.', '!', '?', '…', '。', '！', '？'])
+}
+
+/// How alike two lines are from 0.0 to 1.0, ignoring case and punctuation
+fn similarity(a: &str, b: &str) -> f32 {
+    let normalize = |s: &str| -> Vec<char> {
+        let words: Vec<_> = s
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|word| !word.is_empty())
+            .collect();
+        words.join(" ").to_lowercase().chars().collect()
+    };
+    let (a, b) = (normalize(a), normalize(b));
+    let longest = a.len().max(b.len());
+    if longest == 0 {
+        return 1.0;
+    }
+
+    // Plain old Levenshtein distance. Lines are short enough that this is plenty fast
+    let mut prev_row: Vec<_> = (0..=b.len()).collect();
+    let mut row = vec![0; b.len() + 1];
+    for (i, a_char) in a.iter().enumerate() {
+        row[0] = i + 1;
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = prev_row[j] + usize::from(a_char != b_char);
+            row[j + 1] = substitution.min(prev_row[j + 1] + 1).min(row[j] + 1);
+        }
+        std::mem::swap(&mut prev_row, &mut row);
+    }
+    let distance = prev_row[b.len()];
+    1.0 - distance as f32 / longest as f32
+}
+
+/// Breaks text into rows of at most `width` columns with the first row starting `indent` columns
+/// in
+///
+/// Rows break on whitespace when possible. Words that are too long on their own (common with CJK
+/// text which doesn't use spaces) get split between graphemes instead
+pub fn wrap_text(text: &str, width: usize, indent: usize) -> Vec<String> {
+    let mut rows = Vec::new();
+    let mut row = String::new();
+    let mut row_width = 0;
+    let first_limit = width.saturating_sub(indent);
+    let row_limit = |rows: &[String]| if rows.is_empty() { first_limit } else { width };
+    for word in text.split_whitespace() {
+        let word_width = text_width(word);
+        // Words that would fit on a row of their own start out on the next row instead of getting
+        // split to fit in whatever's left of the first one
+        if rows.is_empty() && row_width == 0 && word_width > first_limit && word_width <= width {
+            rows.push(String::new());
+        }
+        if row_width > 0 {
+            if row_width + 1 + word_width <= row_limit(&rows) {
+                row.push(' ');
+                row.push_str(word);
+                row_width += 1 + word_width;
+                continue;
+            }
+            rows.push(std::mem::take(&mut row));
+            row_width = 0;
+        }
+
+        for grapheme in word.graphemes(true) {
+            let grapheme_width = grapheme.width();
+            if row_width > 0 && row_width + grapheme_width > row_limit(&rows) {
+                rows.push(std::mem::take(&mut row));
+                row_width = 0;
+            }
+            row.push_str(grapheme);
+            row_width += grapheme_width;
+        }
+    }
+    if !row.is_empty() {
+        rows.push(row);
+    }
+    rows
+}
+
+/// How many columns the text takes up
+fn text_width(text: &str) -> usize {
+    text.graphemes(true).map(UnicodeWidthStr::width).sum()
+}
+
+/// Escapes text so that it shows up as-is in a MarkdownV2 message
+///
+/// `teloxide::utils::markdown::escape()` misses backslashes which would otherwise get eaten or
+/// fail the whole message when whisper happens to spit one out
+pub fn escape_markdown_v2(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(
+            c,
+            '_' | '*'
+                | '['
+                | ']'
+                | '('
+                | ')'
+                | '~'
+                | '`'
+                | '>'
+                | '#'
+                | '+'
+                | '-'
+                | '='
+                | '|'
+                | '{'
+                | '}'
+                | '.'
+                | '!'
+                | '\\'
+        ) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Segment timestamps are in centi-seconds. Whisper occasionally hands back a slightly negative
+/// start, so anything out of range gets clamped instead of taking down the worker
+fn centis_to_secs(centis: i64) -> u32 {
+    u32::try_from((centis / 100).max(0)).unwrap_or(u32::MAX)
 }
 
-// TODO: need streaming support for `whisper_rs`
 pub struct SegmentCallbackData {
-    pub segment: i32,
     pub start_timestamp: i64,
     pub end_timestamp: i64,
     pub text: String,
+    pub confidence: f32,
+    /// Only set when the language gets detected for each part of the audio
+    pub language: Option<&'static str>,
+    pub words: Option<Vec<Word>>,
 }
 
 impl From<SegmentCallbackData> for Line {
     fn from(segment: SegmentCallbackData) -> Self {
         let SegmentCallbackData {
-            segment: _,
             start_timestamp,
             end_timestamp,
             text,
+            confidence,
+            language,
+            words,
         } = segment;
 
         Self {
-            // Segment timestamps are in centi-seconds
-            start_secs: (start_timestamp / 100).try_into().unwrap(),
-            end_secs: (end_timestamp / 100).try_into().unwrap(),
-            text,
+            start_secs: centis_to_secs(start_timestamp),
+            end_secs: centis_to_secs(end_timestamp),
+            // Whisper likes to lead its segments with a space and sometimes doubles them up inside
+            text: text.split_whitespace().collect::<Vec<_>>().join(" "),
+            confidence,
+            repeats: 0,
+            language,
+            words,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn line_at(start_timestamp: i64, end_timestamp: i64) -> Line {
+        line_with(start_timestamp, end_timestamp, " Hi")
+    }
+
+    fn line_with(start_timestamp: i64, end_timestamp: i64, text: &str) -> Line {
+        SegmentCallbackData {
+            start_timestamp,
+            end_timestamp,
+            text: text.to_owned(),
+            confidence: 1.0,
+            language: None,
+            words: None,
+        }
+        .into()
+    }
+
+    #[test]
+    fn messy_segment_spacing_gets_normalized() {
+        for (messy, clean) in [
+            (" Hi", "Hi"),
+            ("  Hello,  world.  ", "Hello, world."),
+            ("\tOne.\n\nTwo  three", "One. Two three"),
+            ("   ", ""),
+        ] {
+            assert_eq!(line_with(0, 100, messy).text, clean);
+        }
+    }
+
+    #[test]
+    fn tokens_get_glued_into_words() {
+        let token = |start_centis, text: &str| Word {
+            start_centis,
+            end_centis: start_centis + 10,
+            text: text.to_owned(),
+        };
+        let tokens = [
+            token(0, " Ram"),
+            token(10, "bot"),
+            token(20, " transcri"),
+            token(30, "bes"),
+            token(40, "."),
+        ];
+        let words: Vec<_> = tokens_to_words(tokens)
+            .into_iter()
+            .map(|word| (word.start_centis, word.end_centis, word.text))
+            .collect();
+        assert_eq!(
+            words,
+            [
+                (0, 20, "Rambot".to_owned()),
+                (20, 50, "transcribes.".to_owned())
+            ]
+        );
+    }
+
+    #[test]
+    fn negative_timestamps_clamp_to_zero() {
+        let line = line_at(-12, 250);
+        assert_eq!((line.start_secs, line.end_secs), (0, 2));
+        let line = line_at(i64::MIN, -1);
+        assert_eq!((line.start_secs, line.end_secs), (0, 0));
+    }
+
+    #[test]
+    fn default_template_matches_the_usual_format() {
+        let line = line_at(6_100, 6_200);
+        let template = "{ts} {text}".parse().unwrap();
+        assert_eq!(
+            line.to_telegram_line(None, Some(&template)),
+            line.to_telegram_line(None, None)
+        );
+        assert_eq!(line.to_telegram_line(None, None), "`01:01` Hi");
+    }
+
+    #[test]
+    fn templates_escape_their_literals() {
+        let line = line_at(0, 100);
+        let template: LineTemplate = "[{ts}] - {text}".parse().unwrap();
+        assert_eq!(
+            line.to_telegram_line(None, Some(&template)),
+            r"\[`00:00`\] \- Hi"
+        );
+    }
+
+    #[test]
+    fn bad_templates_get_rejected() {
+        for bad in [
+            "{ts}",
+            "{ts} {txt}",
+            "{text",
+            "text}",
+            &"x{text}".repeat(50),
+        ] {
+            assert!(bad.parse::<LineTemplate>().is_err(), "{bad:?}");
+        }
+        assert!("{text}".parse::<LineTemplate>().is_ok());
+    }
+
+    #[test]
+    fn wrapping_leaves_room_for_the_timestamp() {
+        // `00:00 ` takes up the first 6 columns
+        let line = line_with(0, 100, "one two three four");
+        assert_eq!(
+            line.to_telegram_line(Some(10), None),
+            "`00:00` one\ntwo three\nfour"
+        );
+        // And so does everything a template puts before the text
+        let template: LineTemplate = "[{ts}] - {text}".parse().unwrap();
+        assert_eq!(
+            line.to_telegram_line(Some(13), Some(&template)),
+            "\\[`00:00`\\] \\- one\ntwo three\nfour"
+        );
+        // Unless the text starts on a row of its own
+        let template: LineTemplate = "{ts}\n{text}".parse().unwrap();
+        assert_eq!(
+            line.to_telegram_line(Some(10), Some(&template)),
+            "`00:00`\none two\nthree four"
+        );
+        // Words that don't fit next to the timestamp go on the next row instead of getting split
+        assert_eq!(wrap_text("one two", 8, 6), ["", "one two"]);
+    }
+
+    #[test]
+    fn wrapping_never_splits_wide_graphemes() {
+        // Each of these takes up two columns and CJK doesn't put spaces between its words
+        assert_eq!(
+            wrap_text("你好世界你好世界", 10, 6),
+            ["你好", "世界你好世", "界"]
+        );
+        // Skin tones stick with their emoji
+        assert_eq!(wrap_text("👍🏽👍🏽👍🏽👍🏽", 6, 4), ["👍🏽", "👍🏽👍🏽👍🏽"]);
+        // Family emoji are a bunch of them glued together with zero width joiners
+        let family = "👨\u{200d}👩\u{200d}👧";
+        assert_eq!(
+            wrap_text(&format!("hi {family}{family}"), 3, 0),
+            ["hi", family, family]
+        );
+        // So are the rocket and the rest of the transport and misc symbol emoji
+        assert_eq!(wrap_text("🚀🚀⚡⚡", 4, 0), ["🚀🚀", "⚡⚡"]);
+        // Vowel signs stay on their consonants and viramas glue the consonants around them together
+        assert_eq!(wrap_text("नमस्ते", 1, 0), ["न", "म", "स्ते"]);
+        assert_eq!(wrap_text("नमस्ते नमस्ते", 8, 0), ["नमस्ते", "नमस्ते"]);
+        // Same for Hangul conjoining jamo
+        assert_eq!(
+            wrap_text("\u{1112}\u{1161}\u{11ab}한", 4, 0),
+            ["\u{1112}\u{1161}\u{11ab}한"]
+        );
+    }
+
+    /// Collapses the lines the way that the handler does with an opted in threshold
+    fn collapse(texts: &[&str], threshold: f32) -> Vec<(String, u32)> {
+        let mut lines: Vec<Line> = Vec::new();
+        for (i, text) in (0..).zip(texts) {
+            let line = line_with(i * 100, i * 100 + 100, text);
+            let is_absorbed = lines
+                .last_mut()
+                .is_some_and(|last| last.absorb_similar(&line, threshold));
+            if !is_absorbed {
+                lines.push(line);
+            }
         }
+        lines
+            .into_iter()
+            .map(|line| (line.text, line.repeats))
+            .collect()
+    }
+
+    /// Merges the lines the way that the handler does with an opted in char budget. Each text is
+    /// given as the centi-second it starts at
+    fn merge(texts: &[(i64, &str)], max_chars: usize) -> Vec<(u32, u32, String)> {
+        let mut lines: Vec<Line> = Vec::new();
+        for &(start, text) in texts {
+            let line = line_with(start, start + 100, text);
+            let is_absorbed = lines
+                .last_mut()
+                .is_some_and(|last| last.absorb_fragment_within(&line, max_chars));
+            if !is_absorbed {
+                lines.push(line);
+            }
+        }
+        lines
+            .into_iter()
+            .map(|line| (line.start_secs, line.end_secs, line.text))
+            .collect()
+    }
+
+    #[test]
+    fn fragments_stay_unless_opted_into() {
+        // Nothing sets RAMBOT_MERGE_MAX_CHARS for the tests
+        assert_eq!(LineStyle::from_env().merge_max_chars, None);
+        let mut line = line_with(0, 100, "so");
+        assert!(!line.absorb_fragment(&line_with(100, 200, "anyway")));
+    }
+
+    #[test]
+    fn fragments_merge_up_to_a_sentence_boundary() {
+        let texts = [
+            (0, "So"),
+            (100, "I was"),
+            (200, "thinking."),
+            (300, "Want to"),
+            (400, "grab lunch?"),
+            (500, "Sure"),
+        ];
+        assert_eq!(
+            merge(&texts, 100),
+            [
+                (0, 3, "So I was thinking.".to_owned()),
+                (3, 5, "Want to grab lunch?".to_owned()),
+                (5, 6, "Sure".to_owned()),
+            ]
+        );
+
+        // Quotes and CJK punctuation still end a sentence
+        let texts = [(0, "He said \"stop.\""), (100, "好的。"), (200, "Then")];
+        assert_eq!(merge(&texts, 100).len(), 3);
+    }
+
+    #[test]
+    fn fragments_stop_merging_at_the_budgets() {
+        // "one two three" would be past the 10 char budget
+        let texts = [(0, "one"), (100, "two"), (200, "three"), (300, "four")];
+        assert_eq!(
+            merge(&texts, 10),
+            [
+                (0, 2, "one two".to_owned()),
+                (2, 4, "three four".to_owned()),
+            ]
+        );
+
+        // And a merged line never spans past `MERGE_MAX_SECS`
+        let texts = [(0, "so"), (500, "um"), (1_000, "well")];
+        assert_eq!(
+            merge(&texts, 100),
+            [(0, 6, "so um".to_owned()), (10, 11, "well".to_owned())]
+        );
+    }
+
+    #[test]
+    fn repeats_stay_unless_opted_into() {
+        // Nothing sets RAMBOT_REPEAT_SIMILARITY for the tests
+        assert_eq!(LineStyle::from_env().repeat_similarity, None);
+        let mut line = line_with(0, 100, "Thanks for watching!");
+        assert!(!line.absorb_repeat(&line.clone()));
+    }
+
+    #[test]
+    fn hallucinated_loops_get_collapsed() {
+        // The classic end of video sign off on silence
+        let texts = ["Thanks for watching!"; 30];
+        assert_eq!(
+            collapse(&texts, 0.9),
+            [("Thanks for watching!".to_owned(), 29)]
+        );
+
+        // Slight variations in case and punctuation are still the same loop
+        let texts = [
+            "Subscribe to my channel.",
+            "subscribe to my channel",
+            "Subscribe to my channel!",
+            "Subscribe to my channel.",
+        ];
+        assert_eq!(
+            collapse(&texts, 0.9),
+            [("Subscribe to my channel.".to_owned(), 3)]
+        );
+
+        // Loops that break up resume as their own line afterwards
+        let texts = ["Okay.", "Okay.", "So anyway, the plan.", "Okay.", "Okay."];
+        assert_eq!(
+            collapse(&texts, 0.9),
+            [
+                ("Okay.".to_owned(), 1),
+                ("So anyway, the plan.".to_owned(), 0),
+                ("Okay.".to_owned(), 1)
+            ]
+        );
+    }
+
+    #[test]
+    fn similar_but_different_lines_stay() {
+        let texts = [
+            "I went to the store.",
+            "I went to the park.",
+            "Then I went home.",
+        ];
+        let collapsed = collapse(&texts, 0.9);
+        assert_eq!(collapsed.len(), 3);
+        // A looser threshold does catch them though
+        assert_eq!(collapse(&texts[..2], 0.5).len(), 1);
+    }
+
+    #[test]
+    fn huge_timestamps_saturate() {
+        let line = line_at(i64::from(u32::MAX) * 100 + 100, i64::MAX);
+        assert_eq!((line.start_secs, line.end_secs), (u32::MAX, u32::MAX));
+    }
+
+    #[test]
+    fn markdown_in_speech_stays_as_is() {
+        assert_eq!(
+            escape_markdown_v2("snake_case and *stars*"),
+            r"snake\_case and \*stars\*"
+        );
+        assert_eq!(
+            escape_markdown_v2(r"C:\path [1/3] (v2.0)!"),
+            r"C:\\path \[1/3\] \(v2\.0\)\!"
+        );
+        assert_eq!(escape_markdown_v2("~`>#+-=|{}"), r"\~\`\>\#\+\-\=\|\{\}");
+        // Only the reserved characters get touched
+        assert_eq!(
+            escape_markdown_v2("Grüß dich, 🐏 & co"),
+            "Grüß dich, 🐏 & co"
+        );
+        assert_eq!(escape_markdown_v2(""), "");
     }
 }
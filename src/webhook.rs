@@ -0,0 +1,118 @@
+//! POSTs finished transcriptions off to an HTTP endpoint for external integrations
+//!
+//! Deliveries are fire-and-forget, so a slow or broken endpoint never holds up the transcription
+//! itself. When a secret is configured each body gets signed with HMAC-SHA256 in the
+//! `X-Rambot-Signature-256` header (`sha256=<hex digest>`) so that receivers can check that it
+//! actually came from us
+
+use std::time::Duration;
+
+use crate::Line;
+
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use sha2::Sha256;
+
+const SIGNATURE_HEADER: &str = "X-Rambot-Signature-256";
+/// Keeps an endpoint that never answers from piling up deliveries in the background
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone)]
+pub struct Webhook {
+    client: reqwest::Client,
+    url: reqwest::Url,
+    secret: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct Payload {
+    pub chat_id: i64,
+    pub message_id: i32,
+    /// The voice message's author
+    pub user_id: u64,
+    pub text: String,
+    pub lines: Vec<Line>,
+    /// Replaces an earlier transcript of the same voice message after its audio got swapped out
+    pub edited: bool,
+}
+
+impl Webhook {
+    /// Configured through `RAMBOT_WEBHOOK_URL` and optionally `RAMBOT_WEBHOOK_SECRET`. `None` when
+    /// the url isn't set or isn't valid
+    pub fn from_env() -> Option<Self> {
+        let url = std::env::var("RAMBOT_WEBHOOK_URL").ok()?;
+        let url = match reqwest::Url::parse(&url) {
+            Ok(url) => url,
+            Err(e) => {
+                log::warn!("Ignoring invalid RAMBOT_WEBHOOK_URL: {e}");
+                return None;
+            }
+        };
+        let secret = std::env::var("RAMBOT_WEBHOOK_SECRET").ok();
+        if secret.is_none() {
+            log::info!("RAMBOT_WEBHOOK_SECRET isn't set. Webhook payloads won't be signed");
+        }
+
+        let client = match reqwest::Client::builder().timeout(TIMEOUT).build() {
+            Ok(client) => client,
+            Err(e) => {
+                log::warn!("Failed setting up the webhook's HTTP client: {e}");
+                return None;
+            }
+        };
+
+        Some(Self {
+            client,
+            url,
+            secret,
+        })
+    }
+
+    /// Sends the payload off in the background and only logs if it fails
+    pub fn send(&self, payload: Payload) {
+        let webhook = self.clone();
+        tokio::spawn(async move {
+            if let Err(e) = webhook.post(&payload).await {
+                log::warn!(
+                    "Failed delivering message {} in chat {} to the webhook: {e}",
+                    payload.message_id,
+                    payload.chat_id
+                );
+            }
+        });
+    }
+
+    async fn post(&self, payload: &Payload) -> Result<(), reqwest::Error> {
+        let body = serde_json::to_vec(payload).expect("Payloads are always valid JSON");
+        let mut req = self
+            .client
+            .post(self.url.clone())
+            .header(reqwest::header::CONTENT_TYPE, "application/json");
+        if let Some(secret) = &self.secret {
+            req = req.header(SIGNATURE_HEADER, format!("sha256={}", sign(secret, &body)));
+        }
+        req.body(body).send().await?.error_for_status()?;
+        Ok(())
+    }
+}
+
+fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any size");
+    mac.update(body);
+    hex::encode(mac.finalize().into_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signatures_match_hmac_sha256() {
+        // Test case 2 from RFC 4231
+        assert_eq!(
+            sign("Jefe", b"what do ya want for nothing?"),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+}